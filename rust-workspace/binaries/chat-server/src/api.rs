@@ -0,0 +1,481 @@
+//! The JSON HTTP API of a chat-server instance.
+//!
+//! All routes are versioned and served under `/api/v1`. Responses are JSON (`application/json`),
+//! including errors, which are returned as an [`ErrorResponse`]. The OpenAPI document describing
+//! the API is served at `/api/v1/openapi.json`.
+//!
+//! Until the next release, the routes are also served without the prefix, like before the API was
+//! versioned, along with `GET /messages` for the messages of all joined channels. These responses
+//! carry a `Deprecation: true` header.
+
+use std::{convert::Infallible, sync::Arc, time::UNIX_EPOCH};
+
+use anyhow::Result;
+use dashmap::{mapref::entry::Entry, DashMap};
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, OpenApi, ToSchema};
+use warp::{
+    body::BodyDeserializeError,
+    http::StatusCode,
+    reject::{
+        InvalidHeader, InvalidQuery, LengthRequired, MethodNotAllowed, MissingHeader,
+        PayloadTooLarge, UnsupportedMediaType,
+    },
+    reply::Response,
+    Filter, Rejection, Reply,
+};
+
+use common::{
+    forwarder_health::{ForwarderHealth, ForwarderStatus},
+    ChatMessage, MessageId,
+};
+
+use crate::{
+    channel_subscriber::ConnectionState,
+    chat_server::{ChannelLease, ChatServer, HistoryBackfill, SubscriptionState},
+    message_validation::{validate_channel_name, MessageValidationError},
+    websocket::websocket_route,
+};
+
+/// Limits the size of request bodies, in bytes.
+pub const MAX_REQUEST_BODY_SIZE: u64 = 64 * 1024;
+/// The number of messages returned per page if no limit is given.
+pub const DEFAULT_PAGE_SIZE: usize = 100;
+/// The maximum number of messages returned per page.
+pub const MAX_PAGE_SIZE: usize = 1000;
+
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "chat-server"),
+    paths(
+        node_info,
+        list_subscriptions,
+        join_channel,
+        leave_channel,
+        channel_messages,
+        send_message,
+    ),
+    components(schemas(
+        ChatMessage,
+        ConnectionState,
+        ErrorResponse,
+        ForwarderHealth,
+        ForwarderStatus,
+        HistoryBackfill,
+        MessagePage,
+        NodeInfo,
+        SendMessageRequest,
+        Subscription,
+    ))
+)]
+pub struct ApiDoc;
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct ErrorResponse {
+    pub error: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct NodeInfo {
+    /// The name of the instance, e.g. its pod name.
+    pub node_name: String,
+    pub version: String,
+    pub joined_channels: usize,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct Subscription {
+    pub channel: String,
+    /// When the channel was joined, in milliseconds since the Unix epoch; not set while it is
+    /// being joined.
+    pub joined_at: Option<u64>,
+    /// The number of messages of the channel kept in memory.
+    pub message_count: usize,
+    pub history_backfill: HistoryBackfill,
+    /// Whether the channel is connected to the message broker; not set if this is not tracked.
+    pub connection: Option<ConnectionState>,
+    /// Whether received messages are still added to the channel's history; not set while the
+    /// channel is being joined.
+    pub forwarder: Option<ForwarderHealth>,
+    /// Whether the channel was joined via the API, i.e. whether it can be left via the API.
+    pub joined_via_api: bool,
+}
+
+/// A page of messages, ordered from oldest to newest.
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct MessagePage {
+    pub messages: Vec<ChatMessage>,
+    /// Pass this as `before` to retrieve the preceding page; not set if there are no older
+    /// messages.
+    #[schema(value_type = Option<String>)]
+    pub next_before: Option<MessageId>,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct MessagePageQuery {
+    /// Only return messages preceding the message with this id.
+    #[param(value_type = Option<String>)]
+    pub before: Option<MessageId>,
+    /// Return at most this many messages (at most 1000, 100 by default).
+    pub limit: Option<usize>,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct SendMessageRequest {
+    pub message_text: String,
+}
+
+/// The channels joined via the API, see [`Api::join`].
+#[derive(Clone)]
+pub struct Api {
+    chat_server: ChatServer,
+    channel_leases: Arc<DashMap<String, ChannelLease>>,
+    node_name: String,
+}
+
+impl Api {
+    pub fn new(chat_server: ChatServer, node_name: String) -> Self {
+        Api {
+            chat_server,
+            channel_leases: Default::default(),
+            node_name,
+        }
+    }
+
+    /// Joins the channel until it is left via [`Api::leave`], regardless of any connected clients.
+    ///
+    /// Returns whether the channel was newly joined via the API.
+    pub async fn join(&self, channel_name: &str) -> Result<bool> {
+        if self.channel_leases.contains_key(channel_name) {
+            return Ok(false);
+        }
+
+        // Subscribe before inserting, so that the map is not locked while joining.
+        let channel_lease = self.chat_server.subscribe(channel_name).await?;
+        match self.channel_leases.entry(channel_name.to_string()) {
+            Entry::Occupied(_) => Ok(false),
+            Entry::Vacant(entry) => {
+                entry.insert(channel_lease);
+                Ok(true)
+            }
+        }
+    }
+
+    /// Returns the state of the channel, or `None` if it is not joined.
+    pub fn subscription(&self, channel_name: &str) -> Option<Subscription> {
+        let subscription_state = self.chat_server.subscription_state(channel_name)?;
+
+        Some(self.to_subscription(subscription_state))
+    }
+
+    /// Returns the state of every joined channel, whether joined via the API or by connected
+    /// clients.
+    pub fn subscriptions(&self) -> Vec<Subscription> {
+        self.chat_server
+            .subscription_states()
+            .into_iter()
+            .map(|subscription_state| self.to_subscription(subscription_state))
+            .collect()
+    }
+
+    fn to_subscription(&self, subscription_state: SubscriptionState) -> Subscription {
+        let joined_at = subscription_state.joined_at.map(|joined_at| {
+            joined_at
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64
+        });
+
+        Subscription {
+            joined_via_api: self
+                .channel_leases
+                .contains_key(&subscription_state.channel_name),
+            channel: subscription_state.channel_name,
+            joined_at,
+            message_count: subscription_state.message_count,
+            history_backfill: subscription_state.history_backfill,
+            connection: subscription_state.connection_state,
+            forwarder: subscription_state.forwarder_health,
+        }
+    }
+
+    /// Releases the channel joined via [`Api::join`]. The channel stays joined as long as clients
+    /// are connected to it.
+    ///
+    /// Returns whether the channel had been joined via the API.
+    pub fn leave(&self, channel_name: &str) -> bool {
+        self.channel_leases.remove(channel_name).is_some()
+    }
+
+    /// All routes of the API, including the WebSocket endpoint.
+    pub fn routes(&self) -> impl Filter<Extract = (impl Reply,), Error = Infallible> + Clone {
+        let api = warp::path("api").and(warp::path("v1"));
+
+        let node_info_route = warp::path!("node")
+            .and(warp::get())
+            .and(self.with_api())
+            .map(node_info);
+
+        let list_subscriptions_route = warp::path!("subscriptions")
+            .and(warp::get())
+            .and(self.with_api())
+            .map(list_subscriptions);
+
+        let join_channel_route = warp::path!("subscriptions" / String)
+            .and(warp::put())
+            .and(self.with_api())
+            .then(join_channel);
+
+        let leave_channel_route = warp::path!("subscriptions" / String)
+            .and(warp::delete())
+            .and(self.with_api())
+            .map(leave_channel);
+
+        let channel_messages_route = warp::path!("channels" / String / "messages")
+            .and(warp::get())
+            .and(warp::query::<MessagePageQuery>())
+            .and(self.with_api())
+            .then(channel_messages);
+
+        let send_message_route = warp::path!("channels" / String / "messages")
+            .and(warp::post())
+            .and(warp::body::content_length_limit(MAX_REQUEST_BODY_SIZE))
+            .and(warp::body::json())
+            .and(self.with_api())
+            .then(send_message);
+
+        let openapi_route = warp::path!("openapi.json")
+            .and(warp::get())
+            .map(|| warp::reply::json(&ApiDoc::openapi()));
+
+        let versioned_routes = node_info_route
+            .or(list_subscriptions_route)
+            .or(join_channel_route)
+            .or(leave_channel_route)
+            .or(channel_messages_route)
+            .or(send_message_route)
+            .or(openapi_route)
+            .or(websocket_route(self.chat_server.clone()));
+
+        // The messages of all joined channels, which was only served before versioning the API.
+        let all_messages_route = warp::path!("messages")
+            .and(warp::get())
+            .and(self.with_api())
+            .map(|api: Api| warp::reply::json(&api.chat_server.messages_received()));
+
+        // The paths before versioning the API, kept as deprecated aliases for one release.
+        let unversioned_routes = versioned_routes
+            .clone()
+            .or(all_messages_route)
+            .map(|reply| warp::reply::with_header(reply, "Deprecation", "true"));
+
+        api.and(versioned_routes)
+            .or(unversioned_routes)
+            .recover(handle_rejection)
+    }
+
+    fn with_api(&self) -> impl Filter<Extract = (Api,), Error = Infallible> + Clone {
+        let api = self.clone();
+        warp::any().map(move || api.clone())
+    }
+}
+
+fn json_response<T: Serialize>(body: &T, status_code: StatusCode) -> Response {
+    warp::reply::with_status(warp::reply::json(body), status_code).into_response()
+}
+
+fn error_response<E: ToString>(error: E, status_code: StatusCode) -> Response {
+    let error = error.to_string();
+    json_response(&ErrorResponse { error }, status_code)
+}
+
+/// Information about this chat-server instance.
+#[utoipa::path(
+    get,
+    path = "/api/v1/node",
+    responses((status = 200, body = NodeInfo))
+)]
+fn node_info(api: Api) -> Response {
+    let node_info = NodeInfo {
+        node_name: api.node_name.clone(),
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        joined_channels: api.chat_server.joined_channels().len(),
+    };
+
+    json_response(&node_info, StatusCode::OK)
+}
+
+/// Lists all channels joined by this instance, whether via the API or by connected clients.
+#[utoipa::path(
+    get,
+    path = "/api/v1/subscriptions",
+    responses((status = 200, body = [Subscription]))
+)]
+fn list_subscriptions(api: Api) -> Response {
+    json_response(&api.subscriptions(), StatusCode::OK)
+}
+
+/// Joins the channel until it is left via the API.
+#[utoipa::path(
+    put,
+    path = "/api/v1/subscriptions/{channel}",
+    params(("channel" = String, Path, description = "The name of the channel")),
+    responses(
+        (status = 201, description = "The channel was joined", body = Subscription),
+        (status = 200, description = "The channel had already been joined", body = Subscription),
+        (status = 400, description = "Invalid channel name", body = ErrorResponse),
+        (status = 409, description = "The channel was left concurrently", body = ErrorResponse),
+        (status = 500, body = ErrorResponse),
+    )
+)]
+async fn join_channel(channel_name: String, api: Api) -> Response {
+    if let Err(err) = validate_channel_name(&channel_name) {
+        return error_response(err, StatusCode::BAD_REQUEST);
+    }
+
+    match api.join(&channel_name).await {
+        Ok(is_newly_joined) => {
+            let status_code = match is_newly_joined {
+                true => StatusCode::CREATED,
+                false => StatusCode::OK,
+            };
+            match api.subscription(&channel_name) {
+                Some(subscription) => json_response(&subscription, status_code),
+                None => error_response(
+                    format!("channel {channel_name} was left while joining it"),
+                    StatusCode::CONFLICT,
+                ),
+            }
+        }
+        Err(err) => error_response(err, StatusCode::INTERNAL_SERVER_ERROR),
+    }
+}
+
+/// Leaves a channel that was joined via the API. The channel stays joined as long as clients are
+/// connected to it.
+#[utoipa::path(
+    delete,
+    path = "/api/v1/subscriptions/{channel}",
+    params(("channel" = String, Path, description = "The name of the channel")),
+    responses(
+        (status = 204, description = "The channel was left"),
+        (status = 404, description = "The channel had not been joined via the API", body = ErrorResponse),
+    )
+)]
+fn leave_channel(channel_name: String, api: Api) -> Response {
+    match api.leave(&channel_name) {
+        true => StatusCode::NO_CONTENT.into_response(),
+        false => error_response(
+            format!("channel {channel_name} has not been joined via the API"),
+            StatusCode::NOT_FOUND,
+        ),
+    }
+}
+
+/// Returns a page of the channel's messages, starting with the most recent ones.
+#[utoipa::path(
+    get,
+    path = "/api/v1/channels/{channel}/messages",
+    params(
+        ("channel" = String, Path, description = "The name of the channel"),
+        MessagePageQuery,
+    ),
+    responses(
+        (status = 200, body = MessagePage),
+        (status = 404, description = "The channel is not joined", body = ErrorResponse),
+        (status = 500, body = ErrorResponse),
+    )
+)]
+async fn channel_messages(channel_name: String, query: MessagePageQuery, api: Api) -> Response {
+    let limit = query.limit.unwrap_or(DEFAULT_PAGE_SIZE).min(MAX_PAGE_SIZE);
+
+    // Fetch one more message than requested, to know whether there is a preceding page.
+    let messages = api
+        .chat_server
+        .recent_messages(&channel_name, query.before, limit + 1)
+        .await;
+
+    match messages {
+        Ok(Some(mut messages)) => {
+            let has_preceding_page = messages.len() > limit;
+            if has_preceding_page {
+                messages.remove(0);
+            }
+            let next_before = match has_preceding_page {
+                true => messages.first().map(|chat_message| chat_message.id),
+                false => None,
+            };
+
+            let message_page = MessagePage {
+                messages,
+                next_before,
+            };
+            json_response(&message_page, StatusCode::OK)
+        }
+        Ok(None) => error_response(
+            format!("channel {channel_name} is not joined"),
+            StatusCode::NOT_FOUND,
+        ),
+        Err(err) => error_response(err, StatusCode::INTERNAL_SERVER_ERROR),
+    }
+}
+
+/// Sends a message to the channel. The channel does not need to be joined.
+#[utoipa::path(
+    post,
+    path = "/api/v1/channels/{channel}/messages",
+    params(("channel" = String, Path, description = "The name of the channel")),
+    request_body = SendMessageRequest,
+    responses(
+        (status = 201, description = "The message was stored and published", body = ChatMessage),
+        (status = 400, description = "Invalid message", body = ErrorResponse),
+        (status = 500, body = ErrorResponse),
+    )
+)]
+async fn send_message(channel_name: String, request: SendMessageRequest, api: Api) -> Response {
+    match api
+        .chat_server
+        .send_message(&channel_name, &request.message_text)
+        .await
+    {
+        Ok(chat_message) => json_response(&chat_message, StatusCode::CREATED),
+        Err(err) => {
+            let status_code = match err.downcast_ref::<MessageValidationError>() {
+                Some(_) => StatusCode::BAD_REQUEST,
+                None => StatusCode::INTERNAL_SERVER_ERROR,
+            };
+            error_response(err, status_code)
+        }
+    }
+}
+
+/// Turns rejections, e.g. for unknown routes or malformed requests, into JSON errors.
+///
+/// Rejections that are not handled explicitly are reported as an unknown route, without exposing
+/// their details.
+async fn handle_rejection(rejection: Rejection) -> Result<Response, Infallible> {
+    // More specific rejections take precedence, since every route that did not match the path or
+    // method also contributes a rejection.
+    let (error, status_code) = if let Some(err) = rejection.find::<BodyDeserializeError>() {
+        (err.to_string(), StatusCode::BAD_REQUEST)
+    } else if let Some(err) = rejection.find::<InvalidQuery>() {
+        (err.to_string(), StatusCode::BAD_REQUEST)
+    } else if let Some(err) = rejection.find::<PayloadTooLarge>() {
+        (err.to_string(), StatusCode::PAYLOAD_TOO_LARGE)
+    } else if let Some(err) = rejection.find::<LengthRequired>() {
+        (err.to_string(), StatusCode::LENGTH_REQUIRED)
+    } else if let Some(err) = rejection.find::<UnsupportedMediaType>() {
+        (err.to_string(), StatusCode::UNSUPPORTED_MEDIA_TYPE)
+    } else if let Some(err) = rejection.find::<MissingHeader>() {
+        (err.to_string(), StatusCode::BAD_REQUEST)
+    } else if let Some(err) = rejection.find::<InvalidHeader>() {
+        (err.to_string(), StatusCode::BAD_REQUEST)
+    } else if let Some(err) = rejection.find::<MethodNotAllowed>() {
+        (err.to_string(), StatusCode::METHOD_NOT_ALLOWED)
+    } else {
+        ("Not Found".to_string(), StatusCode::NOT_FOUND)
+    };
+
+    Ok(error_response(error, status_code))
+}
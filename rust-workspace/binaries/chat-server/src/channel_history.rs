@@ -0,0 +1,194 @@
+//! The part of a channel's history that is kept in memory.
+//!
+//! A chat-server instance only caches the recent history of its channels; the full history is kept
+//! by the replication log. Which messages are kept is determined by the [`HistoryRetention`].
+
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{bail, Result};
+use common::{message_sink::MessageSink, ChatMessage, MessageId, SequenceNumber, SequencedMessage};
+use serde::{Deserialize, Serialize};
+
+/// How many sequence numbers of messages retrieved from the replication log are remembered per
+/// channel, see [`ChannelHistory::sequence_number`].
+pub const KNOWN_SEQUENCE_NUMBERS_CAPACITY: usize = 10_000;
+
+/// Limits on the history kept per channel. Messages are evicted oldest first once any of the limits
+/// is exceeded.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HistoryRetention {
+    /// Keep at most this many messages.
+    pub max_messages: Option<usize>,
+    /// Keep at most this many bytes of messages, see [`message_size`].
+    pub max_bytes: Option<usize>,
+    /// Keep messages only for this long after they were created.
+    #[serde(default, with = "humantime_serde")]
+    pub max_age: Option<Duration>,
+}
+
+impl HistoryRetention {
+    /// Keeps all messages.
+    pub fn unbounded() -> Self {
+        Self::default()
+    }
+
+    /// Rejects limits that would keep no messages at all.
+    pub fn validate(&self) -> Result<()> {
+        if self.max_messages == Some(0) {
+            bail!("the maximum number of messages must be positive");
+        }
+        if self.max_bytes == Some(0) {
+            bail!("the maximum number of bytes must be positive");
+        }
+        if self.max_age.is_some_and(|max_age| max_age.is_zero()) {
+            bail!("the maximum age must be positive");
+        }
+
+        Ok(())
+    }
+}
+
+/// The approximate number of bytes a message takes up in memory.
+pub fn message_size(chat_message: &ChatMessage) -> usize {
+    std::mem::size_of::<ChatMessage>()
+        + chat_message.channel.len()
+        + chat_message.message_text.len()
+}
+
+pub struct ChannelHistory {
+    retention: HistoryRetention,
+    messages: VecDeque<ChatMessage>,
+    size_in_bytes: usize,
+    /// Whether older messages have been evicted, i.e. whether the history is incomplete.
+    has_evicted_messages: bool,
+    /// The sequence numbers of the messages most recently retrieved from the replication log,
+    /// whether they are retained or not. Messages received live have no known sequence number.
+    known_sequence_numbers: HashMap<MessageId, SequenceNumber>,
+    /// The ids of `known_sequence_numbers` in the order they were inserted.
+    known_sequence_number_order: VecDeque<MessageId>,
+}
+
+impl ChannelHistory {
+    pub fn new(retention: HistoryRetention) -> Self {
+        ChannelHistory {
+            retention,
+            messages: VecDeque::new(),
+            size_in_bytes: 0,
+            has_evicted_messages: false,
+            known_sequence_numbers: HashMap::new(),
+            known_sequence_number_order: VecDeque::new(),
+        }
+    }
+
+    /// Returns the retained messages, oldest first.
+    pub fn messages(&mut self) -> Vec<ChatMessage> {
+        self.evict_expired_messages();
+
+        self.messages.iter().cloned().collect()
+    }
+
+    /// The number of retained messages, including expired ones that have not been evicted yet.
+    pub fn len(&self) -> usize {
+        self.messages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+
+    /// Adds the newest message, evicting older messages according to the retention.
+    pub fn push(&mut self, chat_message: ChatMessage) {
+        self.size_in_bytes += message_size(&chat_message);
+        self.messages.push_back(chat_message);
+
+        if let Some(max_messages) = self.retention.max_messages {
+            while self.messages.len() > max_messages {
+                self.evict_oldest_message();
+            }
+        }
+        if let Some(max_bytes) = self.retention.max_bytes {
+            while self.size_in_bytes > max_bytes {
+                self.evict_oldest_message();
+            }
+        }
+        self.evict_expired_messages();
+    }
+
+    /// Like [`push`](Self::push), for a message retrieved from the replication log.
+    pub fn push_sequenced(&mut self, message: SequencedMessage) {
+        self.record_sequence_numbers(std::slice::from_ref(&message));
+        self.push(message.chat_message);
+    }
+
+    /// Remembers the sequence numbers of messages retrieved from the replication log, whether they
+    /// are retained or not, forgetting the ones remembered first once
+    /// [`KNOWN_SEQUENCE_NUMBERS_CAPACITY`] is exceeded.
+    pub fn record_sequence_numbers(&mut self, messages: &[SequencedMessage]) {
+        for message in messages {
+            let message_id = message.chat_message.id;
+            if self
+                .known_sequence_numbers
+                .insert(message_id, message.sequence_number)
+                .is_none()
+            {
+                self.known_sequence_number_order.push_back(message_id);
+            }
+        }
+
+        while self.known_sequence_number_order.len() > KNOWN_SEQUENCE_NUMBERS_CAPACITY {
+            if let Some(message_id) = self.known_sequence_number_order.pop_front() {
+                self.known_sequence_numbers.remove(&message_id);
+            }
+        }
+    }
+
+    /// Returns the sequence number of the message, if it has been retrieved from the replication
+    /// log recently.
+    pub fn sequence_number(&self, message_id: &MessageId) -> Option<SequenceNumber> {
+        self.known_sequence_numbers.get(message_id).copied()
+    }
+
+    pub fn has_evicted_messages(&self) -> bool {
+        self.has_evicted_messages
+    }
+
+    /// Marks the history as incomplete without evicting anything, e.g. because older messages
+    /// could not be retrieved, so that they are fetched from the replication log when needed.
+    pub fn mark_incomplete(&mut self) {
+        self.has_evicted_messages = true;
+    }
+
+    fn evict_expired_messages(&mut self) {
+        let Some(max_age) = self.retention.max_age else {
+            return;
+        };
+        let Some(oldest_allowed_creation_time) = SystemTime::now().checked_sub(max_age) else {
+            return;
+        };
+
+        while self.messages.front().is_some_and(|oldest_message| {
+            oldest_message.id.datetime() < oldest_allowed_creation_time
+        }) {
+            self.evict_oldest_message();
+        }
+    }
+
+    fn evict_oldest_message(&mut self) {
+        if let Some(oldest_message) = self.messages.pop_front() {
+            self.size_in_bytes -= message_size(&oldest_message);
+            self.has_evicted_messages = true;
+        }
+    }
+}
+
+impl MessageSink<ChatMessage> for ChannelHistory {
+    fn send(&mut self, chat_message: ChatMessage) -> Result<()> {
+        self.push(chat_message);
+
+        Ok(())
+    }
+}
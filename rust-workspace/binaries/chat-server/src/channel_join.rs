@@ -0,0 +1,273 @@
+//! Joining a channel without missing any message.
+//!
+//! Joining consists of two parts: subscribing to the live messages of the channel and retrieving
+//! its history from the replication log. Both the replication log and the channel subscription
+//! receive the messages of a channel from the message broker in the order they were published.
+//! So once the replication log contains a message that we received live, it also contains every
+//! message that was published before it - this message serves as the synchronization point.
+//! (Messages sent through the replication log are even stored before they are published.)
+//!
+//! The join protocol therefore looks as follows:
+//! 1. Subscribe to the channel; live messages are buffered by the subscription stream.
+//! 2. Retrieve the (recent) history of the channel, see [`ReplicationLogClient::bootstrap`], or
+//!    only its last messages if no more are kept anyway.
+//! 3. As soon as the first live message arrives, incrementally retrieve the history up to and
+//!    including this message (waiting until the replication log has caught up) and replay
+//!    everything we have not seen so far. If retrieving the history failed in step 2, the
+//!    retrieved messages make up the history instead of being replayed.
+//! 4. Afterwards, pass on the buffered and any further live messages.
+//!
+//! Messages that are contained in the history as well as in the live stream are only passed on
+//! once, as long as they are received within [`RecentMessageIds::new`]'s capacity of each other.
+
+use std::{collections::VecDeque, sync::Arc, time::Duration};
+
+use anyhow::{anyhow, Result};
+use futures::StreamExt;
+use tokio::time::Instant;
+
+use common::{
+    recent_message_ids::RecentMessageIds, ChatMessage, ChatMessageStream, SequenceNumber,
+    SequencedMessage,
+};
+
+use crate::replication_log_client::{MessagesRemoved, ReplicationLogClient};
+
+/// How long to wait for the replication log to catch up with the first live message.
+pub const HISTORY_SYNC_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long to wait before asking the replication log again after it failed, or if it does not
+/// support waiting for new messages.
+pub const HISTORY_SYNC_RETRY_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Receives the history of a channel that could not be retrieved when joining it, along with
+/// whether it has been synchronized with the live messages, see [`join_channel`].
+///
+/// Called every time messages of the history are retrieved along with a live message until the
+/// history has been synchronized.
+pub type LateHistorySink = Box<dyn FnMut(Vec<SequencedMessage>, Result<()>) + Send>;
+
+/// The messages of a channel before joining it.
+pub struct PreviousMessages {
+    pub messages: Vec<SequencedMessage>,
+    /// Whether older messages were omitted because the history was bootstrapped from a snapshot or
+    /// limited to the last messages.
+    pub has_omitted_messages: bool,
+}
+
+/// Joins the channel, returning its history and a stream of all messages after that.
+///
+/// If `history_limit` is given, only the channel's last `history_limit` messages are retrieved.
+///
+/// If the history cannot be retrieved, the error is returned instead of the history, but the
+/// stream is returned nonetheless: the history is then retrieved along with the first live
+/// message and passed to `late_history_sink`, instead of being passed on as live messages.
+///
+/// See the [module documentation](self) for the guarantees this provides.
+pub async fn join_channel(
+    channel_name: &str,
+    incoming_message_stream: ChatMessageStream,
+    replication_log_client: Arc<dyn ReplicationLogClient>,
+    history_limit: Option<usize>,
+    late_history_sink: LateHistorySink,
+) -> (Result<PreviousMessages>, ChatMessageStream) {
+    let previous_messages = match history_limit {
+        Some(history_limit) => {
+            replication_log_client
+                .get_messages_before(channel_name, None, history_limit)
+                .await
+        }
+        None => replication_log_client.bootstrap(channel_name).await,
+    };
+    let last_sequence_number = previous_messages
+        .iter()
+        .flatten()
+        .last()
+        .map_or(0, |message| message.sequence_number);
+
+    let mut seen_message_ids = RecentMessageIds::default();
+    let previous_messages = previous_messages.map(|previous_messages| PreviousMessages {
+        // Sequence numbers start at 1, so a history starting later is incomplete.
+        has_omitted_messages: previous_messages
+            .first()
+            .is_some_and(|message| message.sequence_number > 1),
+        messages: previous_messages
+            .into_iter()
+            .filter(|message| seen_message_ids.insert(message.chat_message.id))
+            .collect(),
+    });
+    let late_history_sink = previous_messages.is_err().then_some(late_history_sink);
+
+    let join_state = JoinState {
+        channel_name: channel_name.to_string(),
+        incoming_message_stream,
+        replication_log_client,
+        seen_message_ids,
+        last_sequence_number,
+        synchronized: false,
+        late_history_sink,
+        pending_messages: Default::default(),
+    };
+
+    (previous_messages, Box::pin(join_state.into_stream()))
+}
+
+struct JoinState {
+    channel_name: String,
+    incoming_message_stream: ChatMessageStream,
+    replication_log_client: Arc<dyn ReplicationLogClient>,
+    seen_message_ids: RecentMessageIds,
+    /// The sequence number of the last message we retrieved from the replication log.
+    last_sequence_number: SequenceNumber,
+    /// Whether the replication log has been seen to contain one of the live messages.
+    synchronized: bool,
+    /// Receives the retrieved history until it has been synchronized, if it could not be retrieved
+    /// when joining.
+    late_history_sink: Option<LateHistorySink>,
+    /// Messages that are ready to be passed on.
+    pending_messages: VecDeque<ChatMessage>,
+}
+
+impl JoinState {
+    fn into_stream(self) -> impl futures::Stream<Item = Result<ChatMessage>> + Send {
+        futures::stream::unfold(self, |mut state| async move {
+            loop {
+                if let Some(chat_message) = state.pending_messages.pop_front() {
+                    return Some((Ok(chat_message), state));
+                }
+
+                match state.incoming_message_stream.next().await? {
+                    Err(err) => return Some((Err(err), state)),
+                    Ok(chat_message) => {
+                        if !state.synchronized {
+                            state.catch_up_with_history(&chat_message).await;
+                        }
+
+                        if state.seen_message_ids.insert(chat_message.id) {
+                            state.pending_messages.push_back(chat_message);
+                        }
+                    }
+                }
+            }
+        })
+    }
+
+    /// Queues every message of the history up to the synchronization point that we have not seen
+    /// yet, or passes them to the late history sink, if there is one.
+    ///
+    /// If the replication log does not catch up in time, the live message is passed on anyway and
+    /// the next live message serves as the synchronization point instead.
+    async fn catch_up_with_history(&mut self, synchronization_point: &ChatMessage) {
+        let (new_messages, synchronized) = catch_up_with_history(
+            self.replication_log_client.as_ref(),
+            &self.channel_name,
+            &mut self.last_sequence_number,
+            synchronization_point,
+        )
+        .await;
+
+        if let Err(err) = &synchronized {
+            println!(
+                "Joining channel {} without synchronizing its history yet: {err:#}",
+                self.channel_name
+            );
+        }
+        self.synchronized = synchronized.is_ok();
+        let new_messages = new_messages
+            .into_iter()
+            .filter(|message| self.seen_message_ids.insert(message.chat_message.id));
+        match &mut self.late_history_sink {
+            Some(late_history_sink) => late_history_sink(new_messages.collect(), synchronized),
+            None => self
+                .pending_messages
+                .extend(new_messages.map(|message| message.chat_message)),
+        }
+        if self.synchronized {
+            self.late_history_sink = None;
+        }
+    }
+}
+
+/// Retrieves the history of the channel after `last_sequence_number` up to (excluding) the
+/// synchronization point, waiting until the replication log contains the synchronization point or
+/// [`HISTORY_SYNC_TIMEOUT`] has passed.
+///
+/// Returns the retrieved messages, along with an error if the synchronization point was not
+/// reached in time; `last_sequence_number` is advanced to the last retrieved message. Messages that
+/// have been removed from the replication log meanwhile are skipped.
+pub async fn catch_up_with_history(
+    replication_log_client: &dyn ReplicationLogClient,
+    channel_name: &str,
+    last_sequence_number: &mut SequenceNumber,
+    synchronization_point: &ChatMessage,
+) -> (Vec<SequencedMessage>, Result<()>) {
+    let deadline = Instant::now() + HISTORY_SYNC_TIMEOUT;
+    let mut retrieved_messages = Vec::new();
+    let mut last_error = None;
+
+    loop {
+        let remaining_time = deadline.saturating_duration_since(Instant::now());
+        if remaining_time.is_zero() {
+            return (
+                retrieved_messages,
+                Err(sync_timeout(synchronization_point, last_error)),
+            );
+        }
+        match replication_log_client
+            .wait_for_messages_since(channel_name, *last_sequence_number, remaining_time)
+            .await
+        {
+            Ok(new_messages) => {
+                let has_new_messages = !new_messages.is_empty();
+                // As long as the synchronization point is not contained, all new messages precede
+                // it.
+                for message in new_messages {
+                    if message.chat_message.id == synchronization_point.id {
+                        return (retrieved_messages, Ok(()));
+                    }
+
+                    *last_sequence_number = message.sequence_number;
+                    retrieved_messages.push(message);
+                }
+                if has_new_messages && Instant::now() < deadline {
+                    continue;
+                }
+            }
+            Err(err) => match err.downcast_ref::<MessagesRemoved>() {
+                // The removed messages cannot be caught up with anymore.
+                Some(messages_removed)
+                    if messages_removed.truncated_before.saturating_sub(1)
+                        > *last_sequence_number =>
+                {
+                    println!("Skipping removed messages of channel {channel_name}: {err}");
+                    *last_sequence_number = messages_removed.truncated_before.saturating_sub(1);
+                    continue;
+                }
+                _ => last_error = Some(err),
+            },
+        }
+
+        if Instant::now() + HISTORY_SYNC_RETRY_INTERVAL > deadline {
+            return (
+                retrieved_messages,
+                Err(sync_timeout(synchronization_point, last_error)),
+            );
+        }
+        tokio::time::sleep(HISTORY_SYNC_RETRY_INTERVAL).await;
+    }
+}
+
+/// The error of [`catch_up_with_history`] when the synchronization point was not reached in time.
+fn sync_timeout(
+    synchronization_point: &ChatMessage,
+    last_error: Option<anyhow::Error>,
+) -> anyhow::Error {
+    let timeout = anyhow!(
+        "the replication log did not contain message {} within {HISTORY_SYNC_TIMEOUT:?}",
+        synchronization_point.id
+    );
+
+    match last_error {
+        Some(last_error) => last_error.context(timeout),
+        None => timeout,
+    }
+}
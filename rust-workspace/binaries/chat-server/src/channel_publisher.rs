@@ -0,0 +1,36 @@
+//! Publishing the messages sent through the chat server, see [`ChannelPublisher`].
+//!
+//! Messages are published through the replication log by default, which stores them before
+//! publishing them to the message broker. [`RedisChannelPublisher`] publishes to the message broker
+//! directly, so its messages are only stored once the replication log receives them from there.
+
+use std::sync::Arc;
+
+use anyhow::Result;
+use async_trait::async_trait;
+
+pub use common::channel_publisher::{ChannelPublisher, RedisChannelPublisher};
+use common::ChatMessage;
+
+use crate::replication_log_client::ReplicationLogClient;
+
+/// Appends messages to the replication log, which publishes them once they have been stored.
+pub struct ReplicationLogChannelPublisher {
+    replication_log_client: Arc<dyn ReplicationLogClient>,
+}
+
+impl ReplicationLogChannelPublisher {
+    pub fn new(replication_log_client: Arc<dyn ReplicationLogClient>) -> Self {
+        ReplicationLogChannelPublisher {
+            replication_log_client,
+        }
+    }
+}
+
+#[async_trait]
+impl ChannelPublisher for ReplicationLogChannelPublisher {
+    /// Only returns once the message has been durably stored, see [`ReplicationLogClient::append`].
+    async fn publish(&self, chat_message: &ChatMessage) -> Result<()> {
+        self.replication_log_client.append(chat_message).await
+    }
+}
@@ -1,14 +1,43 @@
 use anyhow::Result;
 use async_trait::async_trait;
 use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
 
-use common::ChatMessageStream;
+use common::{ChatMessageStream, SequenceNumber};
 
 #[async_trait]
 pub trait ChannelSubscriber: Send + Sync {
     async fn subscribe(&self, channel_name: &str) -> Result<ChatMessageStream>;
+
+    /// The state of the connection to the message broker for the channel, if it is tracked.
+    fn connection_state(&self, _channel_name: &str) -> Option<ConnectionState> {
+        None
+    }
+
+    /// Records that the channel's messages up to the sequence number have been retrieved from the
+    /// replication log, e.g. when joining the channel. By default, this is ignored.
+    fn record_retrieved_messages(&self, _channel_name: &str, _sequence_number: SequenceNumber) {}
+}
+
+/// The state of a channel's connection to the message broker.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum ConnectionState {
+    Connected,
+    /// The connection was lost and has not been re-established yet; messages are backfilled once
+    /// it is.
+    Reconnecting {
+        /// The number of failed attempts to reconnect so far.
+        failed_attempts: u32,
+        last_error: String,
+    },
 }
 
+/// Subscribes to channels of the message broker with a single connection per subscription.
+///
+/// The stream of a subscription ends when the connection is lost, e.g. when the message broker
+/// restarts; see [`ReconnectingChannelSubscriber`](crate::reconnecting_subscriber::ReconnectingChannelSubscriber).
 pub struct RedisChannelSubscriber {
     pub redis_url: String,
 }
@@ -22,9 +51,9 @@ impl ChannelSubscriber for RedisChannelSubscriber {
 
         pubsub.subscribe(channel_name).await?;
 
-        let stream = pubsub
-            .into_on_message()
-            .map(common::chat_message_from_redis_msg);
+        let stream = pubsub.into_on_message().filter_map(|msg| {
+            futures::future::ready(common::chat_message_from_redis_msg(msg).transpose())
+        });
 
         Ok(Box::pin(stream))
     }
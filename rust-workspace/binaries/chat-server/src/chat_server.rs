@@ -1,109 +1,673 @@
-use std::sync::{Arc, Mutex};
+use std::{
+    collections::HashSet,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
 
 use anyhow::Result;
-use dashmap::{mapref::entry::Entry, DashMap};
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{broadcast, OnceCell};
+use utoipa::ToSchema;
 
-use common::{stream_to_vec_forwarder::StreamToVecForwarder, ChatMessage, ChatMessageStream};
+use common::{
+    forwarder_health::{ForwarderHealth, ItemErrorPolicy},
+    ingestion_queue::IngestionConfig,
+    message_sink::NotifyingSink,
+    stream_forwarder::StreamForwarder,
+    ChatMessage, ChatMessageStream, MessageId, SequenceNumber,
+};
 
-use crate::{channel_subscriber::ChannelSubscriber, replication_log_client::ReplicationLogClient};
+use crate::{
+    channel_history::{ChannelHistory, HistoryRetention},
+    channel_join::{join_channel, LateHistorySink},
+    channel_publisher::ChannelPublisher,
+    channel_subscriber::{ChannelSubscriber, ConnectionState},
+    message_validation::validate_message,
+    replication_log_client::ReplicationLogClient,
+};
+
+/// How many live messages are buffered for a slow receiver before it misses messages.
+pub const LIVE_MESSAGE_BUFFER_SIZE: usize = 1024;
+
+/// The state of a joined channel, see [`ChatServer::subscription_states`].
+#[derive(Clone, Debug)]
+pub struct SubscriptionState {
+    pub channel_name: String,
+    /// When joining the channel completed, or `None` while it is being joined.
+    pub joined_at: Option<SystemTime>,
+    /// The number of messages of the channel kept in memory.
+    pub message_count: usize,
+    pub history_backfill: HistoryBackfill,
+    /// Whether the channel is connected to the message broker, if the channel subscriber tracks
+    /// it.
+    pub connection_state: Option<ConnectionState>,
+    /// Whether received messages are still added to the channel's history, or `None` while the
+    /// channel is being joined.
+    pub forwarder_health: Option<ForwarderHealth>,
+}
+
+/// Whether the history of a channel was retrieved from the replication log when joining it.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum HistoryBackfill {
+    /// The channel is still being joined.
+    Pending,
+    Succeeded,
+    /// The channel was joined without its history; it is retrieved along with the first live
+    /// message, which then succeeds the backfill. Older messages can be requested via
+    /// [`ChatServer::recent_messages`] in the meantime.
+    Failed {
+        error: String,
+    },
+}
 
 #[derive(Clone)]
 pub struct ChatServer {
-    active_subscriptions: Arc<DashMap<String, ChannelSubscription>>,
+    /// The state of every joined channel, including its messages.
+    active_subscriptions: Arc<DashMap<String, ChannelState>>,
+    channel_publisher: Arc<dyn ChannelPublisher>,
     channel_subscriber: Arc<dyn ChannelSubscriber>,
-    messages_received: Arc<Mutex<Vec<ChatMessage>>>,
+    /// Which messages of a channel are kept in memory.
+    history_retention: HistoryRetention,
+    /// How the messages of a channel's stream are queued and added to its history.
+    ingestion_config: IngestionConfig,
+    /// How to handle erroneous items of a channel's message stream.
+    item_error_policy: ItemErrorPolicy,
+    /// How long to stay subscribed to a channel after its last lease has been dropped.
+    linger_period: Duration,
     replication_log_client: Arc<dyn ReplicationLogClient>,
 }
 
 impl ChatServer {
     pub fn new(
         channel_subscriber: Arc<dyn ChannelSubscriber>,
+        channel_publisher: Arc<dyn ChannelPublisher>,
         replication_log_client: Arc<dyn ReplicationLogClient>,
     ) -> Self {
         ChatServer {
             active_subscriptions: Default::default(),
+            channel_publisher,
             channel_subscriber,
-            messages_received: Default::default(),
+            history_retention: HistoryRetention::unbounded(),
+            ingestion_config: IngestionConfig::default(),
+            item_error_policy: ItemErrorPolicy::default(),
+            linger_period: Duration::ZERO,
             replication_log_client,
         }
     }
 
+    /// Stay subscribed to channels for the given period after their last lease has been dropped,
+    /// so that quickly re-joining a channel does not require fetching its history again.
+    pub fn with_linger_period(mut self, linger_period: Duration) -> Self {
+        self.linger_period = linger_period;
+        self
+    }
+
+    /// Limits the messages kept in memory per channel. Older messages are fetched from the
+    /// replication log when needed, see [`ChatServer::recent_messages`].
+    pub fn with_history_retention(mut self, history_retention: HistoryRetention) -> Self {
+        self.history_retention = history_retention;
+        self
+    }
+
+    /// Determines whether a channel stops receiving messages after an erroneous item of its
+    /// message stream, e.g. a message that could not be deserialized, or whether the item is
+    /// skipped. Aborts by default.
+    pub fn with_item_error_policy(mut self, item_error_policy: ItemErrorPolicy) -> Self {
+        self.item_error_policy = item_error_policy;
+        self
+    }
+
+    /// Bounds the messages of a channel that have been received but not yet added to its history,
+    /// and determines how many are added at once, see [`IngestionConfig`].
+    pub fn with_ingestion_config(mut self, ingestion_config: IngestionConfig) -> Self {
+        self.ingestion_config = ingestion_config;
+        self
+    }
+
+    /// Returns the names of all joined channels, ordered by name.
+    pub fn joined_channels(&self) -> Vec<String> {
+        let mut channel_names: Vec<String> = self
+            .active_subscriptions
+            .iter()
+            .map(|entry| entry.key().clone())
+            .collect();
+        channel_names.sort();
+
+        channel_names
+    }
+
+    /// Returns the state of every joined channel (including channels that are being joined),
+    /// ordered by name.
+    pub fn subscription_states(&self) -> Vec<SubscriptionState> {
+        let mut subscription_states: Vec<_> = self
+            .active_subscriptions
+            .iter()
+            .map(|entry| {
+                let connection_state = self.channel_subscriber.connection_state(entry.key());
+                entry
+                    .value()
+                    .subscription_state(entry.key(), connection_state)
+            })
+            .collect();
+        subscription_states.sort_by(|a, b| a.channel_name.cmp(&b.channel_name));
+
+        subscription_states
+    }
+
+    /// Returns the state of the channel, or `None` if it is not joined.
+    pub fn subscription_state(&self, channel_name: &str) -> Option<SubscriptionState> {
+        self.active_subscriptions
+            .get(channel_name)
+            .map(|channel_state| {
+                let connection_state = self.channel_subscriber.connection_state(channel_name);
+                channel_state.subscription_state(channel_name, connection_state)
+            })
+    }
+
+    /// Returns the messages of all joined channels, grouped by channel (ordered by name).
     pub fn messages_received(&self) -> Vec<ChatMessage> {
-        self.messages_received.lock().unwrap().clone()
-    }
-
-    /// Returns whether the subscription was newly created, similar to
-    /// [`HashSet::insert`](std::collections::HashSet::insert).
-    pub async fn subscribe(&self, channel_name: &str) -> Result<bool> {
-        match self.active_subscriptions.entry(channel_name.to_string()) {
-            Entry::Occupied(_) => Ok(false),
-            Entry::Vacant(empty_entry) => {
-                let incoming_message_stream =
-                    self.channel_subscriber.subscribe(channel_name).await?;
-
-                // Retrieve messages that were previously sent on the channel.
-                //
-                // There is a chance that this already contains messages that are also sent to
-                // incoming_message_stream - this could be avoided by identifying the messages via
-                // some sort of id.
-                //
-                // There is also a small chance that a message takes a long time to arrive at the
-                // replication_log_client but we're too late to receive it from the
-                // channel_subscriber - in this case we will never receive the message. This could
-                // be avoided by waiting a bit after subscribing to the channel before retrieving
-                // the previous messages.
-                let previous_messages = self
-                    .replication_log_client
-                    .get_messages_for_channel(channel_name)
-                    .await?;
-                {
-                    // Open a new block to reduce the scope in which the mutex is being held.
-                    let mut message_list = self.messages_received.lock().unwrap();
-                    message_list.extend(previous_messages);
+        self.joined_channels()
+            .iter()
+            .filter_map(|channel_name| self.messages_for_channel(channel_name))
+            .flatten()
+            .collect()
+    }
+
+    /// Returns the messages of the channel that are kept in memory, or `None` if the channel is not
+    /// joined (including while it is being joined, or if joining it failed).
+    pub fn messages_for_channel(&self, channel_name: &str) -> Option<Vec<ChatMessage>> {
+        self.active_subscriptions
+            .get(channel_name)
+            .filter(|channel_state| channel_state.is_joined())
+            .map(|channel_state| {
+                channel_state
+                    .messages
+                    .message_list
+                    .lock()
+                    .unwrap()
+                    .messages()
+            })
+    }
+
+    /// Returns the last `limit` messages of the channel preceding the message `before` (or the last
+    /// `limit` messages, if not given), or `None` if the channel is not joined (see
+    /// [`ChatServer::messages_for_channel`]).
+    ///
+    /// If not enough messages are kept in memory because older ones have been evicted, only the
+    /// missing messages are retrieved from the replication log. A `before` message that is neither
+    /// kept in memory nor has recently been retrieved from the replication log (e.g. as part of a
+    /// previous page) yields no messages.
+    pub async fn recent_messages(
+        &self,
+        channel_name: &str,
+        before: Option<MessageId>,
+        limit: usize,
+    ) -> Result<Option<Vec<ChatMessage>>> {
+        let channel_messages = match self.active_subscriptions.get(channel_name) {
+            Some(channel_state) if channel_state.is_joined() => Arc::clone(&channel_state.messages),
+            _ => return Ok(None),
+        };
+        let (mut messages, has_evicted_messages, before_sequence_number) = {
+            let mut channel_history = channel_messages.message_list.lock().unwrap();
+            (
+                channel_history.messages(),
+                channel_history.has_evicted_messages(),
+                before.and_then(|before| channel_history.sequence_number(&before)),
+            )
+        };
+        let end_index = match before {
+            Some(before) => messages
+                .iter()
+                .position(|chat_message| chat_message.id == before),
+            None => Some(messages.len()),
+        };
+
+        let Some(end_index) = end_index else {
+            // The page precedes the messages kept in memory.
+            let older_messages = match before_sequence_number {
+                Some(before_sequence_number) => {
+                    self.retrieve_older_messages(
+                        channel_name,
+                        &channel_messages,
+                        Some(before_sequence_number),
+                        limit,
+                    )
+                    .await?
+                }
+                None => vec![],
+            };
+            return Ok(Some(older_messages));
+        };
+
+        let oldest_retained_message_id = messages.first().map(|chat_message| chat_message.id);
+        let retained_message_count = messages.len();
+        messages.truncate(end_index);
+        if messages.len() < limit && has_evicted_messages {
+            let missing_messages = limit - messages.len();
+            let older_messages = match oldest_retained_message_id {
+                None => {
+                    self.retrieve_older_messages(
+                        channel_name,
+                        &channel_messages,
+                        None,
+                        missing_messages,
+                    )
+                    .await?
+                }
+                Some(oldest_retained_message_id) => {
+                    self.retrieve_messages_preceding(
+                        channel_name,
+                        &channel_messages,
+                        oldest_retained_message_id,
+                        retained_message_count,
+                        missing_messages,
+                    )
+                    .await?
                 }
+            };
+
+            let retained_message_ids: HashSet<_> = messages
+                .iter()
+                .map(|chat_message| chat_message.id)
+                .collect();
+            messages = older_messages
+                .into_iter()
+                .filter(|chat_message| !retained_message_ids.contains(&chat_message.id))
+                .chain(messages)
+                .collect();
+        }
+
+        let first_index = messages.len().saturating_sub(limit);
+        Ok(Some(messages.split_off(first_index)))
+    }
+
+    /// Retrieves the last `count` messages before the sequence number `before` (or the channel's
+    /// last messages) from the replication log, remembering their sequence numbers for retrieving
+    /// the preceding page.
+    async fn retrieve_older_messages(
+        &self,
+        channel_name: &str,
+        channel_messages: &ChannelMessages,
+        before: Option<SequenceNumber>,
+        count: usize,
+    ) -> Result<Vec<ChatMessage>> {
+        let older_messages = self
+            .replication_log_client
+            .get_messages_before(channel_name, before, count)
+            .await?;
+        channel_messages
+            .message_list
+            .lock()
+            .unwrap()
+            .record_sequence_numbers(&older_messages);
+
+        Ok(older_messages
+            .into_iter()
+            .map(|message| message.chat_message)
+            .collect())
+    }
 
-                let message_list_clone = Arc::clone(&self.messages_received);
-                let subscription =
-                    ChannelSubscription::new(incoming_message_stream, message_list_clone);
+    /// Retrieves the `count` messages preceding the oldest retained message from the replication
+    /// log.
+    async fn retrieve_messages_preceding(
+        &self,
+        channel_name: &str,
+        channel_messages: &ChannelMessages,
+        oldest_retained_message_id: MessageId,
+        retained_message_count: usize,
+        count: usize,
+    ) -> Result<Vec<ChatMessage>> {
+        let oldest_retained_sequence_number = channel_messages
+            .message_list
+            .lock()
+            .unwrap()
+            .sequence_number(&oldest_retained_message_id);
+        if let Some(oldest_retained_sequence_number) = oldest_retained_sequence_number {
+            return self
+                .retrieve_older_messages(
+                    channel_name,
+                    channel_messages,
+                    Some(oldest_retained_sequence_number),
+                    count,
+                )
+                .await;
+        }
+
+        // The message was received live, so its sequence number is unknown. Being retained, it is
+        // among the last messages of the channel, unless many more have been appended since.
+        let mut last_messages = self
+            .retrieve_older_messages(
+                channel_name,
+                channel_messages,
+                None,
+                retained_message_count + count,
+            )
+            .await?;
+        let Some(oldest_retained_index) = last_messages
+            .iter()
+            .position(|chat_message| chat_message.id == oldest_retained_message_id)
+        else {
+            return Ok(vec![]);
+        };
+        last_messages.truncate(oldest_retained_index);
+
+        Ok(last_messages.split_off(oldest_retained_index.saturating_sub(count)))
+    }
+
+    /// Validates the message and publishes it to the channel.
+    ///
+    /// With the [`ReplicationLogChannelPublisher`](crate::channel_publisher::ReplicationLogChannelPublisher),
+    /// this only returns once the message has been durably stored by the replication log.
+    ///
+    /// Fails with a [`MessageValidationError`](crate::message_validation::MessageValidationError)
+    /// if the message is invalid.
+    pub async fn send_message(
+        &self,
+        channel_name: &str,
+        message_text: &str,
+    ) -> Result<ChatMessage> {
+        validate_message(channel_name, message_text)?;
+
+        let chat_message = ChatMessage::new(channel_name, message_text);
+        self.channel_publisher.publish(&chat_message).await?;
+
+        Ok(chat_message)
+    }
+
+    /// Joins the channel if necessary, returning a lease that keeps the channel joined while it is
+    /// held.
+    ///
+    /// The channel is left once the last lease is dropped (after the linger period, if set); this
+    /// also drops its messages.
+    pub async fn subscribe(&self, channel_name: &str) -> Result<ChannelLease> {
+        let (messages, subscription) = {
+            let mut channel_state = self
+                .active_subscriptions
+                .entry(channel_name.to_string())
+                .or_insert_with(|| ChannelState::new(self.history_retention.clone()));
+            channel_state.holder_count += 1;
 
-                empty_entry.insert(subscription);
-                Ok(true)
+            (
+                Arc::clone(&channel_state.messages),
+                Arc::clone(&channel_state.subscription),
+            )
+        };
+        // Releases the channel again if joining fails, without lingering, so that it is not
+        // reported as being joined and the next subscriber joins it again.
+        let mut lease = ChannelLease {
+            chat_server: self.clone(),
+            channel_name: channel_name.to_string(),
+            messages: Arc::clone(&messages),
+            lingers: false,
+        };
+
+        // Everybody subscribing while the channel is being joined waits for the same join.
+        subscription
+            .get_or_try_init(|| self.join(channel_name, &messages))
+            .await?;
+
+        lease.lingers = true;
+        Ok(lease)
+    }
+
+    /// Returns the number of leases currently held for the channel.
+    pub fn holder_count(&self, channel_name: &str) -> usize {
+        self.active_subscriptions
+            .get(channel_name)
+            .map_or(0, |channel_state| channel_state.holder_count)
+    }
+
+    async fn join(
+        &self,
+        channel_name: &str,
+        messages: &ChannelMessages,
+    ) -> Result<ChannelSubscription> {
+        let incoming_message_stream = self.channel_subscriber.subscribe(channel_name).await?;
+
+        // Retrieve messages that were previously sent on the channel and synchronize them with the
+        // incoming messages, see the `channel_join` module.
+        let (previous_messages, incoming_message_stream) = join_channel(
+            channel_name,
+            incoming_message_stream,
+            Arc::clone(&self.replication_log_client),
+            // More messages would be evicted right away.
+            self.history_retention.max_messages,
+            messages.late_history_sink(channel_name, Arc::clone(&self.channel_subscriber)),
+        )
+        .await;
+        *messages.history_backfill.lock().unwrap() = {
+            // Open a new block to reduce the scope in which the mutex is being held.
+            let mut channel_history = messages.message_list.lock().unwrap();
+            match previous_messages {
+                Ok(previous_messages) => {
+                    if previous_messages.has_omitted_messages {
+                        channel_history.mark_incomplete();
+                    }
+                    if let Some(last_message) = previous_messages.messages.last() {
+                        self.channel_subscriber
+                            .record_retrieved_messages(channel_name, last_message.sequence_number);
+                    }
+                    for message in previous_messages.messages {
+                        channel_history.push_sequenced(message);
+                    }
+                    HistoryBackfill::Succeeded
+                }
+                Err(err) => {
+                    println!("Joining channel {channel_name} without its history: {err}");
+                    channel_history.mark_incomplete();
+                    HistoryBackfill::Failed {
+                        error: err.to_string(),
+                    }
+                }
+            }
+        };
+
+        let message_list_clone = Arc::clone(&messages.message_list);
+        Ok(ChannelSubscription::new(
+            incoming_message_stream,
+            message_list_clone,
+            messages.new_message_sender.clone(),
+            self.item_error_policy,
+            self.ingestion_config.clone(),
+        ))
+    }
+
+    /// Leaves the channel once its last lease has been released, after the linger period if
+    /// `lingers`.
+    fn release(&self, channel_name: &str, lingers: bool) {
+        let subscription = match self.active_subscriptions.get_mut(channel_name) {
+            Some(mut channel_state) => {
+                channel_state.holder_count -= 1;
+                if channel_state.holder_count > 0 {
+                    return;
+                }
+
+                Arc::clone(&channel_state.subscription)
+            }
+            None => return,
+        };
+
+        match tokio::runtime::Handle::try_current() {
+            Ok(runtime) if lingers && !self.linger_period.is_zero() => {
+                let chat_server = self.clone();
+                let channel_name = channel_name.to_string();
+                let linger_period = self.linger_period;
+
+                runtime.spawn(async move {
+                    tokio::time::sleep(linger_period).await;
+                    chat_server.remove_unheld_subscription(&channel_name, &subscription);
+                });
             }
+            _ => self.remove_unheld_subscription(channel_name, &subscription),
         }
     }
 
-    /// Returns whether we have been subscribed in the first place.
-    pub fn unsubscribe(&self, channel_name: &str) -> bool {
+    /// Only removes the subscription if nobody subscribed to the channel again in the meantime.
+    fn remove_unheld_subscription(
+        &self,
+        channel_name: &str,
+        subscription: &Arc<OnceCell<ChannelSubscription>>,
+    ) {
         // The subscription should stop providing messages once it is dropped.
-        let was_subscribed = self.active_subscriptions.remove(channel_name).is_some();
+        self.active_subscriptions
+            .remove_if(channel_name, |_, channel_state| {
+                channel_state.holder_count == 0
+                    && Arc::ptr_eq(&channel_state.subscription, subscription)
+            });
+    }
+}
+
+/// Keeps a channel joined while it is held, see [`ChatServer::subscribe`].
+pub struct ChannelLease {
+    chat_server: ChatServer,
+    channel_name: String,
+    messages: Arc<ChannelMessages>,
+    /// Whether the channel lingers after the lease has been dropped, i.e. it has been joined.
+    lingers: bool,
+}
+
+impl ChannelLease {
+    pub fn channel_name(&self) -> &str {
+        &self.channel_name
+    }
 
-        if was_subscribed {
-            let mut message_list = self.messages_received.lock().unwrap();
-            message_list.retain(|chat_message| chat_message.channel != channel_name);
+    /// Returns the messages received on the channel so far, along with a receiver of all messages
+    /// received afterwards.
+    ///
+    /// Every message of the channel is either contained in the returned messages or delivered by
+    /// the receiver, but not both.
+    pub fn messages_with_updates(&self) -> (Vec<ChatMessage>, broadcast::Receiver<ChatMessage>) {
+        let mut channel_history = self.messages.message_list.lock().unwrap();
+        let new_message_receiver = self.messages.new_message_sender.subscribe();
+
+        (channel_history.messages(), new_message_receiver)
+    }
+}
+
+impl Drop for ChannelLease {
+    fn drop(&mut self) {
+        self.chat_server.release(&self.channel_name, self.lingers);
+    }
+}
+
+struct ChannelState {
+    /// The number of [`ChannelLease`]s for the channel.
+    holder_count: usize,
+    messages: Arc<ChannelMessages>,
+    /// Set once the channel has been joined.
+    subscription: Arc<OnceCell<ChannelSubscription>>,
+}
+
+impl ChannelState {
+    fn new(history_retention: HistoryRetention) -> Self {
+        ChannelState {
+            holder_count: 0,
+            messages: Arc::new(ChannelMessages {
+                message_list: Arc::new(Mutex::new(ChannelHistory::new(history_retention))),
+                new_message_sender: broadcast::channel(LIVE_MESSAGE_BUFFER_SIZE).0,
+                history_backfill: Arc::new(Mutex::new(HistoryBackfill::Pending)),
+            }),
+            subscription: Default::default(),
+        }
+    }
+
+    /// Whether joining the channel has succeeded; until then, its messages are incomplete.
+    fn is_joined(&self) -> bool {
+        self.subscription.initialized()
+    }
+
+    fn subscription_state(
+        &self,
+        channel_name: &str,
+        connection_state: Option<ConnectionState>,
+    ) -> SubscriptionState {
+        let (joined_at, history_backfill, forwarder_health) = match self.subscription.get() {
+            Some(subscription) => (
+                Some(subscription.joined_at),
+                self.messages.history_backfill.lock().unwrap().clone(),
+                Some(subscription.stream_forwarder.health()),
+            ),
+            None => (None, HistoryBackfill::Pending, None),
+        };
+
+        SubscriptionState {
+            channel_name: channel_name.to_string(),
+            joined_at,
+            message_count: self.messages.message_list.lock().unwrap().len(),
+            history_backfill,
+            connection_state,
+            forwarder_health,
         }
+    }
+}
 
-        was_subscribed
+struct ChannelMessages {
+    message_list: Arc<Mutex<ChannelHistory>>,
+    /// Notified of every message that is received live, after adding it to `message_list`.
+    new_message_sender: broadcast::Sender<ChatMessage>,
+    history_backfill: Arc<Mutex<HistoryBackfill>>,
+}
+
+impl ChannelMessages {
+    /// Adds the history that is retrieved after joining the channel failed to retrieve it, without
+    /// notifying anyone of it, and succeeds the backfill once the history is synchronized.
+    fn late_history_sink(
+        &self,
+        channel_name: &str,
+        channel_subscriber: Arc<dyn ChannelSubscriber>,
+    ) -> LateHistorySink {
+        let channel_name = channel_name.to_string();
+        let message_list = Arc::clone(&self.message_list);
+        let history_backfill = Arc::clone(&self.history_backfill);
+
+        Box::new(move |late_messages, synchronized| {
+            if let Some(last_message) = late_messages.last() {
+                channel_subscriber
+                    .record_retrieved_messages(&channel_name, last_message.sequence_number);
+            }
+            let mut channel_history = message_list.lock().unwrap();
+            for message in late_messages {
+                channel_history.push_sequenced(message);
+            }
+            *history_backfill.lock().unwrap() = match synchronized {
+                Ok(()) => HistoryBackfill::Succeeded,
+                Err(err) => HistoryBackfill::Failed {
+                    error: err.to_string(),
+                },
+            };
+        })
     }
 }
 
 struct ChannelSubscription {
-    _stream_to_vec_forwarder: StreamToVecForwarder,
+    joined_at: SystemTime,
+    stream_forwarder: StreamForwarder,
 }
 
 impl ChannelSubscription {
     /// Subscribe to the channel represented by the MessageStream, asynchronously writing to the
-    /// message list on any new message.
+    /// message list and notifying `new_message_sender` on any new message.
     ///
     /// Will automatically stop writing to the message list when dropped.
     fn new(
         incoming_message_stream: ChatMessageStream,
-        message_list: Arc<Mutex<Vec<ChatMessage>>>,
+        message_list: Arc<Mutex<ChannelHistory>>,
+        new_message_sender: broadcast::Sender<ChatMessage>,
+        item_error_policy: ItemErrorPolicy,
+        ingestion_config: IngestionConfig,
     ) -> Self {
-        let _stream_to_vec_forwarder =
-            StreamToVecForwarder::new(incoming_message_stream, message_list);
+        let stream_forwarder = StreamForwarder::with_ingestion_config(
+            incoming_message_stream,
+            NotifyingSink::new(message_list, new_message_sender),
+            item_error_policy,
+            ingestion_config,
+        );
 
         Self {
-            _stream_to_vec_forwarder,
+            joined_at: SystemTime::now(),
+            stream_forwarder,
         }
     }
 }
@@ -0,0 +1,161 @@
+//! The configuration of a chat-server instance, see [`common::config`] for how it is assembled.
+
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use common::{
+    config::{Config, MessageBrokerConfig, ServerConfig},
+    ingestion_queue::IngestionConfig,
+    sharding::ShardMap,
+    DEFAULT_CHANNEL,
+};
+
+use crate::{
+    channel_history::HistoryRetention, message_validation::validate_channel_name,
+    reconnecting_subscriber::Backoff, replication_log_client::DEFAULT_PAGE_SIZE,
+};
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ChatServerConfig {
+    /// The name reported by `GET /api/v1/node`; defaults to the `HOSTNAME` of the instance.
+    pub node_name: Option<String>,
+    pub server: ServerConfig,
+    pub message_broker: MessageBrokerConfig,
+    pub replication_log: ReplicationLogConfig,
+    pub channels: ChannelsConfig,
+    /// Which messages of a channel are kept in memory.
+    pub history: HistoryRetention,
+    pub ingestion: IngestionConfig,
+    /// How long to wait before resubscribing to a channel whose subscription was lost.
+    pub reconnect: Backoff,
+}
+
+impl Default for ChatServerConfig {
+    fn default() -> Self {
+        ChatServerConfig {
+            node_name: None,
+            server: ServerConfig::default(),
+            message_broker: MessageBrokerConfig::default(),
+            replication_log: ReplicationLogConfig::default(),
+            channels: ChannelsConfig::default(),
+            history: HistoryRetention {
+                max_messages: Some(10_000),
+                max_bytes: Some(16 * 1024 * 1024),
+                max_age: None,
+            },
+            ingestion: IngestionConfig {
+                queue_capacity: 4096,
+                max_batch_size: 128,
+                ..IngestionConfig::default()
+            },
+            reconnect: Backoff::default(),
+        }
+    }
+}
+
+impl Config for ChatServerConfig {
+    const ENV_PREFIX: &'static str = "CHAT_SERVER";
+
+    fn validate(&self) -> Result<()> {
+        self.message_broker.validate()?;
+        self.replication_log.validate()?;
+        self.channels.validate()?;
+        self.history
+            .validate()
+            .context("invalid history settings")?;
+        self.ingestion
+            .validate()
+            .context("invalid ingestion settings")?;
+        if self.reconnect.initial_delay > self.reconnect.max_delay {
+            bail!("the initial reconnect delay must not exceed the maximum delay");
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ReplicationLogConfig {
+    /// The URL under which the replication log serves the messages of every channel.
+    pub url: String,
+    /// The URL under which the replication log serves a snapshot of every channel's recent
+    /// history, which newly joined channels start from; without one, they replay the full history.
+    pub snapshot_url: Option<String>,
+    /// Messages are retrieved in pages of this size.
+    pub page_size: usize,
+    /// Requests to the replication log that take longer than this fail.
+    #[serde(with = "humantime_serde")]
+    pub request_timeout: Duration,
+    /// The shards that the channels are partitioned across, see [`common::sharding`]; replaces
+    /// `url` and `snapshot_url`. Every shard serves messages under `/messages`.
+    pub shard_map: Option<ShardMap>,
+    /// Whether newly joined channels start from the snapshots that every shard serves under
+    /// `/snapshots`, like with `snapshot_url`.
+    pub shard_snapshots: bool,
+}
+
+impl Default for ReplicationLogConfig {
+    fn default() -> Self {
+        ReplicationLogConfig {
+            url: "http://replication-log-service:80/messages".to_string(),
+            snapshot_url: Some("http://replication-log-service:80/snapshots".to_string()),
+            page_size: DEFAULT_PAGE_SIZE,
+            request_timeout: Duration::from_secs(10),
+            shard_map: None,
+            shard_snapshots: true,
+        }
+    }
+}
+
+impl ReplicationLogConfig {
+    fn validate(&self) -> Result<()> {
+        reqwest::Url::parse(&self.url)
+            .with_context(|| format!("invalid replication log URL {:?}", self.url))?;
+        if let Some(snapshot_url) = &self.snapshot_url {
+            reqwest::Url::parse(snapshot_url)
+                .with_context(|| format!("invalid snapshot URL {snapshot_url:?}"))?;
+        }
+        if self.page_size == 0 {
+            bail!("the replication log page size must be positive");
+        }
+        if let Some(shard_map) = &self.shard_map {
+            shard_map.validate()?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ChannelsConfig {
+    /// The channels joined on startup, until they are left via the API.
+    pub initial: Vec<String>,
+    /// How long to stay in a channel after the last user left it, in case somebody re-joins soon.
+    #[serde(with = "humantime_serde")]
+    pub linger_period: Duration,
+}
+
+impl Default for ChannelsConfig {
+    fn default() -> Self {
+        ChannelsConfig {
+            initial: vec![DEFAULT_CHANNEL.to_string()],
+            linger_period: Duration::from_secs(30),
+        }
+    }
+}
+
+impl ChannelsConfig {
+    fn validate(&self) -> Result<()> {
+        for channel_name in &self.initial {
+            validate_channel_name(channel_name)
+                .with_context(|| format!("invalid initial channel {channel_name:?}"))?;
+        }
+
+        Ok(())
+    }
+}
@@ -1,6 +1,14 @@
+pub mod api;
+pub mod channel_history;
+pub mod channel_join;
+pub mod channel_publisher;
 pub mod channel_subscriber;
 pub mod chat_server;
+pub mod config;
+pub mod message_validation;
+pub mod reconnecting_subscriber;
 pub mod replication_log_client;
+pub mod websocket;
 
 #[cfg(test)]
 mod tests;
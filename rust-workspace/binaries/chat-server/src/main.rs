@@ -1,46 +1,87 @@
-use std::{convert::Infallible, sync::Arc};
+use std::sync::Arc;
 
 use chat_server::{
-    channel_subscriber::RedisChannelSubscriber, chat_server::ChatServer,
-    replication_log_client::ReqwestReplicationLogClient,
+    api::Api,
+    channel_publisher::ReplicationLogChannelPublisher,
+    channel_subscriber::RedisChannelSubscriber,
+    chat_server::ChatServer,
+    config::{ChatServerConfig, ReplicationLogConfig},
+    reconnecting_subscriber::ReconnectingChannelSubscriber,
+    replication_log_client::{
+        ReplicationLogClient, ReqwestReplicationLogClient, ShardedReplicationLogClient,
+    },
 };
-use common::DEFAULT_CHANNEL;
-use tokio;
-use warp::{Filter, Reply};
+use common::{config::load_config, forwarder_health::ItemErrorPolicy};
 
 #[tokio::main]
 async fn main() {
-    let channel_subscriber = RedisChannelSubscriber {
-        redis_url: "redis://message-broker-service:6379".to_string(),
-    };
-    let replication_log_client = ReqwestReplicationLogClient {
-        replication_log_url: "http://replication-log-service:80/messages".to_string(),
+    let config: ChatServerConfig = match load_config() {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("{err:#}");
+            std::process::exit(2);
+        }
     };
 
-    let chat_server = ChatServer::new(
-        Arc::new(channel_subscriber),
-        Arc::new(replication_log_client),
-    );
-    chat_server.subscribe(DEFAULT_CHANNEL).await.unwrap();
+    let replication_log_client = replication_log_client(config.replication_log);
+    let channel_subscriber = ReconnectingChannelSubscriber::new(
+        Arc::new(RedisChannelSubscriber {
+            redis_url: config.message_broker.url,
+        }),
+        replication_log_client.clone(),
+    )
+    .with_backoff(config.reconnect);
 
-    let messages_route = warp::path!("messages")
-        .and(with_chat_server(chat_server))
-        .and_then(messages_handler);
+    // Sent messages are stored by the replication log before they are published.
+    let channel_publisher = ReplicationLogChannelPublisher::new(replication_log_client.clone());
 
-    let routes = messages_route;
+    let chat_server = ChatServer::new(
+        Arc::new(channel_subscriber),
+        Arc::new(channel_publisher),
+        replication_log_client,
+    )
+    .with_linger_period(config.channels.linger_period)
+    // A single malformed message must not stop a channel from receiving any further messages.
+    .with_item_error_policy(ItemErrorPolicy::Skip)
+    .with_history_retention(config.history)
+    // Received messages wait in a bounded queue and are added to the history in batches, so
+    // that a burst on one channel does not hog the history lock that API requests need.
+    .with_ingestion_config(config.ingestion);
 
-    println!("Started server at localhost:8000");
-    warp::serve(routes).run(([0, 0, 0, 0], 8000)).await;
-}
+    let node_name = config
+        .node_name
+        .or_else(|| std::env::var("HOSTNAME").ok())
+        .unwrap_or_else(|| "chat-server".to_string());
+    let api = Api::new(chat_server, node_name);
+    // The initial channels are joined until they are left via the API.
+    for channel_name in &config.channels.initial {
+        api.join(channel_name).await.unwrap();
+    }
 
-fn with_chat_server(
-    server: ChatServer,
-) -> impl Filter<Extract = (ChatServer,), Error = std::convert::Infallible> + Clone {
-    warp::any().map(move || server.clone())
+    let bind_address = config.server.bind_address;
+    println!("Started server at {bind_address}");
+    warp::serve(api.routes()).run(bind_address).await;
 }
 
-async fn messages_handler(chat_server: ChatServer) -> Result<impl Reply, Infallible> {
-    let serialized_messages = format!("{:?}", chat_server.messages_received());
+fn replication_log_client(config: ReplicationLogConfig) -> Arc<dyn ReplicationLogClient> {
+    let Some(shard_map) = config.shard_map else {
+        let mut client = ReqwestReplicationLogClient::new(config.url)
+            .with_page_size(config.page_size)
+            .with_request_timeout(config.request_timeout);
+        if let Some(snapshot_url) = config.snapshot_url {
+            client = client.with_snapshot_url(snapshot_url);
+        }
+        return Arc::new(client);
+    };
 
-    Ok(serialized_messages)
+    println!("Using {} replication log shards", shard_map.shards.len());
+    Arc::new(ShardedReplicationLogClient::new(shard_map, |shard| {
+        let mut client = ReqwestReplicationLogClient::new(format!("{}/messages", shard.url))
+            .with_page_size(config.page_size)
+            .with_request_timeout(config.request_timeout);
+        if config.shard_snapshots {
+            client = client.with_snapshot_url(format!("{}/snapshots", shard.url));
+        }
+        Arc::new(client)
+    }))
 }
@@ -0,0 +1,63 @@
+use std::fmt;
+
+/// The maximum length of a message text, in characters.
+pub const MAX_MESSAGE_LENGTH: usize = 4096;
+/// The maximum length of a channel name, in characters.
+pub const MAX_CHANNEL_NAME_LENGTH: usize = 128;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum MessageValidationError {
+    EmptyMessage,
+    MessageTooLong,
+    InvalidChannelName,
+}
+
+impl fmt::Display for MessageValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MessageValidationError::EmptyMessage => write!(f, "the message must not be empty"),
+            MessageValidationError::MessageTooLong => write!(
+                f,
+                "the message must not be longer than {MAX_MESSAGE_LENGTH} characters"
+            ),
+            MessageValidationError::InvalidChannelName => write!(
+                f,
+                "channel names must consist of 1 to {MAX_CHANNEL_NAME_LENGTH} ASCII letters, \
+                digits, '-' or '_'"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for MessageValidationError {}
+
+/// Checks whether a message with the given text may be sent to the channel.
+pub fn validate_message(
+    channel_name: &str,
+    message_text: &str,
+) -> Result<(), MessageValidationError> {
+    validate_channel_name(channel_name)?;
+
+    if message_text.trim().is_empty() {
+        return Err(MessageValidationError::EmptyMessage);
+    }
+    if message_text.chars().count() > MAX_MESSAGE_LENGTH {
+        return Err(MessageValidationError::MessageTooLong);
+    }
+
+    Ok(())
+}
+
+/// Checks whether the channel name may be used to send or receive messages.
+pub fn validate_channel_name(channel_name: &str) -> Result<(), MessageValidationError> {
+    let is_valid_channel_name = !channel_name.is_empty()
+        && channel_name.len() <= MAX_CHANNEL_NAME_LENGTH
+        && channel_name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if !is_valid_channel_name {
+        return Err(MessageValidationError::InvalidChannelName);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,302 @@
+//! Surviving restarts of the message broker.
+//!
+//! The stream of a channel subscription ends when the connection to the message broker is lost.
+//! [`ReconnectingChannelSubscriber`] then subscribes to the channel again, waiting exponentially
+//! longer after every failed attempt. Messages that were published while being disconnected are not
+//! delivered by the new subscription, so they are backfilled from the replication log, similar to
+//! joining a channel (see the `channel_join` module):
+//! 1. Right after resubscribing, every message that the replication log contains by then is passed
+//!    on, starting after the last message retrieved when joining the channel (see
+//!    [`ChannelSubscriber::record_retrieved_messages`]) or when backfilling it before.
+//! 2. The first live message serves as the synchronization point: the history is retrieved up to
+//!    this message, in case the replication log had not caught up yet in step 1.
+//!
+//! Backfilled messages may have been passed on before; they are deduplicated when joining the
+//! channel, like any other message received more than once.
+
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use dashmap::DashMap;
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+
+use common::{ChatMessage, ChatMessageStream, SequenceNumber};
+
+use crate::{
+    channel_join::catch_up_with_history,
+    channel_subscriber::{ChannelSubscriber, ConnectionState},
+    replication_log_client::ReplicationLogClient,
+};
+
+/// How long to wait before attempting to resubscribe.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Backoff {
+    /// The delay before the first attempt, which is doubled after every failed attempt.
+    #[serde(with = "humantime_serde")]
+    pub initial_delay: Duration,
+    #[serde(with = "humantime_serde")]
+    pub max_delay: Duration,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Backoff {
+            initial_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(30),
+        }
+    }
+}
+
+impl Backoff {
+    fn delay(&self, failed_attempts: u32) -> Duration {
+        self.initial_delay
+            .saturating_mul(2u32.saturating_pow(failed_attempts))
+            .min(self.max_delay)
+    }
+}
+
+/// Wraps a [`ChannelSubscriber`], resubscribing whenever a subscription's stream ends. See the
+/// [module documentation](self).
+pub struct ReconnectingChannelSubscriber {
+    channel_subscriber: Arc<dyn ChannelSubscriber>,
+    replication_log_client: Arc<dyn ReplicationLogClient>,
+    backoff: Backoff,
+    /// The state of every active subscription.
+    subscription_states: Arc<DashMap<String, Arc<SubscriptionState>>>,
+}
+
+impl ReconnectingChannelSubscriber {
+    pub fn new(
+        channel_subscriber: Arc<dyn ChannelSubscriber>,
+        replication_log_client: Arc<dyn ReplicationLogClient>,
+    ) -> Self {
+        ReconnectingChannelSubscriber {
+            channel_subscriber,
+            replication_log_client,
+            backoff: Backoff::default(),
+            subscription_states: Default::default(),
+        }
+    }
+
+    pub fn with_backoff(mut self, backoff: Backoff) -> Self {
+        self.backoff = backoff;
+        self
+    }
+}
+
+#[async_trait]
+impl ChannelSubscriber for ReconnectingChannelSubscriber {
+    /// Fails if the initial subscription fails; only lost subscriptions are re-established.
+    async fn subscribe(&self, channel_name: &str) -> Result<ChatMessageStream> {
+        let incoming_message_stream = self.channel_subscriber.subscribe(channel_name).await?;
+
+        let subscription_state = Arc::new(SubscriptionState {
+            connection_state: Mutex::new(ConnectionState::Connected),
+            last_sequence_number: AtomicU64::new(0),
+        });
+        self.subscription_states
+            .insert(channel_name.to_string(), Arc::clone(&subscription_state));
+
+        let subscription = Subscription {
+            channel_name: channel_name.to_string(),
+            channel_subscriber: Arc::clone(&self.channel_subscriber),
+            replication_log_client: Arc::clone(&self.replication_log_client),
+            backoff: self.backoff.clone(),
+            incoming_message_stream,
+            state: RegisteredSubscriptionState {
+                subscription_states: Arc::clone(&self.subscription_states),
+                channel_name: channel_name.to_string(),
+                subscription_state,
+            },
+            synchronized: true,
+            pending_messages: Default::default(),
+        };
+
+        Ok(Box::pin(subscription.into_stream()))
+    }
+
+    fn connection_state(&self, channel_name: &str) -> Option<ConnectionState> {
+        self.subscription_states
+            .get(channel_name)
+            .map(|subscription_state| subscription_state.connection_state.lock().unwrap().clone())
+    }
+
+    /// Missed messages are backfilled after the last retrieved message.
+    fn record_retrieved_messages(&self, channel_name: &str, sequence_number: SequenceNumber) {
+        if let Some(subscription_state) = self.subscription_states.get(channel_name) {
+            subscription_state.advance_to(sequence_number);
+        }
+    }
+}
+
+/// The state of a subscription that is shared with its [`ReconnectingChannelSubscriber`].
+struct SubscriptionState {
+    connection_state: Mutex<ConnectionState>,
+    /// The sequence number of the last message retrieved from the replication log, either when
+    /// joining the channel (see [`ChannelSubscriber::record_retrieved_messages`]) or when
+    /// backfilling it. Missed messages are backfilled after this one.
+    last_sequence_number: AtomicU64,
+}
+
+impl SubscriptionState {
+    fn last_sequence_number(&self) -> SequenceNumber {
+        self.last_sequence_number.load(Ordering::SeqCst)
+    }
+
+    fn advance_to(&self, sequence_number: SequenceNumber) {
+        self.last_sequence_number
+            .fetch_max(sequence_number, Ordering::SeqCst);
+    }
+}
+
+struct Subscription {
+    channel_name: String,
+    channel_subscriber: Arc<dyn ChannelSubscriber>,
+    replication_log_client: Arc<dyn ReplicationLogClient>,
+    backoff: Backoff,
+    incoming_message_stream: ChatMessageStream,
+    state: RegisteredSubscriptionState,
+    /// Whether the replication log has been seen to contain a live message since resubscribing.
+    synchronized: bool,
+    /// Messages that are ready to be passed on.
+    pending_messages: VecDeque<ChatMessage>,
+}
+
+impl Subscription {
+    fn into_stream(self) -> impl futures::Stream<Item = Result<ChatMessage>> + Send {
+        futures::stream::unfold(self, |mut subscription| async move {
+            loop {
+                if let Some(chat_message) = subscription.pending_messages.pop_front() {
+                    return Some((Ok(chat_message), subscription));
+                }
+
+                match subscription.incoming_message_stream.next().await {
+                    Some(Ok(chat_message)) => {
+                        if !subscription.synchronized {
+                            subscription.catch_up_with_history(&chat_message).await;
+                        }
+                        subscription.pending_messages.push_back(chat_message);
+                    }
+                    Some(Err(err)) => return Some((Err(err), subscription)),
+                    None => subscription.reconnect().await,
+                }
+            }
+        })
+    }
+
+    /// Resubscribes until it succeeds and queues the messages that were missed in the meantime, as
+    /// far as the replication log contains them yet.
+    async fn reconnect(&mut self) {
+        let channel_name = &self.channel_name;
+        println!("Lost the subscription to channel {channel_name}, resubscribing");
+
+        let mut failed_attempts = 0;
+        self.state
+            .set_connection_state(ConnectionState::Reconnecting {
+                failed_attempts,
+                last_error: "the subscription ended".to_string(),
+            });
+        self.incoming_message_stream = loop {
+            tokio::time::sleep(self.backoff.delay(failed_attempts)).await;
+
+            match self.channel_subscriber.subscribe(channel_name).await {
+                Ok(incoming_message_stream) => break incoming_message_stream,
+                Err(err) => {
+                    failed_attempts += 1;
+                    self.state
+                        .set_connection_state(ConnectionState::Reconnecting {
+                            failed_attempts,
+                            last_error: err.to_string(),
+                        });
+                }
+            }
+        };
+        self.state.set_connection_state(ConnectionState::Connected);
+
+        match self
+            .replication_log_client
+            .get_retained_messages_since(channel_name, self.state.last_sequence_number())
+            .await
+        {
+            Ok(missed_messages) => {
+                if let Some(last_message) = missed_messages.last() {
+                    self.state.advance_to(last_message.sequence_number);
+                }
+                self.pending_messages.extend(
+                    missed_messages
+                        .into_iter()
+                        .map(|message| message.chat_message),
+                );
+            }
+            // The history is still retrieved along with the first live message.
+            Err(err) => println!("Could not backfill channel {channel_name}: {err}"),
+        }
+        self.synchronized = false;
+    }
+
+    async fn catch_up_with_history(&mut self, synchronization_point: &ChatMessage) {
+        let mut last_sequence_number = self.state.last_sequence_number();
+        let (missed_messages, synchronized) = catch_up_with_history(
+            self.replication_log_client.as_ref(),
+            &self.channel_name,
+            &mut last_sequence_number,
+            synchronization_point,
+        )
+        .await;
+        self.state.advance_to(last_sequence_number);
+
+        if let Err(err) = &synchronized {
+            println!(
+                "Resubscribed to channel {} without synchronizing its history yet: {err:#}",
+                self.channel_name
+            );
+        }
+        self.synchronized = synchronized.is_ok();
+        self.pending_messages.extend(
+            missed_messages
+                .into_iter()
+                .map(|message| message.chat_message),
+        );
+    }
+}
+
+/// The state of a subscription, which is unregistered once the subscription is dropped.
+struct RegisteredSubscriptionState {
+    subscription_states: Arc<DashMap<String, Arc<SubscriptionState>>>,
+    channel_name: String,
+    subscription_state: Arc<SubscriptionState>,
+}
+
+impl RegisteredSubscriptionState {
+    fn set_connection_state(&self, connection_state: ConnectionState) {
+        *self.subscription_state.connection_state.lock().unwrap() = connection_state;
+    }
+}
+
+impl std::ops::Deref for RegisteredSubscriptionState {
+    type Target = SubscriptionState;
+
+    fn deref(&self) -> &SubscriptionState {
+        &self.subscription_state
+    }
+}
+
+impl Drop for RegisteredSubscriptionState {
+    fn drop(&mut self) {
+        // The channel may have been subscribed to again in the meantime.
+        self.subscription_states
+            .remove_if(&self.channel_name, |_, subscription_state| {
+                Arc::ptr_eq(subscription_state, &self.subscription_state)
+            });
+    }
+}
@@ -1,26 +1,402 @@
-use anyhow::Result;
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use anyhow::{anyhow, Result};
 use async_trait::async_trait;
 
-use common::ChatMessage;
+use common::{
+    sharding::{HashRing, Shard, ShardMap},
+    snapshot::ChannelSnapshot,
+    ChatMessage, SequenceNumber, SequencedMessage, TRUNCATED_BEFORE_HEADER,
+};
+
+pub use common::MessagesRemoved;
+
+/// Number of messages requested from the replication log at once.
+pub const DEFAULT_PAGE_SIZE: usize = 1000;
 
 #[async_trait]
 pub trait ReplicationLogClient: Send + Sync {
-    async fn get_messages_for_channel(&self, channel_name: &str) -> Result<Vec<ChatMessage>>;
+    /// Returns all messages of the channel with a sequence number greater than `offset`, ordered by
+    /// their sequence number.
+    ///
+    /// Fails with [`MessagesRemoved`] if some of them have been removed; see
+    /// [`get_retained_messages_since`](Self::get_retained_messages_since) to skip them instead.
+    async fn get_messages_since(
+        &self,
+        channel_name: &str,
+        offset: SequenceNumber,
+    ) -> Result<Vec<SequencedMessage>>;
+
+    /// Like [`get_messages_since`](Self::get_messages_since), but if the channel has no messages
+    /// after `offset` yet, waits up to `timeout` for the next one to be appended.
+    ///
+    /// By default, responds right away.
+    async fn wait_for_messages_since(
+        &self,
+        channel_name: &str,
+        offset: SequenceNumber,
+        _timeout: Duration,
+    ) -> Result<Vec<SequencedMessage>> {
+        self.get_messages_since(channel_name, offset).await
+    }
+
+    /// Like [`get_messages_since`](Self::get_messages_since), but only returns the messages after
+    /// the removed ones, if some have been removed.
+    async fn get_retained_messages_since(
+        &self,
+        channel_name: &str,
+        mut offset: SequenceNumber,
+    ) -> Result<Vec<SequencedMessage>> {
+        loop {
+            match self.get_messages_since(channel_name, offset).await {
+                Err(err) => match err.downcast_ref::<MessagesRemoved>() {
+                    // Retrying only helps if the retained messages start after the offset.
+                    Some(messages_removed)
+                        if messages_removed.truncated_before.saturating_sub(1) > offset =>
+                    {
+                        println!("Skipping removed messages of channel {channel_name}: {err}");
+                        offset = messages_removed.truncated_before.saturating_sub(1);
+                    }
+                    _ => return Err(err),
+                },
+                messages => return messages,
+            }
+        }
+    }
+
+    /// Returns the last `count` messages of the channel with a sequence number less than `before`
+    /// (or the last `count` messages, if not given), ordered by their sequence number.
+    ///
+    /// By default, the full history is retrieved and all other messages are dropped.
+    async fn get_messages_before(
+        &self,
+        channel_name: &str,
+        before: Option<SequenceNumber>,
+        count: usize,
+    ) -> Result<Vec<SequencedMessage>> {
+        let mut messages = self.get_retained_messages_since(channel_name, 0).await?;
+        if let Some(before) = before {
+            messages.retain(|message| message.sequence_number < before);
+        }
+        messages.drain(..messages.len().saturating_sub(count));
+
+        Ok(messages)
+    }
+
+    /// Returns the recent history of the channel, ordered by sequence number, for a newly joined
+    /// channel to start from.
+    ///
+    /// Unlike [`get_messages_since`](Self::get_messages_since), this may omit the oldest messages:
+    /// implementations may start from a snapshot of the channel's most recent messages and only
+    /// retrieve the messages after it. By default, the full history is retrieved.
+    async fn bootstrap(&self, channel_name: &str) -> Result<Vec<SequencedMessage>> {
+        self.get_retained_messages_since(channel_name, 0).await
+    }
+
+    /// Durably appends the message to the replication log, which then publishes it to the
+    /// message's channel.
+    ///
+    /// Appending is idempotent: a message that is already contained in the log (identified by its
+    /// id) is not stored again, but still published. So a failed append can safely be retried.
+    async fn append(&self, chat_message: &ChatMessage) -> Result<()>;
+
+    /// Returns the full history of the channel, as far as it is retained.
+    async fn get_messages_for_channel(&self, channel_name: &str) -> Result<Vec<ChatMessage>> {
+        let messages = self.get_retained_messages_since(channel_name, 0).await?;
+
+        Ok(messages
+            .into_iter()
+            .map(|message| message.chat_message)
+            .collect())
+    }
 }
 
 pub struct ReqwestReplicationLogClient {
-    pub replication_log_url: String,
+    replication_log_url: String,
+    /// Messages are retrieved in pages of this size.
+    page_size: usize,
+    /// Requests that take longer than this fail, if given.
+    request_timeout: Option<Duration>,
+    /// The URL under which the replication log serves the snapshot of every channel; without one,
+    /// [`bootstrap`](ReplicationLogClient::bootstrap) retrieves the full history.
+    snapshot_url: Option<String>,
+    /// Shared by all requests, so that connections are reused.
+    http_client: reqwest::Client,
+}
+
+impl ReqwestReplicationLogClient {
+    /// Retrieves messages in pages of [`DEFAULT_PAGE_SIZE`], without a timeout or snapshots.
+    pub fn new(replication_log_url: impl Into<String>) -> Self {
+        ReqwestReplicationLogClient {
+            replication_log_url: replication_log_url.into(),
+            page_size: DEFAULT_PAGE_SIZE,
+            request_timeout: None,
+            snapshot_url: None,
+            http_client: reqwest::Client::new(),
+        }
+    }
+
+    pub fn with_page_size(mut self, page_size: usize) -> Self {
+        self.page_size = page_size;
+        self
+    }
+
+    pub fn with_request_timeout(mut self, request_timeout: Duration) -> Self {
+        self.request_timeout = Some(request_timeout);
+        self
+    }
+
+    pub fn with_snapshot_url(mut self, snapshot_url: impl Into<String>) -> Self {
+        self.snapshot_url = Some(snapshot_url.into());
+        self
+    }
+
+    /// The URL of the channel below `base_url`, with the channel's name percent-encoded as a single
+    /// path segment.
+    fn channel_url(base_url: &str, channel_name: &str) -> Result<reqwest::Url> {
+        let mut url = reqwest::Url::parse(base_url)?;
+        url.path_segments_mut()
+            .map_err(|()| anyhow!("{base_url} cannot be a base URL"))?
+            .pop_if_empty()
+            .push(channel_name);
+
+        Ok(url)
+    }
+
+    /// Starts a request, which fails after the request timeout.
+    fn request(&self, method: reqwest::Method, url: reqwest::Url) -> reqwest::RequestBuilder {
+        let request = self.http_client.request(method, url);
+        match self.request_timeout {
+            Some(request_timeout) => request.timeout(request_timeout),
+            None => request,
+        }
+    }
+
+    /// Returns the latest snapshot of the channel, or `None` if the replication log has not taken
+    /// one yet.
+    async fn get_snapshot(
+        &self,
+        snapshot_url: &str,
+        channel_name: &str,
+    ) -> Result<Option<ChannelSnapshot>> {
+        let response = self
+            .request(
+                reqwest::Method::GET,
+                Self::channel_url(snapshot_url, channel_name)?,
+            )
+            .send()
+            .await?;
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        let encoded_snapshot = response.error_for_status()?.bytes().await?;
+
+        Ok(Some(ChannelSnapshot::decode(
+            channel_name,
+            &encoded_snapshot,
+        )?))
+    }
+
+    /// Retrieves the messages after `offset` page by page; if given, the first request waits up to
+    /// `wait` for a message to be appended.
+    async fn get_messages(
+        &self,
+        channel_name: &str,
+        offset: SequenceNumber,
+        mut wait: Option<Duration>,
+    ) -> Result<Vec<SequencedMessage>> {
+        let page_size = self.page_size;
+        let url = Self::channel_url(&self.replication_log_url, channel_name)?;
+
+        let mut messages = Vec::new();
+        let mut after = offset;
+        loop {
+            let mut request = self
+                .request(reqwest::Method::GET, url.clone())
+                .query(&[("after", after), ("limit", page_size as u64)]);
+            if let Some(wait) = wait.take() {
+                request = request.query(&[("wait_ms", wait.as_millis() as u64)]);
+                if let Some(request_timeout) = self.request_timeout {
+                    request = request.timeout(request_timeout + wait);
+                }
+            }
+            let response = request.send().await?.error_for_status()?;
+            if let Some(truncated_before) = response.headers().get(TRUNCATED_BEFORE_HEADER) {
+                let truncated_before = truncated_before.to_str()?.parse()?;
+                return Err(MessagesRemoved { truncated_before }.into());
+            }
+            let page: Vec<SequencedMessage> = response.json().await?;
+            let is_last_page = page.is_empty() || page.len() < page_size;
+            if let Some(last_message) = page.last() {
+                after = last_message.sequence_number;
+            }
+            messages.extend(page);
+
+            if is_last_page {
+                return Ok(messages);
+            }
+        }
+    }
 }
 
 #[async_trait]
 impl ReplicationLogClient for ReqwestReplicationLogClient {
-    async fn get_messages_for_channel(&self, channel_name: &str) -> Result<Vec<ChatMessage>> {
-        let replication_log_url = &self.replication_log_url;
-        let url = format!("{replication_log_url}/{channel_name}");
-        let body = reqwest::get(url).await?.text().await?;
+    async fn get_messages_since(
+        &self,
+        channel_name: &str,
+        offset: SequenceNumber,
+    ) -> Result<Vec<SequencedMessage>> {
+        self.get_messages(channel_name, offset, None).await
+    }
+
+    async fn wait_for_messages_since(
+        &self,
+        channel_name: &str,
+        offset: SequenceNumber,
+        timeout: Duration,
+    ) -> Result<Vec<SequencedMessage>> {
+        self.get_messages(channel_name, offset, Some(timeout)).await
+    }
+
+    /// Retrieves only the requested messages, with a single request.
+    async fn get_messages_before(
+        &self,
+        channel_name: &str,
+        before: Option<SequenceNumber>,
+        count: usize,
+    ) -> Result<Vec<SequencedMessage>> {
+        let mut request = self
+            .request(
+                reqwest::Method::GET,
+                Self::channel_url(&self.replication_log_url, channel_name)?,
+            )
+            .query(&[("last", count as u64)]);
+        if let Some(before) = before {
+            request = request.query(&[("before", before)]);
+        }
+
+        Ok(request.send().await?.error_for_status()?.json().await?)
+    }
+
+    /// Starts from the channel's latest snapshot, if there is one, and retrieves the messages after
+    /// its watermark.
+    async fn bootstrap(&self, channel_name: &str) -> Result<Vec<SequencedMessage>> {
+        let snapshot = match &self.snapshot_url {
+            Some(snapshot_url) => self.get_snapshot(snapshot_url, channel_name).await?,
+            None => None,
+        };
+        let Some(snapshot) = snapshot else {
+            return self.get_retained_messages_since(channel_name, 0).await;
+        };
+
+        let mut messages = snapshot.messages;
+        messages.extend(
+            self.get_retained_messages_since(channel_name, snapshot.watermark)
+                .await?,
+        );
+
+        Ok(messages)
+    }
+
+    async fn append(&self, chat_message: &ChatMessage) -> Result<()> {
+        let url = Self::channel_url(&self.replication_log_url, &chat_message.channel)?;
+
+        self.request(reqwest::Method::POST, url)
+            .json(chat_message)
+            .send()
+            .await?
+            .error_for_status()?;
+
+        Ok(())
+    }
+}
+
+/// Routes every request to the replication log shard that stores the channel, see
+/// [`common::sharding`].
+pub struct ShardedReplicationLogClient {
+    hash_ring: HashRing,
+    shard_clients: HashMap<String, Arc<dyn ReplicationLogClient>>,
+}
 
-        let deserialized_messages: Vec<ChatMessage> = serde_json::from_str(&body)?;
+impl ShardedReplicationLogClient {
+    /// Creates a client for every shard of the map via `shard_client`.
+    ///
+    /// # Panics
+    ///
+    /// If the map contains no shards, see [`ShardMap::validate`].
+    pub fn new<F>(shard_map: ShardMap, mut shard_client: F) -> Self
+    where
+        F: FnMut(&Shard) -> Arc<dyn ReplicationLogClient>,
+    {
+        let shard_clients = shard_map
+            .shards
+            .iter()
+            .map(|shard| (shard.id.clone(), shard_client(shard)))
+            .collect();
 
-        Ok(deserialized_messages)
+        ShardedReplicationLogClient {
+            hash_ring: HashRing::new(shard_map),
+            shard_clients,
+        }
+    }
+
+    /// The client of the shard that stores the channel.
+    pub fn shard_client(&self, channel_name: &str) -> &dyn ReplicationLogClient {
+        let shard = self.hash_ring.shard_for(channel_name);
+
+        self.shard_clients[&shard.id].as_ref()
+    }
+}
+
+#[async_trait]
+impl ReplicationLogClient for ShardedReplicationLogClient {
+    async fn get_messages_since(
+        &self,
+        channel_name: &str,
+        offset: SequenceNumber,
+    ) -> Result<Vec<SequencedMessage>> {
+        self.shard_client(channel_name)
+            .get_messages_since(channel_name, offset)
+            .await
+    }
+
+    async fn wait_for_messages_since(
+        &self,
+        channel_name: &str,
+        offset: SequenceNumber,
+        timeout: Duration,
+    ) -> Result<Vec<SequencedMessage>> {
+        self.shard_client(channel_name)
+            .wait_for_messages_since(channel_name, offset, timeout)
+            .await
+    }
+
+    async fn get_messages_before(
+        &self,
+        channel_name: &str,
+        before: Option<SequenceNumber>,
+        count: usize,
+    ) -> Result<Vec<SequencedMessage>> {
+        self.shard_client(channel_name)
+            .get_messages_before(channel_name, before, count)
+            .await
+    }
+
+    async fn bootstrap(&self, channel_name: &str) -> Result<Vec<SequencedMessage>> {
+        self.shard_client(channel_name)
+            .bootstrap(channel_name)
+            .await
+    }
+
+    async fn append(&self, chat_message: &ChatMessage) -> Result<()> {
+        self.shard_client(&chat_message.channel)
+            .append(chat_message)
+            .await
+    }
+
+    async fn get_messages_for_channel(&self, channel_name: &str) -> Result<Vec<ChatMessage>> {
+        self.shard_client(channel_name)
+            .get_messages_for_channel(channel_name)
+            .await
     }
 }
@@ -0,0 +1,262 @@
+use std::sync::Arc;
+
+use common::ChatMessage;
+use insta::internals::SettingsBindDropGuard;
+use warp::{test::RequestBuilder, Filter, Reply};
+
+use crate::{api::Api, chat_server::ChatServer};
+
+use super::{
+    mocks::{MockChannelPublisher, MockChannelSubscriber, MockReplicationLogClient},
+    redact_join_times,
+};
+
+fn api_with_history(history: Vec<ChatMessage>) -> Api {
+    let mock_replication_log_client = MockReplicationLogClient::new(history);
+    let mock_channel_subscriber = MockChannelSubscriber::new(mock_replication_log_client.clone());
+    let chat_server = ChatServer::new(
+        Arc::new(mock_channel_subscriber.clone()),
+        Arc::new(MockChannelPublisher {
+            channel_subscriber: mock_channel_subscriber,
+        }),
+        Arc::new(mock_replication_log_client),
+    );
+
+    Api::new(chat_server, "test-node".to_string())
+}
+
+/// Like [`super::redact_message_ids`], but for ids serialized as strings.
+fn redact_serialized_message_ids() -> SettingsBindDropGuard {
+    let mut settings = insta::Settings::clone_current();
+    settings.add_filter(r#""[0-9A-HJKMNP-TV-Z]{26}""#, r#""[id]""#);
+
+    settings.bind_to_scope()
+}
+
+/// Returns the status code and body of the response, after checking that the body is JSON.
+async fn send<F>(request: RequestBuilder, routes: &F) -> String
+where
+    F: Filter + 'static,
+    F::Extract: Reply + Send,
+{
+    let response = request.reply(routes).await;
+    let body = String::from_utf8(response.body().to_vec()).unwrap();
+    if !body.is_empty() {
+        assert_eq!(response.headers()["content-type"], "application/json");
+    }
+
+    format!("{} {body}", response.status().as_u16())
+}
+
+#[tokio::test]
+async fn join_and_leave_channels() {
+    let _redaction = redact_join_times();
+    let api = api_with_history(vec![]);
+    let routes = api.routes();
+
+    let subscriptions = || warp::test::request().path("/api/v1/subscriptions");
+    let subscription = |method: &str, channel: &str| {
+        warp::test::request()
+            .method(method)
+            .path(&format!("/api/v1/subscriptions/{channel}"))
+    };
+
+    insta::assert_snapshot!(send(subscriptions(), &routes).await, @"200 []");
+    insta::assert_snapshot!(
+        send(subscription("PUT", "test-channel"), &routes).await,
+        @r###"
+    201 {"channel":"test-channel","joined_at":"[time]","message_count":0,"history_backfill":{"status":"succeeded"},"connection":null,"forwarder":{"status":"running","received_messages":0,"forwarded_messages":0,"forwarded_batches":0,"queued_messages":0,"queue_overflows":0,"dropped_messages":0,"skipped_errors":0,"last_skipped_error":null},"joined_via_api":true}
+    "###
+    );
+    insta::assert_snapshot!(
+        send(subscription("PUT", "test-channel"), &routes).await,
+        @r###"
+    200 {"channel":"test-channel","joined_at":"[time]","message_count":0,"history_backfill":{"status":"succeeded"},"connection":null,"forwarder":{"status":"running","received_messages":0,"forwarded_messages":0,"forwarded_batches":0,"queued_messages":0,"queue_overflows":0,"dropped_messages":0,"skipped_errors":0,"last_skipped_error":null},"joined_via_api":true}
+    "###
+    );
+    insta::assert_snapshot!(
+        send(subscription("PUT", "invalid%20channel"), &routes).await,
+        @r###"
+    400 {"error":"channel names must consist of 1 to 128 ASCII letters, digits, '-' or '_'"}
+    "###
+    );
+    insta::assert_snapshot!(
+        send(subscriptions(), &routes).await,
+        @r###"
+    200 [{"channel":"test-channel","joined_at":"[time]","message_count":0,"history_backfill":{"status":"succeeded"},"connection":null,"forwarder":{"status":"running","received_messages":0,"forwarded_messages":0,"forwarded_batches":0,"queued_messages":0,"queue_overflows":0,"dropped_messages":0,"skipped_errors":0,"last_skipped_error":null},"joined_via_api":true}]
+    "###
+    );
+    insta::assert_snapshot!(
+        send(warp::test::request().path("/api/v1/node"), &routes).await,
+        @r###"
+    200 {"node_name":"test-node","version":"0.1.0","joined_channels":1}
+    "###
+    );
+
+    insta::assert_snapshot!(send(subscription("DELETE", "test-channel"), &routes).await, @"204 ");
+    insta::assert_snapshot!(send(subscription("DELETE", "test-channel"), &routes).await, @r###"
+    404 {"error":"channel test-channel has not been joined via the API"}
+    "###);
+    insta::assert_snapshot!(send(subscriptions(), &routes).await, @"200 []");
+}
+
+#[tokio::test]
+async fn paginate_messages() {
+    let _redaction = redact_serialized_message_ids();
+    let history: Vec<_> = (1..=5)
+        .map(|i| ChatMessage::new("test-channel", format!("message {i}")))
+        .collect();
+    let api = api_with_history(history.clone());
+    let routes = api.routes();
+    api.join("test-channel").await.unwrap();
+
+    let page = |query: &str| {
+        warp::test::request().path(&format!("/api/v1/channels/test-channel/messages{query}"))
+    };
+
+    insta::assert_snapshot!(send(page("?limit=2"), &routes).await, @r###"
+    200 {"messages":[{"id":"[id]","channel":"test-channel","message_text":"message 4"},{"id":"[id]","channel":"test-channel","message_text":"message 5"}],"next_before":"[id]"}
+    "###);
+    let before = history[3].id;
+    insta::assert_snapshot!(send(page(&format!("?limit=2&before={before}")), &routes).await, @r###"
+    200 {"messages":[{"id":"[id]","channel":"test-channel","message_text":"message 2"},{"id":"[id]","channel":"test-channel","message_text":"message 3"}],"next_before":"[id]"}
+    "###);
+    let before = history[1].id;
+    insta::assert_snapshot!(send(page(&format!("?limit=2&before={before}")), &routes).await, @r###"
+    200 {"messages":[{"id":"[id]","channel":"test-channel","message_text":"message 1"}],"next_before":null}
+    "###);
+    insta::assert_snapshot!(send(page("?limit=many"), &routes).await, @r###"
+    400 {"error":"Invalid query string"}
+    "###);
+    insta::assert_snapshot!(
+        send(warp::test::request().path("/api/v1/channels/other-channel/messages"), &routes).await,
+        @r###"
+    404 {"error":"channel other-channel is not joined"}
+    "###
+    );
+}
+
+#[tokio::test]
+async fn send_messages() {
+    let _redaction = redact_serialized_message_ids();
+    let api = api_with_history(vec![]);
+    let routes = api.routes();
+
+    let send_message = |body: &str| {
+        warp::test::request()
+            .method("POST")
+            .path("/api/v1/channels/test-channel/messages")
+            .header("content-type", "application/json")
+            .body(body)
+    };
+
+    insta::assert_snapshot!(send(send_message(r#"{"message_text":"Hello!"}"#), &routes).await, @r###"
+    201 {"id":"[id]","channel":"test-channel","message_text":"Hello!"}
+    "###);
+    insta::assert_snapshot!(send(send_message(r#"{"message_text":" "}"#), &routes).await, @r###"
+    400 {"error":"the message must not be empty"}
+    "###);
+    insta::assert_snapshot!(send(send_message("not json"), &routes).await, @r###"
+    400 {"error":"Request body deserialize error: expected ident at line 1 column 2"}
+    "###);
+}
+
+#[tokio::test]
+async fn serve_deprecated_unversioned_paths() {
+    let _redaction = redact_serialized_message_ids();
+    let api = api_with_history(vec![ChatMessage::new("test-channel", "message 1")]);
+    let routes = api.routes();
+    api.join("test-channel").await.unwrap();
+
+    let deprecation = |path: &str| {
+        let routes = routes.clone();
+        let path = path.to_string();
+        async move {
+            let response = warp::test::request().path(&path).reply(&routes).await;
+            (
+                response.status().as_u16(),
+                response
+                    .headers()
+                    .get("deprecation")
+                    .map(|value| value.to_str().unwrap().to_string()),
+            )
+        }
+    };
+    assert_eq!(deprecation("/api/v1/node").await, (200, None));
+    assert_eq!(deprecation("/node").await, (200, Some("true".to_string())));
+    assert_eq!(
+        deprecation("/subscriptions").await,
+        (200, Some("true".to_string()))
+    );
+
+    insta::assert_snapshot!(
+        send(warp::test::request().path("/channels/test-channel/messages"), &routes).await,
+        @r###"
+    200 {"messages":[{"id":"[id]","channel":"test-channel","message_text":"message 1"}],"next_before":null}
+    "###
+    );
+    insta::assert_snapshot!(send(warp::test::request().path("/messages"), &routes).await, @r###"
+    200 [{"id":"[id]","channel":"test-channel","message_text":"message 1"}]
+    "###);
+    insta::assert_snapshot!(
+        send(warp::test::request().path("/unknown"), &routes).await,
+        @r###"
+    404 {"error":"Not Found"}
+    "###
+    );
+}
+
+#[tokio::test]
+async fn respond_with_json_errors() {
+    let api = api_with_history(vec![]);
+    let routes = api.routes();
+
+    insta::assert_snapshot!(
+        send(warp::test::request().path("/api/v1/unknown"), &routes).await,
+        @r###"
+    404 {"error":"Not Found"}
+    "###
+    );
+    insta::assert_snapshot!(
+        send(warp::test::request().path("/messages"), &routes).await,
+        @"200 []"
+    );
+    insta::assert_snapshot!(
+        send(warp::test::request().method("PATCH").path("/api/v1/subscriptions/test-channel"), &routes).await,
+        @r###"
+    405 {"error":"HTTP method not allowed"}
+    "###
+    );
+    insta::assert_snapshot!(
+        send(warp::test::request().path("/api/v1/channels/test-channel/ws"), &routes).await,
+        @r###"
+    400 {"error":"Invalid request header \"connection\""}
+    "###
+    );
+    insta::assert_snapshot!(
+        send(warp::test::request().method("POST").path("/api/v1/channels/test-channel/messages").header("content-type", "text/plain").body("hello"), &routes).await,
+        @r###"
+    415 {"error":"The request's content-type is not supported"}
+    "###
+    );
+}
+
+#[tokio::test]
+async fn serve_openapi_document() {
+    let api = api_with_history(vec![]);
+    let response = warp::test::request()
+        .path("/api/v1/openapi.json")
+        .reply(&api.routes())
+        .await;
+    let document: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+
+    let paths: Vec<_> = document["paths"].as_object().unwrap().keys().collect();
+    insta::assert_debug_snapshot!(paths, @r###"
+    [
+        "/api/v1/channels/{channel}/messages",
+        "/api/v1/node",
+        "/api/v1/subscriptions",
+        "/api/v1/subscriptions/{channel}",
+    ]
+    "###);
+}
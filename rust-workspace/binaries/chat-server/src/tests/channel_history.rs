@@ -0,0 +1,91 @@
+use std::time::{Duration, SystemTime};
+
+use common::{ChatMessage, MessageId};
+
+use crate::channel_history::{message_size, ChannelHistory, HistoryRetention};
+
+fn message_texts(channel_history: &mut ChannelHistory) -> Vec<String> {
+    channel_history
+        .messages()
+        .into_iter()
+        .map(|chat_message| chat_message.message_text)
+        .collect()
+}
+
+#[test]
+fn keep_all_messages_by_default() {
+    let mut channel_history = ChannelHistory::new(HistoryRetention::unbounded());
+
+    for i in 1..=3 {
+        channel_history.push(ChatMessage::new("test-channel", format!("message {i}")));
+    }
+
+    assert_eq!(message_texts(&mut channel_history).len(), 3);
+    assert!(!channel_history.has_evicted_messages());
+}
+
+#[test]
+fn evict_by_message_count() {
+    let mut channel_history = ChannelHistory::new(HistoryRetention {
+        max_messages: Some(2),
+        ..Default::default()
+    });
+
+    for i in 1..=3 {
+        channel_history.push(ChatMessage::new("test-channel", format!("message {i}")));
+    }
+
+    insta::assert_debug_snapshot!(message_texts(&mut channel_history), @r###"
+    [
+        "message 2",
+        "message 3",
+    ]
+    "###);
+    assert!(channel_history.has_evicted_messages());
+}
+
+#[test]
+fn evict_by_size() {
+    let messages: Vec<_> = (1..=3)
+        .map(|i| ChatMessage::new("test-channel", format!("message {i}")))
+        .collect();
+    let mut channel_history = ChannelHistory::new(HistoryRetention {
+        // Only fits two of the messages.
+        max_bytes: Some(2 * message_size(&messages[0]) + 1),
+        ..Default::default()
+    });
+
+    for chat_message in messages {
+        channel_history.push(chat_message);
+    }
+
+    insta::assert_debug_snapshot!(message_texts(&mut channel_history), @r###"
+    [
+        "message 2",
+        "message 3",
+    ]
+    "###);
+}
+
+#[test]
+fn evict_by_age() {
+    let mut channel_history = ChannelHistory::new(HistoryRetention {
+        max_age: Some(Duration::from_secs(60)),
+        ..Default::default()
+    });
+
+    let two_minutes_ago = SystemTime::now() - Duration::from_secs(120);
+    channel_history.push(ChatMessage {
+        id: MessageId::from_datetime(two_minutes_ago),
+        channel: "test-channel".to_string(),
+        message_text: "old message".to_string(),
+    });
+    channel_history.push(ChatMessage::new("test-channel", "new message"));
+
+    insta::assert_debug_snapshot!(message_texts(&mut channel_history), @r###"
+    [
+        "new message",
+    ]
+    "###);
+    assert!(channel_history.has_evicted_messages());
+}
@@ -1,99 +1,51 @@
 use std::{sync::Arc, time::Duration};
 
-use anyhow::{Error, Result};
-use async_trait::async_trait;
-use futures::{future, TryStreamExt};
-use tokio::sync::broadcast::{self, Receiver, Sender};
-use tokio_stream::wrappers::BroadcastStream;
-
-use common::{ChatMessage, ChatMessageStream};
+use common::{forwarder_health::ItemErrorPolicy, ChatMessage};
 
 use crate::{
-    channel_subscriber::ChannelSubscriber, chat_server::ChatServer,
+    channel_history::HistoryRetention, channel_publisher::ReplicationLogChannelPublisher,
+    chat_server::ChatServer, message_validation::MessageValidationError,
     replication_log_client::ReplicationLogClient,
 };
 
-#[derive(Clone)]
-struct MockChannelSubscriber {
-    message_sender: Sender<ChatMessage>,
-    /// Keep a dummy receiver alive so that we can "publish" messages even if there are no active
-    /// subscribers.
-    _dummy_receiver: Arc<Receiver<ChatMessage>>,
-}
-
-impl MockChannelSubscriber {
-    pub fn new() -> Self {
-        let (message_sender, receiver) = broadcast::channel(16);
-        let _dummy_receiver = Arc::new(receiver);
-
-        MockChannelSubscriber {
-            message_sender,
-            _dummy_receiver,
-        }
-    }
-
-    pub fn publish_message(&self, msg: ChatMessage) -> Result<()> {
-        let _num_receivers = self.message_sender.send(msg)?;
-
-        Ok(())
-    }
-}
-
-struct MockReplicationLogClient {
-    pub messages: Vec<ChatMessage>,
-}
-
-#[async_trait]
-impl ReplicationLogClient for MockReplicationLogClient {
-    async fn get_messages_for_channel(&self, channel_name: &str) -> Result<Vec<ChatMessage>> {
-        let messages_for_channel = self
-            .messages
-            .iter()
-            .filter(|chat_message| chat_message.channel == channel_name)
-            .cloned()
-            .collect();
-        Ok(messages_for_channel)
-    }
-}
-
-#[async_trait]
-impl ChannelSubscriber for MockChannelSubscriber {
-    async fn subscribe(&self, channel_name: &str) -> Result<ChatMessageStream> {
-        let channel_name = channel_name.to_string();
-        let message_receiver = self.message_sender.subscribe();
-        let stream = BroadcastStream::new(message_receiver)
-            .try_filter(move |msg| future::ready(msg.channel == channel_name))
-            .map_err(Error::from);
-
-        Ok(Box::pin(stream))
-    }
-}
+use super::{
+    mocks::{
+        MockChannelPublisher, MockChannelSubscriber, MockItemsChannelSubscriber,
+        MockPublishingReplicationLogClient, MockReplicationLogClient,
+    },
+    redact_join_times, redact_message_ids,
+};
 
 #[tokio::test]
 async fn subscribe() {
-    let mock_channel_subscriber = MockChannelSubscriber::new();
-    let mock_replication_log_client = MockReplicationLogClient { messages: vec![] };
+    let _settings = redact_message_ids();
+    let mock_replication_log_client = MockReplicationLogClient::new(vec![]);
+    let mock_channel_subscriber = MockChannelSubscriber::new(mock_replication_log_client.clone());
     let chat_server = ChatServer::new(
         Arc::new(mock_channel_subscriber.clone()),
+        Arc::new(MockChannelPublisher {
+            channel_subscriber: mock_channel_subscriber.clone(),
+        }),
         Arc::new(mock_replication_log_client),
     );
 
     insta::assert_debug_snapshot!(chat_server.messages_received(), @"[]");
 
     let channel_name = "test-channel".to_string();
-    chat_server.subscribe(&channel_name).await.unwrap();
+    let _channel_lease = chat_server.subscribe(&channel_name).await.unwrap();
 
     mock_channel_subscriber
-        .publish_message(ChatMessage {
-            channel: channel_name.clone(),
-            message_text: "This message should show up in the client.".to_string(),
-        })
+        .publish_message(ChatMessage::new(
+            channel_name.clone(),
+            "This message should show up in the client.",
+        ))
         .unwrap();
     // Since the message is handled asynchronously, we have to wait a little.
     tokio::time::sleep(Duration::from_millis(100)).await;
     insta::assert_debug_snapshot!(chat_server.messages_received(), @r###"
     [
         ChatMessage {
+            id: [id],
             channel: "test-channel",
             message_text: "This message should show up in the client.",
         },
@@ -102,15 +54,16 @@ async fn subscribe() {
 
     let unrelated_channel_name = "some-other-channel".to_string();
     mock_channel_subscriber
-        .publish_message(ChatMessage {
-            channel: unrelated_channel_name,
-            message_text: "This message should not show up.".to_string(),
-        })
+        .publish_message(ChatMessage::new(
+            unrelated_channel_name,
+            "This message should not show up.",
+        ))
         .unwrap();
     tokio::time::sleep(Duration::from_millis(100)).await;
     insta::assert_debug_snapshot!(chat_server.messages_received(), @r###"
     [
         ChatMessage {
+            id: [id],
             channel: "test-channel",
             message_text: "This message should show up in the client.",
         },
@@ -120,99 +73,839 @@ async fn subscribe() {
 
 #[tokio::test]
 async fn unsubscribe() {
-    let mock_channel_subscriber = MockChannelSubscriber::new();
-    let mock_replication_log_client = MockReplicationLogClient { messages: vec![] };
+    let _settings = redact_message_ids();
+    let mock_replication_log_client = MockReplicationLogClient::new(vec![]);
+    let mock_channel_subscriber = MockChannelSubscriber::new(mock_replication_log_client.clone());
     let chat_server = ChatServer::new(
         Arc::new(mock_channel_subscriber.clone()),
+        Arc::new(MockChannelPublisher {
+            channel_subscriber: mock_channel_subscriber.clone(),
+        }),
         Arc::new(mock_replication_log_client),
     );
 
     let channel_name = "test-channel".to_string();
-    chat_server.subscribe(&channel_name).await.unwrap();
+    let channel_lease = chat_server.subscribe(&channel_name).await.unwrap();
 
     mock_channel_subscriber
-        .publish_message(ChatMessage {
-            channel: channel_name.clone(),
-            message_text: "This message should only show up until we unsubscribe.".to_string(),
-        })
+        .publish_message(ChatMessage::new(
+            channel_name.clone(),
+            "This message should only show up until we unsubscribe.",
+        ))
         .unwrap();
     // Make sure the message had enough time to be handled.
     tokio::time::sleep(Duration::from_millis(100)).await;
     insta::assert_debug_snapshot!(chat_server.messages_received(), @r###"
     [
         ChatMessage {
+            id: [id],
             channel: "test-channel",
             message_text: "This message should only show up until we unsubscribe.",
         },
     ]
     "###);
 
-    chat_server.unsubscribe(&channel_name);
+    drop(channel_lease);
     insta::assert_debug_snapshot!(chat_server.messages_received(), @"[]");
 
     mock_channel_subscriber
-        .publish_message(ChatMessage {
-            channel: channel_name.clone(),
-            message_text:
-                "This message should not show up in the client because we already unsubscribed."
-                    .to_string(),
-        })
+        .publish_message(ChatMessage::new(
+            channel_name.clone(),
+            "This message should not show up in the client because we already unsubscribed.",
+        ))
         .unwrap();
     tokio::time::sleep(Duration::from_millis(100)).await;
     insta::assert_debug_snapshot!(chat_server.messages_received(), @"[]");
 }
 
+#[tokio::test]
+async fn keep_channel_until_last_lease_is_dropped() {
+    let mock_replication_log_client = MockReplicationLogClient::new(vec![]);
+    let mock_channel_subscriber = MockChannelSubscriber::new(mock_replication_log_client.clone());
+    let chat_server = ChatServer::new(
+        Arc::new(mock_channel_subscriber.clone()),
+        Arc::new(MockChannelPublisher {
+            channel_subscriber: mock_channel_subscriber.clone(),
+        }),
+        Arc::new(mock_replication_log_client),
+    );
+
+    let first_channel_lease = chat_server.subscribe("test-channel").await.unwrap();
+    let second_channel_lease = chat_server.subscribe("test-channel").await.unwrap();
+    assert_eq!(chat_server.holder_count("test-channel"), 2);
+
+    mock_channel_subscriber
+        .publish_message(ChatMessage::new("test-channel", "message 1"))
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    // The channel is still joined by the second holder.
+    drop(first_channel_lease);
+    assert_eq!(chat_server.holder_count("test-channel"), 1);
+    mock_channel_subscriber
+        .publish_message(ChatMessage::new("test-channel", "message 2"))
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(chat_server.messages_received().len(), 2);
+
+    drop(second_channel_lease);
+    assert_eq!(chat_server.holder_count("test-channel"), 0);
+    assert!(chat_server.messages_received().is_empty());
+}
+
+#[tokio::test]
+async fn linger_after_last_lease_is_dropped() {
+    let mock_replication_log_client = MockReplicationLogClient::new(vec![]);
+    let mock_channel_subscriber = MockChannelSubscriber::new(mock_replication_log_client.clone());
+    let chat_server = ChatServer::new(
+        Arc::new(mock_channel_subscriber.clone()),
+        Arc::new(MockChannelPublisher {
+            channel_subscriber: mock_channel_subscriber.clone(),
+        }),
+        Arc::new(mock_replication_log_client),
+    )
+    .with_linger_period(Duration::from_millis(300));
+
+    let channel_lease = chat_server.subscribe("test-channel").await.unwrap();
+    mock_channel_subscriber
+        .publish_message(ChatMessage::new("test-channel", "message 1"))
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    drop(channel_lease);
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    // Still joined, so new messages are received.
+    mock_channel_subscriber
+        .publish_message(ChatMessage::new("test-channel", "message 2"))
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(chat_server.messages_received().len(), 2);
+
+    // Re-joining during the linger period keeps the channel.
+    let channel_lease = chat_server.subscribe("test-channel").await.unwrap();
+    tokio::time::sleep(Duration::from_millis(400)).await;
+    assert_eq!(chat_server.messages_received().len(), 2);
+
+    drop(channel_lease);
+    tokio::time::sleep(Duration::from_millis(400)).await;
+    assert!(chat_server.messages_received().is_empty());
+}
+
 #[tokio::test]
 async fn retrieve_messages_from_replication_log() {
-    let mock_channel_subscriber = MockChannelSubscriber::new();
-    let mock_replication_log_client = MockReplicationLogClient {
-        messages: vec![
-            ChatMessage::new("test-channel1", "message 1 on test-channel1"),
-            ChatMessage::new("test-channel2", "message 1 on test-channel2"),
-            ChatMessage::new("test-channel1", "message 2 on test-channel1"),
-            ChatMessage::new("test-channel2", "message 2 on test-channel2"),
-        ],
-    };
+    let _settings = redact_message_ids();
+    let mock_replication_log_client = MockReplicationLogClient::new(vec![
+        ChatMessage::new("test-channel1", "message 1 on test-channel1"),
+        ChatMessage::new("test-channel2", "message 1 on test-channel2"),
+        ChatMessage::new("test-channel1", "message 2 on test-channel1"),
+        ChatMessage::new("test-channel2", "message 2 on test-channel2"),
+    ]);
+    let mock_channel_subscriber = MockChannelSubscriber::new(mock_replication_log_client.clone());
 
     let chat_server = ChatServer::new(
         Arc::new(mock_channel_subscriber.clone()),
+        Arc::new(MockChannelPublisher {
+            channel_subscriber: mock_channel_subscriber.clone(),
+        }),
         Arc::new(mock_replication_log_client),
     );
     insta::assert_debug_snapshot!(chat_server.messages_received(), @"[]");
 
-    chat_server.subscribe("test-channel1").await.unwrap();
+    let _first_channel_lease = chat_server.subscribe("test-channel1").await.unwrap();
     insta::assert_debug_snapshot!(chat_server.messages_received(), @r###"
     [
         ChatMessage {
+            id: [id],
             channel: "test-channel1",
             message_text: "message 1 on test-channel1",
         },
         ChatMessage {
+            id: [id],
             channel: "test-channel1",
             message_text: "message 2 on test-channel1",
         },
     ]
     "###);
 
-    chat_server.subscribe("test-channel2").await.unwrap();
+    let _second_channel_lease = chat_server.subscribe("test-channel2").await.unwrap();
     insta::assert_debug_snapshot!(chat_server.messages_received(), @r###"
     [
         ChatMessage {
+            id: [id],
             channel: "test-channel1",
             message_text: "message 1 on test-channel1",
         },
         ChatMessage {
+            id: [id],
             channel: "test-channel1",
             message_text: "message 2 on test-channel1",
         },
         ChatMessage {
+            id: [id],
             channel: "test-channel2",
             message_text: "message 1 on test-channel2",
         },
         ChatMessage {
+            id: [id],
             channel: "test-channel2",
             message_text: "message 2 on test-channel2",
         },
     ]
     "###);
 }
+
+#[tokio::test]
+async fn read_messages_per_channel() {
+    let _settings = redact_message_ids();
+    let mock_replication_log_client = MockReplicationLogClient::new(vec![
+        ChatMessage::new("test-channel1", "message 1 on test-channel1"),
+        ChatMessage::new("test-channel2", "message 1 on test-channel2"),
+    ]);
+    let mock_channel_subscriber = MockChannelSubscriber::new(mock_replication_log_client.clone());
+    let chat_server = ChatServer::new(
+        Arc::new(mock_channel_subscriber.clone()),
+        Arc::new(MockChannelPublisher {
+            channel_subscriber: mock_channel_subscriber.clone(),
+        }),
+        Arc::new(mock_replication_log_client),
+    );
+
+    let _first_channel_lease = chat_server.subscribe("test-channel1").await.unwrap();
+    let second_channel_lease = chat_server.subscribe("test-channel2").await.unwrap();
+    mock_channel_subscriber
+        .publish_message(ChatMessage::new(
+            "test-channel2",
+            "message 2 on test-channel2",
+        ))
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    insta::assert_debug_snapshot!(chat_server.messages_for_channel("test-channel2"), @r###"
+    Some(
+        [
+            ChatMessage {
+                id: [id],
+                channel: "test-channel2",
+                message_text: "message 1 on test-channel2",
+            },
+            ChatMessage {
+                id: [id],
+                channel: "test-channel2",
+                message_text: "message 2 on test-channel2",
+            },
+        ],
+    )
+    "###);
+
+    drop(second_channel_lease);
+    assert!(chat_server.messages_for_channel("test-channel2").is_none());
+    assert_eq!(
+        chat_server
+            .messages_for_channel("test-channel1")
+            .unwrap()
+            .len(),
+        1
+    );
+}
+
+#[tokio::test]
+async fn provide_no_messages_of_channels_that_are_not_joined() {
+    let mock_replication_log_client =
+        MockReplicationLogClient::new(vec![ChatMessage::new("test-channel", "message 1")]);
+    let mock_channel_subscriber = MockChannelSubscriber::new(mock_replication_log_client.clone());
+    let chat_server = ChatServer::new(
+        Arc::new(mock_channel_subscriber.clone()),
+        Arc::new(MockChannelPublisher {
+            channel_subscriber: mock_channel_subscriber.clone(),
+        }),
+        Arc::new(mock_replication_log_client),
+    )
+    .with_linger_period(Duration::from_secs(60));
+
+    // A channel that could not be joined is left right away, despite the linger period.
+    mock_channel_subscriber.set_unavailable(true);
+    assert!(chat_server.subscribe("test-channel").await.is_err());
+    assert!(chat_server.subscription_state("test-channel").is_none());
+    assert!(chat_server.subscription_states().is_empty());
+    assert!(chat_server.messages_for_channel("test-channel").is_none());
+    assert!(chat_server
+        .recent_messages("test-channel", None, 10)
+        .await
+        .unwrap()
+        .is_none());
+
+    // Subscribing again joins the channel again.
+    mock_channel_subscriber.set_unavailable(false);
+    let _channel_lease = chat_server.subscribe("test-channel").await.unwrap();
+    assert_eq!(
+        chat_server
+            .messages_for_channel("test-channel")
+            .unwrap()
+            .len(),
+        1
+    );
+}
+
+#[tokio::test]
+async fn fetch_evicted_messages_from_replication_log() {
+    let mock_replication_log_client = MockReplicationLogClient::new(
+        (1..=3)
+            .map(|i| ChatMessage::new("test-channel", format!("message {i}")))
+            .collect(),
+    );
+    let mock_channel_subscriber = MockChannelSubscriber::new(mock_replication_log_client.clone());
+    let chat_server = ChatServer::new(
+        Arc::new(mock_channel_subscriber.clone()),
+        Arc::new(MockChannelPublisher {
+            channel_subscriber: mock_channel_subscriber.clone(),
+        }),
+        Arc::new(mock_replication_log_client.clone()),
+    )
+    .with_history_retention(HistoryRetention {
+        max_messages: Some(2),
+        ..Default::default()
+    });
+
+    let _channel_lease = chat_server.subscribe("test-channel").await.unwrap();
+    mock_channel_subscriber
+        .publish_message(ChatMessage::new("test-channel", "message 4"))
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let message_texts = |messages: Vec<ChatMessage>| -> Vec<String> {
+        messages
+            .into_iter()
+            .map(|chat_message| chat_message.message_text)
+            .collect()
+    };
+    insta::assert_debug_snapshot!(
+        message_texts(chat_server.messages_for_channel("test-channel").unwrap()),
+        @r###"
+    [
+        "message 3",
+        "message 4",
+    ]
+    "###
+    );
+    insta::assert_debug_snapshot!(
+        message_texts(chat_server.recent_messages("test-channel", None, 3).await.unwrap().unwrap()),
+        @r###"
+    [
+        "message 2",
+        "message 3",
+        "message 4",
+    ]
+    "###
+    );
+    assert!(chat_server
+        .recent_messages("other-channel", None, 3)
+        .await
+        .unwrap()
+        .is_none());
+    // Joining only retrieved the retained messages, and only the missing message was retrieved.
+    assert_eq!(
+        mock_replication_log_client.requests_before(),
+        vec![(None, 2), (Some(3), 1)]
+    );
+
+    // Once only live messages are retained, their sequence numbers are unknown.
+    for i in 5..=6 {
+        mock_channel_subscriber
+            .publish_message(ChatMessage::new("test-channel", format!("message {i}")))
+            .unwrap();
+    }
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let messages = chat_server
+        .recent_messages("test-channel", None, 3)
+        .await
+        .unwrap()
+        .unwrap();
+    let before = messages[0].id;
+    insta::assert_debug_snapshot!(message_texts(messages), @r###"
+    [
+        "message 4",
+        "message 5",
+        "message 6",
+    ]
+    "###);
+    // The preceding page starts at a message that is no longer retained.
+    insta::assert_debug_snapshot!(
+        message_texts(chat_server.recent_messages("test-channel", Some(before), 2).await.unwrap().unwrap()),
+        @r###"
+    [
+        "message 2",
+        "message 3",
+    ]
+    "###
+    );
+    assert_eq!(
+        mock_replication_log_client.requests_before()[2..],
+        [(None, 3), (Some(4), 2)]
+    );
+}
+
+#[tokio::test]
+async fn fetch_messages_omitted_by_snapshot_from_replication_log() {
+    let mock_replication_log_client = MockReplicationLogClient::new(
+        (1..=3)
+            .map(|i| ChatMessage::new("test-channel", format!("message {i}")))
+            .collect(),
+    )
+    .with_snapshot_size(1);
+    let mock_channel_subscriber = MockChannelSubscriber::new(mock_replication_log_client.clone());
+    let chat_server = ChatServer::new(
+        Arc::new(mock_channel_subscriber.clone()),
+        Arc::new(MockChannelPublisher {
+            channel_subscriber: mock_channel_subscriber.clone(),
+        }),
+        Arc::new(mock_replication_log_client),
+    );
+
+    let _channel_lease = chat_server.subscribe("test-channel").await.unwrap();
+    assert_eq!(
+        chat_server
+            .messages_for_channel("test-channel")
+            .unwrap()
+            .len(),
+        1
+    );
+    assert_eq!(
+        chat_server
+            .recent_messages("test-channel", None, 3)
+            .await
+            .unwrap()
+            .unwrap()
+            .len(),
+        3
+    );
+}
+
+#[tokio::test]
+async fn report_subscription_state() {
+    let mock_replication_log_client = MockReplicationLogClient::new(vec![
+        ChatMessage::new("test-channel", "message 1"),
+        ChatMessage::new("test-channel", "message 2"),
+    ]);
+    let mock_channel_subscriber = MockChannelSubscriber::new(mock_replication_log_client.clone());
+    let chat_server = ChatServer::new(
+        Arc::new(mock_channel_subscriber.clone()),
+        Arc::new(MockChannelPublisher {
+            channel_subscriber: mock_channel_subscriber.clone(),
+        }),
+        Arc::new(mock_replication_log_client),
+    );
+
+    assert!(chat_server.subscription_state("test-channel").is_none());
+    let _channel_lease = chat_server.subscribe("test-channel").await.unwrap();
+
+    let subscription_state = chat_server.subscription_state("test-channel").unwrap();
+    assert!(subscription_state.joined_at.is_some());
+    insta::assert_debug_snapshot!(
+        (subscription_state.message_count, subscription_state.history_backfill),
+        @r###"
+    (
+        2,
+        Succeeded,
+    )
+    "###
+    );
+}
+
+#[tokio::test]
+async fn join_without_history_if_replication_log_is_unavailable() {
+    let mock_replication_log_client = MockReplicationLogClient::new(vec![
+        ChatMessage::new("test-channel", "message 1"),
+        ChatMessage::new("test-channel", "message 2"),
+    ]);
+    let mock_channel_subscriber = MockChannelSubscriber::new(mock_replication_log_client.clone());
+    let chat_server = ChatServer::new(
+        Arc::new(mock_channel_subscriber.clone()),
+        Arc::new(MockChannelPublisher {
+            channel_subscriber: mock_channel_subscriber.clone(),
+        }),
+        Arc::new(mock_replication_log_client.clone()),
+    );
+
+    let _settings = redact_join_times();
+    mock_replication_log_client.set_unavailable(true);
+    let channel_lease = chat_server.subscribe("test-channel").await.unwrap();
+    insta::assert_debug_snapshot!(chat_server.subscription_states(), @r###"
+    [
+        SubscriptionState {
+            channel_name: "test-channel",
+            joined_at: Some(
+                [time],
+            ),
+            message_count: 0,
+            history_backfill: Failed {
+                error: "the replication log is unavailable",
+            },
+            connection_state: None,
+            forwarder_health: Some(
+                ForwarderHealth {
+                    status: Running,
+                    received_messages: 0,
+                    forwarded_messages: 0,
+                    forwarded_batches: 0,
+                    queued_messages: 0,
+                    queue_overflows: 0,
+                    dropped_messages: 0,
+                    skipped_errors: 0,
+                    last_skipped_error: None,
+                },
+            ),
+        },
+    ]
+    "###);
+
+    // Older messages are retrieved on demand once the replication log is available again.
+    mock_replication_log_client.set_unavailable(false);
+    let message_texts: Vec<_> = chat_server
+        .recent_messages("test-channel", None, 10)
+        .await
+        .unwrap()
+        .unwrap()
+        .into_iter()
+        .map(|chat_message| chat_message.message_text)
+        .collect();
+    insta::assert_debug_snapshot!(message_texts, @r###"
+    [
+        "message 1",
+        "message 2",
+    ]
+    "###);
+
+    // They are also retrieved along with the first live message, but only it is passed on live.
+    let (_, mut new_messages) = channel_lease.messages_with_updates();
+    mock_channel_subscriber
+        .publish_message(ChatMessage::new("test-channel", "message 3"))
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let message_texts: Vec<_> = chat_server
+        .messages_for_channel("test-channel")
+        .unwrap()
+        .into_iter()
+        .map(|chat_message| chat_message.message_text)
+        .collect();
+    insta::assert_debug_snapshot!(message_texts, @r###"
+    [
+        "message 1",
+        "message 2",
+        "message 3",
+    ]
+    "###);
+    let mut live_message_texts = Vec::new();
+    while let Ok(chat_message) = new_messages.try_recv() {
+        live_message_texts.push(chat_message.message_text);
+    }
+    insta::assert_debug_snapshot!(live_message_texts, @r###"
+    [
+        "message 3",
+    ]
+    "###);
+    insta::assert_debug_snapshot!(chat_server.subscription_states()[0].history_backfill, @"Succeeded");
+}
+
+#[tokio::test]
+async fn ignore_duplicates_from_replication_log_and_channel() {
+    let _settings = redact_message_ids();
+    let message_sent_during_subscription =
+        ChatMessage::new("test-channel", "message received from both sources");
+    let mock_replication_log_client =
+        MockReplicationLogClient::new(vec![message_sent_during_subscription.clone()]);
+    let mock_channel_subscriber = MockChannelSubscriber::new(mock_replication_log_client.clone());
+
+    let chat_server = ChatServer::new(
+        Arc::new(mock_channel_subscriber.clone()),
+        Arc::new(MockChannelPublisher {
+            channel_subscriber: mock_channel_subscriber.clone(),
+        }),
+        Arc::new(mock_replication_log_client),
+    );
+    let _channel_lease = chat_server.subscribe("test-channel").await.unwrap();
+
+    mock_channel_subscriber
+        .publish_message_to_subscribers(message_sent_during_subscription)
+        .unwrap();
+    mock_channel_subscriber
+        .publish_message(ChatMessage::new("test-channel", "new message"))
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    insta::assert_debug_snapshot!(chat_server.messages_received(), @r###"
+    [
+        ChatMessage {
+            id: [id],
+            channel: "test-channel",
+            message_text: "message received from both sources",
+        },
+    ]
+    "###);
+}
+
+#[tokio::test]
+async fn receive_messages_that_reach_replication_log_late() {
+    let _settings = redact_message_ids();
+    let mock_replication_log_client = MockReplicationLogClient::new(vec![]);
+    let mock_channel_subscriber = MockChannelSubscriber::new(mock_replication_log_client.clone());
+    let chat_server = ChatServer::new(
+        Arc::new(mock_channel_subscriber.clone()),
+        Arc::new(MockChannelPublisher {
+            channel_subscriber: mock_channel_subscriber.clone(),
+        }),
+        Arc::new(mock_replication_log_client.clone()),
+    );
+
+    // This message was published before we subscribed, but has not reached the replication log
+    // yet.
+    let delayed_message = ChatMessage::new("test-channel", "published before subscribing");
+    let _channel_lease = chat_server.subscribe("test-channel").await.unwrap();
+    insta::assert_debug_snapshot!(chat_server.messages_received(), @"[]");
+
+    // A message that is published after subscribing, which reaches us before the replication log.
+    let live_message = ChatMessage::new("test-channel", "published after subscribing");
+    mock_channel_subscriber
+        .publish_message_to_subscribers(live_message.clone())
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    // We have to wait for the replication log to catch up before receiving the live message.
+    insta::assert_debug_snapshot!(chat_server.messages_received(), @"[]");
+
+    mock_replication_log_client.add_message(delayed_message);
+    mock_replication_log_client.add_message(live_message);
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    insta::assert_debug_snapshot!(chat_server.messages_received(), @r###"
+    [
+        ChatMessage {
+            id: [id],
+            channel: "test-channel",
+            message_text: "published before subscribing",
+        },
+        ChatMessage {
+            id: [id],
+            channel: "test-channel",
+            message_text: "published after subscribing",
+        },
+    ]
+    "###);
+}
+
+#[tokio::test]
+async fn replay_buffered_messages_after_history() {
+    let _settings = redact_message_ids();
+    let mock_replication_log_client =
+        MockReplicationLogClient::new(vec![ChatMessage::new("test-channel", "message 1")]);
+    let mock_channel_subscriber = MockChannelSubscriber::new(mock_replication_log_client.clone());
+    let chat_server = ChatServer::new(
+        Arc::new(mock_channel_subscriber.clone()),
+        Arc::new(MockChannelPublisher {
+            channel_subscriber: mock_channel_subscriber.clone(),
+        }),
+        Arc::new(mock_replication_log_client.clone()),
+    );
+
+    let _channel_lease = chat_server.subscribe("test-channel").await.unwrap();
+
+    // Messages 2 and 3 reach us live, but only message 2 has reached the replication log yet.
+    let message_3 = ChatMessage::new("test-channel", "message 3");
+    mock_channel_subscriber
+        .publish_message(ChatMessage::new("test-channel", "message 2"))
+        .unwrap();
+    mock_channel_subscriber
+        .publish_message_to_subscribers(message_3.clone())
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    mock_replication_log_client.add_message(message_3);
+
+    // Messages that arrive after synchronizing are passed on directly.
+    mock_channel_subscriber
+        .publish_message_to_subscribers(ChatMessage::new("test-channel", "message 4"))
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    insta::assert_debug_snapshot!(chat_server.messages_received(), @r###"
+    [
+        ChatMessage {
+            id: [id],
+            channel: "test-channel",
+            message_text: "message 1",
+        },
+        ChatMessage {
+            id: [id],
+            channel: "test-channel",
+            message_text: "message 2",
+        },
+        ChatMessage {
+            id: [id],
+            channel: "test-channel",
+            message_text: "message 3",
+        },
+        ChatMessage {
+            id: [id],
+            channel: "test-channel",
+            message_text: "message 4",
+        },
+    ]
+    "###);
+}
+
+#[tokio::test]
+async fn send_message() {
+    let _settings = redact_message_ids();
+    let mock_replication_log_client = MockReplicationLogClient::new(vec![]);
+    let mock_channel_subscriber = MockChannelSubscriber::new(mock_replication_log_client.clone());
+    let chat_server = ChatServer::new(
+        Arc::new(mock_channel_subscriber.clone()),
+        // Appends to the replication log, which publishes the message afterwards.
+        Arc::new(ReplicationLogChannelPublisher::new(Arc::new(
+            MockPublishingReplicationLogClient {
+                channel_subscriber: mock_channel_subscriber,
+            },
+        ))),
+        Arc::new(mock_replication_log_client.clone()),
+    );
+    let _channel_lease = chat_server.subscribe("test-channel").await.unwrap();
+
+    let sent_message = chat_server
+        .send_message("test-channel", "Hello everyone!")
+        .await
+        .unwrap();
+    // The message is stored in the replication log before sending it is acknowledged.
+    let stored_message_ids: Vec<_> = mock_replication_log_client
+        .get_messages_for_channel("test-channel")
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|message| message.id)
+        .collect();
+    assert_eq!(stored_message_ids, vec![sent_message.id]);
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let messages_received = chat_server.messages_received();
+    assert_eq!(messages_received.len(), 1);
+    assert_eq!(messages_received[0].id, sent_message.id);
+    insta::assert_debug_snapshot!(messages_received, @r###"
+    [
+        ChatMessage {
+            id: [id],
+            channel: "test-channel",
+            message_text: "Hello everyone!",
+        },
+    ]
+    "###);
+}
+
+#[tokio::test]
+async fn reject_invalid_messages() {
+    let mock_replication_log_client = MockReplicationLogClient::new(vec![]);
+    let mock_channel_subscriber = MockChannelSubscriber::new(mock_replication_log_client.clone());
+    let chat_server = ChatServer::new(
+        Arc::new(mock_channel_subscriber.clone()),
+        Arc::new(MockChannelPublisher {
+            channel_subscriber: mock_channel_subscriber.clone(),
+        }),
+        Arc::new(mock_replication_log_client.clone()),
+    );
+
+    let too_long_message = "a".repeat(5000);
+    let invalid_messages = [
+        ("test-channel", "  "),
+        ("test-channel", too_long_message.as_str()),
+        ("", "Hello everyone!"),
+        ("test channel", "Hello everyone!"),
+    ];
+    let validation_errors: Vec<_> =
+        futures::future::join_all(invalid_messages.iter().map(|(channel_name, message_text)| {
+            chat_server.send_message(channel_name, message_text)
+        }))
+        .await
+        .into_iter()
+        .map(|result| {
+            result
+                .unwrap_err()
+                .downcast::<MessageValidationError>()
+                .unwrap()
+        })
+        .collect();
+    insta::assert_debug_snapshot!(validation_errors, @r###"
+    [
+        EmptyMessage,
+        MessageTooLong,
+        InvalidChannelName,
+        InvalidChannelName,
+    ]
+    "###);
+
+    // Nothing should have been published.
+    assert!(mock_replication_log_client
+        .get_messages_for_channel("test-channel")
+        .await
+        .unwrap()
+        .is_empty());
+}
+
+#[tokio::test]
+async fn handle_erroneous_messages_according_to_policy() {
+    let chat_server = |item_error_policy| {
+        let mock_channel_subscriber = MockItemsChannelSubscriber {
+            items: vec![Err("malformed message".to_string())],
+        };
+        let mock_replication_log_client = Arc::new(MockReplicationLogClient::new(vec![]));
+        ChatServer::new(
+            Arc::new(mock_channel_subscriber),
+            Arc::new(ReplicationLogChannelPublisher::new(
+                mock_replication_log_client.clone(),
+            )),
+            mock_replication_log_client,
+        )
+        .with_item_error_policy(item_error_policy)
+    };
+    let skipping_chat_server = chat_server(ItemErrorPolicy::Skip);
+    let aborting_chat_server = chat_server(ItemErrorPolicy::Abort);
+
+    let _skipping_lease = skipping_chat_server
+        .subscribe("test-channel")
+        .await
+        .unwrap();
+    let _aborting_lease = aborting_chat_server
+        .subscribe("test-channel")
+        .await
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let forwarder_health = |chat_server: &ChatServer| {
+        chat_server
+            .subscription_state("test-channel")
+            .unwrap()
+            .forwarder_health
+            .unwrap()
+    };
+    insta::assert_debug_snapshot!(forwarder_health(&skipping_chat_server), @r###"
+    ForwarderHealth {
+        status: Running,
+        received_messages: 0,
+        forwarded_messages: 0,
+        forwarded_batches: 0,
+        queued_messages: 0,
+        queue_overflows: 0,
+        dropped_messages: 0,
+        skipped_errors: 1,
+        last_skipped_error: Some(
+            "malformed message",
+        ),
+    }
+    "###);
+    insta::assert_debug_snapshot!(forwarder_health(&aborting_chat_server), @r###"
+    ForwarderHealth {
+        status: Failed {
+            error: "malformed message",
+        },
+        received_messages: 0,
+        forwarded_messages: 0,
+        forwarded_batches: 0,
+        queued_messages: 0,
+        queue_overflows: 0,
+        dropped_messages: 0,
+        skipped_errors: 0,
+        last_skipped_error: None,
+    }
+    "###);
+}
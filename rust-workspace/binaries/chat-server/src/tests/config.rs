@@ -0,0 +1,157 @@
+use common::config::{load_config_from, ConfigArgs};
+
+use crate::config::ChatServerConfig;
+
+fn load_config(env_vars: &[(&str, &str)]) -> anyhow::Result<ChatServerConfig> {
+    let env_vars = env_vars
+        .iter()
+        .map(|(name, value)| (name.to_string(), value.to_string()));
+
+    load_config_from(&ConfigArgs::default(), env_vars)
+}
+
+#[test]
+fn default_to_the_cluster_setup() {
+    insta::assert_debug_snapshot!(load_config(&[]).unwrap(), @r###"
+    ChatServerConfig {
+        node_name: None,
+        server: ServerConfig {
+            bind_address: 0.0.0.0:8000,
+        },
+        message_broker: MessageBrokerConfig {
+            url: "redis://message-broker-service:6379",
+        },
+        replication_log: ReplicationLogConfig {
+            url: "http://replication-log-service:80/messages",
+            snapshot_url: Some(
+                "http://replication-log-service:80/snapshots",
+            ),
+            page_size: 1000,
+            request_timeout: 10s,
+            shard_map: None,
+            shard_snapshots: true,
+        },
+        channels: ChannelsConfig {
+            initial: [
+                "default-channel",
+            ],
+            linger_period: 30s,
+        },
+        history: HistoryRetention {
+            max_messages: Some(
+                10000,
+            ),
+            max_bytes: Some(
+                16777216,
+            ),
+            max_age: None,
+        },
+        ingestion: IngestionConfig {
+            queue_capacity: 4096,
+            max_batch_size: 128,
+            overflow_policy: Block,
+        },
+        reconnect: Backoff {
+            initial_delay: 100ms,
+            max_delay: 30s,
+        },
+    }
+    "###);
+}
+
+#[test]
+fn configure_channels_and_timeouts() {
+    let config = load_config(&[
+        ("CHAT_SERVER__CHANNELS__INITIAL", r#"["lobby", "random"]"#),
+        ("CHAT_SERVER__CHANNELS__LINGER_PERIOD", "2m"),
+        ("CHAT_SERVER__HISTORY__MAX_AGE", "1h 30m"),
+        ("CHAT_SERVER__INGESTION__OVERFLOW_POLICY", "drop_oldest"),
+        ("CHAT_SERVER__REPLICATION_LOG__REQUEST_TIMEOUT", "500ms"),
+    ])
+    .unwrap();
+
+    insta::assert_debug_snapshot!(
+        (
+            config.channels,
+            config.history.max_age,
+            config.ingestion.overflow_policy,
+            config.replication_log.request_timeout,
+        ),
+        @r###"
+    (
+        ChannelsConfig {
+            initial: [
+                "lobby",
+                "random",
+            ],
+            linger_period: 120s,
+        },
+        Some(
+            5400s,
+        ),
+        DropOldest,
+        500ms,
+    )
+    "###
+    );
+}
+
+#[test]
+fn configure_replication_log_shards() {
+    let config = load_config(&[
+        (
+            "CHAT_SERVER__REPLICATION_LOG__SHARD_MAP",
+            r#"{ shards = [{ id = "shard-1", url = "http://shard-1:80" }, { id = "shard-2", url = "http://shard-2:80" }] }"#,
+        ),
+        ("CHAT_SERVER__REPLICATION_LOG__SHARD_SNAPSHOTS", "false"),
+    ])
+    .unwrap();
+
+    assert!(!config.replication_log.shard_snapshots);
+    insta::assert_debug_snapshot!(config.replication_log.shard_map, @r###"
+    Some(
+        ShardMap {
+            shards: [
+                Shard {
+                    id: "shard-1",
+                    url: "http://shard-1:80",
+                },
+                Shard {
+                    id: "shard-2",
+                    url: "http://shard-2:80",
+                },
+            ],
+            virtual_nodes: 64,
+        },
+    )
+    "###);
+}
+
+#[test]
+fn reject_invalid_settings() {
+    let error = |env_vars| format!("{:#}", load_config(env_vars).unwrap_err());
+
+    insta::assert_snapshot!(error(&[("CHAT_SERVER__CHANNELS__INITIAL", r#"["no spaces"]"#)]), @r###"
+    invalid configuration: invalid initial channel "no spaces": channel names must consist of 1 to 128 ASCII letters, digits, '-' or '_'
+    "###);
+    insta::assert_snapshot!(error(&[("CHAT_SERVER__REPLICATION_LOG__URL", "replication-log")]), @r###"
+    invalid configuration: invalid replication log URL "replication-log": relative URL without a base
+    "###);
+    insta::assert_snapshot!(error(&[("CHAT_SERVER__REPLICATION_LOG__SHARD_MAP", r#"{ shards = [{ id = "shard-1", url = "shard-1" }] }"#)]), @r###"
+    invalid configuration: invalid URL of shard "shard-1": relative URL without a base
+    "###);
+    insta::assert_snapshot!(error(&[("CHAT_SERVER__INGESTION__MAX_BATCH_SIZE", "0")]), @"invalid configuration: invalid ingestion settings: the queue capacity and the maximum batch size must be positive");
+    insta::assert_snapshot!(error(&[("CHAT_SERVER__HISTORY__MAX_MESSAGES", "0")]), @"invalid configuration: invalid history settings: the maximum number of messages must be positive");
+    insta::assert_snapshot!(error(&[("CHAT_SERVER__HISTORY__MAX_AGE", "0s")]), @"invalid configuration: invalid history settings: the maximum age must be positive");
+    insta::assert_snapshot!(error(&[("CHAT_SERVER__HISTORY__MAX_AGE", "-1h")]), @r###"
+    invalid configuration: invalid value: string "-1h", expected a duration
+    in `history.max_age`
+
+    "###);
+    insta::assert_snapshot!(error(&[("CHAT_SERVER__RECONNECT__INITIAL_DELAY", "1h")]), @"invalid configuration: the initial reconnect delay must not exceed the maximum delay");
+    insta::assert_snapshot!(error(&[("CHAT_SERVER__CHANNELS__LINGER_PERIOD", "soon")]), @r###"
+    invalid configuration: invalid value: string "soon", expected a duration
+    in `channels.linger_period`
+
+    "###);
+}
@@ -0,0 +1,275 @@
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, Mutex,
+};
+
+use anyhow::{bail, Error, Result};
+use async_trait::async_trait;
+use futures::{future, TryStreamExt};
+use stream_cancel::{StreamExt, Trigger, Tripwire};
+use tokio::sync::broadcast::{self, Receiver, Sender};
+use tokio_stream::wrappers::BroadcastStream;
+
+use common::{ChatMessage, ChatMessageStream, SequenceNumber, SequencedMessage};
+
+use crate::{
+    channel_publisher::ChannelPublisher, channel_subscriber::ChannelSubscriber,
+    replication_log_client::ReplicationLogClient,
+};
+
+#[derive(Clone)]
+pub struct MockChannelSubscriber {
+    message_sender: Sender<ChatMessage>,
+    /// Keep a dummy receiver alive so that we can "publish" messages even if there are no active
+    /// subscribers.
+    _dummy_receiver: Arc<Receiver<ChatMessage>>,
+    /// Published messages are also sent to the replication log, like the message broker would.
+    replication_log_client: MockReplicationLogClient,
+    /// Dropping the trigger ends all current subscriptions, like losing the connection would.
+    disconnect_trigger: Arc<Mutex<Trigger>>,
+    disconnect_tripwire: Arc<Mutex<Tripwire>>,
+    /// If set, subscribing fails.
+    is_unavailable: Arc<AtomicBool>,
+}
+
+impl MockChannelSubscriber {
+    pub fn new(replication_log_client: MockReplicationLogClient) -> Self {
+        let (message_sender, receiver) = broadcast::channel(16);
+        let _dummy_receiver = Arc::new(receiver);
+        let (disconnect_trigger, disconnect_tripwire) = Tripwire::new();
+
+        MockChannelSubscriber {
+            message_sender,
+            _dummy_receiver,
+            replication_log_client,
+            disconnect_trigger: Arc::new(Mutex::new(disconnect_trigger)),
+            disconnect_tripwire: Arc::new(Mutex::new(disconnect_tripwire)),
+            is_unavailable: Default::default(),
+        }
+    }
+
+    /// Ends all current subscriptions.
+    pub fn disconnect(&self) {
+        let (disconnect_trigger, disconnect_tripwire) = Tripwire::new();
+        *self.disconnect_tripwire.lock().unwrap() = disconnect_tripwire;
+        // Replacing the trigger drops the previous one.
+        *self.disconnect_trigger.lock().unwrap() = disconnect_trigger;
+    }
+
+    pub fn set_unavailable(&self, is_unavailable: bool) {
+        self.is_unavailable.store(is_unavailable, Ordering::SeqCst);
+    }
+
+    pub fn publish_message(&self, msg: ChatMessage) -> Result<()> {
+        self.replication_log_client.add_message(msg.clone());
+
+        self.publish_message_to_subscribers(msg)
+    }
+
+    /// Only deliver the message to the subscribers, e.g. to emulate that the message has not yet
+    /// reached the replication log.
+    pub fn publish_message_to_subscribers(&self, msg: ChatMessage) -> Result<()> {
+        let _num_receivers = self.message_sender.send(msg)?;
+
+        Ok(())
+    }
+}
+
+/// The `before` and `count` arguments of a retrieval of the last messages.
+type RequestBefore = (Option<SequenceNumber>, usize);
+
+#[derive(Clone)]
+pub struct MockReplicationLogClient {
+    messages: Arc<Mutex<Vec<ChatMessage>>>,
+    /// If set, retrieving messages fails.
+    is_unavailable: Arc<AtomicBool>,
+    /// If set, bootstrapping only returns this many of the most recent messages, like starting
+    /// from a snapshot would.
+    snapshot_size: Option<usize>,
+    /// The arguments of every call of `get_messages_before`.
+    requests_before: Arc<Mutex<Vec<RequestBefore>>>,
+    /// The offsets of every call of `get_messages_since`.
+    requests_since: Arc<Mutex<Vec<SequenceNumber>>>,
+}
+
+impl MockReplicationLogClient {
+    pub fn new(messages: Vec<ChatMessage>) -> Self {
+        MockReplicationLogClient {
+            messages: Arc::new(Mutex::new(messages)),
+            is_unavailable: Default::default(),
+            snapshot_size: None,
+            requests_before: Default::default(),
+            requests_since: Default::default(),
+        }
+    }
+
+    pub fn with_snapshot_size(mut self, snapshot_size: usize) -> Self {
+        self.snapshot_size = Some(snapshot_size);
+        self
+    }
+
+    pub fn set_unavailable(&self, is_unavailable: bool) {
+        self.is_unavailable.store(is_unavailable, Ordering::SeqCst);
+    }
+
+    pub fn add_message(&self, msg: ChatMessage) {
+        self.messages.lock().unwrap().push(msg);
+    }
+
+    pub fn requests_before(&self) -> Vec<RequestBefore> {
+        self.requests_before.lock().unwrap().clone()
+    }
+
+    pub fn requests_since(&self) -> Vec<SequenceNumber> {
+        self.requests_since.lock().unwrap().clone()
+    }
+
+    fn sequenced_messages(&self, channel_name: &str) -> Result<Vec<SequencedMessage>> {
+        if self.is_unavailable.load(Ordering::SeqCst) {
+            bail!("the replication log is unavailable");
+        }
+
+        let messages_for_channel = self
+            .messages
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|chat_message| chat_message.channel == channel_name)
+            .zip(1..)
+            .map(|(chat_message, sequence_number)| SequencedMessage {
+                sequence_number,
+                chat_message: chat_message.clone(),
+            })
+            .collect();
+        Ok(messages_for_channel)
+    }
+}
+
+#[async_trait]
+impl ReplicationLogClient for MockReplicationLogClient {
+    async fn get_messages_since(
+        &self,
+        channel_name: &str,
+        offset: SequenceNumber,
+    ) -> Result<Vec<SequencedMessage>> {
+        self.requests_since.lock().unwrap().push(offset);
+
+        let mut messages = self.sequenced_messages(channel_name)?;
+        messages.retain(|message| message.sequence_number > offset);
+
+        Ok(messages)
+    }
+
+    async fn get_messages_before(
+        &self,
+        channel_name: &str,
+        before: Option<SequenceNumber>,
+        count: usize,
+    ) -> Result<Vec<SequencedMessage>> {
+        self.requests_before.lock().unwrap().push((before, count));
+
+        let mut messages = self.sequenced_messages(channel_name)?;
+        if let Some(before) = before {
+            messages.retain(|message| message.sequence_number < before);
+        }
+        messages.drain(..messages.len().saturating_sub(count));
+
+        Ok(messages)
+    }
+
+    async fn bootstrap(&self, channel_name: &str) -> Result<Vec<SequencedMessage>> {
+        let mut messages = self.sequenced_messages(channel_name)?;
+        if let Some(snapshot_size) = self.snapshot_size {
+            messages.drain(..messages.len().saturating_sub(snapshot_size));
+        }
+
+        Ok(messages)
+    }
+
+    async fn append(&self, chat_message: &ChatMessage) -> Result<()> {
+        self.add_message(chat_message.clone());
+
+        Ok(())
+    }
+}
+
+/// Publishes through the mocked message broker, i.e. to the [`MockChannelSubscriber`].
+pub struct MockChannelPublisher {
+    pub channel_subscriber: MockChannelSubscriber,
+}
+
+#[async_trait]
+impl ChannelPublisher for MockChannelPublisher {
+    async fn publish(&self, chat_message: &ChatMessage) -> Result<()> {
+        self.channel_subscriber
+            .publish_message(chat_message.clone())
+    }
+}
+
+/// A replication log that publishes appended messages through the mocked message broker, i.e. to
+/// the [`MockChannelSubscriber`].
+pub struct MockPublishingReplicationLogClient {
+    pub channel_subscriber: MockChannelSubscriber,
+}
+
+#[async_trait]
+impl ReplicationLogClient for MockPublishingReplicationLogClient {
+    async fn get_messages_since(
+        &self,
+        channel_name: &str,
+        offset: SequenceNumber,
+    ) -> Result<Vec<SequencedMessage>> {
+        self.channel_subscriber
+            .replication_log_client
+            .get_messages_since(channel_name, offset)
+            .await
+    }
+
+    async fn append(&self, chat_message: &ChatMessage) -> Result<()> {
+        // Adds the message to the replication log before delivering it to the subscribers.
+        self.channel_subscriber
+            .publish_message(chat_message.clone())
+    }
+}
+
+#[async_trait]
+impl ChannelSubscriber for MockChannelSubscriber {
+    async fn subscribe(&self, channel_name: &str) -> Result<ChatMessageStream> {
+        if self.is_unavailable.load(Ordering::SeqCst) {
+            bail!("the message broker is unavailable");
+        }
+
+        let channel_name = channel_name.to_string();
+        let message_receiver = self.message_sender.subscribe();
+        let disconnect_tripwire = self.disconnect_tripwire.lock().unwrap().clone();
+        let stream = BroadcastStream::new(message_receiver)
+            .try_filter(move |msg| future::ready(msg.channel == channel_name))
+            .map_err(Error::from)
+            .take_until_if(disconnect_tripwire);
+
+        Ok(Box::pin(stream))
+    }
+}
+
+/// Delivers the given items to every subscriber of any channel, including errors.
+pub struct MockItemsChannelSubscriber {
+    pub items: Vec<Result<ChatMessage, String>>,
+}
+
+#[async_trait]
+impl ChannelSubscriber for MockItemsChannelSubscriber {
+    async fn subscribe(&self, _channel_name: &str) -> Result<ChatMessageStream> {
+        let items: Vec<_> = self
+            .items
+            .iter()
+            .cloned()
+            .map(|item| item.map_err(Error::msg))
+            .collect();
+
+        // Stay subscribed after the items have been delivered.
+        Ok(Box::pin(futures::StreamExt::chain(
+            futures::stream::iter(items),
+            futures::stream::pending(),
+        )))
+    }
+}
@@ -1,2 +1,28 @@
+use insta::internals::SettingsBindDropGuard;
+
+mod api;
+mod channel_history;
 mod chat_server;
+mod config;
+mod mocks;
+mod reconnecting_subscriber;
 mod replication_log_client;
+mod websocket;
+
+/// Message ids are randomly generated; this replaces them in snapshots so that these stay stable.
+fn redact_message_ids() -> SettingsBindDropGuard {
+    let mut settings = insta::Settings::clone_current();
+    settings.add_filter(r"Ulid\(\s*\d+,?\s*\)", "[id]");
+
+    settings.bind_to_scope()
+}
+
+/// Replaces the times at which channels were joined in snapshots, both as `SystemTime` and as
+/// serialized by the API.
+fn redact_join_times() -> SettingsBindDropGuard {
+    let mut settings = insta::Settings::clone_current();
+    settings.add_filter(r"SystemTime \{[^}]*\}", "[time]");
+    settings.add_filter(r#""joined_at":\d+"#, r#""joined_at":"[time]""#);
+
+    settings.bind_to_scope()
+}
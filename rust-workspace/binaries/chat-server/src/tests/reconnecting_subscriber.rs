@@ -0,0 +1,179 @@
+use std::{sync::Arc, time::Duration};
+
+use common::ChatMessage;
+
+use crate::{
+    channel_subscriber::{ChannelSubscriber, ConnectionState},
+    chat_server::ChatServer,
+    reconnecting_subscriber::{Backoff, ReconnectingChannelSubscriber},
+};
+
+use super::mocks::{MockChannelPublisher, MockChannelSubscriber, MockReplicationLogClient};
+
+fn reconnecting_chat_server() -> (
+    ChatServer,
+    Arc<ReconnectingChannelSubscriber>,
+    MockChannelSubscriber,
+) {
+    let (chat_server, reconnecting_subscriber, mock_channel_subscriber, _) =
+        reconnecting_chat_server_with_history(vec![]);
+
+    (
+        chat_server,
+        reconnecting_subscriber,
+        mock_channel_subscriber,
+    )
+}
+
+fn reconnecting_chat_server_with_history(
+    history: Vec<ChatMessage>,
+) -> (
+    ChatServer,
+    Arc<ReconnectingChannelSubscriber>,
+    MockChannelSubscriber,
+    MockReplicationLogClient,
+) {
+    let mock_replication_log_client = MockReplicationLogClient::new(history);
+    let mock_channel_subscriber = MockChannelSubscriber::new(mock_replication_log_client.clone());
+    let reconnecting_subscriber = Arc::new(
+        ReconnectingChannelSubscriber::new(
+            Arc::new(mock_channel_subscriber.clone()),
+            Arc::new(mock_replication_log_client.clone()),
+        )
+        .with_backoff(Backoff {
+            initial_delay: Duration::from_millis(10),
+            max_delay: Duration::from_millis(40),
+        }),
+    );
+    let chat_server = ChatServer::new(
+        reconnecting_subscriber.clone(),
+        Arc::new(MockChannelPublisher {
+            channel_subscriber: mock_channel_subscriber.clone(),
+        }),
+        Arc::new(mock_replication_log_client.clone()),
+    );
+
+    (
+        chat_server,
+        reconnecting_subscriber,
+        mock_channel_subscriber,
+        mock_replication_log_client,
+    )
+}
+
+fn message_texts(chat_server: &ChatServer) -> Vec<String> {
+    chat_server
+        .messages_for_channel("test-channel")
+        .unwrap()
+        .into_iter()
+        .map(|chat_message| chat_message.message_text)
+        .collect()
+}
+
+#[tokio::test]
+async fn backfill_messages_missed_while_disconnected() {
+    let (chat_server, _, mock_channel_subscriber) = reconnecting_chat_server();
+    let _channel_lease = chat_server.subscribe("test-channel").await.unwrap();
+
+    mock_channel_subscriber
+        .publish_message(ChatMessage::new("test-channel", "before disconnecting"))
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    mock_channel_subscriber.disconnect();
+    // Only reaches the replication log, since nobody is subscribed right now.
+    mock_channel_subscriber
+        .publish_message(ChatMessage::new("test-channel", "while disconnected"))
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    mock_channel_subscriber
+        .publish_message(ChatMessage::new("test-channel", "after reconnecting"))
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    insta::assert_debug_snapshot!(message_texts(&chat_server), @r###"
+    [
+        "before disconnecting",
+        "while disconnected",
+        "after reconnecting",
+    ]
+    "###);
+}
+
+#[tokio::test]
+async fn backfill_only_messages_after_joining() {
+    let (chat_server, _, mock_channel_subscriber, mock_replication_log_client) =
+        reconnecting_chat_server_with_history(vec![
+            ChatMessage::new("test-channel", "message 1"),
+            ChatMessage::new("test-channel", "message 2"),
+        ]);
+    let _channel_lease = chat_server.subscribe("test-channel").await.unwrap();
+
+    mock_channel_subscriber.disconnect();
+    mock_channel_subscriber
+        .publish_message(ChatMessage::new("test-channel", "while disconnected"))
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    insta::assert_debug_snapshot!(mock_replication_log_client.requests_since(), @r###"
+    [
+        2,
+        2,
+    ]
+    "###);
+    insta::assert_debug_snapshot!(message_texts(&chat_server), @r###"
+    [
+        "message 1",
+        "message 2",
+        "while disconnected",
+    ]
+    "###);
+}
+
+#[tokio::test]
+async fn report_connection_state() {
+    let (chat_server, reconnecting_subscriber, mock_channel_subscriber) =
+        reconnecting_chat_server();
+    assert_eq!(
+        reconnecting_subscriber.connection_state("test-channel"),
+        None
+    );
+
+    let channel_lease = chat_server.subscribe("test-channel").await.unwrap();
+    assert_eq!(
+        reconnecting_subscriber.connection_state("test-channel"),
+        Some(ConnectionState::Connected)
+    );
+
+    mock_channel_subscriber.set_unavailable(true);
+    mock_channel_subscriber.disconnect();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let connection_state = chat_server
+        .subscription_state("test-channel")
+        .unwrap()
+        .connection_state;
+    let Some(ConnectionState::Reconnecting {
+        failed_attempts,
+        last_error,
+    }) = connection_state
+    else {
+        panic!("unexpected connection state: {connection_state:?}");
+    };
+    assert!(failed_attempts > 0);
+    insta::assert_snapshot!(last_error, @"the message broker is unavailable");
+
+    mock_channel_subscriber.set_unavailable(false);
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(
+        reconnecting_subscriber.connection_state("test-channel"),
+        Some(ConnectionState::Connected)
+    );
+
+    // The subscription ends once the forwarding task notices that the lease has been dropped.
+    drop(channel_lease);
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert_eq!(
+        reconnecting_subscriber.connection_state("test-channel"),
+        None
+    );
+}
@@ -1,17 +1,47 @@
-use common::{ChatMessage, DEFAULT_CHANNEL};
-use httpmock::prelude::{MockServer, GET};
+use std::{sync::Arc, time::Duration};
 
-use crate::replication_log_client::{ReplicationLogClient, ReqwestReplicationLogClient};
+use anyhow::Result;
+use async_trait::async_trait;
+use common::{
+    sharding::{HashRing, Shard, ShardMap},
+    snapshot::ChannelSnapshot,
+    ChatMessage, SequenceNumber, SequencedMessage, DEFAULT_CHANNEL,
+};
+use httpmock::prelude::{MockServer, GET, POST};
+
+use crate::{
+    channel_join::catch_up_with_history,
+    replication_log_client::{
+        MessagesRemoved, ReplicationLogClient, ReqwestReplicationLogClient,
+        ShardedReplicationLogClient,
+    },
+};
+
+use super::redact_message_ids;
+
+/// Assigns sequence numbers like the replication log would.
+fn sequenced(chat_messages: Vec<ChatMessage>) -> Vec<SequencedMessage> {
+    chat_messages
+        .into_iter()
+        .zip(1..)
+        .map(|(chat_message, sequence_number)| SequencedMessage {
+            sequence_number,
+            chat_message,
+        })
+        .collect()
+}
 
 #[tokio::test]
 async fn reqwest_client_get_messages() {
+    let _settings = redact_message_ids();
     let server = MockServer::start();
 
-    let messages_on_default_channel = vec![ChatMessage::new(DEFAULT_CHANNEL, "test-message1")];
-    let messages_on_other_channel = vec![
+    let messages_on_default_channel =
+        sequenced(vec![ChatMessage::new(DEFAULT_CHANNEL, "test-message1")]);
+    let messages_on_other_channel = sequenced(vec![
         ChatMessage::new("other-channel", "test-message2"),
         ChatMessage::new("other-channel", "test-message3"),
-    ];
+    ]);
 
     let _default_channel_mock = server.mock(|when, then| {
         when.method(GET).path(format!("/{DEFAULT_CHANNEL}"));
@@ -24,9 +54,7 @@ async fn reqwest_client_get_messages() {
             .body(serde_json::to_string(&messages_on_other_channel).unwrap());
     });
 
-    let client = ReqwestReplicationLogClient {
-        replication_log_url: server.base_url(),
-    };
+    let client = ReqwestReplicationLogClient::new(server.base_url());
 
     let retrieved_messages_for_default_channel = client
         .get_messages_for_channel(DEFAULT_CHANNEL)
@@ -35,6 +63,7 @@ async fn reqwest_client_get_messages() {
     insta::assert_debug_snapshot!(retrieved_messages_for_default_channel, @r###"
     [
         ChatMessage {
+            id: [id],
             channel: "default-channel",
             message_text: "test-message1",
         },
@@ -48,13 +77,454 @@ async fn reqwest_client_get_messages() {
     insta::assert_debug_snapshot!(retrieved_messages_for_other_channel, @r###"
     [
         ChatMessage {
+            id: [id],
             channel: "other-channel",
             message_text: "test-message2",
         },
         ChatMessage {
+            id: [id],
             channel: "other-channel",
             message_text: "test-message3",
         },
     ]
     "###);
 }
+
+#[tokio::test]
+async fn reqwest_client_reports_removed_messages() {
+    let server = MockServer::start();
+
+    let messages = sequenced(vec![
+        ChatMessage::new(DEFAULT_CHANNEL, "test-message1"),
+        ChatMessage::new(DEFAULT_CHANNEL, "test-message2"),
+        ChatMessage::new(DEFAULT_CHANNEL, "test-message3"),
+    ]);
+    let truncated_mock = server.mock(|when, then| {
+        when.method(GET)
+            .path(format!("/{DEFAULT_CHANNEL}"))
+            .query_param("after", "0");
+        then.status(200)
+            .header("Truncated-Before", "3")
+            .body(serde_json::to_string(&messages[2..]).unwrap());
+    });
+    let retained_mock = server.mock(|when, then| {
+        when.method(GET)
+            .path(format!("/{DEFAULT_CHANNEL}"))
+            .query_param("after", "2");
+        then.status(200)
+            .body(serde_json::to_string(&messages[2..]).unwrap());
+    });
+
+    let client = ReqwestReplicationLogClient::new(server.base_url());
+
+    let err = client
+        .get_messages_since(DEFAULT_CHANNEL, 0)
+        .await
+        .unwrap_err();
+    assert_eq!(
+        err.downcast_ref::<MessagesRemoved>(),
+        Some(&MessagesRemoved {
+            truncated_before: 3
+        })
+    );
+
+    let retained_messages = client
+        .get_retained_messages_since(DEFAULT_CHANNEL, 0)
+        .await
+        .unwrap();
+    assert_eq!(
+        retained_messages
+            .iter()
+            .map(|message| message.sequence_number)
+            .collect::<Vec<_>>(),
+        [3]
+    );
+    truncated_mock.assert_hits(2);
+    retained_mock.assert();
+}
+
+#[tokio::test]
+async fn reqwest_client_get_messages_since_in_pages() {
+    let server = MockServer::start();
+
+    let messages = sequenced(vec![
+        ChatMessage::new(DEFAULT_CHANNEL, "test-message1"),
+        ChatMessage::new(DEFAULT_CHANNEL, "test-message2"),
+        ChatMessage::new(DEFAULT_CHANNEL, "test-message3"),
+        ChatMessage::new(DEFAULT_CHANNEL, "test-message4"),
+        ChatMessage::new(DEFAULT_CHANNEL, "test-message5"),
+    ]);
+
+    let page_mocks: Vec<_> = [(1, &messages[1..3]), (3, &messages[3..5]), (5, &[])]
+        .into_iter()
+        .map(|(after, page)| {
+            server.mock(|when, then| {
+                when.method(GET)
+                    .path(format!("/{DEFAULT_CHANNEL}"))
+                    .query_param("after", after.to_string())
+                    .query_param("limit", "2");
+                then.status(200).body(serde_json::to_string(page).unwrap());
+            })
+        })
+        .collect();
+
+    let client = ReqwestReplicationLogClient::new(server.base_url()).with_page_size(2);
+
+    let retrieved_messages = client.get_messages_since(DEFAULT_CHANNEL, 1).await.unwrap();
+    for page_mock in page_mocks {
+        page_mock.assert();
+    }
+    let retrieved_texts: Vec<_> = retrieved_messages
+        .into_iter()
+        .map(|message| (message.sequence_number, message.chat_message.message_text))
+        .collect();
+    insta::assert_debug_snapshot!(retrieved_texts, @r###"
+    [
+        (
+            2,
+            "test-message2",
+        ),
+        (
+            3,
+            "test-message3",
+        ),
+        (
+            4,
+            "test-message4",
+        ),
+        (
+            5,
+            "test-message5",
+        ),
+    ]
+    "###);
+}
+
+#[tokio::test]
+async fn reqwest_client_encodes_channel_names_and_fails_on_error_responses() {
+    let server = MockServer::start();
+
+    let messages = sequenced(vec![ChatMessage::new(
+        "a channel/with?chars",
+        "test-message1",
+    )]);
+    let encoded_channel_mock = server.mock(|when, then| {
+        when.method(GET)
+            .path("/messages/a%20channel%2Fwith%3Fchars")
+            .query_param("after", "0");
+        then.status(200)
+            .body(serde_json::to_string(&messages).unwrap());
+    });
+    let _failing_mock = server.mock(|when, then| {
+        when.method(GET).path("/messages/failing-channel");
+        then.status(500).body("[]");
+    });
+
+    let client = ReqwestReplicationLogClient::new(server.url("/messages"));
+
+    let retrieved_messages = client
+        .get_messages_since("a channel/with?chars", 0)
+        .await
+        .unwrap();
+    encoded_channel_mock.assert();
+    assert_eq!(retrieved_messages.len(), 1);
+
+    let err = client
+        .get_messages_since("failing-channel", 0)
+        .await
+        .unwrap_err();
+    insta::assert_snapshot!(err.to_string().replace(&server.base_url(), "[url]"), @"HTTP status server error (500 Internal Server Error) for url ([url]/messages/failing-channel?after=0&limit=1000)");
+}
+
+#[tokio::test]
+async fn reqwest_client_waits_for_new_messages() {
+    let server = MockServer::start();
+
+    let messages = sequenced(vec![
+        ChatMessage::new(DEFAULT_CHANNEL, "test-message1"),
+        ChatMessage::new(DEFAULT_CHANNEL, "test-message2"),
+    ]);
+    // Only the first request waits; the following pages are retrieved right away.
+    let waiting_mock = server.mock(|when, then| {
+        when.method(GET)
+            .path(format!("/{DEFAULT_CHANNEL}"))
+            .query_param("after", "0")
+            .query_param("wait_ms", "2000");
+        then.status(200)
+            .body(serde_json::to_string(&messages[..1]).unwrap());
+    });
+    let next_page_mock = server.mock(|when, then| {
+        when.method(GET)
+            .path(format!("/{DEFAULT_CHANNEL}"))
+            .query_param("after", "1")
+            .matches(|request| {
+                !request
+                    .query_params
+                    .iter()
+                    .flatten()
+                    .any(|(name, _)| name == "wait_ms")
+            });
+        then.status(200)
+            .body(serde_json::to_string(&messages[1..]).unwrap());
+    });
+    let _last_page_mock = server.mock(|when, then| {
+        when.method(GET)
+            .path(format!("/{DEFAULT_CHANNEL}"))
+            .query_param("after", "2");
+        then.status(200).body("[]");
+    });
+
+    let client = ReqwestReplicationLogClient::new(server.base_url())
+        .with_page_size(1)
+        .with_request_timeout(Duration::from_secs(1));
+
+    let retrieved_messages = client
+        .wait_for_messages_since(DEFAULT_CHANNEL, 0, Duration::from_secs(2))
+        .await
+        .unwrap();
+    waiting_mock.assert();
+    next_page_mock.assert();
+    assert_eq!(retrieved_messages.len(), 2);
+}
+
+#[tokio::test]
+async fn reqwest_client_bootstraps_from_snapshot() {
+    let server = MockServer::start();
+
+    let messages = sequenced(
+        (1..=3)
+            .map(|i| ChatMessage::new(DEFAULT_CHANNEL, format!("test-message{i}")))
+            .collect(),
+    );
+    let snapshot = ChannelSnapshot {
+        channel: DEFAULT_CHANNEL.to_string(),
+        watermark: 2,
+        messages: messages[1..2].to_vec(),
+    };
+    let _snapshot_mock = server.mock(|when, then| {
+        when.method(GET)
+            .path(format!("/snapshots/{DEFAULT_CHANNEL}"));
+        then.status(200).body(snapshot.encode().unwrap());
+    });
+    let tail_mock = server.mock(|when, then| {
+        when.method(GET)
+            .path(format!("/messages/{DEFAULT_CHANNEL}"))
+            .query_param("after", "2");
+        then.status(200)
+            .body(serde_json::to_string(&messages[2..]).unwrap());
+    });
+    // Without a snapshot, the full history is retrieved.
+    let _missing_snapshot_mock = server.mock(|when, then| {
+        when.method(GET).path("/snapshots/other-channel");
+        then.status(404);
+    });
+    let full_history_mock = server.mock(|when, then| {
+        when.method(GET)
+            .path("/messages/other-channel")
+            .query_param("after", "0");
+        then.status(200).body("[]");
+    });
+
+    let client = ReqwestReplicationLogClient::new(server.url("/messages"))
+        .with_snapshot_url(server.url("/snapshots"));
+
+    let bootstrapped_texts: Vec<_> = client
+        .bootstrap(DEFAULT_CHANNEL)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|message| (message.sequence_number, message.chat_message.message_text))
+        .collect();
+    tail_mock.assert();
+    insta::assert_debug_snapshot!(bootstrapped_texts, @r###"
+    [
+        (
+            2,
+            "test-message2",
+        ),
+        (
+            3,
+            "test-message3",
+        ),
+    ]
+    "###);
+
+    assert!(client.bootstrap("other-channel").await.unwrap().is_empty());
+    full_history_mock.assert();
+}
+
+#[tokio::test]
+async fn reqwest_client_append() {
+    let server = MockServer::start();
+
+    let chat_message = ChatMessage::new(DEFAULT_CHANNEL, "test-message");
+    let append_mock = server.mock(|when, then| {
+        when.method(POST)
+            .path(format!("/{DEFAULT_CHANNEL}"))
+            .json_body_obj(&chat_message);
+        then.status(201);
+    });
+
+    let client = ReqwestReplicationLogClient::new(server.base_url());
+
+    client.append(&chat_message).await.unwrap();
+    append_mock.assert();
+
+    // Fails if the message could not be stored.
+    let unavailable_channel_mock = server.mock(|when, then| {
+        when.method(POST).path("/unavailable-channel");
+        then.status(500);
+    });
+    assert!(client
+        .append(&ChatMessage::new("unavailable-channel", "test-message"))
+        .await
+        .is_err());
+    unavailable_channel_mock.assert();
+}
+
+#[tokio::test]
+async fn sharded_client_routes_channels_to_their_shards() {
+    let servers = [MockServer::start(), MockServer::start()];
+    let shard_map = ShardMap::new(
+        servers
+            .iter()
+            .enumerate()
+            .map(|(i, server)| Shard {
+                id: format!("shard-{}", i + 1),
+                url: server.base_url(),
+            })
+            .collect(),
+    );
+
+    // Every shard answers with a message that names the shard.
+    let mocks: Vec<_> = servers
+        .iter()
+        .zip(&shard_map.shards)
+        .map(|(server, shard)| {
+            let messages = sequenced(vec![ChatMessage::new("any-channel", shard.id.clone())]);
+            let get_mock = server.mock(|when, then| {
+                when.method(GET).path_contains("/messages/");
+                then.status(200)
+                    .body(serde_json::to_string(&messages).unwrap());
+            });
+            let append_mock = server.mock(|when, then| {
+                when.method(POST).path_contains("/messages/");
+                then.status(201);
+            });
+
+            (get_mock, append_mock)
+        })
+        .collect();
+
+    let hash_ring = HashRing::new(shard_map.clone());
+    let client = ShardedReplicationLogClient::new(shard_map, |shard| {
+        Arc::new(ReqwestReplicationLogClient::new(format!(
+            "{}/messages",
+            shard.url
+        )))
+    });
+
+    let channels: Vec<_> = (0..10).map(|i| format!("channel-{i}")).collect();
+    for channel in &channels {
+        let messages = client.get_messages_for_channel(channel).await.unwrap();
+        assert_eq!(messages[0].message_text, hash_ring.shard_for(channel).id);
+
+        client
+            .append(&ChatMessage::new(channel.as_str(), "test-message"))
+            .await
+            .unwrap();
+    }
+
+    for ((get_mock, append_mock), shard) in mocks.iter().zip(&hash_ring.shard_map().shards) {
+        let shard_channels = channels
+            .iter()
+            .filter(|channel| hash_ring.shard_for(channel) == shard)
+            .count();
+        // Both shards own some of the channels.
+        assert!(shard_channels > 0);
+        assert_eq!(get_mock.hits(), shard_channels);
+        assert_eq!(append_mock.hits(), shard_channels);
+    }
+}
+
+#[tokio::test]
+async fn reqwest_client_gets_last_messages_with_a_single_request() {
+    let server = MockServer::start();
+    let messages = sequenced(
+        (1..=3)
+            .map(|i| ChatMessage::new(DEFAULT_CHANNEL, format!("message {i}")))
+            .collect(),
+    );
+
+    let last_messages_mock = server.mock(|when, then| {
+        when.method(GET)
+            .path(format!("/{DEFAULT_CHANNEL}"))
+            .query_param("last", "2")
+            .query_param("before", "4");
+        then.status(200)
+            .body(serde_json::to_string(&messages[1..]).unwrap());
+    });
+
+    let client = ReqwestReplicationLogClient::new(server.base_url()).with_page_size(1);
+    let last_messages = client
+        .get_messages_before(DEFAULT_CHANNEL, Some(4), 2)
+        .await
+        .unwrap();
+
+    last_messages_mock.assert();
+    assert_eq!(
+        last_messages
+            .iter()
+            .map(|message| message.sequence_number)
+            .collect::<Vec<_>>(),
+        vec![2, 3]
+    );
+}
+
+/// A replication log that has removed every message of every channel.
+struct TruncatedReplicationLog {
+    truncated_before: SequenceNumber,
+}
+
+#[async_trait]
+impl ReplicationLogClient for TruncatedReplicationLog {
+    async fn get_messages_since(
+        &self,
+        _channel_name: &str,
+        _offset: SequenceNumber,
+    ) -> Result<Vec<SequencedMessage>> {
+        Err(MessagesRemoved {
+            truncated_before: self.truncated_before,
+        }
+        .into())
+    }
+
+    async fn append(&self, _chat_message: &ChatMessage) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn removed_messages_do_not_stall_the_history() {
+    for truncated_before in [0, 1, 5] {
+        let replication_log_client = TruncatedReplicationLog { truncated_before };
+
+        let err = replication_log_client
+            .get_messages_before(DEFAULT_CHANNEL, None, 10)
+            .await
+            .unwrap_err();
+        assert!(err.downcast_ref::<MessagesRemoved>().is_some());
+
+        let mut last_sequence_number = 0;
+        let (messages, synchronized) = catch_up_with_history(
+            &replication_log_client,
+            DEFAULT_CHANNEL,
+            &mut last_sequence_number,
+            &ChatMessage::new(DEFAULT_CHANNEL, "never stored"),
+        )
+        .await;
+        assert!(messages.is_empty());
+        assert!(synchronized.is_err());
+        assert_eq!(last_sequence_number, truncated_before.saturating_sub(1));
+    }
+}
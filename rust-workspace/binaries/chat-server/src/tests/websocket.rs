@@ -0,0 +1,105 @@
+use std::{sync::Arc, time::Duration};
+
+use common::ChatMessage;
+use warp::test::WsClient;
+
+use crate::{chat_server::ChatServer, websocket::websocket_route};
+
+use super::mocks::{MockChannelPublisher, MockChannelSubscriber, MockReplicationLogClient};
+
+/// Returns the `type` of the next message, along with its message text or error.
+async fn receive(client: &mut WsClient) -> String {
+    let frame = client.recv().await.unwrap();
+    let message: serde_json::Value = serde_json::from_str(frame.to_str().unwrap()).unwrap();
+
+    match message["type"].as_str().unwrap() {
+        "message" => format!("message: {}", message["message_text"].as_str().unwrap()),
+        message_type => format!("{message_type}: {}", message["error"].as_str().unwrap()),
+    }
+}
+
+fn chat_server_with_history(
+    history: Vec<ChatMessage>,
+) -> (ChatServer, MockChannelSubscriber, MockReplicationLogClient) {
+    let mock_replication_log_client = MockReplicationLogClient::new(history);
+    let mock_channel_subscriber = MockChannelSubscriber::new(mock_replication_log_client.clone());
+    let chat_server = ChatServer::new(
+        Arc::new(mock_channel_subscriber.clone()),
+        Arc::new(MockChannelPublisher {
+            channel_subscriber: mock_channel_subscriber.clone(),
+        }),
+        Arc::new(mock_replication_log_client.clone()),
+    );
+
+    (
+        chat_server,
+        mock_channel_subscriber,
+        mock_replication_log_client,
+    )
+}
+
+#[tokio::test]
+async fn chat_via_websocket() {
+    let (chat_server, mock_channel_subscriber, _) = chat_server_with_history(vec![
+        ChatMessage::new("test-channel", "previous message"),
+        ChatMessage::new("other-channel", "message on another channel"),
+    ]);
+    let route = websocket_route(chat_server);
+
+    let mut client = warp::test::ws()
+        .path("/channels/test-channel/ws")
+        .handshake(route)
+        .await
+        .unwrap();
+    insta::assert_snapshot!(receive(&mut client).await, @"message: previous message");
+
+    mock_channel_subscriber
+        .publish_message(ChatMessage::new("test-channel", "live message"))
+        .unwrap();
+    mock_channel_subscriber
+        .publish_message(ChatMessage::new("other-channel", "unrelated live message"))
+        .unwrap();
+    insta::assert_snapshot!(receive(&mut client).await, @"message: live message");
+
+    client.send_text("Hello everyone!").await;
+    insta::assert_snapshot!(receive(&mut client).await, @"message: Hello everyone!");
+
+    client.send_text(" ").await;
+    insta::assert_snapshot!(receive(&mut client).await, @"error: the message must not be empty");
+}
+
+#[tokio::test]
+async fn subscribe_while_clients_are_connected() {
+    let (chat_server, _, _) = chat_server_with_history(vec![]);
+    let route = websocket_route(chat_server.clone());
+
+    let connect = || {
+        warp::test::ws()
+            .path("/channels/test-channel/ws")
+            .handshake(route.clone())
+    };
+    let first_client = connect().await.unwrap();
+    let second_client = connect().await.unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(chat_server.holder_count("test-channel"), 2);
+
+    drop(first_client);
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(chat_server.holder_count("test-channel"), 1);
+
+    drop(second_client);
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(chat_server.holder_count("test-channel"), 0);
+}
+
+#[tokio::test]
+async fn reject_invalid_channel_names() {
+    let (chat_server, _, _) = chat_server_with_history(vec![]);
+    let route = websocket_route(chat_server);
+
+    let result = warp::test::ws()
+        .path("/channels/invalid%20channel/ws")
+        .handshake(route)
+        .await;
+    assert!(result.is_err());
+}
@@ -0,0 +1,131 @@
+//! Live chatting via WebSockets.
+//!
+//! A client connects to a single channel via `GET /api/v1/channels/{channel}/ws`. It first receives the
+//! history of the channel, followed by every new message of the channel as it arrives. Every text
+//! frame sent by the client is sent as a new message to the channel.
+//!
+//! Messages are sent to the client as JSON objects tagged by their `type`: either a `message`
+//! (a chat message) or an `error`, e.g. if a message sent by the client was invalid.
+
+use anyhow::Result;
+use futures::{SinkExt, StreamExt};
+use serde::Serialize;
+use tokio::sync::broadcast::error::RecvError;
+use warp::{
+    http::StatusCode,
+    ws::{Message, WebSocket, Ws},
+    Filter, Rejection, Reply,
+};
+
+use common::ChatMessage;
+
+use crate::{
+    api::ErrorResponse,
+    chat_server::{ChannelLease, ChatServer},
+    message_validation::validate_channel_name,
+};
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ServerMessage {
+    Message(ChatMessage),
+    Error { error: String },
+}
+
+impl ServerMessage {
+    fn to_frame(&self) -> Result<Message> {
+        Ok(Message::text(serde_json::to_string(self)?))
+    }
+}
+
+/// `GET /channels/{channel}/ws`, relative to the API prefix; see the [module documentation](self).
+pub fn websocket_route(
+    chat_server: ChatServer,
+) -> impl Filter<Extract = (Box<dyn Reply>,), Error = Rejection> + Clone {
+    warp::path!("channels" / String / "ws")
+        .and(warp::ws())
+        .and(warp::any().map(move || chat_server.clone()))
+        .map(|channel_name: String, ws: Ws, chat_server: ChatServer| {
+            if let Err(err) = validate_channel_name(&channel_name) {
+                let error = ErrorResponse {
+                    error: err.to_string(),
+                };
+                return Box::new(warp::reply::with_status(
+                    warp::reply::json(&error),
+                    StatusCode::BAD_REQUEST,
+                )) as Box<dyn Reply>;
+            }
+
+            Box::new(ws.on_upgrade(move |websocket| {
+                handle_connection(websocket, channel_name, chat_server)
+            }))
+        })
+}
+
+async fn handle_connection(websocket: WebSocket, channel_name: String, chat_server: ChatServer) {
+    // Keeps the channel joined while the client is connected.
+    let channel_lease = match chat_server.subscribe(&channel_name).await {
+        Ok(channel_lease) => channel_lease,
+        Err(err) => {
+            println!("Could not join channel {channel_name}: {err}");
+            return;
+        }
+    };
+
+    if let Err(err) = serve_connection(websocket, &channel_lease, &chat_server).await {
+        println!("WebSocket connection on channel {channel_name} failed: {err}");
+    }
+}
+
+async fn serve_connection(
+    websocket: WebSocket,
+    channel_lease: &ChannelLease,
+    chat_server: &ChatServer,
+) -> Result<()> {
+    let channel_name = channel_lease.channel_name();
+    let (mut outgoing, mut incoming) = websocket.split();
+
+    let (previous_messages, mut new_messages) = channel_lease.messages_with_updates();
+    for chat_message in previous_messages {
+        outgoing
+            .send(ServerMessage::Message(chat_message).to_frame()?)
+            .await?;
+    }
+
+    loop {
+        tokio::select! {
+            new_message = new_messages.recv() => match new_message {
+                Ok(chat_message) => {
+                    outgoing.send(ServerMessage::Message(chat_message).to_frame()?).await?;
+                }
+                // The client would silently miss messages; it has to reconnect instead.
+                Err(RecvError::Lagged(_)) => {
+                    let error = "too many messages were missed, please reconnect".to_string();
+                    outgoing.send(ServerMessage::Error { error }.to_frame()?).await?;
+                    outgoing.close().await?;
+                    return Ok(());
+                }
+                Err(RecvError::Closed) => return Ok(()),
+            },
+            frame = incoming.next() => {
+                let frame = match frame {
+                    Some(frame) => frame?,
+                    None => return Ok(()),
+                };
+                if frame.is_close() {
+                    return Ok(());
+                }
+                // Other frames, like pings or binary frames, are ignored.
+                let Ok(message_text) = frame.to_str() else {
+                    continue;
+                };
+
+                // The sent message reaches the client like any other new message.
+                if let Err(err) = chat_server.send_message(channel_name, message_text).await {
+                    let error = err.to_string();
+                    outgoing.send(ServerMessage::Error { error }.to_frame()?).await?;
+                }
+            }
+        }
+    }
+}
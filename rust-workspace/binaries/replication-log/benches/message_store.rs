@@ -0,0 +1,64 @@
+//! Read latency of a channel depending on the number of messages in unrelated channels.
+//!
+//! Run with `cargo bench -p replication-log`.
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+
+use common::ChatMessage;
+use replication_log::storage::{InMemoryMessageStore, MessageStore};
+
+const READ_CHANNEL: &str = "read-channel";
+const MESSAGES_IN_READ_CHANNEL: u64 = 100;
+const UNRELATED_CHANNELS: usize = 100;
+
+fn store_with_unrelated_messages(unrelated_messages: usize) -> InMemoryMessageStore {
+    let message_store = InMemoryMessageStore::default();
+
+    for i in 0..unrelated_messages {
+        let channel = format!("unrelated-channel-{}", i % UNRELATED_CHANNELS);
+        message_store
+            .append(ChatMessage::new(channel, "unrelated message"))
+            .unwrap();
+    }
+    for _ in 0..MESSAGES_IN_READ_CHANNEL {
+        message_store
+            .append(ChatMessage::new(READ_CHANNEL, "message"))
+            .unwrap();
+    }
+
+    message_store
+}
+
+fn read_channel(c: &mut Criterion) {
+    let mut group = c.benchmark_group("in_memory_read_channel");
+
+    for unrelated_messages in [0, 10_000, 100_000, 1_000_000] {
+        let message_store = store_with_unrelated_messages(unrelated_messages);
+
+        group.bench_with_input(
+            BenchmarkId::new("full_history", unrelated_messages),
+            &message_store,
+            |b, message_store| {
+                b.iter(|| message_store.messages_after(black_box(READ_CHANNEL), 0, None))
+            },
+        );
+        group.bench_with_input(
+            BenchmarkId::new("last_ten_messages", unrelated_messages),
+            &message_store,
+            |b, message_store| {
+                b.iter(|| {
+                    message_store.messages_after(
+                        black_box(READ_CHANNEL),
+                        MESSAGES_IN_READ_CHANNEL - 10,
+                        None,
+                    )
+                })
+            },
+        );
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, read_channel);
+criterion_main!(benches);
@@ -0,0 +1,148 @@
+//! The configuration of the replication log, see [`common::config`] for how it is assembled.
+
+use std::path::PathBuf;
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use common::config::{Config, MessageBrokerConfig, ServerConfig};
+
+use crate::{
+    raft::RaftConfig,
+    replication::ReplicationConfig,
+    retention::RetentionConfig,
+    sharding::ShardingConfig,
+    snapshots::SnapshotConfig,
+    storage::{SegmentFileOptions, StorageBackend},
+};
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ReplicationLogConfig {
+    pub server: ServerConfig,
+    pub message_broker: MessageBrokerConfig,
+    pub storage: StorageConfig,
+    /// Which messages of every channel are kept.
+    pub retention: RetentionConfig,
+    /// How the snapshots that new chat-server instances bootstrap from are taken.
+    pub snapshots: SnapshotConfig,
+    /// Whether this instance follows a leader, see [`crate::replication`].
+    pub replication: ReplicationConfig,
+    /// Whether this instance is a member of a Raft group, see [`crate::raft`].
+    pub raft: RaftConfig,
+    /// Which channels this instance stores, see [`crate::sharding`].
+    pub sharding: ShardingConfig,
+    /// Who may use the administrative endpoints.
+    pub admin: AdminConfig,
+}
+
+impl Config for ReplicationLogConfig {
+    const ENV_PREFIX: &'static str = "REPLICATION_LOG";
+
+    fn validate(&self) -> Result<()> {
+        self.message_broker.validate()?;
+        if self.storage.backend() != StorageBackend::InMemory && self.storage.data_dir.is_none() {
+            bail!(
+                "storage backend {:?} needs a data directory",
+                self.storage.backend()
+            );
+        }
+        self.retention
+            .validate()
+            .context("invalid retention settings")?;
+        self.snapshots
+            .validate()
+            .context("invalid snapshot settings")?;
+        self.replication
+            .validate()
+            .context("invalid replication settings")?;
+        self.raft.validate().context("invalid Raft settings")?;
+        self.admin.validate().context("invalid admin settings")?;
+        self.sharding
+            .validate()
+            .context("invalid sharding settings")?;
+        if self.sharding.previous_shard_map.is_some()
+            && (self.replication.leader_url.is_some() || self.raft.node_id.is_some())
+        {
+            bail!(
+                "a shard that is being added cannot follow a leader or be a member of a Raft group"
+            );
+        }
+        if self.raft.node_id.is_some() {
+            if self.replication.leader_url.is_some() {
+                bail!("a member of a Raft group cannot follow a leader");
+            }
+            // A node that forgets its votes or its log after a restart breaks the guarantees of
+            // the group.
+            if self.storage.backend() == StorageBackend::InMemory {
+                bail!("a member of a Raft group needs a persistent storage backend");
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StorageConfig {
+    /// See [`StorageConfig::backend`].
+    pub backend: Option<StorageBackend>,
+    /// Where all backends but [`StorageBackend::InMemory`] keep their data.
+    pub data_dir: Option<PathBuf>,
+    pub segment_files: SegmentFileOptions,
+}
+
+impl StorageConfig {
+    /// If no backend is given, messages are stored in segment files if a data directory is given
+    /// and in memory otherwise.
+    pub fn backend(&self) -> StorageBackend {
+        match (self.backend, &self.data_dir) {
+            (Some(backend), _) => backend,
+            (None, Some(_)) => StorageBackend::SegmentFiles,
+            (None, None) => StorageBackend::InMemory,
+        }
+    }
+}
+
+/// Guards the administrative endpoints, e.g. for promoting a follower.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AdminConfig {
+    /// Requests to the administrative endpoints have to send this token as
+    /// `Authorization: Bearer <token>`. Without one, the endpoints are disabled.
+    pub token: Option<String>,
+}
+
+impl AdminConfig {
+    pub fn validate(&self) -> Result<()> {
+        if self.token.as_ref().is_some_and(|token| token.is_empty()) {
+            bail!("the admin token must not be empty");
+        }
+
+        Ok(())
+    }
+
+    /// Whether the value of the `Authorization` header contains the configured token.
+    pub fn is_authorized(&self, authorization: Option<&str>) -> bool {
+        self.token
+            .as_deref()
+            .is_some_and(|token| is_bearer_token(authorization, token))
+    }
+}
+
+/// Whether the value of an `Authorization` header is `Bearer <token>`.
+pub fn is_bearer_token(authorization: Option<&str>, token: &str) -> bool {
+    let Some(presented_token) = authorization.and_then(|value| value.strip_prefix("Bearer "))
+    else {
+        return false;
+    };
+
+    // Compares every byte, so that the time taken does not reveal how much of a guess was right.
+    presented_token.len() == token.len()
+        && presented_token
+            .bytes()
+            .zip(token.bytes())
+            .fold(0, |difference, (a, b)| difference | (a ^ b))
+            == 0
+}
@@ -1,4 +1,11 @@
+pub mod config;
 pub mod message_log;
+pub mod raft;
+pub mod replication;
+pub mod retention;
+pub mod sharding;
+pub mod snapshots;
+pub mod storage;
 
 #[cfg(test)]
 mod tests;
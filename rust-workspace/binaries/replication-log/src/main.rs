@@ -1,28 +1,366 @@
-use std::convert::Infallible;
+use std::{collections::HashMap, convert::Infallible, sync::Arc, time::Duration};
 
 use anyhow::Result;
-use common::ChatMessageStream;
+use common::{
+    channel_publisher::{republish_plain_text, ChannelPublisher, RedisChannelPublisher},
+    config::load_config,
+    forwarder_health::{ForwarderStatus, ItemErrorPolicy},
+    snapshot::SNAPSHOT_CONTENT_TYPE,
+    ChatMessage, ChatMessageStream, SequenceNumber, TRUNCATED_BEFORE_HEADER,
+};
 use futures::StreamExt;
-use replication_log::message_log::MessageLog;
-use tokio;
-use warp::{Filter, Reply};
+use replication_log::{
+    config::{AdminConfig, ReplicationLogConfig, StorageConfig},
+    message_log::MessageLog,
+    raft::{
+        server::{raft_message_route, spawn_raft_server, HttpTransport, RaftHandle},
+        storage::FileRaftStorage,
+        NotLeader, RaftNode,
+    },
+    replication::{
+        spawn_follower_task, Epoch, EpochFence, HttpLeader, Leader, Replication,
+        LEADER_EPOCH_HEADER,
+    },
+    retention::{spawn_raft_retention_task, spawn_retention_task},
+    sharding::{buffer_until_promoted, remove_foreign_channels, PreviousShards, ShardAssignment},
+    snapshots::{spawn_snapshot_task, Snapshots},
+    storage::{open_message_store, MessageStore},
+};
+use serde::Deserialize;
+use warp::{http::StatusCode, Filter, Reply};
+
+/// Limits the size of request bodies, in bytes.
+const MAX_REQUEST_BODY_SIZE: u64 = 64 * 1024;
+
+/// Limits how long a request waits for new messages, see [`MessagesQuery::wait_ms`].
+const MAX_WAIT: Duration = Duration::from_secs(30);
 
 #[tokio::main]
 async fn main() {
-    let all_channels_stream = subscribe_all_channels("redis://message-broker-service:6379")
+    let config: ReplicationLogConfig = match load_config() {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("{err:#}");
+            std::process::exit(2);
+        }
+    };
+
+    if let Some(node_id) = config.raft.node_id {
+        return run_raft_node(config, node_id).await;
+    }
+
+    let shard_assignment = config.sharding.assignment();
+    // The epoch has to survive restarts, so that a superseded leader is not followed again.
+    let epoch_fence = Arc::new(match &config.storage.data_dir {
+        Some(data_dir) => EpochFence::open(data_dir.join("replication-epoch")).unwrap(),
+        None => EpochFence::default(),
+    });
+    // A new shard follows the previous shards of its channels until it is promoted, see
+    // `replication_log::sharding`.
+    let (replication, leader): (Replication, Option<Arc<dyn Leader>>) = match (
+        &config.replication.leader_url,
+        &config.sharding.previous_shard_map,
+    ) {
+        (Some(leader_url), _) => (
+            Replication::follower(leader_url, Arc::clone(&epoch_fence)),
+            Some(Arc::new(
+                HttpLeader::new(
+                    leader_url.clone(),
+                    config.replication.request_timeout,
+                    epoch_fence,
+                )
+                .unwrap(),
+            )),
+        ),
+        (None, Some(previous_shard_map)) => {
+            let previous_shards = PreviousShards::new(
+                shard_assignment.clone().unwrap(),
+                previous_shard_map.clone(),
+                // Every previous shard has its own epochs.
+                |shard| {
+                    Arc::new(
+                        HttpLeader::new(
+                            shard.url.clone(),
+                            config.replication.request_timeout,
+                            Arc::default(),
+                        )
+                        .unwrap(),
+                    )
+                },
+            );
+            (
+                Replication::follower_of_several(previous_shards.urls(), epoch_fence),
+                Some(Arc::new(previous_shards)),
+            )
+        }
+        (None, None) => (Replication::leader(epoch_fence), None),
+    };
+
+    let channel_publisher: Arc<dyn ChannelPublisher> =
+        Arc::new(RedisChannelPublisher::new(&config.message_broker.url).unwrap());
+    // Plain text messages are republished by the leader storing their channel.
+    let republishes = {
+        let replication = replication.clone();
+        let shard_assignment = shard_assignment.clone();
+        move |channel: &str| replication.is_leader() && owns(shard_assignment.as_ref(), channel)
+    };
+    let is_new_shard = config.sharding.previous_shard_map.is_some();
+    let all_channels_stream = if replication.is_leader() || is_new_shard {
+        subscribe_all_channels(
+            &config.message_broker.url,
+            Arc::clone(&channel_publisher),
+            republishes,
+        )
         .await
-        .unwrap();
+        .unwrap()
+    } else {
+        // Until then, a follower receives the messages through its leader.
+        subscribe_all_channels_once_leader(
+            replication.clone(),
+            config.message_broker.url.clone(),
+            Arc::clone(&channel_publisher),
+            republishes,
+        )
+    };
+    let message_store = open_configured_message_store(config.storage).unwrap();
+    let mut all_channels_stream = owned_channels(all_channels_stream, shard_assignment.clone());
+    if is_new_shard {
+        all_channels_stream = buffer_until_promoted(
+            all_channels_stream,
+            replication.clone(),
+            Arc::clone(&message_store),
+        );
+    }
 
-    let message_log = MessageLog::new(all_channels_stream);
+    // A single malformed message must not stop the log from storing any further messages.
+    let message_log =
+        MessageLog::new(message_store, all_channels_stream, ItemErrorPolicy::Skip).unwrap();
+    if let Some(leader) = leader {
+        spawn_follower_task(
+            message_log.clone(),
+            replication.clone(),
+            leader,
+            config.replication.clone(),
+        );
+    }
+    spawn_retention_task(message_log.clone(), config.retention);
+    let snapshots = Snapshots::default();
+    spawn_snapshot_task(message_log.clone(), snapshots.clone(), config.snapshots);
 
-    let messages_route = warp::path!("messages" / String)
+    let messages_route = warp::get()
+        .and(warp::path!("messages" / String))
+        .and(warp::query::<MessagesQuery>())
+        .and(with_message_log(message_log.clone()))
+        .and(warp::any().map(|| None))
+        .and_then(messages_handler);
+
+    let append_route = warp::post()
+        .and(warp::path!("messages" / String))
+        .and(warp::body::content_length_limit(MAX_REQUEST_BODY_SIZE))
+        .and(warp::body::json())
+        .and(with_message_log(message_log.clone()))
+        .and(warp::any().map(move || Arc::clone(&channel_publisher)))
+        .and(with_replication(replication.clone()))
+        .and(with_shard_assignment(shard_assignment.clone()))
+        .and_then(append_handler);
+
+    let channels_route = warp::path!("channels")
+        .and(warp::get())
+        .and(with_message_log(message_log.clone()))
+        .and(with_replication(replication.clone()).map(Some))
+        .and_then(channels_handler);
+
+    let replication_route = warp::path!("replication")
+        .and(warp::get())
+        .and(with_replication(replication.clone()))
+        .map(|replication: Replication| warp::reply::json(&replication.status()));
+
+    let promote_route = warp::path!("admin" / "promote")
+        .and(warp::post())
+        .and(with_admin_authorization(config.admin.clone()))
+        .and(warp::query::<PromoteQuery>())
+        .and(with_replication(replication))
+        .and_then(promote_handler);
+
+    // The other shards tell whether they have copied the channels that moved to them.
+    let other_shards = Arc::new(other_shards(
+        shard_assignment.as_ref(),
+        config.replication.request_timeout,
+    ));
+    let remove_foreign_channels_route = warp::path!("admin" / "remove-foreign-channels")
+        .and(warp::post())
+        .and(with_admin_authorization(config.admin))
+        .and(with_message_log(message_log.clone()))
+        .and(with_shard_assignment(shard_assignment))
+        .and(warp::any().map(move || Arc::clone(&other_shards)))
+        .and_then(remove_foreign_channels_handler);
+
+    let snapshot_route = warp::path!("snapshots" / String)
+        .and(warp::get())
+        .and(warp::any().map(move || snapshots.clone()))
+        .and_then(snapshot_handler);
+
+    let health_route = warp::get()
+        .and(warp::path!("health"))
         .and(with_message_log(message_log))
+        .and_then(health_handler);
+
+    let routes = messages_route
+        .or(append_route)
+        .or(snapshot_route)
+        .or(channels_route)
+        .or(replication_route)
+        .or(promote_route)
+        .or(remove_foreign_channels_route)
+        .or(health_route);
+
+    let bind_address = config.server.bind_address;
+    println!("Started server at {bind_address}");
+    warp::serve(routes).run(bind_address).await;
+}
+
+/// Runs this instance as a member of a Raft group, see [`replication_log::raft`].
+///
+/// Every member subscribes to all channels, but only the leader appends the received messages.
+async fn run_raft_node(config: ReplicationLogConfig, node_id: u64) {
+    let raft_config = config.raft;
+    let member_ids: Vec<_> = raft_config.members.iter().map(|member| member.id).collect();
+
+    let message_store = open_configured_message_store(config.storage.clone()).unwrap();
+    // Messages are only appended once they have been committed by the group.
+    let message_log = MessageLog::new(
+        message_store,
+        Box::pin(futures::stream::pending()),
+        ItemErrorPolicy::Skip,
+    )
+    .unwrap();
+
+    // Checked by the configuration's validation.
+    let data_dir = config.storage.data_dir.unwrap();
+    let raft_storage = FileRaftStorage::open(data_dir.join("raft")).unwrap();
+    let node = RaftNode::new(
+        node_id,
+        &member_ids,
+        raft_config.timeouts(),
+        Box::new(raft_storage),
+        random_seed(),
+    )
+    .unwrap();
+    let transport = Arc::new(HttpTransport::new(&raft_config).unwrap());
+    let (raft, _) = spawn_raft_server(node, message_log.clone(), transport, &raft_config);
+
+    let shard_assignment = config.sharding.assignment();
+    let channel_publisher: Arc<dyn ChannelPublisher> =
+        Arc::new(RedisChannelPublisher::new(&config.message_broker.url).unwrap());
+    // Plain text messages are republished by the leader.
+    let republishes = {
+        let raft = raft.clone();
+        let shard_assignment = shard_assignment.clone();
+        move |channel: &str| raft.is_leader() && owns(shard_assignment.as_ref(), channel)
+    };
+    let all_channels_stream = subscribe_all_channels(
+        &config.message_broker.url,
+        Arc::clone(&channel_publisher),
+        republishes,
+    )
+    .await
+    .unwrap();
+    let all_channels_stream = owned_channels(all_channels_stream, shard_assignment.clone());
+    tokio::spawn(append_as_leader(
+        raft.clone(),
+        message_log.clone(),
+        all_channels_stream,
+    ));
+
+    spawn_raft_retention_task(message_log.clone(), raft.clone(), config.retention);
+    let snapshots = Snapshots::default();
+    spawn_snapshot_task(message_log.clone(), snapshots.clone(), config.snapshots);
+
+    let messages_route = warp::get()
+        .and(warp::path!("messages" / String))
+        .and(warp::query::<MessagesQuery>())
+        .and(with_message_log(message_log.clone()))
+        .and(with_raft(raft.clone()).map(Some))
         .and_then(messages_handler);
 
-    let routes = messages_route;
+    let append_route = warp::post()
+        .and(warp::path!("messages" / String))
+        .and(warp::body::content_length_limit(MAX_REQUEST_BODY_SIZE))
+        .and(warp::body::json())
+        .and(with_raft(raft.clone()))
+        .and(warp::any().map(move || Arc::clone(&channel_publisher)))
+        .and(with_shard_assignment(shard_assignment))
+        .and_then(raft_append_handler);
+
+    let raft_route = raft_message_route(raft.clone(), &raft_config);
+
+    let raft_status_route = warp::path!("raft" / "status")
+        .and(warp::get())
+        .and(with_raft(raft))
+        .map(|raft: RaftHandle| warp::reply::json(&raft.status()));
+
+    let channels_route = warp::path!("channels")
+        .and(warp::get())
+        .and(with_message_log(message_log))
+        .and(warp::any().map(|| None))
+        .and_then(channels_handler);
+
+    let snapshot_route = warp::path!("snapshots" / String)
+        .and(warp::get())
+        .and(warp::any().map(move || snapshots.clone()))
+        .and_then(snapshot_handler);
+
+    let routes = messages_route
+        .or(append_route)
+        .or(raft_route)
+        .or(raft_status_route)
+        .or(snapshot_route)
+        .or(channels_route);
+
+    let bind_address = config.server.bind_address;
+    println!("Started Raft node {node_id} at {bind_address}");
+    warp::serve(routes).run(bind_address).await;
+}
 
-    println!("Started server at localhost:8000");
-    warp::serve(routes).run(([0, 0, 0, 0], 8000)).await;
+/// Seeds the randomization of election timeouts, which has to differ between restarts.
+fn random_seed() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos() as u64
+}
+
+/// Appends the messages received from the message broker through the group while this node is
+/// the leader; the other members drop them.
+async fn append_as_leader(
+    raft: RaftHandle,
+    message_log: MessageLog,
+    mut stream: ChatMessageStream,
+) {
+    while let Some(chat_message) = stream.next().await {
+        let chat_message = match chat_message {
+            Ok(chat_message) => chat_message,
+            Err(err) => {
+                println!("Skipping a malformed message: {err:#}");
+                continue;
+            }
+        };
+        // Messages appended via the API are published after they have been stored.
+        if !raft.is_leader() {
+            continue;
+        }
+        let (channel, message_id) = (chat_message.channel.clone(), chat_message.id);
+        let already_stored = message_log
+            .run_blocking(move |message_log| message_log.contains(&channel, message_id))
+            .await;
+        if already_stored.unwrap_or(false) {
+            continue;
+        }
+
+        if let Err(err) = raft.append(chat_message).await {
+            println!("Appending a received message failed: {err:#}");
+        }
+    }
 }
 
 fn with_message_log(
@@ -31,23 +369,548 @@ fn with_message_log(
     warp::any().map(move || message_log.clone())
 }
 
+fn with_raft(raft: RaftHandle) -> impl Filter<Extract = (RaftHandle,), Error = Infallible> + Clone {
+    warp::any().map(move || raft.clone())
+}
+
+fn with_shard_assignment(
+    shard_assignment: Option<ShardAssignment>,
+) -> impl Filter<Extract = (Option<ShardAssignment>,), Error = Infallible> + Clone {
+    warp::any().map(move || shard_assignment.clone())
+}
+
+fn with_replication(
+    replication: Replication,
+) -> impl Filter<Extract = (Replication,), Error = Infallible> + Clone {
+    warp::any().map(move || replication.clone())
+}
+
+/// Extracts the response to send instead if the request does not carry the admin token: with
+/// `401 Unauthorized`, or with `403 Forbidden` if no token is configured.
+fn with_admin_authorization(
+    admin_config: AdminConfig,
+) -> impl Filter<Extract = (Result<(), warp::reply::WithStatus<String>>,), Error = Infallible> + Clone
+{
+    warp::header::optional::<String>("authorization")
+        .map(move |authorization: Option<String>| {
+            if admin_config.token.is_none() {
+                return Err(warp::reply::with_status(
+                    "the admin endpoints are disabled, configure an admin token to use them"
+                        .to_string(),
+                    StatusCode::FORBIDDEN,
+                ));
+            }
+            if !admin_config.is_authorized(authorization.as_deref()) {
+                return Err(warp::reply::with_status(
+                    "missing or invalid admin token".to_string(),
+                    StatusCode::UNAUTHORIZED,
+                ));
+            }
+
+            Ok(())
+        })
+        // An invalid header is treated like a missing one.
+        .or(warp::any().map(|| {
+            Err(warp::reply::with_status(
+                "missing or invalid admin token".to_string(),
+                StatusCode::UNAUTHORIZED,
+            ))
+        }))
+        .unify()
+}
+
+/// Query parameters for retrieving a range of messages.
+#[derive(Deserialize)]
+struct MessagesQuery {
+    /// Only return messages with a sequence number greater than this.
+    #[serde(default)]
+    after: SequenceNumber,
+    /// Return at most this many messages.
+    limit: Option<usize>,
+    /// Instead of the messages after `after`, return the last this many messages preceding
+    /// `before`.
+    last: Option<usize>,
+    /// Only used along with `last`: return messages with a sequence number less than this, or the
+    /// last messages of the channel if not given.
+    before: Option<SequenceNumber>,
+    /// If there are no messages after `after` yet, wait up to this many milliseconds (at most
+    /// [`MAX_WAIT`]) for the next one instead of responding with an empty list.
+    wait_ms: Option<u64>,
+    /// Whether the response must contain every message appended before the request, which only
+    /// the leader of a Raft group can ensure.
+    #[serde(default)]
+    linearizable: bool,
+}
+
+/// Responds with the requested messages. If messages after `after` have been removed by a
+/// retention policy, the [`TRUNCATED_BEFORE_HEADER`] tells from where on messages are retained.
+///
+/// Linearizable reads wait for a read barrier of the Raft group, responding with
+/// `421 Misdirected Request` on members other than the leader. Without Raft, every read from the
+/// leader is linearizable.
 async fn messages_handler(
     channel_name: String,
+    query: MessagesQuery,
+    message_log: MessageLog,
+    raft: Option<RaftHandle>,
+) -> Result<impl Reply, Infallible> {
+    if let Some(raft) = raft.filter(|_| query.linearizable) {
+        if let Err(err) = raft.read_barrier().await {
+            return Ok(raft_error_response(&raft, err));
+        }
+    }
+
+    let messages = match (query.last, query.wait_ms) {
+        (Some(last), _) => {
+            let (channel, before) = (channel_name.clone(), query.before);
+            message_log
+                .run_blocking(move |message_log| {
+                    message_log.messages_before(&channel, before, last)
+                })
+                .await
+        }
+        (None, Some(wait_ms)) => {
+            let timeout = Duration::from_millis(wait_ms).min(MAX_WAIT);
+            message_log
+                .wait_for_messages_after(&channel_name, query.after, query.limit, timeout)
+                .await
+        }
+        (None, None) => {
+            let (channel, after, limit) = (channel_name.clone(), query.after, query.limit);
+            message_log
+                .run_blocking(move |message_log| message_log.messages_after(&channel, after, limit))
+                .await
+        }
+    };
+    let messages = match messages {
+        Ok(messages) => messages,
+        Err(err) => {
+            return Ok(
+                warp::reply::with_status(err.to_string(), StatusCode::INTERNAL_SERVER_ERROR)
+                    .into_response(),
+            )
+        }
+    };
+    // Read after the messages, so that a truncation in between cannot go unnoticed. Sequence
+    // numbers have no gaps, apart from removed messages, so messages are missing if the returned ones
+    // do not start right after `after` (or fewer than the last requested messages are returned).
+    let truncated_before = message_log
+        .run_blocking(move |message_log| message_log.truncated_before(&channel_name))
+        .await;
+    let truncated_before = match truncated_before {
+        Ok(truncated_before) => truncated_before,
+        Err(err) => {
+            return Ok(
+                warp::reply::with_status(err.to_string(), StatusCode::INTERNAL_SERVER_ERROR)
+                    .into_response(),
+            )
+        }
+    };
+    let misses_messages = |truncated_before: SequenceNumber| {
+        let first_sequence_number = messages
+            .first()
+            .map_or(truncated_before, |message| message.sequence_number);
+        match query.last {
+            Some(last) => messages.len() < last && first_sequence_number > 1,
+            None => first_sequence_number > query.after + 1,
+        }
+    };
+    let misses_messages =
+        truncated_before.filter(|&truncated_before| misses_messages(truncated_before));
+
+    let serialized_messages = match serde_json::to_string(&messages) {
+        Ok(serialized_messages) => serialized_messages,
+        Err(err) => {
+            return Ok(
+                warp::reply::with_status(err.to_string(), StatusCode::INTERNAL_SERVER_ERROR)
+                    .into_response(),
+            )
+        }
+    };
+
+    let mut response =
+        warp::reply::with_status(serialized_messages, StatusCode::OK).into_response();
+    if let Some(truncated_before) = misses_messages {
+        response
+            .headers_mut()
+            .insert(TRUNCATED_BEFORE_HEADER, truncated_before.into());
+    }
+
+    Ok(response)
+}
+
+/// Responds with the latest snapshot of the channel, encoded as described in
+/// [`common::snapshot`], or with `404 Not Found` if none has been taken yet.
+async fn snapshot_handler(
+    channel_name: String,
+    snapshots: Snapshots,
+) -> Result<impl Reply, Infallible> {
+    let Some(stored_snapshot) = snapshots.get(&channel_name) else {
+        return Ok(StatusCode::NOT_FOUND.into_response());
+    };
+
+    Ok(warp::reply::with_header(
+        stored_snapshot.encoded_snapshot.clone(),
+        "Content-Type",
+        SNAPSHOT_CONTENT_TYPE,
+    )
+    .into_response())
+}
+
+/// Stores the message and only then publishes it to its channel, so that a message is never
+/// delivered to subscribers without being contained in the log.
+///
+/// Only the leader accepts messages; followers respond with `421 Misdirected Request`, as do shards
+/// for channels of other shards.
+///
+/// Responds with `201 Created` and the stored message, or with `200 OK` and `null` if the message
+/// had already been stored. In the latter case, the message is published again, since the previous
+/// attempt to publish it might have failed.
+async fn append_handler(
+    channel_name: String,
+    chat_message: ChatMessage,
     message_log: MessageLog,
+    channel_publisher: Arc<dyn ChannelPublisher>,
+    replication: Replication,
+    shard_assignment: Option<ShardAssignment>,
 ) -> Result<impl Reply, Infallible> {
-    match serde_json::to_string(&message_log.messages_received(&channel_name)) {
-        Ok(serialized_messages) => Ok(warp::reply::with_status(
-            serialized_messages,
-            warp::http::StatusCode::OK,
+    if let Some(message) = foreign_channel_message(&channel_name, shard_assignment.as_ref()) {
+        return Ok(warp::reply::with_status(
+            message.into_response(),
+            StatusCode::MISDIRECTED_REQUEST,
+        ));
+    }
+    let follows = match replication.leader_urls() {
+        [] => None,
+        [leader_url] => Some(format!(
+            "this instance follows {leader_url}, append to the leader instead"
+        )),
+        leader_urls => Some(format!(
+            "this instance follows the leaders {leader_urls:?}, append to them instead"
         )),
+    };
+    if let Some(message) = follows {
+        return Ok(warp::reply::with_status(
+            message.into_response(),
+            StatusCode::MISDIRECTED_REQUEST,
+        ));
+    }
+    if chat_message.channel != channel_name {
+        return Ok(warp::reply::with_status(
+            "the message's channel does not match the channel of the log".into_response(),
+            StatusCode::BAD_REQUEST,
+        ));
+    }
+
+    let appended_message = {
+        let chat_message = chat_message.clone();
+        message_log
+            .run_blocking(move |message_log| message_log.append(chat_message))
+            .await
+    };
+    let appended_message = match appended_message {
+        Ok(appended_message) => appended_message,
+        Err(err) => {
+            return Ok(warp::reply::with_status(
+                err.to_string().into_response(),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            ))
+        }
+    };
+
+    if let Err(err) = channel_publisher.publish(&chat_message).await {
+        return Ok(warp::reply::with_status(
+            err.to_string().into_response(),
+            StatusCode::INTERNAL_SERVER_ERROR,
+        ));
+    }
+
+    let status_code = match appended_message {
+        Some(_) => StatusCode::CREATED,
+        None => StatusCode::OK,
+    };
+    Ok(warp::reply::with_status(
+        warp::reply::json(&appended_message).into_response(),
+        status_code,
+    ))
+}
+
+/// Appends the message through the Raft group and publishes it afterwards, see
+/// [`append_handler`].
+///
+/// Only the leader accepts messages; other members respond with `421 Misdirected Request`.
+async fn raft_append_handler(
+    channel_name: String,
+    chat_message: ChatMessage,
+    raft: RaftHandle,
+    channel_publisher: Arc<dyn ChannelPublisher>,
+    shard_assignment: Option<ShardAssignment>,
+) -> Result<impl Reply, Infallible> {
+    if let Some(message) = foreign_channel_message(&channel_name, shard_assignment.as_ref()) {
+        return Ok(
+            warp::reply::with_status(message, StatusCode::MISDIRECTED_REQUEST).into_response(),
+        );
+    }
+    if chat_message.channel != channel_name {
+        return Ok(warp::reply::with_status(
+            "the message's channel does not match the channel of the log",
+            StatusCode::BAD_REQUEST,
+        )
+        .into_response());
+    }
+
+    let appended_message = match raft.append(chat_message.clone()).await {
+        Ok(appended_message) => appended_message,
+        Err(err) => return Ok(raft_error_response(&raft, err)),
+    };
+
+    if let Err(err) = channel_publisher.publish(&chat_message).await {
+        return Ok(
+            warp::reply::with_status(err.to_string(), StatusCode::INTERNAL_SERVER_ERROR)
+                .into_response(),
+        );
+    }
+
+    let status_code = match appended_message {
+        Some(_) => StatusCode::CREATED,
+        None => StatusCode::OK,
+    };
+    Ok(warp::reply::with_status(warp::reply::json(&appended_message), status_code).into_response())
+}
+
+/// Responds with `421 Misdirected Request` and the leader's URL, if known, when this node is not
+/// the leader, and with `503 Service Unavailable` otherwise, e.g. if no majority is reachable.
+fn raft_error_response(raft: &RaftHandle, err: anyhow::Error) -> warp::reply::Response {
+    if err.is::<NotLeader>() {
+        let message = match raft.leader_url() {
+            Some(leader_url) => format!("{err}, send the request to the leader at {leader_url}"),
+            None => err.to_string(),
+        };
+        return warp::reply::with_status(message, StatusCode::MISDIRECTED_REQUEST).into_response();
+    }
+
+    warp::reply::with_status(format!("{err:#}"), StatusCode::SERVICE_UNAVAILABLE).into_response()
+}
+
+/// Tells which shard stores the channel, if this instance is a shard that does not.
+fn foreign_channel_message(
+    channel_name: &str,
+    shard_assignment: Option<&ShardAssignment>,
+) -> Option<String> {
+    let shard_assignment = shard_assignment?;
+    if shard_assignment.owns(channel_name) {
+        return None;
+    }
+
+    let owner = shard_assignment.owner(channel_name);
+    Some(format!(
+        "channel {channel_name:?} is stored by shard {:?} at {}, append to it instead",
+        owner.id, owner.url
+    ))
+}
+
+/// Removes the messages of the channels that this shard no longer stores and responds with their
+/// names, or with `409 Conflict` if this instance is not a shard.
+async fn remove_foreign_channels_handler(
+    authorization: Result<(), warp::reply::WithStatus<String>>,
+    message_log: MessageLog,
+    shard_assignment: Option<ShardAssignment>,
+    other_shards: Arc<HashMap<String, Arc<dyn Leader>>>,
+) -> Result<impl Reply, Infallible> {
+    if let Err(response) = authorization {
+        return Ok(response.into_response());
+    }
+    let Some(shard_assignment) = shard_assignment else {
+        return Ok(warp::reply::with_status(
+            "this instance is not a shard".to_string(),
+            StatusCode::CONFLICT,
+        )
+        .into_response());
+    };
+
+    let removed_channels =
+        remove_foreign_channels(&message_log, &shard_assignment, &other_shards).await;
+    match removed_channels {
+        Ok(removed_channels) => {
+            println!(
+                "Removed the messages of {} channels, kept {} channels that their shard has not \
+                 copied yet",
+                removed_channels.removed.len(),
+                removed_channels.not_copied.len()
+            );
+            Ok(warp::reply::json(&removed_channels).into_response())
+        }
         Err(err) => Ok(warp::reply::with_status(
             err.to_string(),
-            warp::http::StatusCode::INTERNAL_SERVER_ERROR,
-        )),
+            StatusCode::INTERNAL_SERVER_ERROR,
+        )
+        .into_response()),
     }
 }
 
-async fn subscribe_all_channels(redis_url: &str) -> Result<ChatMessageStream> {
+/// Responds with the position of every channel, which followers replicate up to, along with the
+/// epoch of this instance in the [`LEADER_EPOCH_HEADER`] unless it is a member of a Raft group.
+async fn channels_handler(
+    message_log: MessageLog,
+    replication: Option<Replication>,
+) -> Result<impl Reply, Infallible> {
+    let channel_positions = message_log
+        .run_blocking(|message_log| message_log.channel_positions())
+        .await;
+    match channel_positions {
+        Ok(channel_positions) => {
+            let mut response = warp::reply::json(&channel_positions).into_response();
+            if let Some(replication) = replication {
+                response
+                    .headers_mut()
+                    .insert(LEADER_EPOCH_HEADER, replication.epoch().into());
+            }
+            Ok(response)
+        }
+        Err(err) => Ok(warp::reply::with_status(
+            err.to_string(),
+            StatusCode::INTERNAL_SERVER_ERROR,
+        )
+        .into_response()),
+    }
+}
+
+/// Query parameters for promoting a follower.
+#[derive(Deserialize)]
+struct PromoteQuery {
+    /// The epoch of the new leadership, which has to be greater than every epoch before, see
+    /// [`EpochFence`].
+    epoch: Epoch,
+}
+
+/// Makes this follower the leader and responds with its replication status, or with
+/// `409 Conflict` if it is the leader already or the epoch is not greater than the current one.
+async fn promote_handler(
+    authorization: Result<(), warp::reply::WithStatus<String>>,
+    query: PromoteQuery,
+    replication: Replication,
+) -> Result<impl Reply, Infallible> {
+    if let Err(response) = authorization {
+        return Ok(response.into_response());
+    }
+
+    match replication.promote(query.epoch).await {
+        Ok(()) => {
+            println!("Promoted to leader");
+            Ok(warp::reply::json(&replication.status()).into_response())
+        }
+        Err(err) => {
+            Ok(warp::reply::with_status(err.to_string(), StatusCode::CONFLICT).into_response())
+        }
+    }
+}
+
+/// Responds with the health of storing the messages received from the message broker, with
+/// `503 Service Unavailable` if they are no longer being stored.
+async fn health_handler(message_log: MessageLog) -> Result<impl Reply, Infallible> {
+    let forwarder_health = message_log.forwarder_health();
+    let status_code = match forwarder_health.status {
+        ForwarderStatus::Running => StatusCode::OK,
+        ForwarderStatus::Finished | ForwarderStatus::Failed { .. } => {
+            StatusCode::SERVICE_UNAVAILABLE
+        }
+    };
+
+    Ok(warp::reply::with_status(
+        warp::reply::json(&forwarder_health),
+        status_code,
+    ))
+}
+
+fn open_configured_message_store(storage_config: StorageConfig) -> Result<Arc<dyn MessageStore>> {
+    let backend = storage_config.backend();
+
+    println!("Using storage backend {backend:?}");
+    open_message_store(
+        backend,
+        storage_config.data_dir.as_deref(),
+        storage_config.segment_files,
+    )
+}
+
+/// Drops the messages of channels that other shards store, if this instance is a shard.
+fn owned_channels(
+    stream: ChatMessageStream,
+    shard_assignment: Option<ShardAssignment>,
+) -> ChatMessageStream {
+    let Some(shard_assignment) = shard_assignment else {
+        return stream;
+    };
+
+    Box::pin(stream.filter(move |chat_message| {
+        let is_owned = chat_message.as_ref().map_or(true, |chat_message| {
+            shard_assignment.owns(&chat_message.channel)
+        });
+        futures::future::ready(is_owned)
+    }))
+}
+
+/// The shards of the map but this instance's own by their id, replicated like leaders.
+fn other_shards(
+    shard_assignment: Option<&ShardAssignment>,
+    request_timeout: Duration,
+) -> HashMap<String, Arc<dyn Leader>> {
+    let Some(shard_assignment) = shard_assignment else {
+        return HashMap::new();
+    };
+
+    shard_assignment
+        .shard_map()
+        .shards
+        .iter()
+        .filter(|shard| shard.id != shard_assignment.shard_id())
+        .map(|shard| {
+            // Every shard has its own epochs.
+            let leader: Arc<dyn Leader> = Arc::new(
+                HttpLeader::new(shard.url.clone(), request_timeout, Arc::default()).unwrap(),
+            );
+            (shard.id.clone(), leader)
+        })
+        .collect()
+}
+
+/// Whether this instance stores the channel, i.e. it is no shard or the channel belongs to it.
+fn owns(shard_assignment: Option<&ShardAssignment>, channel: &str) -> bool {
+    shard_assignment.is_none_or(|shard_assignment| shard_assignment.owns(channel))
+}
+
+/// Subscribes to all channels once this instance has become the leader, see
+/// [`replication_log::replication`].
+fn subscribe_all_channels_once_leader<F>(
+    replication: Replication,
+    redis_url: String,
+    channel_publisher: Arc<dyn ChannelPublisher>,
+    republishes: F,
+) -> ChatMessageStream
+where
+    F: Fn(&str) -> bool + Send + Sync + 'static,
+{
+    let subscription = async move {
+        replication.wait_for_leadership().await;
+        match subscribe_all_channels(&redis_url, channel_publisher, republishes).await {
+            Ok(stream) => stream,
+            Err(err) => Box::pin(futures::stream::iter([Err(err)])) as ChatMessageStream,
+        }
+    };
+
+    Box::pin(futures::stream::once(subscription).flatten())
+}
+
+/// Subscribes to all channels, republishing plain text messages if `republishes` their channel,
+/// see [`republish_plain_text`].
+async fn subscribe_all_channels<F>(
+    redis_url: &str,
+    channel_publisher: Arc<dyn ChannelPublisher>,
+    republishes: F,
+) -> Result<ChatMessageStream>
+where
+    F: Fn(&str) -> bool + Send + Sync + 'static,
+{
     let redis_client = redis::Client::open(redis_url)?;
     let connection = redis_client.get_async_connection().await?;
     let mut pubsub = connection.into_pubsub();
@@ -56,7 +919,11 @@ async fn subscribe_all_channels(redis_url: &str) -> Result<ChatMessageStream> {
 
     let stream = pubsub
         .into_on_message()
-        .map(common::chat_message_from_redis_msg);
+        .map(common::received_message_from_redis_msg);
 
-    Ok(Box::pin(stream))
+    Ok(republish_plain_text(
+        Box::pin(stream),
+        channel_publisher,
+        republishes,
+    ))
 }
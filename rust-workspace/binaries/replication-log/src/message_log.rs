@@ -1,36 +1,443 @@
-use std::sync::{Arc, Mutex};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Condvar, Mutex},
+    time::{Duration, SystemTime},
+};
 
-use common::{stream_to_vec_forwarder::StreamToVecForwarder, ChatMessage, ChatMessageStream};
+use anyhow::{bail, Result};
+use dashmap::DashMap;
+use tokio::sync::Notify;
+
+use common::{
+    forwarder_health::{ForwarderHealth, ItemErrorPolicy},
+    message_sink::{BlockingSink, ClosureSink},
+    recent_message_ids::RecentMessageIds,
+    stream_forwarder::StreamForwarder,
+    ChatMessage, ChatMessageStream, MessageId, SequenceNumber, SequencedMessage,
+};
+
+use crate::{
+    replication::ChannelPosition,
+    retention::{self, RetentionConfig},
+    storage::MessageStore,
+};
+
+/// The number of most recent messages per channel whose ids are remembered to recognize duplicates.
+pub const DEDUPLICATION_WINDOW: usize = 1000;
 
 #[derive(Clone)]
 pub struct MessageLog {
-    //TODO: instead of storing messages in memory, save them in a database/key-value store
-    messages_received: Arc<Mutex<Vec<ChatMessage>>>,
-    _message_forwarder: Arc<StreamToVecForwarder>,
+    message_appender: Arc<DeduplicatingAppender>,
+    message_forwarder: Arc<StreamForwarder>,
 }
 
 impl MessageLog {
-    pub fn new(incoming_messages: ChatMessageStream) -> Self {
-        let messages_received: Arc<Mutex<Vec<ChatMessage>>> = Default::default();
+    /// Messages that are received more than once (identified by their id) are only stored once,
+    /// regardless of whether they were appended directly or received from the stream, as long as
+    /// they are received again within the channel's last [`DEDUPLICATION_WINDOW`] messages.
+    ///
+    /// Every stored message is assigned the next sequence number of its channel by the store.
+    ///
+    /// Erroneous items of the stream are handled according to `item_error_policy`, whereas failing
+    /// to store a message always stops storing the stream's messages.
+    pub fn new(
+        message_store: Arc<dyn MessageStore>,
+        incoming_messages: ChatMessageStream,
+        item_error_policy: ItemErrorPolicy,
+    ) -> Result<Self> {
+        let message_appender = Arc::new(DeduplicatingAppender {
+            message_store,
+            message_ids: Default::default(),
+            appended_notifications: Default::default(),
+        });
 
-        let _message_forwarder = Arc::new(StreamToVecForwarder::new(
+        let forwarded_message_appender = Arc::clone(&message_appender);
+        let message_forwarder = Arc::new(StreamForwarder::new(
             incoming_messages,
-            Arc::clone(&messages_received),
+            BlockingSink::new(ClosureSink::new(move |chat_message| {
+                forwarded_message_appender.append(chat_message)?;
+                Ok(())
+            })),
+            item_error_policy,
         ));
 
-        MessageLog {
-            messages_received,
-            _message_forwarder,
+        Ok(MessageLog {
+            message_appender,
+            message_forwarder,
+        })
+    }
+
+    /// Runs `f` on tokio's blocking thread pool, since the storage backends perform blocking I/O.
+    pub async fn run_blocking<T, F>(&self, f: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&MessageLog) -> Result<T> + Send + 'static,
+    {
+        let message_log = self.clone();
+
+        tokio::task::spawn_blocking(move || f(&message_log)).await?
+    }
+
+    /// Stores the message, unless it has already been stored before.
+    ///
+    /// Returns the stored message, or `None` if it is a duplicate.
+    pub fn append(&self, chat_message: ChatMessage) -> Result<Option<SequencedMessage>> {
+        self.message_appender.append(chat_message)
+    }
+
+    /// Whether the message with the given id is among the channel's most recent messages (see
+    /// [`DEDUPLICATION_WINDOW`]) or is being appended.
+    pub fn contains(&self, channel: &str, message_id: MessageId) -> Result<bool> {
+        let channel_message_ids = self.message_appender.channel_message_ids(channel)?;
+        let channel_message_ids = channel_message_ids.lock().unwrap();
+
+        Ok(channel_message_ids.contains(&message_id))
+    }
+
+    /// Whether the messages of the incoming stream are still being stored.
+    pub fn forwarder_health(&self) -> ForwarderHealth {
+        self.message_forwarder.health()
+    }
+
+    /// Returns the messages of the channel, ordered by their sequence number.
+    pub fn messages_received(&self, channel: &str) -> Result<Vec<SequencedMessage>> {
+        self.messages_after(channel, 0, None)
+    }
+
+    /// Returns the messages of the channel with a sequence number greater than `after`, ordered by
+    /// their sequence number.
+    ///
+    /// At most `limit` messages are returned, if given.
+    pub fn messages_after(
+        &self,
+        channel: &str,
+        after: SequenceNumber,
+        limit: Option<usize>,
+    ) -> Result<Vec<SequencedMessage>> {
+        self.message_appender
+            .message_store
+            .messages_after(channel, after, limit)
+    }
+
+    /// Returns the last `count` messages of the channel with a sequence number less than `before`
+    /// (or the last `count` messages, if not given), ordered by their sequence number.
+    pub fn messages_before(
+        &self,
+        channel: &str,
+        before: Option<SequenceNumber>,
+        count: usize,
+    ) -> Result<Vec<SequencedMessage>> {
+        let next_sequence_number = self.next_sequence_number(channel)?;
+        let end = before.map_or(next_sequence_number, |before| {
+            before.min(next_sequence_number)
+        });
+
+        // Sequence numbers are contiguous, except for removed messages at the beginning.
+        let mut messages =
+            self.messages_after(channel, end.saturating_sub(count as u64 + 1), Some(count))?;
+        messages.retain(|message| message.sequence_number < end);
+
+        Ok(messages)
+    }
+
+    /// Like [`messages_after`](Self::messages_after), but if there are no such messages yet, waits
+    /// up to `timeout` for the next message appended to the channel.
+    pub async fn wait_for_messages_after(
+        &self,
+        channel: &str,
+        after: SequenceNumber,
+        limit: Option<usize>,
+        timeout: Duration,
+    ) -> Result<Vec<SequencedMessage>> {
+        let deadline = tokio::time::Instant::now() + timeout;
+        let appended = self.message_appender.appended_notification(channel);
+
+        loop {
+            // Created before reading, so that a message appended in between is not missed.
+            let next_append = appended.notified();
+            let channel = channel.to_string();
+            let messages = self
+                .run_blocking(move |message_log| message_log.messages_after(&channel, after, limit))
+                .await?;
+            if !messages.is_empty() {
+                return Ok(messages);
+            }
+
+            if tokio::time::timeout_at(deadline, next_append)
+                .await
+                .is_err()
+            {
+                return Ok(messages);
+            }
         }
     }
 
-    pub fn messages_received(&self, channel: &str) -> Vec<ChatMessage> {
-        let all_messages = self.messages_received.lock().unwrap();
+    /// Returns the names of all channels that messages have been appended to.
+    pub fn channels(&self) -> Result<Vec<String>> {
+        self.message_appender.message_store.channels()
+    }
+
+    /// Returns the sequence number that the next message appended to the channel is assigned.
+    pub fn next_sequence_number(&self, channel: &str) -> Result<SequenceNumber> {
+        self.message_appender
+            .message_store
+            .next_sequence_number(channel)
+    }
 
-        all_messages
-            .iter()
-            .filter(|message| message.channel == channel)
-            .cloned()
+    /// Returns the sequence number that the next message appended to every channel is assigned.
+    pub fn channel_positions(&self) -> Result<Vec<ChannelPosition>> {
+        let message_store = &self.message_appender.message_store;
+
+        message_store
+            .channels()?
+            .into_iter()
+            .map(|channel| {
+                Ok(ChannelPosition {
+                    next_sequence_number: message_store.next_sequence_number(&channel)?,
+                    channel,
+                })
+            })
             .collect()
     }
+
+    /// Stores the messages of another log with their sequence numbers, see
+    /// [`replication`](crate::replication).
+    ///
+    /// Messages with a sequence number that has already been assigned in this log are skipped. If
+    /// a message's sequence number is greater than the next one of its channel, the messages in
+    /// between are considered removed by the other log's retention policy.
+    pub fn replicate(&self, messages: Vec<SequencedMessage>) -> Result<()> {
+        self.message_appender.replicate(messages)
+    }
+
+    /// Removes the messages of the channel with a sequence number less than `sequence_number`,
+    /// see [`MessageStore::truncate_before`].
+    pub fn truncate_before(&self, channel: &str, sequence_number: SequenceNumber) -> Result<()> {
+        self.message_appender
+            .message_store
+            .truncate_before(channel, sequence_number)
+    }
+
+    /// Returns the sequence number before which the channel's messages have been removed by a
+    /// retention policy, if any.
+    ///
+    /// Reading the messages after an earlier sequence number silently misses the removed ones.
+    pub fn truncated_before(&self, channel: &str) -> Result<Option<SequenceNumber>> {
+        self.message_appender
+            .message_store
+            .truncated_before(channel)
+    }
+
+    /// Returns the channels whose messages exceed their retention policy, see
+    /// [`retention::retention_horizons`].
+    pub fn retention_horizons(
+        &self,
+        retention_config: &RetentionConfig,
+        now: SystemTime,
+    ) -> Result<Vec<(String, SequenceNumber)>> {
+        retention::retention_horizons(
+            self.message_appender.message_store.as_ref(),
+            retention_config,
+            now,
+        )
+    }
+
+    /// Reclaims the space of removed messages, see [`MessageStore::compact`].
+    pub fn compact(&self) -> Result<()> {
+        self.message_appender.message_store.compact()
+    }
+
+    /// Removes the messages that exceed the retention policy of their channel, see
+    /// [`retention::enforce_retention`].
+    pub fn enforce_retention(
+        &self,
+        retention_config: &RetentionConfig,
+        now: SystemTime,
+    ) -> Result<()> {
+        retention::enforce_retention(
+            self.message_appender.message_store.as_ref(),
+            retention_config,
+            now,
+        )
+    }
+}
+
+struct DeduplicatingAppender {
+    message_store: Arc<dyn MessageStore>,
+    /// The ids of the messages of every channel that has been appended to; loaded from the store
+    /// once a channel is first appended to.
+    ///
+    /// Every channel has its own lock, which is only held while checking and recording ids, not
+    /// while the store writes a message.
+    message_ids: DashMap<String, Arc<Mutex<ChannelMessageIds>>>,
+    /// Notified whenever a message is appended to the channel, for every channel that is being
+    /// waited for, see [`MessageLog::wait_for_messages_after`].
+    appended_notifications: DashMap<String, Arc<Notify>>,
+}
+
+struct ChannelMessageIds {
+    /// The ids of the channel's most recent messages, see [`DEDUPLICATION_WINDOW`].
+    stored: RecentMessageIds,
+    /// The messages that are being appended, so that a message appended concurrently from two
+    /// sources is only stored once.
+    appending: HashMap<MessageId, Arc<PendingAppend>>,
+}
+
+impl ChannelMessageIds {
+    fn contains(&self, message_id: &MessageId) -> bool {
+        self.stored.contains(message_id) || self.appending.contains_key(message_id)
+    }
+}
+
+/// The outcome of a message's append that is still in progress, which concurrent appends of the
+/// same message wait for.
+#[derive(Default)]
+struct PendingAppend {
+    /// Why appending the message failed, if it did; `None` while it is in progress.
+    outcome: Mutex<Option<Result<(), String>>>,
+    finished: Condvar,
+}
+
+impl PendingAppend {
+    fn finish(&self, outcome: Result<(), String>) {
+        *self.outcome.lock().unwrap() = Some(outcome);
+        self.finished.notify_all();
+    }
+
+    fn wait(&self) -> Result<(), String> {
+        let mut outcome = self.outcome.lock().unwrap();
+        loop {
+            if let Some(outcome) = &*outcome {
+                return outcome.clone();
+            }
+            outcome = self.finished.wait(outcome).unwrap();
+        }
+    }
+}
+
+impl DeduplicatingAppender {
+    fn appended_notification(&self, channel: &str) -> Arc<Notify> {
+        Arc::clone(
+            &self
+                .appended_notifications
+                .entry(channel.to_string())
+                .or_default(),
+        )
+    }
+
+    fn channel_message_ids(&self, channel: &str) -> Result<Arc<Mutex<ChannelMessageIds>>> {
+        // Clone the ids, so that we do not hold a lock on the map while accessing them.
+        if let Some(channel_message_ids) = self.message_ids.get(channel) {
+            return Ok(Arc::clone(&channel_message_ids));
+        }
+
+        // Loaded without holding any lock. If the channel's ids are loaded concurrently, the ones
+        // inserted first are used, which may already contain appended messages.
+        let mut stored = RecentMessageIds::new(DEDUPLICATION_WINDOW);
+        for message_id in self
+            .message_store
+            .recent_message_ids(channel, DEDUPLICATION_WINDOW)?
+        {
+            stored.insert(message_id);
+        }
+
+        Ok(Arc::clone(
+            &self
+                .message_ids
+                .entry(channel.to_string())
+                .or_insert_with(|| {
+                    Arc::new(Mutex::new(ChannelMessageIds {
+                        stored,
+                        appending: HashMap::new(),
+                    }))
+                }),
+        ))
+    }
+
+    fn notify_appended(&self, channel: &str) {
+        if let Some(appended) = self.appended_notifications.get(channel) {
+            appended.notify_waiters();
+        }
+    }
+
+    /// A message that is received again while it is being appended waits for the first append:
+    /// it is a duplicate if that succeeds and fails if that fails.
+    fn append(&self, chat_message: ChatMessage) -> Result<Option<SequencedMessage>> {
+        let channel_message_ids = self.channel_message_ids(&chat_message.channel)?;
+        let message_id = chat_message.id;
+        let pending_append = {
+            let mut channel_message_ids = channel_message_ids.lock().unwrap();
+            if channel_message_ids.stored.contains(&message_id) {
+                return Ok(None);
+            }
+            if let Some(pending_append) = channel_message_ids.appending.get(&message_id) {
+                let pending_append = Arc::clone(pending_append);
+                drop(channel_message_ids);
+                return match pending_append.wait() {
+                    Ok(()) => Ok(None),
+                    Err(err) => bail!("appending message {message_id} concurrently failed: {err}"),
+                };
+            }
+
+            let pending_append = Arc::new(PendingAppend::default());
+            channel_message_ids
+                .appending
+                .insert(message_id, Arc::clone(&pending_append));
+            pending_append
+        };
+
+        let appended_message = self.message_store.append(chat_message);
+
+        let mut channel_message_ids = channel_message_ids.lock().unwrap();
+        channel_message_ids.appending.remove(&message_id);
+        let sequenced_message = match appended_message {
+            Ok(sequenced_message) => sequenced_message,
+            Err(err) => {
+                pending_append.finish(Err(format!("{err:#}")));
+                return Err(err);
+            }
+        };
+        channel_message_ids.stored.insert(message_id);
+        drop(channel_message_ids);
+        pending_append.finish(Ok(()));
+        self.notify_appended(&sequenced_message.chat_message.channel);
+
+        Ok(Some(sequenced_message))
+    }
+
+    /// Only called by a single task at a time, i.e. the follower replicating its leader, so
+    /// determining the channel's next sequence number does not race with other replications.
+    fn replicate(&self, messages: Vec<SequencedMessage>) -> Result<()> {
+        for message in messages {
+            let channel = &message.chat_message.channel;
+            let next_sequence_number = self.message_store.next_sequence_number(channel)?;
+            if message.sequence_number < next_sequence_number {
+                continue;
+            }
+            if message.sequence_number > next_sequence_number {
+                self.message_store
+                    .truncate_before(channel, message.sequence_number)?;
+            }
+
+            let message_id = message.chat_message.id;
+            let sequenced_message = self.message_store.append(message.chat_message)?;
+            if sequenced_message.sequence_number != message.sequence_number {
+                bail!(
+                    "replicated message {message_id} was stored with sequence number {} instead \
+                     of {}",
+                    sequenced_message.sequence_number,
+                    message.sequence_number
+                );
+            }
+            let channel = &sequenced_message.chat_message.channel;
+            self.channel_message_ids(channel)?
+                .lock()
+                .unwrap()
+                .stored
+                .insert(message_id);
+            self.notify_appended(channel);
+        }
+
+        Ok(())
+    }
 }
@@ -0,0 +1,231 @@
+//! Running the replication log as a Raft group, for automatic failover.
+//!
+//! Every instance of the group is a [`RaftNode`]. Messages are appended to the log of the elected
+//! leader, replicated to the other nodes and, once a majority of the nodes has stored them
+//! (committed), applied to the [`MessageLog`](crate::message_log::MessageLog) of every node in the
+//! same order, so that all nodes assign the same sequence numbers. If the leader fails, the
+//! remaining majority elects a new one; committed messages are never lost.
+//!
+//! [`RaftNode`] implements the protocol as a deterministic state machine that is driven by ticks
+//! and incoming messages, so that it can be tested without timers or a network. The
+//! [`server`] runs a node, exchanging its messages over HTTP and applying committed entries.
+//!
+//! Reads from a node's message log may be stale. A linearizable read first waits for a
+//! [read barrier](server::RaftHandle::read_barrier): the leader confirms with a majority that it
+//! is still the leader and waits until every message appended before has been applied.
+//!
+//! Retention policies are enforced by the leader only: it appends the resulting truncations to the
+//! log as [`Command::TruncateBefore`], so that every node removes the same messages at the same
+//! point.
+//!
+//! Entries that have been applied are replaced by a [`Snapshot`] every
+//! [`RaftConfig::snapshot_interval`] entries, so that the log does not grow forever. The message log
+//! itself holds the state; a snapshot only records the position of every channel. A node whose log
+//! ends before the leader's snapshot restores its message log by copying the messages up to these
+//! positions from the leader, and continues with the entries after the snapshot.
+
+use std::{fmt, time::Duration};
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use common::{ChatMessage, SequenceNumber};
+
+use crate::replication::ChannelPosition;
+
+mod node;
+pub mod server;
+pub mod storage;
+
+pub use node::{PendingSnapshot, RaftNode, RaftRole, RaftTimeouts, ReadId, Ready};
+
+pub type NodeId = u64;
+/// Elections are numbered by terms; there is at most one leader per term.
+pub type Term = u64;
+/// Position of an entry in the Raft log, starting at 1.
+pub type LogIndex = u64;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Entry {
+    /// The term in which the entry was appended by the leader.
+    pub term: Term,
+    pub command: Command,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum Command {
+    /// Appended by a new leader to commit the entries of previous terms.
+    Noop,
+    Append(ChatMessage),
+    /// Removes the messages of the channel before the sequence number, see
+    /// [`MessageLog::truncate_before`](crate::message_log::MessageLog::truncate_before).
+    TruncateBefore {
+        channel: String,
+        sequence_number: SequenceNumber,
+    },
+}
+
+/// Replaces the entries of the log up to `last_included_index`, see the
+/// [module documentation](self).
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub last_included_index: LogIndex,
+    pub last_included_term: Term,
+    /// The position of every channel of the message log once the entries have been applied.
+    pub channel_positions: Vec<ChannelPosition>,
+}
+
+/// The persistent state of a node besides its log.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HardState {
+    pub term: Term,
+    /// The candidate this node voted for in `term`.
+    pub voted_for: Option<NodeId>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Message {
+    pub from: NodeId,
+    pub to: NodeId,
+    /// The sender's term.
+    pub term: Term,
+    pub body: MessageBody,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum MessageBody {
+    RequestVote {
+        last_log_index: LogIndex,
+        last_log_term: Term,
+    },
+    Vote {
+        granted: bool,
+    },
+    /// Also sent without entries as a heartbeat.
+    AppendEntries {
+        prev_log_index: LogIndex,
+        prev_log_term: Term,
+        entries: Vec<Entry>,
+        leader_commit: LogIndex,
+        /// Echoed in the response, to confirm reads that waited for this heartbeat.
+        heartbeat_id: u64,
+    },
+    /// Sent instead of `AppendEntries` if the entries the node needs have been replaced by the
+    /// snapshot. Answered by an `AppendEntriesResponse` once the node has restored it.
+    InstallSnapshot {
+        snapshot: Snapshot,
+        heartbeat_id: u64,
+    },
+    AppendEntriesResponse {
+        success: bool,
+        /// The index up to which the log matches the leader's, if successful.
+        match_index: LogIndex,
+        /// The index of the last entry of the log, as a hint for the leader where to continue.
+        last_log_index: LogIndex,
+        heartbeat_id: u64,
+    },
+}
+
+/// Returned when a request has to be made to the leader instead.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct NotLeader {
+    /// The current leader, if known.
+    pub leader_id: Option<NodeId>,
+}
+
+impl fmt::Display for NotLeader {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.leader_id {
+            Some(leader_id) => write!(f, "this node is not the leader, node {leader_id} is"),
+            None => write!(f, "this node is not the leader, and no leader is known"),
+        }
+    }
+}
+
+impl std::error::Error for NotLeader {}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RaftConfig {
+    /// The id of this instance within the group; without one, Raft is disabled.
+    pub node_id: Option<NodeId>,
+    /// Every member of the group, including this instance.
+    pub members: Vec<RaftMember>,
+    /// The members send their messages to each other with this secret as
+    /// `Authorization: Bearer <secret>`, and reject messages without it; required with Raft.
+    pub secret: Option<String>,
+    /// The time between two ticks, which election and heartbeat timeouts are counted in.
+    #[serde(with = "humantime_serde")]
+    pub tick_interval: Duration,
+    pub election_ticks: u32,
+    pub heartbeat_ticks: u32,
+    /// Appends and read barriers that take longer than this fail.
+    #[serde(with = "humantime_serde")]
+    pub request_timeout: Duration,
+    /// Every this many applied entries, a snapshot is taken, and the entries up to the previous
+    /// snapshot are removed from the log.
+    pub snapshot_interval: u64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RaftMember {
+    pub id: NodeId,
+    /// The base URL of the member's HTTP API.
+    pub url: String,
+}
+
+impl Default for RaftConfig {
+    fn default() -> Self {
+        RaftConfig {
+            node_id: None,
+            members: vec![],
+            secret: None,
+            tick_interval: Duration::from_millis(100),
+            election_ticks: 10,
+            heartbeat_ticks: 2,
+            request_timeout: Duration::from_secs(5),
+            snapshot_interval: 10_000,
+        }
+    }
+}
+
+impl RaftConfig {
+    pub fn validate(&self) -> Result<()> {
+        let Some(node_id) = self.node_id else {
+            return Ok(());
+        };
+
+        if self.secret.as_ref().is_none_or(|secret| secret.is_empty()) {
+            bail!("the members need a secret to authenticate their messages");
+        }
+        if !self.members.iter().any(|member| member.id == node_id) {
+            bail!("the members must include this node ({node_id})");
+        }
+        for (i, member) in self.members.iter().enumerate() {
+            if self.members[..i].iter().any(|other| other.id == member.id) {
+                bail!("member {} is listed more than once", member.id);
+            }
+            reqwest::Url::parse(&member.url)
+                .with_context(|| format!("invalid URL of member {}", member.id))?;
+        }
+        if self.tick_interval.is_zero() || self.heartbeat_ticks == 0 {
+            bail!("the tick interval and the heartbeat ticks must be positive");
+        }
+        if self.election_ticks <= self.heartbeat_ticks {
+            bail!("the election ticks must exceed the heartbeat ticks");
+        }
+        if self.snapshot_interval == 0 {
+            bail!("the snapshot interval must be positive");
+        }
+
+        Ok(())
+    }
+
+    pub fn timeouts(&self) -> RaftTimeouts {
+        RaftTimeouts {
+            election_ticks: self.election_ticks,
+            heartbeat_ticks: self.heartbeat_ticks,
+        }
+    }
+}
@@ -0,0 +1,791 @@
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::replication::ChannelPosition;
+
+use super::{
+    storage::{RaftState, RaftStorage},
+    Command, Entry, HardState, LogIndex, Message, MessageBody, NodeId, NotLeader, Snapshot, Term,
+};
+
+/// Limits the size of a single `AppendEntries` message.
+const MAX_ENTRIES_PER_MESSAGE: usize = 256;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RaftRole {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+/// Timeouts, in ticks.
+#[derive(Clone, Copy, Debug)]
+pub struct RaftTimeouts {
+    /// A follower that has not heard from a leader for at least this long (randomly up to twice as
+    /// long) starts an election.
+    pub election_ticks: u32,
+    /// How often the leader sends heartbeats; has to be well below `election_ticks`.
+    pub heartbeat_ticks: u32,
+}
+
+/// Identifies a read requested via [`RaftNode::read_index`].
+pub type ReadId = u64;
+
+/// The output of a [`RaftNode`], see [`RaftNode::take_ready`].
+#[derive(Debug, Default)]
+pub struct Ready {
+    /// Messages to send to other nodes; they may be lost, duplicated or reordered.
+    pub messages: Vec<Message>,
+    /// Entries that have been committed since the last call, to be applied in order.
+    pub committed_entries: Vec<(LogIndex, Entry)>,
+    /// Reads that are linearizable once the entries up to the given index have been applied.
+    pub reads: Vec<(ReadId, LogIndex)>,
+    /// Reads that failed because the node is no longer the leader.
+    pub failed_reads: Vec<ReadId>,
+    /// A snapshot of the leader that the message log has to be restored to before any further
+    /// entries can be applied, see [`RaftNode::restore_snapshot`].
+    pub snapshot: Option<PendingSnapshot>,
+}
+
+/// A snapshot received from the leader, see [`Ready::snapshot`].
+#[derive(Clone, Debug)]
+pub struct PendingSnapshot {
+    /// The leader, which the message log can be restored from.
+    pub leader_id: NodeId,
+    pub snapshot: Snapshot,
+    heartbeat_id: u64,
+}
+
+/// A member of a Raft group, see the [module documentation](super).
+///
+/// The node persists its state to its [`RaftStorage`] before it emits any message that depends
+/// on it, so that it keeps its promises after a restart.
+pub struct RaftNode {
+    id: NodeId,
+    /// The other members of the group.
+    peers: Vec<NodeId>,
+    timeouts: RaftTimeouts,
+    storage: Box<dyn RaftStorage>,
+    hard_state: HardState,
+    /// Replaces the entries up to its last included index.
+    snapshot: Snapshot,
+    /// The entries after the snapshot: the entry with index `i` is at position
+    /// `i - snapshot.last_included_index - 1`.
+    log: Vec<Entry>,
+    commit_index: LogIndex,
+    /// Committed entries up to this index have been handed out via [`Ready`].
+    handed_out_index: LogIndex,
+    role: RaftRole,
+    leader_id: Option<NodeId>,
+    election_elapsed: u32,
+    election_timeout: u32,
+    heartbeat_elapsed: u32,
+    /// Only used by candidates.
+    votes: BTreeSet<NodeId>,
+    /// Only used by leaders: the next entry to send to every peer and the last one known to match.
+    next_index: BTreeMap<NodeId, LogIndex>,
+    match_index: BTreeMap<NodeId, LogIndex>,
+    /// Only used by leaders: the last heartbeat sent and the last one acknowledged by every peer.
+    heartbeat_id: u64,
+    acked_heartbeat_ids: BTreeMap<NodeId, u64>,
+    /// Only used by leaders: reads waiting for a majority to acknowledge the given heartbeat.
+    pending_reads: VecDeque<(ReadId, u64)>,
+    /// State of the pseudo-random number generator that randomizes election timeouts.
+    random_state: u64,
+    ready: Ready,
+}
+
+impl RaftNode {
+    /// Restores the node from its storage; `members` may include the node itself.
+    ///
+    /// Election timeouts are randomized deterministically, based on `seed` and the node's id.
+    pub fn new(
+        id: NodeId,
+        members: &[NodeId],
+        timeouts: RaftTimeouts,
+        mut storage: Box<dyn RaftStorage>,
+        seed: u64,
+    ) -> Result<Self> {
+        let RaftState {
+            hard_state,
+            snapshot,
+            log,
+        } = storage.load()?;
+        // Entries are only applied once they have been committed. The snapshot is only saved once
+        // the message log has been restored to it.
+        let applied_index = storage
+            .applied_index()?
+            .min(snapshot.last_included_index + log.len() as LogIndex)
+            .max(snapshot.last_included_index);
+
+        let mut node = RaftNode {
+            id,
+            peers: members
+                .iter()
+                .copied()
+                .filter(|&member| member != id)
+                .collect(),
+            timeouts,
+            storage,
+            hard_state,
+            snapshot,
+            log,
+            commit_index: applied_index,
+            handed_out_index: applied_index,
+            role: RaftRole::Follower,
+            leader_id: None,
+            election_elapsed: 0,
+            election_timeout: timeouts.election_ticks,
+            heartbeat_elapsed: 0,
+            votes: BTreeSet::new(),
+            next_index: BTreeMap::new(),
+            match_index: BTreeMap::new(),
+            heartbeat_id: 0,
+            acked_heartbeat_ids: BTreeMap::new(),
+            pending_reads: VecDeque::new(),
+            // Must not be zero.
+            random_state: (seed ^ id.wrapping_mul(0x9E37_79B9_7F4A_7C15)) | 1,
+            ready: Ready::default(),
+        };
+        node.reset_election_timer();
+
+        Ok(node)
+    }
+
+    pub fn id(&self) -> NodeId {
+        self.id
+    }
+
+    pub fn role(&self) -> RaftRole {
+        self.role
+    }
+
+    pub fn term(&self) -> Term {
+        self.hard_state.term
+    }
+
+    pub fn leader_id(&self) -> Option<NodeId> {
+        self.leader_id
+    }
+
+    pub fn commit_index(&self) -> LogIndex {
+        self.commit_index
+    }
+
+    pub fn last_index(&self) -> LogIndex {
+        self.snapshot.last_included_index + self.log.len() as LogIndex
+    }
+
+    /// The index of the last entry that has been replaced by a snapshot, 0 if there is none.
+    pub fn snapshot_index(&self) -> LogIndex {
+        self.snapshot.last_included_index
+    }
+
+    /// Returns the entry at the given index, if the log contains it and it has not been replaced
+    /// by a snapshot.
+    pub fn entry(&self, index: LogIndex) -> Option<&Entry> {
+        let position = index.checked_sub(self.snapshot.last_included_index + 1)?;
+
+        self.log.get(position as usize)
+    }
+
+    /// Advances the node's clock, which may start an election or send heartbeats.
+    pub fn tick(&mut self) -> Result<()> {
+        if self.role == RaftRole::Leader {
+            self.heartbeat_elapsed += 1;
+            if self.heartbeat_elapsed >= self.timeouts.heartbeat_ticks {
+                self.heartbeat_elapsed = 0;
+                self.broadcast_append_entries();
+            }
+        } else {
+            self.election_elapsed += 1;
+            if self.election_elapsed >= self.election_timeout {
+                self.start_election()?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Appends the command to the log, if this node is the leader; it is applied once it has been
+    /// committed.
+    ///
+    /// Returns the term and index of the new entry: if the entry committed at this index has a
+    /// different term, the command has been discarded because the leadership was lost. Fails with
+    /// [`NotLeader`] on other nodes.
+    pub fn propose(&mut self, command: Command) -> Result<(Term, LogIndex)> {
+        if self.role != RaftRole::Leader {
+            return Err(self.not_leader().into());
+        }
+
+        let term = self.hard_state.term;
+        self.append_to_log(vec![Entry { term, command }])?;
+        self.broadcast_append_entries();
+        self.maybe_commit();
+
+        Ok((term, self.last_index()))
+    }
+
+    /// Requests a linearizable read, which is reported via [`Ready::reads`] once a majority has
+    /// confirmed that this node is still the leader.
+    ///
+    /// Fails with [`NotLeader`] on other nodes.
+    pub fn read_index(&mut self, read_id: ReadId) -> Result<()> {
+        if self.role != RaftRole::Leader {
+            return Err(self.not_leader().into());
+        }
+
+        self.heartbeat_id += 1;
+        self.pending_reads.push_back((read_id, self.heartbeat_id));
+        self.broadcast_append_entries();
+        self.confirm_reads();
+
+        Ok(())
+    }
+
+    /// Records that the committed entries up to the given index have been applied, so that they
+    /// are not handed out again after a restart.
+    pub fn record_applied(&mut self, index: LogIndex) -> Result<()> {
+        self.storage.save_applied_index(index)
+    }
+
+    /// Replaces the entries up to the given index, which have been applied, by a snapshot with
+    /// the channel positions of the message log right after applying them.
+    pub fn compact(
+        &mut self,
+        index: LogIndex,
+        channel_positions: Vec<ChannelPosition>,
+    ) -> Result<()> {
+        if index <= self.snapshot.last_included_index {
+            return Ok(());
+        }
+        if index > self.handed_out_index {
+            bail!("cannot replace entry {index} by a snapshot before it has been applied");
+        }
+
+        let snapshot = Snapshot {
+            last_included_index: index,
+            last_included_term: self.term_at(index),
+            channel_positions,
+        };
+        self.storage.save_snapshot(&snapshot)?;
+        self.log
+            .drain(..(index - self.snapshot.last_included_index) as usize);
+        self.snapshot = snapshot;
+
+        Ok(())
+    }
+
+    /// Replaces the log by the leader's snapshot, once the message log has been restored to it,
+    /// keeping the entries after it if they match the leader's. See [`Ready::snapshot`].
+    pub fn restore_snapshot(&mut self, pending_snapshot: PendingSnapshot) -> Result<()> {
+        let PendingSnapshot {
+            leader_id,
+            snapshot,
+            heartbeat_id,
+        } = pending_snapshot;
+        let index = snapshot.last_included_index;
+
+        if index > self.commit_index {
+            let keeps_entries =
+                index <= self.last_index() && self.term_at(index) == snapshot.last_included_term;
+            if !keeps_entries && self.last_index() > self.snapshot.last_included_index {
+                // The entries conflict with the leader's, so they have not been committed.
+                self.storage
+                    .truncate(self.snapshot.last_included_index + 1)?;
+                self.log.clear();
+            }
+            self.storage.save_snapshot(&snapshot)?;
+            let replaced_entries = (index - self.snapshot.last_included_index) as usize;
+            self.log.drain(..replaced_entries.min(self.log.len()));
+            self.snapshot = snapshot;
+            self.commit_index = index;
+            self.handed_out_index = index;
+        }
+
+        self.send(
+            leader_id,
+            MessageBody::AppendEntriesResponse {
+                success: true,
+                match_index: index,
+                last_log_index: self.last_index(),
+                heartbeat_id,
+            },
+        );
+
+        Ok(())
+    }
+
+    /// Takes the node's output since the last call.
+    pub fn take_ready(&mut self) -> Ready {
+        let committed_entries = (self.handed_out_index + 1..=self.commit_index)
+            .map(|index| (index, self.entry(index).unwrap().clone()))
+            .collect();
+        self.handed_out_index = self.commit_index;
+
+        Ready {
+            committed_entries,
+            ..std::mem::take(&mut self.ready)
+        }
+    }
+
+    /// Handles a message from another node.
+    pub fn step(&mut self, message: Message) -> Result<()> {
+        if message.to != self.id {
+            return Ok(());
+        }
+
+        if message.term > self.hard_state.term {
+            let leader_id = matches!(
+                message.body,
+                MessageBody::AppendEntries { .. } | MessageBody::InstallSnapshot { .. }
+            )
+            .then_some(message.from);
+            self.become_follower(message.term, leader_id)?;
+        }
+        if message.term < self.hard_state.term {
+            // Let a stale candidate or leader know about the newer term.
+            match message.body {
+                MessageBody::RequestVote { .. } => {
+                    self.send(message.from, MessageBody::Vote { granted: false })
+                }
+                MessageBody::AppendEntries { heartbeat_id, .. }
+                | MessageBody::InstallSnapshot { heartbeat_id, .. } => self.send(
+                    message.from,
+                    MessageBody::AppendEntriesResponse {
+                        success: false,
+                        match_index: 0,
+                        last_log_index: self.last_index(),
+                        heartbeat_id,
+                    },
+                ),
+                _ => {}
+            }
+            return Ok(());
+        }
+
+        match message.body {
+            MessageBody::RequestVote {
+                last_log_index,
+                last_log_term,
+            } => {
+                let can_vote = self
+                    .hard_state
+                    .voted_for
+                    .is_none_or(|voted_for| voted_for == message.from);
+                let is_up_to_date =
+                    (last_log_term, last_log_index) >= (self.last_term(), self.last_index());
+                let granted = can_vote && is_up_to_date;
+                if granted {
+                    self.hard_state.voted_for = Some(message.from);
+                    self.storage.save_hard_state(&self.hard_state)?;
+                    self.reset_election_timer();
+                }
+                self.send(message.from, MessageBody::Vote { granted });
+            }
+            MessageBody::Vote { granted } => {
+                if self.role == RaftRole::Candidate && granted {
+                    self.votes.insert(message.from);
+                    if self.votes.len() >= self.majority() {
+                        self.become_leader()?;
+                    }
+                }
+            }
+            MessageBody::AppendEntries {
+                prev_log_index,
+                prev_log_term,
+                entries,
+                leader_commit,
+                heartbeat_id,
+            } => self.handle_append_entries(
+                message.from,
+                prev_log_index,
+                prev_log_term,
+                entries,
+                leader_commit,
+                heartbeat_id,
+            )?,
+            MessageBody::InstallSnapshot {
+                snapshot,
+                heartbeat_id,
+            } => self.handle_install_snapshot(message.from, snapshot, heartbeat_id)?,
+            MessageBody::AppendEntriesResponse {
+                success,
+                match_index,
+                last_log_index,
+                heartbeat_id,
+            } => self.handle_append_entries_response(
+                message.from,
+                success,
+                match_index,
+                last_log_index,
+                heartbeat_id,
+            ),
+        }
+
+        Ok(())
+    }
+
+    fn handle_append_entries(
+        &mut self,
+        leader_id: NodeId,
+        mut prev_log_index: LogIndex,
+        mut prev_log_term: Term,
+        mut entries: Vec<Entry>,
+        leader_commit: LogIndex,
+        heartbeat_id: u64,
+    ) -> Result<()> {
+        self.follow(leader_id)?;
+
+        if prev_log_index < self.snapshot.last_included_index {
+            // The entries replaced by the snapshot have been committed, so they match the leader's.
+            let replaced_entries = self.snapshot.last_included_index - prev_log_index;
+            entries.drain(..(replaced_entries as usize).min(entries.len()));
+            prev_log_index = self.snapshot.last_included_index;
+            prev_log_term = self.snapshot.last_included_term;
+        }
+
+        let log_matches =
+            prev_log_index <= self.last_index() && self.term_at(prev_log_index) == prev_log_term;
+        if !log_matches {
+            self.send(
+                leader_id,
+                MessageBody::AppendEntriesResponse {
+                    success: false,
+                    match_index: 0,
+                    last_log_index: self.last_index(),
+                    heartbeat_id,
+                },
+            );
+            return Ok(());
+        }
+
+        // Skip the entries we already have; only a conflicting entry (and everything after it)
+        // is replaced, so that a delayed message never shortens the log.
+        let mut new_entries = entries;
+        let mut index = prev_log_index;
+        while !new_entries.is_empty() && index < self.last_index() {
+            if self.term_at(index + 1) != new_entries[0].term {
+                self.storage.truncate(index + 1)?;
+                self.log
+                    .truncate((index - self.snapshot.last_included_index) as usize);
+                break;
+            }
+            new_entries.remove(0);
+            index += 1;
+        }
+        let last_new_index = index + new_entries.len() as LogIndex;
+        self.append_to_log(new_entries)?;
+
+        let commit_index = leader_commit.min(last_new_index);
+        if commit_index > self.commit_index {
+            self.commit_index = commit_index;
+        }
+
+        self.send(
+            leader_id,
+            MessageBody::AppendEntriesResponse {
+                success: true,
+                match_index: last_new_index,
+                last_log_index: self.last_index(),
+                heartbeat_id,
+            },
+        );
+
+        Ok(())
+    }
+
+    /// Hands the snapshot out via [`Ready::snapshot`], unless the node has committed its entries
+    /// already.
+    fn handle_install_snapshot(
+        &mut self,
+        leader_id: NodeId,
+        snapshot: Snapshot,
+        heartbeat_id: u64,
+    ) -> Result<()> {
+        self.follow(leader_id)?;
+
+        let pending_snapshot = PendingSnapshot {
+            leader_id,
+            snapshot,
+            heartbeat_id,
+        };
+        if pending_snapshot.snapshot.last_included_index <= self.commit_index {
+            return self.restore_snapshot(pending_snapshot);
+        }
+        self.ready.snapshot = Some(pending_snapshot);
+
+        Ok(())
+    }
+
+    /// Accepts the sender of `AppendEntries` or `InstallSnapshot` of the current term as the
+    /// leader.
+    fn follow(&mut self, leader_id: NodeId) -> Result<()> {
+        if self.role != RaftRole::Follower {
+            self.become_follower(self.hard_state.term, Some(leader_id))?;
+        }
+        self.leader_id = Some(leader_id);
+        self.election_elapsed = 0;
+
+        Ok(())
+    }
+
+    fn handle_append_entries_response(
+        &mut self,
+        peer: NodeId,
+        success: bool,
+        match_index: LogIndex,
+        last_log_index: LogIndex,
+        heartbeat_id: u64,
+    ) {
+        if self.role != RaftRole::Leader {
+            return;
+        }
+
+        let acked_heartbeat_id = self.acked_heartbeat_ids.entry(peer).or_default();
+        *acked_heartbeat_id = (*acked_heartbeat_id).max(heartbeat_id);
+
+        let next_index = self.next_index.get(&peer).copied().unwrap_or(1);
+        if success {
+            let peer_match_index = self.match_index.entry(peer).or_default();
+            *peer_match_index = (*peer_match_index).max(match_index);
+            self.next_index
+                .insert(peer, next_index.max(match_index + 1));
+            self.maybe_commit();
+
+            if self.next_index[&peer] <= self.last_index() {
+                self.send_append_entries(peer);
+            }
+        } else {
+            // Go back until the logs match, skipping entries the peer does not have at all.
+            let next_index = (next_index - 1).min(last_log_index + 1).max(1);
+            self.next_index.insert(peer, next_index);
+            self.send_append_entries(peer);
+        }
+
+        self.confirm_reads();
+    }
+
+    fn become_follower(&mut self, term: Term, leader_id: Option<NodeId>) -> Result<()> {
+        if term > self.hard_state.term {
+            self.hard_state = HardState {
+                term,
+                voted_for: None,
+            };
+            self.storage.save_hard_state(&self.hard_state)?;
+        }
+        if self.role == RaftRole::Leader {
+            self.ready
+                .failed_reads
+                .extend(self.pending_reads.drain(..).map(|(read_id, _)| read_id));
+        }
+
+        self.role = RaftRole::Follower;
+        self.leader_id = leader_id;
+        self.reset_election_timer();
+
+        Ok(())
+    }
+
+    fn start_election(&mut self) -> Result<()> {
+        self.hard_state = HardState {
+            term: self.hard_state.term + 1,
+            voted_for: Some(self.id),
+        };
+        self.storage.save_hard_state(&self.hard_state)?;
+
+        self.role = RaftRole::Candidate;
+        self.leader_id = None;
+        self.votes = BTreeSet::from([self.id]);
+        self.reset_election_timer();
+
+        if self.votes.len() >= self.majority() {
+            return self.become_leader();
+        }
+        for peer in self.peers.clone() {
+            self.send(
+                peer,
+                MessageBody::RequestVote {
+                    last_log_index: self.last_index(),
+                    last_log_term: self.last_term(),
+                },
+            );
+        }
+
+        Ok(())
+    }
+
+    fn become_leader(&mut self) -> Result<()> {
+        self.role = RaftRole::Leader;
+        self.leader_id = Some(self.id);
+        self.heartbeat_elapsed = 0;
+        self.next_index = self
+            .peers
+            .iter()
+            .map(|&peer| (peer, self.last_index() + 1))
+            .collect();
+        self.match_index = self.peers.iter().map(|&peer| (peer, 0)).collect();
+        self.acked_heartbeat_ids.clear();
+
+        // Entries of previous terms are only committed along with an entry of the current term.
+        self.append_to_log(vec![Entry {
+            term: self.hard_state.term,
+            command: Command::Noop,
+        }])?;
+        self.broadcast_append_entries();
+        self.maybe_commit();
+
+        Ok(())
+    }
+
+    /// Commits the last entry of the current term that a majority has stored.
+    fn maybe_commit(&mut self) {
+        for index in (self.commit_index + 1..=self.last_index()).rev() {
+            if self.term_at(index) != self.hard_state.term {
+                // Terms never decrease along the log.
+                break;
+            }
+
+            let replicas = 1 + self
+                .match_index
+                .values()
+                .filter(|&&match_index| match_index >= index)
+                .count();
+            if replicas >= self.majority() {
+                self.commit_index = index;
+                break;
+            }
+        }
+
+        self.confirm_reads();
+    }
+
+    /// Reports the reads whose heartbeat a majority has acknowledged, once the leader has
+    /// committed an entry of its term (before, it might not know the latest committed entries).
+    fn confirm_reads(&mut self) {
+        if self.role != RaftRole::Leader || self.term_at(self.commit_index) != self.hard_state.term
+        {
+            return;
+        }
+
+        while let Some(&(read_id, heartbeat_id)) = self.pending_reads.front() {
+            let acknowledgements = 1 + self
+                .acked_heartbeat_ids
+                .values()
+                .filter(|&&acked_heartbeat_id| acked_heartbeat_id >= heartbeat_id)
+                .count();
+            if acknowledgements < self.majority() {
+                break;
+            }
+
+            self.pending_reads.pop_front();
+            self.ready.reads.push((read_id, self.commit_index));
+        }
+    }
+
+    fn broadcast_append_entries(&mut self) {
+        for peer in self.peers.clone() {
+            self.send_append_entries(peer);
+        }
+    }
+
+    fn send_append_entries(&mut self, peer: NodeId) {
+        let next_index = self.next_index.get(&peer).copied().unwrap_or(1);
+        let prev_log_index = next_index - 1;
+        if prev_log_index < self.snapshot.last_included_index {
+            // The peer lacks entries that have been replaced by the snapshot.
+            self.send(
+                peer,
+                MessageBody::InstallSnapshot {
+                    snapshot: self.snapshot.clone(),
+                    heartbeat_id: self.heartbeat_id,
+                },
+            );
+            return;
+        }
+
+        let entries = self.log[(prev_log_index - self.snapshot.last_included_index) as usize..]
+            .iter()
+            .take(MAX_ENTRIES_PER_MESSAGE)
+            .cloned()
+            .collect();
+
+        self.send(
+            peer,
+            MessageBody::AppendEntries {
+                prev_log_index,
+                prev_log_term: self.term_at(prev_log_index),
+                entries,
+                leader_commit: self.commit_index,
+                heartbeat_id: self.heartbeat_id,
+            },
+        );
+    }
+
+    fn append_to_log(&mut self, entries: Vec<Entry>) -> Result<()> {
+        if entries.is_empty() {
+            return Ok(());
+        }
+
+        self.storage.append(&entries)?;
+        self.log.extend(entries);
+
+        Ok(())
+    }
+
+    fn send(&mut self, to: NodeId, body: MessageBody) {
+        self.ready.messages.push(Message {
+            from: self.id,
+            to,
+            term: self.hard_state.term,
+            body,
+        });
+    }
+
+    fn not_leader(&self) -> NotLeader {
+        NotLeader {
+            leader_id: self.leader_id,
+        }
+    }
+
+    fn majority(&self) -> usize {
+        let members = self.peers.len() + 1;
+
+        members / 2 + 1
+    }
+
+    fn last_term(&self) -> Term {
+        self.term_at(self.last_index())
+    }
+
+    /// The term of the entry at the given index, which has to be in the log or be the last one
+    /// replaced by the snapshot; 0 for index 0.
+    fn term_at(&self, index: LogIndex) -> Term {
+        if index == self.snapshot.last_included_index {
+            return self.snapshot.last_included_term;
+        }
+
+        self.entry(index).unwrap().term
+    }
+
+    fn reset_election_timer(&mut self) {
+        self.election_elapsed = 0;
+        let election_ticks = self.timeouts.election_ticks.max(1);
+        self.election_timeout =
+            election_ticks + (self.next_random() % election_ticks as u64) as u32;
+    }
+
+    /// xorshift64, which is good enough to spread election timeouts.
+    fn next_random(&mut self) -> u64 {
+        let mut x = self.random_state;
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.random_state = x;
+
+        x
+    }
+}
@@ -0,0 +1,633 @@
+//! Runs a [`RaftNode`], exchanging its messages with the other nodes and applying committed
+//! entries to the message log.
+//!
+//! Everything that may block, i.e. the node persisting its state and applying entries, runs on
+//! tokio's blocking thread pool.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    convert::Infallible,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    sync::{mpsc, oneshot},
+    task::JoinHandle,
+};
+use warp::{http::StatusCode, Filter, Rejection, Reply};
+
+use common::{ChatMessage, SequenceNumber, SequencedMessage};
+
+use crate::{
+    config::is_bearer_token,
+    message_log::{MessageLog, DEDUPLICATION_WINDOW},
+    replication::{replicate_up_to, ChannelPosition, HttpLeader, Leader},
+};
+
+use super::{
+    Command, Entry, LogIndex, Message, NodeId, NotLeader, PendingSnapshot, RaftConfig, RaftNode,
+    RaftRole, ReadId, Term,
+};
+
+type ProposalResponse = oneshot::Sender<Result<Option<SequencedMessage>>>;
+
+/// How many requests may wait for the node before sending more blocks.
+const REQUEST_QUEUE_SIZE: usize = 1024;
+
+/// Messages are copied from the leader in pages of this size when restoring a snapshot.
+const SNAPSHOT_PAGE_SIZE: usize = 1000;
+
+/// Delivers messages to other nodes; messages may be lost.
+pub trait RaftTransport: Send + Sync {
+    fn send(&self, message: Message);
+
+    /// The message log of another node, which a snapshot of that node can be restored from, see
+    /// [`Ready::snapshot`](super::Ready::snapshot).
+    fn snapshot_source(&self, node_id: NodeId) -> Option<Arc<dyn Leader>>;
+}
+
+/// Posts messages to the `/raft` route of the other nodes, without waiting for the response.
+/// Snapshots are restored via the other nodes' HTTP API, like a follower replicates its leader.
+pub struct HttpTransport {
+    member_urls: HashMap<NodeId, String>,
+    /// Authenticates the messages, see [`RaftConfig::secret`].
+    secret: String,
+    http_client: reqwest::Client,
+    request_timeout: Duration,
+}
+
+impl HttpTransport {
+    pub fn new(raft_config: &RaftConfig) -> Result<Self> {
+        Ok(HttpTransport {
+            member_urls: member_urls(raft_config),
+            secret: raft_config.secret.clone().unwrap_or_default(),
+            http_client: reqwest::Client::builder()
+                .timeout(raft_config.request_timeout)
+                .build()?,
+            request_timeout: raft_config.request_timeout,
+        })
+    }
+}
+
+impl RaftTransport for HttpTransport {
+    fn send(&self, message: Message) {
+        let Some(member_url) = self.member_urls.get(&message.to) else {
+            return;
+        };
+
+        let request = self
+            .http_client
+            .post(format!("{member_url}/raft"))
+            .bearer_auth(&self.secret)
+            .json(&message);
+        tokio::spawn(async move {
+            // Raft copes with lost messages; the message is sent again after a timeout.
+            let _ = request.send().await;
+        });
+    }
+
+    fn snapshot_source(&self, node_id: NodeId) -> Option<Arc<dyn Leader>> {
+        let member_url = self.member_urls.get(&node_id)?;
+        // The members of a Raft group are not promoted, so they have no epochs.
+        let leader = HttpLeader::new(member_url.clone(), self.request_timeout, Arc::default());
+
+        Some(Arc::new(leader.ok()?))
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RaftStatus {
+    pub node_id: NodeId,
+    pub role: RaftRole,
+    pub term: Term,
+    pub leader_id: Option<NodeId>,
+    pub commit_index: LogIndex,
+    pub applied_index: LogIndex,
+    /// The entries up to this index have been replaced by a snapshot.
+    pub snapshot_index: LogIndex,
+    /// Why the node stopped, if it did.
+    pub error: Option<String>,
+}
+
+enum Request {
+    Step(Message),
+    Propose {
+        command: Command,
+        response: ProposalResponse,
+    },
+    ReadBarrier {
+        response: oneshot::Sender<Result<()>>,
+    },
+}
+
+/// Serves `POST /raft`, which passes the messages of the other members to the node, see
+/// [`HttpTransport`]. Messages without the group's secret are rejected with `401 Unauthorized`.
+pub fn raft_message_route(
+    raft: RaftHandle,
+    raft_config: &RaftConfig,
+) -> impl Filter<Extract = (warp::reply::Response,), Error = Rejection> + Clone {
+    let secret = raft_config.secret.clone().unwrap_or_default();
+
+    warp::path!("raft")
+        .and(warp::post())
+        // An invalid header is treated like a missing one.
+        .and(
+            warp::header::optional::<String>("authorization")
+                .or(warp::any().map(|| None))
+                .unify(),
+        )
+        .map(move |authorization: Option<String>| {
+            // An empty secret is rejected by the configuration's validation.
+            !secret.is_empty() && is_bearer_token(authorization.as_deref(), &secret)
+        })
+        .and(warp::body::json())
+        .and(warp::any().map(move || raft.clone()))
+        .and_then(raft_message_handler)
+}
+
+async fn raft_message_handler(
+    is_authorized: bool,
+    message: Message,
+    raft: RaftHandle,
+) -> Result<warp::reply::Response, Infallible> {
+    if !is_authorized {
+        return Ok(warp::reply::with_status(
+            "missing or invalid Raft secret".to_string(),
+            StatusCode::UNAUTHORIZED,
+        )
+        .into_response());
+    }
+
+    match raft.step(message).await {
+        Ok(()) => Ok(StatusCode::NO_CONTENT.into_response()),
+        Err(err) => Ok(
+            warp::reply::with_status(err.to_string(), StatusCode::SERVICE_UNAVAILABLE)
+                .into_response(),
+        ),
+    }
+}
+
+/// Sends requests to a node run by [`spawn_raft_server`].
+#[derive(Clone)]
+pub struct RaftHandle {
+    requests: mpsc::Sender<Request>,
+    status: Arc<Mutex<RaftStatus>>,
+    member_urls: Arc<HashMap<NodeId, String>>,
+    request_timeout: Duration,
+}
+
+impl RaftHandle {
+    /// Passes a message from another node to this one.
+    pub async fn step(&self, message: Message) -> Result<()> {
+        self.requests
+            .send(Request::Step(message))
+            .await
+            .map_err(|_| node_stopped())
+    }
+
+    /// Appends the message to the log of every node, like [`MessageLog::append`], once a majority
+    /// has stored it.
+    ///
+    /// Fails with [`NotLeader`] unless this node is the leader (and remains so until the message
+    /// is committed).
+    pub async fn append(&self, chat_message: ChatMessage) -> Result<Option<SequencedMessage>> {
+        self.propose(Command::Append(chat_message)).await
+    }
+
+    /// Removes the messages of the channel before the sequence number from the log of every node,
+    /// like [`MessageLog::truncate_before`], once a majority has stored the truncation.
+    ///
+    /// Fails with [`NotLeader`] unless this node is the leader (and remains so until the
+    /// truncation is committed).
+    pub async fn truncate_before(
+        &self,
+        channel: &str,
+        sequence_number: SequenceNumber,
+    ) -> Result<()> {
+        self.propose(Command::TruncateBefore {
+            channel: channel.to_string(),
+            sequence_number,
+        })
+        .await?;
+
+        Ok(())
+    }
+
+    async fn propose(&self, command: Command) -> Result<Option<SequencedMessage>> {
+        let (response, receive_response) = oneshot::channel();
+        self.request(Request::Propose { command, response }, receive_response)
+            .await
+    }
+
+    /// Waits until every message appended before has been applied to this node's message log, so
+    /// that reading it afterwards is linearizable.
+    ///
+    /// Fails with [`NotLeader`] unless this node is the leader.
+    pub async fn read_barrier(&self) -> Result<()> {
+        let (response, receive_response) = oneshot::channel();
+        self.request(Request::ReadBarrier { response }, receive_response)
+            .await
+    }
+
+    pub fn status(&self) -> RaftStatus {
+        self.status.lock().unwrap().clone()
+    }
+
+    pub fn is_leader(&self) -> bool {
+        self.status().role == RaftRole::Leader
+    }
+
+    /// The URL of the member that this node considers the leader, if it is another one.
+    pub fn leader_url(&self) -> Option<String> {
+        let status = self.status();
+
+        status
+            .leader_id
+            .filter(|&leader_id| leader_id != status.node_id)
+            .and_then(|leader_id| self.member_urls.get(&leader_id).cloned())
+    }
+
+    async fn request<T>(
+        &self,
+        request: Request,
+        receive_response: oneshot::Receiver<Result<T>>,
+    ) -> Result<T> {
+        self.requests
+            .send(request)
+            .await
+            .map_err(|_| node_stopped())?;
+
+        match tokio::time::timeout(self.request_timeout, receive_response).await {
+            Ok(Ok(response)) => response,
+            Ok(Err(_)) => Err(node_stopped()),
+            Err(_) => Err(anyhow!(
+                "the request timed out, a majority of the nodes might be unavailable"
+            )),
+        }
+    }
+}
+
+/// Runs the node: ticks it every [`RaftConfig::tick_interval`], sends its messages via the
+/// transport and applies committed entries to the message log.
+///
+/// The node stops if its storage or the message log fails, since it can no longer keep its
+/// promises to the other nodes.
+pub fn spawn_raft_server(
+    node: RaftNode,
+    message_log: MessageLog,
+    transport: Arc<dyn RaftTransport>,
+    raft_config: &RaftConfig,
+) -> (RaftHandle, JoinHandle<()>) {
+    let (requests, receive_requests) = mpsc::channel(REQUEST_QUEUE_SIZE);
+    let mut server = RaftServer {
+        status: Arc::new(Mutex::new(RaftStatus {
+            node_id: node.id(),
+            role: node.role(),
+            term: node.term(),
+            leader_id: node.leader_id(),
+            commit_index: node.commit_index(),
+            applied_index: node.commit_index(),
+            snapshot_index: node.snapshot_index(),
+            error: None,
+        })),
+        applied_index: node.commit_index(),
+        node: Arc::new(Mutex::new(node)),
+        message_log,
+        transport,
+        snapshot_interval: raft_config.snapshot_interval,
+        recorded_positions: None,
+        proposals: BTreeMap::new(),
+        reads: HashMap::new(),
+        next_read_id: 0,
+        confirmed_reads: Vec::new(),
+    };
+
+    let handle = RaftHandle {
+        requests,
+        status: Arc::clone(&server.status),
+        member_urls: Arc::new(member_urls(raft_config)),
+        request_timeout: raft_config.request_timeout,
+    };
+    let tick_interval = raft_config.tick_interval;
+    let join_handle = tokio::spawn(async move {
+        if let Err(err) = server.run(receive_requests, tick_interval).await {
+            println!("The Raft node stopped: {err:#}");
+            server.status.lock().unwrap().error = Some(format!("{err:#}"));
+        }
+    });
+
+    (handle, join_handle)
+}
+
+/// The positions of the message log's channels after applying the entry with the given index.
+type RecordedPositions = (LogIndex, Vec<ChannelPosition>);
+
+struct RaftServer {
+    /// Only locked by the server itself, mostly on the blocking thread pool, see
+    /// [`RaftServer::with_node`].
+    node: Arc<Mutex<RaftNode>>,
+    message_log: MessageLog,
+    transport: Arc<dyn RaftTransport>,
+    status: Arc<Mutex<RaftStatus>>,
+    applied_index: LogIndex,
+    /// See [`RaftConfig::snapshot_interval`].
+    snapshot_interval: u64,
+    /// The positions that the log is compacted to once the next ones are recorded, so that
+    /// followers that lag behind a little can still catch up from the log.
+    recorded_positions: Option<RecordedPositions>,
+    /// Proposals waiting for the entry at the given index to be committed, with the entry's term.
+    proposals: BTreeMap<LogIndex, (Term, ProposalResponse)>,
+    /// Read barriers waiting for the leader to be confirmed.
+    reads: HashMap<ReadId, oneshot::Sender<Result<()>>>,
+    next_read_id: ReadId,
+    /// Read barriers waiting for the entries up to the given index to be applied.
+    confirmed_reads: Vec<(LogIndex, oneshot::Sender<Result<()>>)>,
+}
+
+impl RaftServer {
+    async fn run(
+        &mut self,
+        mut requests: mpsc::Receiver<Request>,
+        tick_interval: Duration,
+    ) -> Result<()> {
+        let mut ticks = tokio::time::interval(tick_interval);
+
+        loop {
+            tokio::select! {
+                _ = ticks.tick() => self.with_node(|node| node.tick()).await?,
+                request = requests.recv() => match request {
+                    Some(request) => self.handle_request(request).await?,
+                    // Every handle has been dropped.
+                    None => return Ok(()),
+                },
+            }
+
+            self.process_ready().await?;
+        }
+    }
+
+    /// Runs `f` on the node on tokio's blocking thread pool, since the node persists its state
+    /// before returning.
+    async fn with_node<T, F>(&self, f: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut RaftNode) -> Result<T> + Send + 'static,
+    {
+        let node = Arc::clone(&self.node);
+
+        tokio::task::spawn_blocking(move || f(&mut node.lock().unwrap())).await?
+    }
+
+    async fn handle_request(&mut self, request: Request) -> Result<()> {
+        match request {
+            Request::Step(message) => self.with_node(|node| node.step(message)).await?,
+            Request::Propose { command, response } => {
+                match self.with_node(|node| Ok(node.propose(command))).await? {
+                    Ok((term, index)) => {
+                        self.proposals.insert(index, (term, response));
+                    }
+                    Err(err) if err.is::<NotLeader>() => {
+                        let _ = response.send(Err(err));
+                    }
+                    Err(err) => return Err(err),
+                }
+            }
+            Request::ReadBarrier { response } => {
+                self.next_read_id += 1;
+                // Only sends a heartbeat, without persisting anything.
+                match self.node.lock().unwrap().read_index(self.next_read_id) {
+                    Ok(()) => {
+                        self.reads.insert(self.next_read_id, response);
+                    }
+                    Err(err) if err.is::<NotLeader>() => {
+                        let _ = response.send(Err(err));
+                    }
+                    Err(err) => return Err(err),
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn process_ready(&mut self) -> Result<()> {
+        let ready = self.node.lock().unwrap().take_ready();
+
+        for message in ready.messages {
+            self.transport.send(message);
+        }
+
+        if !ready.committed_entries.is_empty() {
+            for committed_entries in apply_batches(ready.committed_entries) {
+                let last_index = committed_entries[committed_entries.len() - 1].0;
+                let applied_entries = self
+                    .message_log
+                    .run_blocking(move |message_log| apply_entries(message_log, committed_entries))
+                    .await?;
+                self.applied_index = last_index;
+
+                for (index, term, appended_message) in applied_entries {
+                    if let Some((proposed_term, response)) = self.proposals.remove(&index) {
+                        let _ = response.send(if proposed_term == term {
+                            Ok(appended_message)
+                        } else {
+                            // Another leader has replaced the entry.
+                            Err(self.not_leader().into())
+                        });
+                    }
+                }
+                self.with_node(move |node| node.record_applied(last_index))
+                    .await?;
+            }
+            self.record_positions().await?;
+        }
+        if let Some(pending_snapshot) = ready.snapshot {
+            self.restore_snapshot(pending_snapshot).await?;
+        }
+
+        for (read_id, index) in ready.reads {
+            if let Some(response) = self.reads.remove(&read_id) {
+                self.confirmed_reads.push((index, response));
+            }
+        }
+        for read_id in ready.failed_reads {
+            if let Some(response) = self.reads.remove(&read_id) {
+                let _ = response.send(Err(self.not_leader().into()));
+            }
+        }
+        let applied_index = self.applied_index;
+        let (applied_reads, confirmed_reads): (Vec<_>, Vec<_>) =
+            std::mem::take(&mut self.confirmed_reads)
+                .into_iter()
+                .partition(|(index, _)| *index <= applied_index);
+        self.confirmed_reads = confirmed_reads;
+        for (_, response) in applied_reads {
+            let _ = response.send(Ok(()));
+        }
+
+        let node = self.node.lock().unwrap();
+        if node.role() != RaftRole::Leader {
+            // The entries might still be committed by the next leader, but proposing them again
+            // is harmless: appending the same message again is deduplicated.
+            for (_, (_, response)) in std::mem::take(&mut self.proposals) {
+                let _ = response.send(Err(NotLeader {
+                    leader_id: node.leader_id(),
+                }
+                .into()));
+            }
+        }
+        *self.status.lock().unwrap() = RaftStatus {
+            node_id: node.id(),
+            role: node.role(),
+            term: node.term(),
+            leader_id: node.leader_id(),
+            commit_index: node.commit_index(),
+            applied_index: self.applied_index,
+            snapshot_index: node.snapshot_index(),
+            error: None,
+        };
+
+        Ok(())
+    }
+
+    /// Records the positions of the message log every [`RaftConfig::snapshot_interval`] applied
+    /// entries, replacing the entries up to the previously recorded positions by a snapshot.
+    async fn record_positions(&mut self) -> Result<()> {
+        let last_recorded_index = match &self.recorded_positions {
+            Some((index, _)) => *index,
+            None => self.node.lock().unwrap().snapshot_index(),
+        };
+        if self.applied_index < last_recorded_index + self.snapshot_interval {
+            return Ok(());
+        }
+
+        // Every committed entry has been applied, so the positions match the applied index.
+        let channel_positions = self
+            .message_log
+            .run_blocking(|message_log| message_log.channel_positions())
+            .await?;
+        let recorded_positions = (self.applied_index, channel_positions);
+        if let Some((index, channel_positions)) =
+            self.recorded_positions.replace(recorded_positions)
+        {
+            self.with_node(move |node| node.compact(index, channel_positions))
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Restores the message log to the leader's snapshot by copying the messages up to the
+    /// snapshot's positions from the leader, which has applied the snapshot's entries.
+    ///
+    /// If that fails, the node waits for the leader to send the snapshot again.
+    async fn restore_snapshot(&mut self, pending_snapshot: PendingSnapshot) -> Result<()> {
+        let leader_id = pending_snapshot.leader_id;
+        let index = pending_snapshot.snapshot.last_included_index;
+        let Some(leader) = self.transport.snapshot_source(leader_id) else {
+            println!("Cannot restore the snapshot of node {leader_id}, which is unknown");
+            return Ok(());
+        };
+
+        println!("Restoring the snapshot of entry {index} from node {leader_id}");
+        let restored = replicate_up_to(
+            &self.message_log,
+            leader.as_ref(),
+            pending_snapshot.snapshot.channel_positions.clone(),
+            SNAPSHOT_PAGE_SIZE,
+        )
+        .await;
+        if let Err(err) = restored {
+            println!("Restoring the snapshot of entry {index} failed: {err:#}");
+            return Ok(());
+        }
+
+        self.with_node(move |node| {
+            node.restore_snapshot(pending_snapshot)?;
+            node.record_applied(index)
+        })
+        .await?;
+        self.applied_index = index;
+        self.recorded_positions = None;
+
+        Ok(())
+    }
+
+    fn not_leader(&self) -> NotLeader {
+        NotLeader {
+            leader_id: self.node.lock().unwrap().leader_id(),
+        }
+    }
+}
+
+/// Splits committed entries into the batches that are applied before recording the applied index.
+///
+/// Entries applied before a crash, but not recorded as applied, are applied again after the
+/// restart. This is idempotent as long as appending a message again is recognized as a duplicate,
+/// i.e. as long as it is among the channel's last [`DEDUPLICATION_WINDOW`] messages and has not
+/// been removed since. So a batch has at most that many entries, and every truncation forms a
+/// batch of its own.
+pub fn apply_batches(entries: Vec<(LogIndex, Entry)>) -> Vec<Vec<(LogIndex, Entry)>> {
+    let mut batches = Vec::new();
+    let mut batch = Vec::new();
+    for entry in entries {
+        if matches!(entry.1.command, Command::TruncateBefore { .. }) {
+            if !batch.is_empty() {
+                batches.push(std::mem::take(&mut batch));
+            }
+            batches.push(vec![entry]);
+            continue;
+        }
+
+        batch.push(entry);
+        if batch.len() == DEDUPLICATION_WINDOW {
+            batches.push(std::mem::take(&mut batch));
+        }
+    }
+    if !batch.is_empty() {
+        batches.push(batch);
+    }
+
+    batches
+}
+
+/// Applies the entries to the message log, returning the index, term and appended message of
+/// every entry.
+fn apply_entries(
+    message_log: &MessageLog,
+    entries: Vec<(LogIndex, Entry)>,
+) -> Result<Vec<(LogIndex, Term, Option<SequencedMessage>)>> {
+    entries
+        .into_iter()
+        .map(|(index, entry)| {
+            let appended_message = match entry.command {
+                Command::Noop => None,
+                Command::Append(chat_message) => message_log.append(chat_message)?,
+                Command::TruncateBefore {
+                    channel,
+                    sequence_number,
+                } => {
+                    message_log.truncate_before(&channel, sequence_number)?;
+                    None
+                }
+            };
+
+            Ok((index, entry.term, appended_message))
+        })
+        .collect()
+}
+
+fn member_urls(raft_config: &RaftConfig) -> HashMap<NodeId, String> {
+    raft_config
+        .members
+        .iter()
+        .map(|member| (member.id, member.url.clone()))
+        .collect()
+}
+
+fn node_stopped() -> anyhow::Error {
+    anyhow!("the Raft node has stopped")
+}
@@ -0,0 +1,346 @@
+//! Where a [`RaftNode`](super::RaftNode) persists its state.
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{bail, Context, Result};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use super::{Entry, HardState, LogIndex, Snapshot};
+
+const HARD_STATE_FILE_NAME: &str = "hard_state.json";
+const APPLIED_INDEX_FILE_NAME: &str = "applied_index.json";
+const LOG_FILE_NAME: &str = "log";
+const RECORD_HEADER_SIZE: usize = 8;
+
+/// The persistent state of a node, see [`RaftStorage::load`].
+#[derive(Clone, Debug, Default)]
+pub struct RaftState {
+    pub hard_state: HardState,
+    pub snapshot: Snapshot,
+    /// The entries after the snapshot, the first one having index
+    /// `snapshot.last_included_index + 1`.
+    pub log: Vec<Entry>,
+}
+
+/// Every method must only return once the change is durable.
+pub trait RaftStorage: Send {
+    fn load(&mut self) -> Result<RaftState>;
+
+    fn save_hard_state(&mut self, hard_state: &HardState) -> Result<()>;
+
+    /// Appends the entries to the end of the log.
+    fn append(&mut self, entries: &[Entry]) -> Result<()>;
+
+    /// Removes the entries with an index of at least `from`, which has to be after the snapshot.
+    fn truncate(&mut self, from: LogIndex) -> Result<()>;
+
+    /// Replaces the entries up to the snapshot's last included index by the snapshot, keeping the
+    /// entries after it. The snapshot has to be more recent than the current one.
+    fn save_snapshot(&mut self, snapshot: &Snapshot) -> Result<()>;
+
+    /// The index up to which committed entries have been applied to the message log.
+    fn applied_index(&mut self) -> Result<LogIndex>;
+
+    fn save_applied_index(&mut self, applied_index: LogIndex) -> Result<()>;
+}
+
+/// Keeps the state in memory, e.g. for tests.
+///
+/// Clones share the state, so a node can be restarted from the state of a crashed one.
+#[derive(Clone, Default)]
+pub struct InMemoryRaftStorage {
+    state: Arc<Mutex<InMemoryRaftState>>,
+}
+
+#[derive(Default)]
+struct InMemoryRaftState {
+    state: RaftState,
+    applied_index: LogIndex,
+}
+
+impl InMemoryRaftStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl RaftStorage for InMemoryRaftStorage {
+    fn load(&mut self) -> Result<RaftState> {
+        Ok(self.state.lock().unwrap().state.clone())
+    }
+
+    fn save_hard_state(&mut self, hard_state: &HardState) -> Result<()> {
+        self.state.lock().unwrap().state.hard_state = hard_state.clone();
+
+        Ok(())
+    }
+
+    fn append(&mut self, entries: &[Entry]) -> Result<()> {
+        self.state
+            .lock()
+            .unwrap()
+            .state
+            .log
+            .extend_from_slice(entries);
+
+        Ok(())
+    }
+
+    fn truncate(&mut self, from: LogIndex) -> Result<()> {
+        let state = &mut self.state.lock().unwrap().state;
+        let kept_entries = from.saturating_sub(state.snapshot.last_included_index + 1);
+        state.log.truncate(kept_entries as usize);
+
+        Ok(())
+    }
+
+    fn save_snapshot(&mut self, snapshot: &Snapshot) -> Result<()> {
+        let state = &mut self.state.lock().unwrap().state;
+        let replaced_entries = snapshot.last_included_index - state.snapshot.last_included_index;
+        state
+            .log
+            .drain(..(replaced_entries as usize).min(state.log.len()));
+        state.snapshot = snapshot.clone();
+
+        Ok(())
+    }
+
+    fn applied_index(&mut self) -> Result<LogIndex> {
+        Ok(self.state.lock().unwrap().applied_index)
+    }
+
+    fn save_applied_index(&mut self, applied_index: LogIndex) -> Result<()> {
+        self.state.lock().unwrap().applied_index = applied_index;
+
+        Ok(())
+    }
+}
+
+/// Keeps the state in a directory.
+///
+/// The log is a single file of records like the segments of the
+/// [segment file store](crate::storage::segment_file): the snapshot, if any, followed by one
+/// record per entry. Saving a snapshot replaces the file atomically. The hard state and the
+/// applied index are small JSON files that are replaced atomically.
+pub struct FileRaftStorage {
+    directory: PathBuf,
+    log_file: File,
+    /// The index of the last entry replaced by the snapshot, 0 without one.
+    snapshot_index: LogIndex,
+    /// The offset of every entry's record within the log file, followed by the file's size.
+    record_offsets: Vec<u64>,
+}
+
+/// The payload of a record of the log file.
+#[derive(Serialize, Deserialize)]
+enum LogRecord {
+    Snapshot(Snapshot),
+    Entry(Entry),
+}
+
+impl FileRaftStorage {
+    /// Opens the storage in the given directory, creating it if necessary.
+    ///
+    /// A torn write at the end of the log is truncated.
+    pub fn open<P: AsRef<Path>>(directory: P) -> Result<Self> {
+        let directory = directory.as_ref().to_path_buf();
+        fs::create_dir_all(&directory)
+            .with_context(|| format!("could not create directory {}", directory.display()))?;
+
+        let log_file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .write(true)
+            .truncate(false)
+            .open(directory.join(LOG_FILE_NAME))?;
+
+        Ok(FileRaftStorage {
+            directory,
+            log_file,
+            snapshot_index: 0,
+            record_offsets: vec![0],
+        })
+    }
+
+    fn log_size(&self) -> u64 {
+        *self.record_offsets.last().unwrap()
+    }
+}
+
+impl RaftStorage for FileRaftStorage {
+    fn load(&mut self) -> Result<RaftState> {
+        let hard_state = read_json_file(&self.directory.join(HARD_STATE_FILE_NAME))?;
+
+        let contents = fs::read(self.directory.join(LOG_FILE_NAME))?;
+        let mut snapshot = Snapshot::default();
+        let mut log = Vec::new();
+        let mut offset = 0;
+        self.record_offsets = vec![0];
+        while let Some((payload, record_size)) = parse_record(&contents[offset..]) {
+            offset += record_size;
+            match serde_json::from_slice(payload)? {
+                LogRecord::Snapshot(loaded_snapshot) if log.is_empty() => {
+                    snapshot = loaded_snapshot;
+                    self.record_offsets = vec![offset as u64];
+                }
+                LogRecord::Snapshot(_) => bail!("the log contains a snapshot after its entries"),
+                LogRecord::Entry(entry) => {
+                    log.push(entry);
+                    self.record_offsets.push(offset as u64);
+                }
+            }
+        }
+        self.snapshot_index = snapshot.last_included_index;
+
+        if offset < contents.len() {
+            // A record that was only partially written when the process crashed.
+            self.log_file.set_len(offset as u64)?;
+            self.log_file.sync_all()?;
+        }
+
+        Ok(RaftState {
+            hard_state: hard_state.unwrap_or_default(),
+            snapshot,
+            log,
+        })
+    }
+
+    fn save_hard_state(&mut self, hard_state: &HardState) -> Result<()> {
+        write_json_file(&self.directory.join(HARD_STATE_FILE_NAME), hard_state)
+    }
+
+    fn append(&mut self, entries: &[Entry]) -> Result<()> {
+        let mut records = Vec::new();
+        let mut record_offsets = Vec::with_capacity(entries.len());
+        for entry in entries {
+            append_record(&mut records, &LogRecord::Entry(entry.clone()))?;
+            record_offsets.push(self.log_size() + records.len() as u64);
+        }
+
+        // The file is opened without `append`, so that it can be truncated.
+        let log_size = self.log_size();
+        write_at(&mut self.log_file, log_size, &records)?;
+        self.log_file.sync_data()?;
+        self.record_offsets.extend(record_offsets);
+
+        Ok(())
+    }
+
+    fn truncate(&mut self, from: LogIndex) -> Result<()> {
+        let Some(kept_entries) = from.checked_sub(self.snapshot_index + 1) else {
+            bail!("cannot truncate the log from entry {from}, which is part of the snapshot");
+        };
+        let Some(&size) = self.record_offsets.get(kept_entries as usize) else {
+            bail!("cannot truncate the log from entry {from}, which is beyond its end");
+        };
+
+        self.log_file.set_len(size)?;
+        self.log_file.sync_data()?;
+        self.record_offsets.truncate(kept_entries as usize + 1);
+
+        Ok(())
+    }
+
+    fn save_snapshot(&mut self, snapshot: &Snapshot) -> Result<()> {
+        // The entries after the snapshot are kept, if there are any.
+        let replaced_entries = ((snapshot.last_included_index - self.snapshot_index) as usize)
+            .min(self.record_offsets.len() - 1);
+        let kept_records_offset = self.record_offsets[replaced_entries];
+
+        let mut kept_records = vec![0; (self.log_size() - kept_records_offset) as usize];
+        self.log_file.seek(SeekFrom::Start(kept_records_offset))?;
+        self.log_file.read_exact(&mut kept_records)?;
+        let mut contents = Vec::new();
+        append_record(&mut contents, &LogRecord::Snapshot(snapshot.clone()))?;
+        let snapshot_size = contents.len() as u64;
+        contents.extend_from_slice(&kept_records);
+
+        let log_path = self.directory.join(LOG_FILE_NAME);
+        replace_file(&log_path, &contents)?;
+        self.log_file = OpenOptions::new().read(true).write(true).open(&log_path)?;
+        self.snapshot_index = snapshot.last_included_index;
+        self.record_offsets = self.record_offsets[replaced_entries..]
+            .iter()
+            .map(|offset| offset - kept_records_offset + snapshot_size)
+            .collect();
+
+        Ok(())
+    }
+
+    fn applied_index(&mut self) -> Result<LogIndex> {
+        Ok(read_json_file(&self.directory.join(APPLIED_INDEX_FILE_NAME))?.unwrap_or_default())
+    }
+
+    fn save_applied_index(&mut self, applied_index: LogIndex) -> Result<()> {
+        write_json_file(
+            &self.directory.join(APPLIED_INDEX_FILE_NAME),
+            &applied_index,
+        )
+    }
+}
+
+/// Appends a record with the serialized payload to `records`.
+fn append_record(records: &mut Vec<u8>, payload: &LogRecord) -> Result<()> {
+    let payload = serde_json::to_vec(payload)?;
+    records.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    records.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+    records.extend_from_slice(&payload);
+
+    Ok(())
+}
+
+fn write_at(file: &mut File, offset: u64, bytes: &[u8]) -> Result<()> {
+    file.seek(SeekFrom::Start(offset))?;
+    file.write_all(bytes)?;
+
+    Ok(())
+}
+
+/// Parses the record at the start of `bytes`, returning its payload and its total size.
+///
+/// Returns `None` if there is no complete and intact record, i.e. at the end of the log or on a
+/// torn write.
+fn parse_record(bytes: &[u8]) -> Option<(&[u8], usize)> {
+    let header = bytes.get(..RECORD_HEADER_SIZE)?;
+    let payload_length = u32::from_le_bytes(header[0..4].try_into().unwrap()) as usize;
+    let checksum = u32::from_le_bytes(header[4..8].try_into().unwrap());
+    let payload = bytes[RECORD_HEADER_SIZE..].get(..payload_length)?;
+
+    if crc32fast::hash(payload) != checksum {
+        return None;
+    }
+
+    Some((payload, RECORD_HEADER_SIZE + payload_length))
+}
+
+fn read_json_file<T: DeserializeOwned>(path: &Path) -> Result<Option<T>> {
+    match fs::read(path) {
+        Ok(contents) => Ok(Some(serde_json::from_slice(&contents)?)),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+fn write_json_file<T: Serialize>(path: &Path, value: &T) -> Result<()> {
+    replace_file(path, &serde_json::to_vec(value)?)
+}
+
+/// Replaces the file atomically and durably, so that a crash leaves either the old or the new one.
+fn replace_file(path: &Path, contents: &[u8]) -> Result<()> {
+    let mut temporary_path = path.as_os_str().to_owned();
+    temporary_path.push(".tmp");
+
+    let mut file = File::create(&temporary_path)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    fs::rename(&temporary_path, path)?;
+    // The rename is only durable once the directory has been synced.
+    File::open(path.parent().context("the file has no directory")?)?.sync_all()?;
+
+    Ok(())
+}
@@ -0,0 +1,597 @@
+//! Copies of the replication log on follower instances.
+//!
+//! One instance is the leader: it stores the messages received from the message broker and
+//! accepts appends. Every other instance is a follower of the leader: it periodically asks the
+//! leader for the position of every channel and retrieves the messages after its own position,
+//! storing them with the leader's sequence numbers. So followers can serve reads, at the cost of
+//! lagging behind the leader a little; they reject appends. Messages that the leader removes, e.g.
+//! by a retention policy, before a follower has replicated them are skipped, recording the gap as a
+//! truncation of the follower's log, so that readers of the follower are told about it as well.
+//!
+//! Failover is manual: [`Replication::promote`] turns a follower into a leader, which stops
+//! tailing its former leader and starts storing the messages received from the message broker.
+//! The former leader has to be shut down (or restarted as a follower) beforehand, otherwise both
+//! would assign sequence numbers independently.
+//!
+//! Every promotion starts a new epoch, which has to be greater than every epoch before. Leaders
+//! report their epoch and followers refuse to replicate a leader whose epoch is lower than the
+//! highest one they have seen, see [`EpochFence`]. So a former leader that comes back, e.g. after
+//! a network partition, is not followed anymore once the promoted one has been.
+
+use std::{
+    fs::{self, File},
+    io::Write,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use tokio::{sync::watch, task::JoinHandle, time::Instant};
+
+use common::{MessagesRemoved, SequenceNumber, SequencedMessage, TRUNCATED_BEFORE_HEADER};
+
+use crate::message_log::MessageLog;
+
+/// The HTTP header by which a replication log instance reports its epoch, see [`EpochFence`].
+pub const LEADER_EPOCH_HEADER: &str = "Leader-Epoch";
+
+/// Numbers the leaderships of a replication log: every promotion starts a greater epoch. Instances
+/// that have never been promoted or followed a promoted leader are in epoch 0.
+pub type Epoch = u64;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ReplicationConfig {
+    /// The base URL of the leader to follow; without one, the instance is the leader.
+    pub leader_url: Option<String>,
+    /// How often a follower asks the leader for new messages.
+    #[serde(with = "humantime_serde")]
+    pub poll_interval: Duration,
+    /// Messages are retrieved from the leader in pages of this size.
+    pub page_size: usize,
+    /// Requests to the leader that take longer than this fail.
+    #[serde(with = "humantime_serde")]
+    pub request_timeout: Duration,
+}
+
+impl Default for ReplicationConfig {
+    fn default() -> Self {
+        ReplicationConfig {
+            leader_url: None,
+            poll_interval: Duration::from_millis(500),
+            page_size: 1000,
+            request_timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+impl ReplicationConfig {
+    pub fn validate(&self) -> Result<()> {
+        if let Some(leader_url) = &self.leader_url {
+            reqwest::Url::parse(leader_url)
+                .with_context(|| format!("invalid leader URL {leader_url:?}"))?;
+        }
+        if self.poll_interval.is_zero() || self.page_size == 0 {
+            bail!("the poll interval and the page size must be positive");
+        }
+
+        Ok(())
+    }
+}
+
+/// How far a channel of a log has progressed.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChannelPosition {
+    pub channel: String,
+    /// The sequence number that the next message appended to the channel is assigned.
+    pub next_sequence_number: SequenceNumber,
+}
+
+/// The log that a follower replicates.
+#[async_trait]
+pub trait Leader: Send + Sync {
+    async fn channel_positions(&self) -> Result<Vec<ChannelPosition>>;
+
+    /// Returns at most `limit` messages of the channel with a sequence number greater than
+    /// `after`, ordered by their sequence number.
+    ///
+    /// Fails with [`MessagesRemoved`] if some of these messages have been removed, e.g. by a
+    /// retention policy.
+    async fn messages_after(
+        &self,
+        channel: &str,
+        after: SequenceNumber,
+        limit: usize,
+    ) -> Result<Vec<SequencedMessage>>;
+}
+
+/// Replicates a log within the same process, e.g. for tests.
+#[async_trait]
+impl Leader for MessageLog {
+    async fn channel_positions(&self) -> Result<Vec<ChannelPosition>> {
+        MessageLog::channel_positions(self)
+    }
+
+    async fn messages_after(
+        &self,
+        channel: &str,
+        after: SequenceNumber,
+        limit: usize,
+    ) -> Result<Vec<SequencedMessage>> {
+        let messages = MessageLog::messages_after(self, channel, after, Some(limit))?;
+        // Read after the messages, like the HTTP API does, so that no truncation goes unnoticed.
+        if let Some(truncated_before) = self.truncated_before(channel)? {
+            let first_sequence_number = messages
+                .first()
+                .map_or(truncated_before, |message| message.sequence_number);
+            if first_sequence_number > after + 1 {
+                return Err(MessagesRemoved { truncated_before }.into());
+            }
+        }
+
+        Ok(messages)
+    }
+}
+
+/// Replicates the log of another replication log instance via its HTTP API.
+pub struct HttpLeader {
+    leader_url: String,
+    http_client: reqwest::Client,
+    /// Refuses to replicate the leader once it has been superseded.
+    epoch_fence: Arc<EpochFence>,
+}
+
+impl HttpLeader {
+    pub fn new(
+        leader_url: String,
+        request_timeout: Duration,
+        epoch_fence: Arc<EpochFence>,
+    ) -> Result<Self> {
+        Ok(HttpLeader {
+            leader_url,
+            http_client: reqwest::Client::builder()
+                .timeout(request_timeout)
+                .build()?,
+            epoch_fence,
+        })
+    }
+}
+
+#[async_trait]
+impl Leader for HttpLeader {
+    /// Fails if the leader's epoch is lower than the highest one seen.
+    async fn channel_positions(&self) -> Result<Vec<ChannelPosition>> {
+        let leader_url = &self.leader_url;
+        let response = self
+            .http_client
+            .get(format!("{leader_url}/channels"))
+            .send()
+            .await?
+            .error_for_status()?;
+
+        let epoch = match response.headers().get(LEADER_EPOCH_HEADER) {
+            Some(epoch) => epoch.to_str()?.parse()?,
+            None => 0,
+        };
+        self.epoch_fence
+            .observe(epoch)
+            .await
+            .with_context(|| format!("refusing to replicate {leader_url}"))?;
+
+        Ok(response.json().await?)
+    }
+
+    async fn messages_after(
+        &self,
+        channel: &str,
+        after: SequenceNumber,
+        limit: usize,
+    ) -> Result<Vec<SequencedMessage>> {
+        let leader_url = &self.leader_url;
+        let mut url = reqwest::Url::parse(&format!("{leader_url}/messages"))?;
+        url.path_segments_mut()
+            .map_err(|()| anyhow!("{leader_url} cannot be a base URL"))?
+            .push(channel);
+        let response = self
+            .http_client
+            .get(url)
+            .query(&[("after", after), ("limit", limit as u64)])
+            .send()
+            .await?
+            .error_for_status()?;
+        if let Some(truncated_before) = response.headers().get(TRUNCATED_BEFORE_HEADER) {
+            let truncated_before = truncated_before.to_str()?.parse()?;
+            return Err(MessagesRemoved { truncated_before }.into());
+        }
+
+        Ok(response.json().await?)
+    }
+}
+
+/// Keeps the highest epoch that an instance has been promoted in or seen its leader in, refusing
+/// to follow a leader with a lower epoch. See the [module documentation](self).
+#[derive(Default)]
+pub struct EpochFence {
+    epoch: AtomicU64,
+    /// Held while changing the epoch, so that it is persisted in order.
+    changing: tokio::sync::Mutex<()>,
+    /// Where the epoch is persisted, so that it is not forgotten across restarts.
+    path: Option<PathBuf>,
+}
+
+impl EpochFence {
+    /// Persists the epoch in the file, starting from the epoch stored in it, if it exists.
+    pub fn open(path: PathBuf) -> Result<Self> {
+        let epoch = match fs::read_to_string(&path) {
+            Ok(epoch) => epoch
+                .trim()
+                .parse()
+                .with_context(|| format!("invalid epoch file {path:?}"))?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => 0,
+            Err(err) => return Err(err.into()),
+        };
+
+        Ok(EpochFence {
+            epoch: AtomicU64::new(epoch),
+            changing: Default::default(),
+            path: Some(path),
+        })
+    }
+
+    pub fn epoch(&self) -> Epoch {
+        self.epoch.load(Ordering::SeqCst)
+    }
+
+    /// Records the epoch of the leader, failing if the leader has been superseded, i.e. if its
+    /// epoch is lower than the highest one seen.
+    pub async fn observe(&self, leader_epoch: Epoch) -> Result<()> {
+        let _changing = self.changing.lock().await;
+        let epoch = self.epoch();
+        if leader_epoch < epoch {
+            bail!("the leader's epoch {leader_epoch} has been superseded by epoch {epoch}");
+        }
+
+        self.store(leader_epoch).await
+    }
+
+    /// Starts a new epoch, failing unless it is greater than every epoch seen.
+    async fn start(&self, new_epoch: Epoch) -> Result<()> {
+        let _changing = self.changing.lock().await;
+        let epoch = self.epoch();
+        if new_epoch <= epoch {
+            bail!("the new epoch has to be greater than the current epoch {epoch}");
+        }
+
+        self.store(new_epoch).await
+    }
+
+    async fn store(&self, epoch: Epoch) -> Result<()> {
+        if epoch == self.epoch() {
+            return Ok(());
+        }
+        if let Some(path) = self.path.clone() {
+            tokio::task::spawn_blocking(move || write_epoch_file(&path, epoch)).await??;
+        }
+        self.epoch.store(epoch, Ordering::SeqCst);
+
+        Ok(())
+    }
+}
+
+/// Replaces the file atomically and durably, so that a crash leaves either the old or the new one.
+fn write_epoch_file(path: &Path, epoch: Epoch) -> Result<()> {
+    let temporary_path = path.with_extension("tmp");
+
+    let mut file = File::create(&temporary_path)?;
+    file.write_all(epoch.to_string().as_bytes())?;
+    file.sync_all()?;
+    fs::rename(&temporary_path, path)?;
+    if let Some(directory) = path
+        .parent()
+        .filter(|directory| !directory.as_os_str().is_empty())
+    {
+        File::open(directory)?.sync_all()?;
+    }
+
+    Ok(())
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    Leader,
+    Follower,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ReplicationStatus {
+    pub role: Role,
+    /// The epoch that this instance was promoted in, or that its leader is in.
+    pub epoch: Epoch,
+    /// The leaders that a follower replicates: a single one, or the previous shards of a new
+    /// shard's channels, see [`crate::sharding`]. Empty for the leader.
+    pub leader_urls: Vec<String>,
+    /// How many messages the follower was behind the leader when it last asked for new messages.
+    pub lag_messages: u64,
+    /// How long ago the follower last caught up with the leader, in seconds; `None` if it never
+    /// has.
+    pub seconds_since_last_sync: Option<f64>,
+    /// Why the follower could not catch up with the leader the last time it tried, if it failed.
+    pub last_error: Option<String>,
+    /// The number of messages replicated since the instance started.
+    pub replicated_messages: u64,
+}
+
+/// The role of a replication log instance and how far it has replicated its leader.
+#[derive(Clone)]
+pub struct Replication {
+    inner: Arc<ReplicationInner>,
+}
+
+struct ReplicationInner {
+    leader_urls: Vec<String>,
+    role: watch::Sender<Role>,
+    epoch_fence: Arc<EpochFence>,
+    progress: Mutex<FollowerProgress>,
+    /// Held while replicating, so that a follower is only promoted in between.
+    replicating: tokio::sync::Mutex<()>,
+}
+
+#[derive(Default)]
+struct FollowerProgress {
+    lag_messages: u64,
+    last_sync: Option<Instant>,
+    last_error: Option<String>,
+    replicated_messages: u64,
+}
+
+/// The outcome of [`replicate_once`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ReplicationRound {
+    /// How many messages the follower was behind the leader before replicating them.
+    pub lag_messages: u64,
+    pub replicated_messages: u64,
+}
+
+impl Replication {
+    pub fn leader(epoch_fence: Arc<EpochFence>) -> Self {
+        Self::new(Vec::new(), Role::Leader, epoch_fence)
+    }
+
+    /// A follower of the leader at the given URL, which is only reported in the status. The epoch
+    /// fence is the one its leader is replicated with, see [`HttpLeader::new`].
+    pub fn follower(leader_url: impl Into<String>, epoch_fence: Arc<EpochFence>) -> Self {
+        Self::new(vec![leader_url.into()], Role::Follower, epoch_fence)
+    }
+
+    /// A follower of several leaders, each replicated for some of the channels, e.g. the previous
+    /// shards of a new shard, see [`crate::sharding::PreviousShards`].
+    pub fn follower_of_several(leader_urls: Vec<String>, epoch_fence: Arc<EpochFence>) -> Self {
+        Self::new(leader_urls, Role::Follower, epoch_fence)
+    }
+
+    fn new(leader_urls: Vec<String>, role: Role, epoch_fence: Arc<EpochFence>) -> Self {
+        Replication {
+            inner: Arc::new(ReplicationInner {
+                leader_urls,
+                role: watch::channel(role).0,
+                epoch_fence,
+                progress: Mutex::default(),
+                replicating: tokio::sync::Mutex::new(()),
+            }),
+        }
+    }
+
+    pub fn role(&self) -> Role {
+        *self.inner.role.borrow()
+    }
+
+    pub fn is_leader(&self) -> bool {
+        self.role() == Role::Leader
+    }
+
+    /// The epoch that this instance was promoted in, or that its leader is in.
+    pub fn epoch(&self) -> Epoch {
+        self.inner.epoch_fence.epoch()
+    }
+
+    /// The URLs of the leaders, if this instance is a follower.
+    pub fn leader_urls(&self) -> &[String] {
+        match self.role() {
+            Role::Leader => &[],
+            Role::Follower => &self.inner.leader_urls,
+        }
+    }
+
+    pub fn status(&self) -> ReplicationStatus {
+        let progress = self.inner.progress.lock().unwrap();
+
+        ReplicationStatus {
+            role: self.role(),
+            epoch: self.epoch(),
+            leader_urls: self.leader_urls().to_vec(),
+            lag_messages: progress.lag_messages,
+            seconds_since_last_sync: progress
+                .last_sync
+                .map(|last_sync| last_sync.elapsed().as_secs_f64()),
+            last_error: progress.last_error.clone(),
+            replicated_messages: progress.replicated_messages,
+        }
+    }
+
+    /// Makes a follower the leader in the given epoch, after it has finished the replication round
+    /// in progress.
+    ///
+    /// Fails if this instance is the leader already or the epoch is not greater than the current
+    /// one, see [`EpochFence`].
+    pub async fn promote(&self, epoch: Epoch) -> Result<()> {
+        let _replicating = self.inner.replicating.lock().await;
+        if self.is_leader() {
+            bail!("this instance is the leader already");
+        }
+
+        self.inner.epoch_fence.start(epoch).await?;
+        self.inner.role.send_replace(Role::Leader);
+
+        Ok(())
+    }
+
+    /// Waits until this instance is the leader.
+    pub async fn wait_for_leadership(&self) {
+        let mut role = self.inner.role.subscribe();
+        while *role.borrow_and_update() != Role::Leader {
+            // The sender lives as long as `self`, so this never fails.
+            let _ = role.changed().await;
+        }
+    }
+
+    fn record_round(&self, round: Result<ReplicationRound>) {
+        let mut progress = self.inner.progress.lock().unwrap();
+        match round {
+            Ok(round) => {
+                progress.lag_messages = round.lag_messages;
+                progress.last_sync = Some(Instant::now());
+                progress.last_error = None;
+                progress.replicated_messages += round.replicated_messages;
+            }
+            Err(err) => progress.last_error = Some(format!("{err:#}")),
+        }
+    }
+}
+
+/// Retrieves the messages of every channel that the follower's log lacks from the leader.
+pub async fn replicate_once(
+    message_log: &MessageLog,
+    leader: &dyn Leader,
+    page_size: usize,
+) -> Result<ReplicationRound> {
+    let leader_positions = leader.channel_positions().await?;
+
+    replicate_up_to(message_log, leader, leader_positions, page_size).await
+}
+
+/// Retrieves the messages of every channel that the follower's log lacks from the leader, up to
+/// (excluding) the given positions of the leader's channels, even if the leader has progressed
+/// since.
+pub async fn replicate_up_to(
+    message_log: &MessageLog,
+    leader: &dyn Leader,
+    leader_positions: Vec<ChannelPosition>,
+    page_size: usize,
+) -> Result<ReplicationRound> {
+    let mut round = ReplicationRound {
+        lag_messages: 0,
+        replicated_messages: 0,
+    };
+    for leader_position in leader_positions {
+        let channel = &leader_position.channel;
+        let mut next_sequence_number = channel_next_sequence_number(message_log, channel).await?;
+        round.lag_messages += leader_position
+            .next_sequence_number
+            .saturating_sub(next_sequence_number);
+
+        while next_sequence_number < leader_position.next_sequence_number {
+            let messages = leader
+                .messages_after(channel, next_sequence_number - 1, page_size)
+                .await;
+            let mut messages = match messages {
+                Ok(messages) => messages,
+                Err(err) => match err.downcast_ref::<MessagesRemoved>() {
+                    // Skip the removed messages, recording the gap like the leader has.
+                    Some(&MessagesRemoved { truncated_before })
+                        if truncated_before > next_sequence_number =>
+                    {
+                        println!(
+                            "Skipping the messages of channel {channel:?} before sequence number \
+                             {truncated_before}, which the leader removed before they were \
+                             replicated"
+                        );
+                        let truncate_before =
+                            truncated_before.min(leader_position.next_sequence_number);
+                        let truncated_channel = channel.clone();
+                        message_log
+                            .run_blocking(move |message_log| {
+                                message_log.truncate_before(&truncated_channel, truncate_before)
+                            })
+                            .await?;
+                        next_sequence_number =
+                            channel_next_sequence_number(message_log, channel).await?;
+                        continue;
+                    }
+                    _ => return Err(err),
+                },
+            };
+            messages
+                .retain(|message| message.sequence_number < leader_position.next_sequence_number);
+            if messages.is_empty() {
+                // The leader has removed the remaining messages, e.g. by a retention policy.
+                let channel = channel.clone();
+                let truncate_before = leader_position.next_sequence_number;
+                message_log
+                    .run_blocking(move |message_log| {
+                        message_log.truncate_before(&channel, truncate_before)
+                    })
+                    .await?;
+                break;
+            }
+
+            round.replicated_messages += messages.len() as u64;
+            message_log
+                .run_blocking(move |message_log| message_log.replicate(messages))
+                .await?;
+            next_sequence_number = channel_next_sequence_number(message_log, channel).await?;
+        }
+    }
+
+    Ok(round)
+}
+
+async fn channel_next_sequence_number(
+    message_log: &MessageLog,
+    channel: &str,
+) -> Result<SequenceNumber> {
+    let channel = channel.to_string();
+
+    message_log
+        .run_blocking(move |message_log| message_log.next_sequence_number(&channel))
+        .await
+}
+
+/// Replicates the leader every [`ReplicationConfig::poll_interval`] until the follower is
+/// promoted, see [`replicate_once`].
+pub fn spawn_follower_task(
+    message_log: MessageLog,
+    replication: Replication,
+    leader: Arc<dyn Leader>,
+    replication_config: ReplicationConfig,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        loop {
+            {
+                let _replicating = replication.inner.replicating.lock().await;
+                if replication.is_leader() {
+                    return;
+                }
+
+                let round =
+                    replicate_once(&message_log, leader.as_ref(), replication_config.page_size)
+                        .await;
+                if let Err(err) = &round {
+                    println!("Replicating the leader failed: {err:#}");
+                }
+                replication.record_round(round);
+            }
+
+            tokio::select! {
+                _ = tokio::time::sleep(replication_config.poll_interval) => {}
+                _ = replication.wait_for_leadership() => {}
+            }
+        }
+    })
+}
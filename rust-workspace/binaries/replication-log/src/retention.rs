@@ -0,0 +1,288 @@
+//! Limits on which messages the replication log keeps.
+//!
+//! Every channel is subject to a [`RetentionPolicy`], which may limit the age, number and size of
+//! its messages. Policies are configured per channel name pattern, see [`RetentionConfig`]. A
+//! background task periodically removes the oldest messages of every channel that exceed its
+//! policy and then compacts the store to reclaim their space.
+//!
+//! Removed messages are gone for good; reading from before the oldest retained message is reported
+//! via [`MessageLog::truncated_before`](crate::message_log::MessageLog::truncated_before).
+//!
+//! In a Raft group, only the leader enforces the policies, appending the truncations to the Raft
+//! log, see [`spawn_raft_retention_task`].
+
+use std::{
+    collections::BTreeMap,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use tokio::task::JoinHandle;
+
+use common::SequenceNumber;
+
+use crate::{message_log::MessageLog, raft::server::RaftHandle, storage::MessageStore};
+
+/// The number of messages read at once to sum up their sizes, see
+/// [`RetentionPolicy::retention_horizon`].
+pub const RETENTION_PAGE_SIZE: usize = 1000;
+
+/// Messages are removed oldest first once any of the limits is exceeded.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RetentionPolicy {
+    /// Keep messages only for this long after they were created.
+    #[serde(default, with = "humantime_serde")]
+    pub max_age: Option<Duration>,
+    /// Keep at most this many messages.
+    pub max_messages: Option<usize>,
+    /// Keep at most this many bytes of message texts.
+    pub max_bytes: Option<usize>,
+}
+
+impl RetentionPolicy {
+    /// Keeps all messages.
+    pub fn unbounded() -> Self {
+        Self::default()
+    }
+
+    /// Returns the sequence number that the channel has to be truncated before, or `None` if all
+    /// of its messages are retained.
+    ///
+    /// Only the messages that may be retained are read: the message limit follows from the
+    /// channel's sequence numbers, the oldest message young enough is found by a binary search and
+    /// sizes are only summed up for the newest messages.
+    pub fn retention_horizon(
+        &self,
+        message_store: &dyn MessageStore,
+        channel: &str,
+        now: SystemTime,
+    ) -> Result<Option<SequenceNumber>> {
+        // The channel's messages have the sequence numbers from `first` to before `next`, without
+        // gaps.
+        let first = message_store.truncated_before(channel)?.unwrap_or(1);
+        let next = message_store.next_sequence_number(channel)?;
+        let mut horizon = first;
+
+        if let Some(max_messages) = self.max_messages {
+            horizon = horizon.max(next.saturating_sub(max_messages as SequenceNumber));
+        }
+
+        if let Some(min_creation_time) = self.max_age.and_then(|max_age| now.checked_sub(max_age)) {
+            // Message ids are ordered by their creation time, so the messages that are too old
+            // precede all others.
+            let (mut too_old, mut young_enough) = (horizon, next);
+            while too_old < young_enough {
+                let middle = too_old + (young_enough - too_old) / 2;
+                let is_too_old = message_store
+                    .messages_after(channel, middle - 1, Some(1))?
+                    .first()
+                    .is_some_and(|message| message.chat_message.id.datetime() < min_creation_time);
+                if is_too_old {
+                    too_old = middle + 1;
+                } else {
+                    young_enough = middle;
+                }
+            }
+            horizon = young_enough;
+        }
+
+        if let Some(max_bytes) = self.max_bytes {
+            // Sum up the sizes from the newest message backwards, page by page.
+            let mut retained_bytes = 0;
+            let mut page_end = next;
+            'pages: while page_end > horizon {
+                let page_start = page_end
+                    .saturating_sub(RETENTION_PAGE_SIZE as SequenceNumber)
+                    .max(horizon);
+                let page = message_store.messages_after(
+                    channel,
+                    page_start - 1,
+                    Some((page_end - page_start) as usize),
+                )?;
+                for message in page.iter().rev() {
+                    retained_bytes += message.chat_message.message_text.len();
+                    if retained_bytes > max_bytes {
+                        horizon = message.sequence_number + 1;
+                        break 'pages;
+                    }
+                }
+                page_end = page_start;
+            }
+        }
+
+        Ok((horizon > first).then_some(horizon))
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RetentionConfig {
+    /// How often retention policies are enforced.
+    #[serde(with = "humantime_serde")]
+    pub compaction_interval: Duration,
+    /// Applies to every channel that no pattern of `channels` matches.
+    pub default: RetentionPolicy,
+    /// Policies by channel name pattern, in which `*` matches any sequence of characters. A
+    /// channel is subject to the policy of the longest pattern it matches.
+    pub channels: BTreeMap<String, RetentionPolicy>,
+}
+
+impl Default for RetentionConfig {
+    fn default() -> Self {
+        RetentionConfig {
+            compaction_interval: Duration::from_secs(60),
+            default: RetentionPolicy::unbounded(),
+            channels: BTreeMap::new(),
+        }
+    }
+}
+
+impl RetentionConfig {
+    pub fn validate(&self) -> Result<()> {
+        if self.compaction_interval.is_zero() {
+            bail!("the compaction interval must be positive");
+        }
+        if self.channels.keys().any(|pattern| pattern.is_empty()) {
+            bail!("channel patterns must not be empty");
+        }
+
+        Ok(())
+    }
+
+    /// Returns the policy that the channel is subject to.
+    pub fn policy(&self, channel: &str) -> &RetentionPolicy {
+        self.channels
+            .iter()
+            .filter(|(pattern, _)| matches_pattern(pattern, channel))
+            .max_by_key(|(pattern, _)| pattern.len())
+            .map_or(&self.default, |(_, policy)| policy)
+    }
+}
+
+/// Returns every channel whose messages exceed its policy, along with the sequence number that it
+/// has to be truncated before, see [`RetentionPolicy::retention_horizon`].
+pub fn retention_horizons(
+    message_store: &dyn MessageStore,
+    retention_config: &RetentionConfig,
+    now: SystemTime,
+) -> Result<Vec<(String, SequenceNumber)>> {
+    let mut horizons = Vec::new();
+    for channel in message_store.channels()? {
+        let policy = retention_config.policy(&channel);
+        if *policy == RetentionPolicy::unbounded() {
+            continue;
+        }
+
+        if let Some(truncate_before) = policy.retention_horizon(message_store, &channel, now)? {
+            horizons.push((channel, truncate_before));
+        }
+    }
+
+    Ok(horizons)
+}
+
+/// Removes the messages that exceed the policy of their channel and compacts the store.
+pub fn enforce_retention(
+    message_store: &dyn MessageStore,
+    retention_config: &RetentionConfig,
+    now: SystemTime,
+) -> Result<()> {
+    for (channel, truncate_before) in retention_horizons(message_store, retention_config, now)? {
+        message_store.truncate_before(&channel, truncate_before)?;
+    }
+
+    message_store.compact()
+}
+
+/// Periodically enforces the retention policies on the log's messages, see [`enforce_retention`].
+pub fn spawn_retention_task(
+    message_log: MessageLog,
+    retention_config: RetentionConfig,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(retention_config.compaction_interval);
+        loop {
+            interval.tick().await;
+
+            let message_log = message_log.clone();
+            let retention_config = retention_config.clone();
+            let result = tokio::task::spawn_blocking(move || {
+                message_log.enforce_retention(&retention_config, SystemTime::now())
+            })
+            .await;
+            match result {
+                Ok(Ok(())) => {}
+                Ok(Err(err)) => println!("Enforcing retention policies failed: {err}"),
+                Err(err) => println!("Enforcing retention policies panicked: {err}"),
+            }
+        }
+    })
+}
+
+/// Like [`spawn_retention_task`], but for a member of a Raft group: while the member is the
+/// leader, it appends the truncations to the Raft log, so that every member removes the same
+/// messages. Every member compacts its own store.
+pub fn spawn_raft_retention_task(
+    message_log: MessageLog,
+    raft: RaftHandle,
+    retention_config: RetentionConfig,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(retention_config.compaction_interval);
+        loop {
+            interval.tick().await;
+
+            if raft.is_leader() {
+                let retention_config = retention_config.clone();
+                let horizons = message_log
+                    .run_blocking(move |message_log| {
+                        message_log.retention_horizons(&retention_config, SystemTime::now())
+                    })
+                    .await;
+                let truncated = match horizons {
+                    Ok(horizons) => truncate_via_raft(&raft, horizons).await,
+                    Err(err) => Err(err),
+                };
+                if let Err(err) = truncated {
+                    println!("Enforcing retention policies failed: {err:#}");
+                }
+            }
+
+            let compacted = message_log
+                .run_blocking(|message_log| message_log.compact())
+                .await;
+            if let Err(err) = compacted {
+                println!("Compacting the message store failed: {err:#}");
+            }
+        }
+    })
+}
+
+async fn truncate_via_raft(
+    raft: &RaftHandle,
+    horizons: Vec<(String, SequenceNumber)>,
+) -> Result<()> {
+    for (channel, truncate_before) in horizons {
+        raft.truncate_before(&channel, truncate_before).await?;
+    }
+
+    Ok(())
+}
+
+/// Whether the channel name matches the pattern, in which `*` matches any sequence of characters.
+fn matches_pattern(pattern: &str, channel: &str) -> bool {
+    match pattern.split_once('*') {
+        None => pattern == channel,
+        Some((prefix, rest)) => {
+            let Some(remainder) = channel.strip_prefix(prefix) else {
+                return false;
+            };
+            // Let the wildcard match as few characters as possible before trying more.
+            (0..=remainder.len())
+                .filter(|&skipped| remainder.is_char_boundary(skipped))
+                .any(|skipped| matches_pattern(rest, &remainder[skipped..]))
+        }
+    }
+}
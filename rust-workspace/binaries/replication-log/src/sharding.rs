@@ -0,0 +1,400 @@
+//! Storing only the channels of one shard, see [`common::sharding`].
+//!
+//! Every shard is a replication log instance (or a group of them, with followers or Raft) that
+//! stores and accepts appends for the channels the shard map assigns to it; the chat-server routes
+//! every channel to its shard.
+//!
+//! Adding shards moves some channels from the existing shards to the new ones. Rebalancing works
+//! like a failover from the previous shards of the moved channels to the new shards:
+//!
+//! 1. The new shards start with the new shard map and the previous one
+//!    ([`ShardingConfig::previous_shard_map`]). They follow their [`PreviousShards`], copying the
+//!    channels they take over with their sequence numbers, see [`crate::replication`]. Meanwhile,
+//!    they buffer the messages of these channels that they receive from the message broker, see
+//!    [`buffer_until_promoted`].
+//! 2. Once they have caught up, the existing shards and the chat-servers switch to the new shard
+//!    map. The existing shards stop storing the moved channels; appends to them are rejected until
+//!    the next step.
+//! 3. The new shards are promoted, which stops copying the moved channels and starts storing their
+//!    messages, beginning with the buffered ones. So the messages published to the message broker
+//!    directly between steps 2 and 3 are kept as well.
+//! 4. The moved channels are removed from the existing shards once the new shards have copied
+//!    them, see [`remove_foreign_channels`].
+
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
+
+use anyhow::{bail, Context, Result};
+use async_trait::async_trait;
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+
+use common::{
+    sharding::{HashRing, Shard, ShardMap},
+    ChatMessage, ChatMessageStream, SequenceNumber, SequencedMessage,
+};
+
+use crate::{
+    message_log::{MessageLog, DEDUPLICATION_WINDOW},
+    replication::{ChannelPosition, Leader, Replication},
+    storage::MessageStore,
+};
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ShardingConfig {
+    /// The shard of this instance; without one, the instance stores every channel.
+    pub shard_id: Option<String>,
+    pub shard_map: Option<ShardMap>,
+    /// The shard map before this shard was added; if given, the instance first follows the
+    /// previous shards of its channels, see the [module documentation](self).
+    pub previous_shard_map: Option<ShardMap>,
+}
+
+impl ShardingConfig {
+    pub fn validate(&self) -> Result<()> {
+        let Some(shard_id) = &self.shard_id else {
+            if self.shard_map.is_some() || self.previous_shard_map.is_some() {
+                bail!("a shard map needs the id of this instance's shard");
+            }
+            return Ok(());
+        };
+        let Some(shard_map) = &self.shard_map else {
+            bail!("shard {shard_id:?} needs a shard map");
+        };
+
+        shard_map.validate().context("invalid shard map")?;
+        if shard_map.shard(shard_id).is_none() {
+            bail!("the shard map must include this instance's shard {shard_id:?}");
+        }
+        if let Some(previous_shard_map) = &self.previous_shard_map {
+            previous_shard_map
+                .validate()
+                .context("invalid previous shard map")?;
+        }
+
+        Ok(())
+    }
+
+    /// The channels this instance stores, if it is a shard.
+    pub fn assignment(&self) -> Option<ShardAssignment> {
+        Some(ShardAssignment::new(
+            self.shard_id.clone()?,
+            self.shard_map.clone()?,
+        ))
+    }
+}
+
+/// Decides which channels a shard stores.
+#[derive(Clone)]
+pub struct ShardAssignment {
+    shard_id: String,
+    hash_ring: Arc<HashRing>,
+}
+
+impl ShardAssignment {
+    /// # Panics
+    ///
+    /// If the map contains no shards, see [`ShardMap::validate`].
+    pub fn new(shard_id: String, shard_map: ShardMap) -> Self {
+        ShardAssignment {
+            shard_id,
+            hash_ring: Arc::new(HashRing::new(shard_map)),
+        }
+    }
+
+    pub fn shard_id(&self) -> &str {
+        &self.shard_id
+    }
+
+    pub fn shard_map(&self) -> &ShardMap {
+        self.hash_ring.shard_map()
+    }
+
+    /// The shard that stores the channel.
+    pub fn owner(&self, channel: &str) -> &Shard {
+        self.hash_ring.shard_for(channel)
+    }
+
+    /// Whether this shard stores the channel.
+    pub fn owns(&self, channel: &str) -> bool {
+        self.owner(channel).id == self.shard_id
+    }
+}
+
+/// The shards that stored a new shard's channels before it was added, replicated like a single
+/// leader.
+pub struct PreviousShards {
+    assignment: ShardAssignment,
+    previous_hash_ring: HashRing,
+    /// The previous shards by their id.
+    leaders: HashMap<String, Arc<dyn Leader>>,
+}
+
+impl PreviousShards {
+    /// Replicates the channels that `assignment` assigns to its shard from their shard in
+    /// `previous_shard_map`, which is accessed via `leader`.
+    ///
+    /// # Panics
+    ///
+    /// If the previous map contains no shards, see [`ShardMap::validate`].
+    pub fn new<F>(assignment: ShardAssignment, previous_shard_map: ShardMap, mut leader: F) -> Self
+    where
+        F: FnMut(&Shard) -> Arc<dyn Leader>,
+    {
+        let leaders = previous_shard_map
+            .shards
+            .iter()
+            .map(|shard| (shard.id.clone(), leader(shard)))
+            .collect();
+
+        PreviousShards {
+            assignment,
+            previous_hash_ring: HashRing::new(previous_shard_map),
+            leaders,
+        }
+    }
+
+    /// The URLs of the previous shards, to be reported as the leaders, see
+    /// [`crate::replication::Replication::follower_of_several`].
+    pub fn urls(&self) -> Vec<String> {
+        self.previous_hash_ring
+            .shard_map()
+            .shards
+            .iter()
+            .map(|shard| shard.url.clone())
+            .collect()
+    }
+
+    fn leader(&self, channel: &str) -> &dyn Leader {
+        let previous_shard = self.previous_hash_ring.shard_for(channel);
+
+        self.leaders[&previous_shard.id].as_ref()
+    }
+}
+
+#[async_trait]
+impl Leader for PreviousShards {
+    /// The positions of the channels that move to this shard, each from its previous shard.
+    async fn channel_positions(&self) -> Result<Vec<ChannelPosition>> {
+        let mut channel_positions = Vec::new();
+        for (shard_id, leader) in &self.leaders {
+            let positions = leader
+                .channel_positions()
+                .await
+                .with_context(|| format!("could not reach previous shard {shard_id:?}"))?;
+
+            channel_positions.extend(positions.into_iter().filter(|position| {
+                self.assignment.owns(&position.channel)
+                    && self.previous_hash_ring.shard_for(&position.channel).id == *shard_id
+            }));
+        }
+
+        Ok(channel_positions)
+    }
+
+    async fn messages_after(
+        &self,
+        channel: &str,
+        after: SequenceNumber,
+        limit: usize,
+    ) -> Result<Vec<SequencedMessage>> {
+        self.leader(channel)
+            .messages_after(channel, after, limit)
+            .await
+    }
+}
+
+/// Yields the messages of `stream` once `replication` has been promoted, starting with the ones
+/// received before, so that a new shard keeps the messages published to the message broker
+/// directly while rebalancing, see the [module documentation](self).
+///
+/// Every message is buffered until then. The buffered messages that have been copied from the
+/// previous shards into `message_store` meanwhile are dropped on promotion, so that they are not
+/// stored twice, however many there are.
+pub fn buffer_until_promoted(
+    mut stream: ChatMessageStream,
+    replication: Replication,
+    message_store: Arc<dyn MessageStore>,
+) -> ChatMessageStream {
+    let buffering = tokio::spawn(async move {
+        let mut buffer = MessageBuffer::default();
+        let promoted = replication.wait_for_leadership();
+        tokio::pin!(promoted);
+        loop {
+            tokio::select! {
+                biased;
+                () = &mut promoted => break,
+                chat_message = stream.next() => match chat_message {
+                    Some(Ok(chat_message)) => buffer.push(chat_message),
+                    Some(Err(err)) => println!("Skipping a malformed message: {err:#}"),
+                    None => {
+                        promoted.await;
+                        break;
+                    }
+                },
+            }
+        }
+
+        let buffered_messages = tokio::task::spawn_blocking(move || {
+            match buffer.uncopied_messages(message_store.as_ref()) {
+                Ok(uncopied_messages) => uncopied_messages,
+                Err(err) => {
+                    // Better stored twice than not at all.
+                    println!(
+                        "Storing every buffered message, the copied ones are unknown: {err:#}"
+                    );
+                    buffer.messages
+                }
+            }
+        })
+        .await?;
+
+        Ok::<_, anyhow::Error>((buffered_messages, stream))
+    });
+
+    let promoted = async move {
+        let err = match buffering.await {
+            Ok(Ok((buffered_messages, stream))) => {
+                return Box::pin(
+                    futures::stream::iter(buffered_messages.into_iter().map(Ok)).chain(stream),
+                ) as ChatMessageStream;
+            }
+            Ok(Err(err)) => err,
+            Err(err) => err.into(),
+        };
+
+        Box::pin(futures::stream::iter([Err(err)])) as ChatMessageStream
+    };
+
+    Box::pin(futures::stream::once(promoted).flatten())
+}
+
+/// The messages buffered by [`buffer_until_promoted`], in the order they were received.
+#[derive(Default)]
+struct MessageBuffer {
+    messages: Vec<ChatMessage>,
+    /// How many messages of every channel are buffered.
+    channel_counts: HashMap<String, usize>,
+}
+
+impl MessageBuffer {
+    fn push(&mut self, chat_message: ChatMessage) {
+        *self
+            .channel_counts
+            .entry(chat_message.channel.clone())
+            .or_default() += 1;
+        self.messages.push(chat_message);
+    }
+
+    /// Returns the messages that the store lacks, in the order they were received.
+    ///
+    /// The previous shard stored the copied messages in the order they were published, like they
+    /// were received here, so the copied ones are among the channel's last messages: at most one
+    /// per buffered message, plus the ones published while the subscriptions were set up.
+    fn uncopied_messages(&self, message_store: &dyn MessageStore) -> Result<Vec<ChatMessage>> {
+        let mut copied_ids = HashSet::new();
+        for (channel, count) in &self.channel_counts {
+            copied_ids
+                .extend(message_store.recent_message_ids(channel, count + DEDUPLICATION_WINDOW)?);
+        }
+
+        Ok(self
+            .messages
+            .iter()
+            .filter(|chat_message| !copied_ids.contains(&chat_message.id))
+            .cloned()
+            .collect())
+    }
+}
+
+/// The outcome of [`remove_foreign_channels`].
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RemovedChannels {
+    /// The channels whose messages have been removed.
+    pub removed: Vec<String>,
+    /// The channels whose messages have been kept, since the shard that stores them now has not
+    /// copied all of them yet.
+    pub not_copied: Vec<String>,
+}
+
+/// Removes the messages of every channel that the shard no longer stores, e.g. after rebalancing,
+/// once the shard that stores the channel now has copied them. `shards` are the other shards of
+/// the map by their id, whose channel positions tell how far they have copied.
+///
+/// Their space is reclaimed by the next compaction, see [`crate::retention`].
+pub async fn remove_foreign_channels(
+    message_log: &MessageLog,
+    assignment: &ShardAssignment,
+    shards: &HashMap<String, Arc<dyn Leader>>,
+) -> Result<RemovedChannels> {
+    let foreign_positions = {
+        let assignment = assignment.clone();
+        message_log
+            .run_blocking(move |message_log| {
+                let mut foreign_positions = Vec::new();
+                for position in message_log.channel_positions()? {
+                    if !assignment.owns(&position.channel)
+                        && !message_log
+                            .messages_after(&position.channel, 0, Some(1))?
+                            .is_empty()
+                    {
+                        foreign_positions.push(position);
+                    }
+                }
+
+                Ok(foreign_positions)
+            })
+            .await?
+    };
+
+    // The positions of every shard's channels, only retrieved from the shards that are needed.
+    let mut owner_positions: HashMap<String, HashMap<String, SequenceNumber>> = HashMap::new();
+    let mut removed_channels = RemovedChannels::default();
+    let mut copied_positions = Vec::new();
+    for position in foreign_positions {
+        let owner_id = &assignment.owner(&position.channel).id;
+        if !owner_positions.contains_key(owner_id) {
+            let Some(owner) = shards.get(owner_id) else {
+                bail!("shard {owner_id:?} is unknown");
+            };
+            let positions = owner
+                .channel_positions()
+                .await
+                .with_context(|| format!("could not reach shard {owner_id:?}"))?;
+            owner_positions.insert(
+                owner_id.clone(),
+                positions
+                    .into_iter()
+                    .map(|position| (position.channel, position.next_sequence_number))
+                    .collect(),
+            );
+        }
+
+        let owner_next_sequence_number = owner_positions[owner_id]
+            .get(&position.channel)
+            .copied()
+            .unwrap_or(0);
+        if owner_next_sequence_number >= position.next_sequence_number {
+            copied_positions.push(position);
+        } else {
+            removed_channels.not_copied.push(position.channel);
+        }
+    }
+
+    removed_channels.removed = message_log
+        .run_blocking(move |message_log| {
+            let mut removed = Vec::new();
+            for position in copied_positions {
+                message_log.truncate_before(&position.channel, position.next_sequence_number)?;
+                removed.push(position.channel);
+            }
+
+            Ok(removed)
+        })
+        .await?;
+
+    Ok(removed_channels)
+}
@@ -0,0 +1,139 @@
+//! Periodic snapshots of the recent history of every channel.
+//!
+//! A new chat-server instance would otherwise have to replay the full history of every channel it
+//! joins. Instead, it downloads the channel's latest [`ChannelSnapshot`] (the most recent messages
+//! up to a watermark, compressed) and then only retrieves the messages after the watermark.
+//!
+//! Snapshots are derived from the stored messages, so they are only kept in memory and rebuilt
+//! after a restart.
+
+use std::{sync::Arc, time::Duration};
+
+use anyhow::{bail, Result};
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use tokio::task::JoinHandle;
+
+use common::{snapshot::ChannelSnapshot, SequencedMessage};
+
+use crate::message_log::MessageLog;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SnapshotConfig {
+    /// How often the snapshots of channels with new messages are updated.
+    #[serde(with = "humantime_serde")]
+    pub interval: Duration,
+    /// How many of the most recent messages a snapshot contains.
+    pub max_messages: usize,
+}
+
+impl Default for SnapshotConfig {
+    fn default() -> Self {
+        SnapshotConfig {
+            interval: Duration::from_secs(30),
+            max_messages: 1000,
+        }
+    }
+}
+
+impl SnapshotConfig {
+    pub fn validate(&self) -> Result<()> {
+        if self.interval.is_zero() || self.max_messages == 0 {
+            bail!("the snapshot interval and the number of messages per snapshot must be positive");
+        }
+
+        Ok(())
+    }
+}
+
+/// A snapshot along with its encoded form, which is handed out as is.
+pub struct StoredSnapshot {
+    pub snapshot: ChannelSnapshot,
+    pub encoded_snapshot: Vec<u8>,
+}
+
+/// The latest snapshot of every channel.
+#[derive(Clone, Default)]
+pub struct Snapshots {
+    snapshots: Arc<DashMap<String, Arc<StoredSnapshot>>>,
+}
+
+impl Snapshots {
+    /// Returns the latest snapshot of the channel, if one has been taken yet.
+    pub fn get(&self, channel: &str) -> Option<Arc<StoredSnapshot>> {
+        self.snapshots.get(channel).map(|entry| Arc::clone(&entry))
+    }
+
+    /// Updates the snapshot of every channel that received messages since its last snapshot.
+    ///
+    /// Only the messages after the previous snapshot's watermark are read from the log. Messages
+    /// that have been removed by a retention policy in the meantime are dropped from the snapshot.
+    pub fn take_snapshots(&self, message_log: &MessageLog, max_messages: usize) -> Result<()> {
+        for channel in message_log.channels()? {
+            let previous_snapshot = self.get(&channel);
+            let watermark = previous_snapshot
+                .as_ref()
+                .map_or(0, |stored_snapshot| stored_snapshot.snapshot.watermark);
+
+            let new_messages = message_log.messages_after(&channel, watermark, None)?;
+            let Some(last_message) = new_messages.last() else {
+                continue;
+            };
+            let watermark = last_message.sequence_number;
+
+            let truncated_before = message_log.truncated_before(&channel)?.unwrap_or(0);
+            let previous_messages = previous_snapshot
+                .iter()
+                .flat_map(|stored_snapshot| stored_snapshot.snapshot.messages.iter().cloned());
+            let mut messages: Vec<SequencedMessage> = previous_messages
+                .chain(new_messages)
+                .filter(|message| message.sequence_number >= truncated_before)
+                .collect();
+            messages.drain(..messages.len().saturating_sub(max_messages));
+
+            let snapshot = ChannelSnapshot {
+                channel: channel.clone(),
+                watermark,
+                messages,
+            };
+            let encoded_snapshot = snapshot.encode()?;
+            self.snapshots.insert(
+                channel,
+                Arc::new(StoredSnapshot {
+                    snapshot,
+                    encoded_snapshot,
+                }),
+            );
+        }
+
+        Ok(())
+    }
+}
+
+/// Periodically updates the snapshots, see [`Snapshots::take_snapshots`].
+pub fn spawn_snapshot_task(
+    message_log: MessageLog,
+    snapshots: Snapshots,
+    snapshot_config: SnapshotConfig,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(snapshot_config.interval);
+        loop {
+            interval.tick().await;
+
+            let message_log = message_log.clone();
+            let snapshots = snapshots.clone();
+            let max_messages = snapshot_config.max_messages;
+            let result = tokio::task::spawn_blocking(move || {
+                snapshots.take_snapshots(&message_log, max_messages)
+            })
+            .await;
+            match result {
+                Ok(Ok(())) => {}
+                Ok(Err(err)) => println!("Taking snapshots failed: {err}"),
+                Err(err) => println!("Taking snapshots panicked: {err}"),
+            }
+        }
+    })
+}
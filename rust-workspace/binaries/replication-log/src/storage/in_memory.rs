@@ -0,0 +1,126 @@
+use std::sync::{Arc, RwLock};
+
+use anyhow::Result;
+use dashmap::DashMap;
+
+use common::{ChatMessage, SequenceNumber, SequencedMessage};
+
+use super::{next_sequence_number, MessageStore};
+
+/// Keeps all messages in memory; they are lost when the process exits.
+///
+/// Every channel has its own append-only list of messages, so reading a channel neither has to
+/// look at other channels' messages nor blocks appending to them.
+#[derive(Default)]
+pub struct InMemoryMessageStore {
+    channels: DashMap<String, Arc<RwLock<ChannelMessages>>>,
+}
+
+#[derive(Default)]
+struct ChannelMessages {
+    messages: Vec<SequencedMessage>,
+    truncated_before: Option<SequenceNumber>,
+}
+
+impl ChannelMessages {
+    fn next_sequence_number(&self) -> SequenceNumber {
+        next_sequence_number(
+            self.messages.last().map(|message| message.sequence_number),
+            self.truncated_before,
+        )
+    }
+}
+
+impl InMemoryMessageStore {
+    fn channel_messages(&self, channel: &str) -> Option<Arc<RwLock<ChannelMessages>>> {
+        // Clone the list, so that we do not hold a lock on the map while accessing it.
+        self.channels.get(channel).map(|entry| Arc::clone(&entry))
+    }
+
+    fn channel_messages_or_default(&self, channel: &str) -> Arc<RwLock<ChannelMessages>> {
+        match self.channel_messages(channel) {
+            Some(channel_messages) => channel_messages,
+            None => Arc::clone(&self.channels.entry(channel.to_string()).or_default()),
+        }
+    }
+}
+
+impl MessageStore for InMemoryMessageStore {
+    fn append(&self, chat_message: ChatMessage) -> Result<SequencedMessage> {
+        let channel_messages = self.channel_messages_or_default(&chat_message.channel);
+        let mut channel_messages = channel_messages.write().unwrap();
+
+        let sequence_number = channel_messages.next_sequence_number();
+        let sequenced_message = SequencedMessage {
+            sequence_number,
+            chat_message,
+        };
+        channel_messages.messages.push(sequenced_message.clone());
+
+        Ok(sequenced_message)
+    }
+
+    fn messages_after(
+        &self,
+        channel: &str,
+        after: SequenceNumber,
+        limit: Option<usize>,
+    ) -> Result<Vec<SequencedMessage>> {
+        let channel_messages = match self.channel_messages(channel) {
+            Some(channel_messages) => channel_messages,
+            None => return Ok(vec![]),
+        };
+        let channel_messages = channel_messages.read().unwrap();
+
+        let start = channel_messages
+            .messages
+            .partition_point(|message| message.sequence_number <= after);
+        let messages = channel_messages.messages[start..]
+            .iter()
+            .take(limit.unwrap_or(usize::MAX))
+            .cloned()
+            .collect();
+
+        Ok(messages)
+    }
+
+    fn next_sequence_number(&self, channel: &str) -> Result<SequenceNumber> {
+        Ok(match self.channel_messages(channel) {
+            Some(channel_messages) => channel_messages.read().unwrap().next_sequence_number(),
+            None => next_sequence_number(None, None),
+        })
+    }
+
+    fn channels(&self) -> Result<Vec<String>> {
+        Ok(self
+            .channels
+            .iter()
+            .map(|entry| entry.key().clone())
+            .collect())
+    }
+
+    fn truncate_before(&self, channel: &str, sequence_number: SequenceNumber) -> Result<()> {
+        let channel_messages = self.channel_messages_or_default(channel);
+        let mut channel_messages = channel_messages.write().unwrap();
+        if channel_messages
+            .truncated_before
+            .is_some_and(|before| before >= sequence_number)
+        {
+            return Ok(());
+        }
+
+        let end = channel_messages
+            .messages
+            .partition_point(|message| message.sequence_number < sequence_number);
+        channel_messages.messages.drain(..end);
+        channel_messages.truncated_before = Some(sequence_number);
+
+        Ok(())
+    }
+
+    fn truncated_before(&self, channel: &str) -> Result<Option<SequenceNumber>> {
+        Ok(self
+            .channel_messages(channel)
+            .and_then(|channel_messages| channel_messages.read().unwrap().truncated_before))
+    }
+}
@@ -0,0 +1,135 @@
+//! Storage backends for the [`MessageLog`](crate::message_log::MessageLog).
+
+use std::{path::Path, str::FromStr, sync::Arc};
+
+use anyhow::{anyhow, bail, Result};
+use serde::{Deserialize, Serialize};
+
+use common::{ChatMessage, MessageId, SequenceNumber, SequencedMessage};
+
+pub mod in_memory;
+pub mod redb_store;
+pub mod segment_file;
+
+pub use in_memory::InMemoryMessageStore;
+pub use redb_store::RedbMessageStore;
+pub use segment_file::{FsyncPolicy, SegmentFileMessageStore, SegmentFileOptions};
+
+/// Persists messages and assigns them their sequence numbers.
+pub trait MessageStore: Send + Sync {
+    /// Appends the message to its channel, assigning it the next sequence number of the channel.
+    fn append(&self, chat_message: ChatMessage) -> Result<SequencedMessage>;
+
+    /// Returns the messages of the channel with a sequence number greater than `after`, ordered by
+    /// their sequence number.
+    ///
+    /// At most `limit` messages are returned, if given.
+    fn messages_after(
+        &self,
+        channel: &str,
+        after: SequenceNumber,
+        limit: Option<usize>,
+    ) -> Result<Vec<SequencedMessage>>;
+
+    /// Returns the sequence number that the next message appended to the channel is assigned.
+    fn next_sequence_number(&self, channel: &str) -> Result<SequenceNumber>;
+
+    /// Returns the ids of the channel's last `count` messages, oldest first, e.g. to recognize
+    /// duplicates after a restart. Only these messages are read.
+    fn recent_message_ids(&self, channel: &str, count: usize) -> Result<Vec<MessageId>> {
+        // Sequence numbers have no gaps, apart from the messages removed before the oldest one.
+        let after = self
+            .next_sequence_number(channel)?
+            .saturating_sub(count as SequenceNumber + 1);
+
+        Ok(self
+            .messages_after(channel, after, None)?
+            .into_iter()
+            .map(|message| message.chat_message.id)
+            .collect())
+    }
+
+    /// Returns the names of all channels that messages have been appended to.
+    fn channels(&self) -> Result<Vec<String>>;
+
+    /// Removes the messages of the channel with a sequence number less than `sequence_number`,
+    /// e.g. to enforce a [`RetentionPolicy`](crate::retention::RetentionPolicy).
+    ///
+    /// The removed messages are no longer returned, but may only be physically removed by
+    /// [`MessageStore::compact`]. Sequence numbers are never reused: messages appended afterwards
+    /// are numbered as if no message had been removed. Truncating before an earlier sequence number
+    /// than before has no effect.
+    fn truncate_before(&self, channel: &str, sequence_number: SequenceNumber) -> Result<()>;
+
+    /// Returns the sequence number that the channel has been truncated before, i.e. its messages
+    /// with a smaller sequence number have been removed, or `None` if it has not been truncated.
+    fn truncated_before(&self, channel: &str) -> Result<Option<SequenceNumber>>;
+
+    /// Reclaims the space of removed messages.
+    fn compact(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Returns the sequence number of the next message appended to a channel, given the sequence number
+/// of its last stored message and the sequence number it has been truncated before, if any.
+fn next_sequence_number(
+    last_sequence_number: Option<SequenceNumber>,
+    truncated_before: Option<SequenceNumber>,
+) -> SequenceNumber {
+    let after_last_message = last_sequence_number.map_or(1, |last| last + 1);
+
+    after_last_message.max(truncated_before.unwrap_or(1))
+}
+
+/// The available [`MessageStore`] implementations.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum StorageBackend {
+    #[serde(rename = "memory")]
+    InMemory,
+    SegmentFiles,
+    Redb,
+}
+
+impl FromStr for StorageBackend {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "memory" => Ok(StorageBackend::InMemory),
+            "segment-files" => Ok(StorageBackend::SegmentFiles),
+            "redb" => Ok(StorageBackend::Redb),
+            _ => {
+                bail!("unknown storage backend {s:?}, expected one of: memory, segment-files, redb")
+            }
+        }
+    }
+}
+
+/// Opens a store of the given backend; all backends but [`StorageBackend::InMemory`] keep their
+/// data in `data_dir`. The `segment_file_options` only apply to [`StorageBackend::SegmentFiles`].
+pub fn open_message_store(
+    backend: StorageBackend,
+    data_dir: Option<&Path>,
+    segment_file_options: SegmentFileOptions,
+) -> Result<Arc<dyn MessageStore>> {
+    let data_dir =
+        || data_dir.ok_or_else(|| anyhow!("storage backend {backend:?} needs a data directory"));
+
+    match backend {
+        StorageBackend::InMemory => Ok(Arc::new(InMemoryMessageStore::default())),
+        StorageBackend::SegmentFiles => Ok(Arc::new(SegmentFileMessageStore::open(
+            data_dir()?,
+            segment_file_options,
+        )?)),
+        StorageBackend::Redb => {
+            let data_dir = data_dir()?;
+            std::fs::create_dir_all(data_dir)?;
+
+            Ok(Arc::new(RedbMessageStore::open(
+                data_dir.join("messages.redb"),
+            )?))
+        }
+    }
+}
@@ -0,0 +1,157 @@
+//! A store backed by the embedded key-value store [redb](https://www.redb.org/).
+//!
+//! Messages are keyed by `(channel, sequence number)`, so reading a range of a channel's messages
+//! only touches that channel's entries. Removed messages are deleted right away; redb reuses the
+//! space they took up.
+
+use std::{collections::BTreeSet, path::Path};
+
+use anyhow::Result;
+use redb::{Database, ReadableTable, TableDefinition};
+
+use common::{ChatMessage, SequenceNumber, SequencedMessage};
+
+use super::{next_sequence_number, MessageStore};
+
+/// Maps `(channel, sequence number)` to the JSON-serialized [`ChatMessage`].
+const MESSAGES_TABLE: TableDefinition<(&str, SequenceNumber), &[u8]> =
+    TableDefinition::new("messages");
+/// Maps a channel to the sequence number it has been truncated before.
+const TRUNCATIONS_TABLE: TableDefinition<&str, SequenceNumber> =
+    TableDefinition::new("truncations");
+
+pub struct RedbMessageStore {
+    database: Database,
+}
+
+impl RedbMessageStore {
+    /// Opens the database file at the given path, creating it if necessary.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let database = Database::create(path)?;
+
+        // Make sure the tables exist, so that read transactions can open them.
+        let write_transaction = database.begin_write()?;
+        write_transaction.open_table(MESSAGES_TABLE)?;
+        write_transaction.open_table(TRUNCATIONS_TABLE)?;
+        write_transaction.commit()?;
+
+        Ok(RedbMessageStore { database })
+    }
+}
+
+impl MessageStore for RedbMessageStore {
+    fn append(&self, chat_message: ChatMessage) -> Result<SequencedMessage> {
+        let channel = chat_message.channel.as_str();
+        let serialized_message = serde_json::to_vec(&chat_message)?;
+
+        // Write transactions are serialized by redb, so there is no race in determining the next
+        // sequence number.
+        let write_transaction = self.database.begin_write()?;
+        let sequence_number = {
+            let mut table = write_transaction.open_table(MESSAGES_TABLE)?;
+            let truncations = write_transaction.open_table(TRUNCATIONS_TABLE)?;
+            let sequence_number = channel_next_sequence_number(&table, &truncations, channel)?;
+
+            table.insert((channel, sequence_number), serialized_message.as_slice())?;
+            sequence_number
+        };
+        write_transaction.commit()?;
+
+        Ok(SequencedMessage {
+            sequence_number,
+            chat_message,
+        })
+    }
+
+    fn messages_after(
+        &self,
+        channel: &str,
+        after: SequenceNumber,
+        limit: Option<usize>,
+    ) -> Result<Vec<SequencedMessage>> {
+        if after == SequenceNumber::MAX {
+            return Ok(vec![]);
+        }
+
+        let read_transaction = self.database.begin_read()?;
+        let table = read_transaction.open_table(MESSAGES_TABLE)?;
+
+        table
+            .range((channel, after + 1)..=(channel, SequenceNumber::MAX))?
+            .take(limit.unwrap_or(usize::MAX))
+            .map(|entry| {
+                let (key, value) = entry?;
+
+                Ok(SequencedMessage {
+                    sequence_number: key.value().1,
+                    chat_message: serde_json::from_slice(value.value())?,
+                })
+            })
+            .collect()
+    }
+
+    fn next_sequence_number(&self, channel: &str) -> Result<SequenceNumber> {
+        let read_transaction = self.database.begin_read()?;
+        let table = read_transaction.open_table(MESSAGES_TABLE)?;
+        let truncations = read_transaction.open_table(TRUNCATIONS_TABLE)?;
+
+        channel_next_sequence_number(&table, &truncations, channel)
+    }
+
+    fn channels(&self) -> Result<Vec<String>> {
+        let read_transaction = self.database.begin_read()?;
+        let table = read_transaction.open_table(MESSAGES_TABLE)?;
+        let truncations = read_transaction.open_table(TRUNCATIONS_TABLE)?;
+
+        let mut channels = BTreeSet::new();
+        for entry in table.iter()? {
+            channels.insert(entry?.0.value().0.to_string());
+        }
+        // Channels whose messages have all been removed.
+        for entry in truncations.iter()? {
+            channels.insert(entry?.0.value().to_string());
+        }
+
+        Ok(channels.into_iter().collect())
+    }
+
+    fn truncate_before(&self, channel: &str, sequence_number: SequenceNumber) -> Result<()> {
+        let write_transaction = self.database.begin_write()?;
+        {
+            let mut truncations = write_transaction.open_table(TRUNCATIONS_TABLE)?;
+            let truncated_before = truncations.get(channel)?.map(|value| value.value());
+            if truncated_before.is_some_and(|before| before >= sequence_number) {
+                return Ok(());
+            }
+            truncations.insert(channel, sequence_number)?;
+
+            let mut table = write_transaction.open_table(MESSAGES_TABLE)?;
+            table.retain_in((channel, 0)..(channel, sequence_number), |_, _| false)?;
+        }
+        write_transaction.commit()?;
+
+        Ok(())
+    }
+
+    fn truncated_before(&self, channel: &str) -> Result<Option<SequenceNumber>> {
+        let read_transaction = self.database.begin_read()?;
+        let truncations = read_transaction.open_table(TRUNCATIONS_TABLE)?;
+
+        Ok(truncations.get(channel)?.map(|value| value.value()))
+    }
+}
+
+fn channel_next_sequence_number(
+    table: &impl ReadableTable<(&'static str, SequenceNumber), &'static [u8]>,
+    truncations: &impl ReadableTable<&'static str, SequenceNumber>,
+    channel: &str,
+) -> Result<SequenceNumber> {
+    let last_sequence_number = table
+        .range((channel, 0)..=(channel, SequenceNumber::MAX))?
+        .next_back()
+        .transpose()?
+        .map(|(key, _)| key.value().1);
+    let truncated_before = truncations.get(channel)?.map(|value| value.value());
+
+    Ok(next_sequence_number(last_sequence_number, truncated_before))
+}
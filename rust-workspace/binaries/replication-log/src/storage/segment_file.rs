@@ -0,0 +1,560 @@
+//! An append-only log made of segment files.
+//!
+//! Messages are appended to the active segment file as records of the form
+//!
+//! ```text
+//! | payload length (u32, LE) | CRC32 of payload (u32, LE) | payload (JSON) |
+//! ```
+//!
+//! Once the active segment exceeds the configured maximum size, a new segment is started. Segment
+//! files are named after their (zero-padded) segment number, so they can be read in order.
+//!
+//! A crash may leave a partially written record at the end of the last segment. Such torn writes
+//! are detected via the length and checksum of the record and truncated when opening the store.
+//! The position of every record is kept in an in-memory index per channel, so reads only touch the
+//! records they return.
+//!
+//! Removed messages are only dropped from the index right away; the sequence numbers that channels
+//! have been truncated before are persisted in a separate file, so removed messages are skipped
+//! when opening the store. Compaction rewrites every segment but the active one without its
+//! removed messages, deleting segments that have no messages left.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{self, File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{Mutex, RwLock},
+};
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use common::{ChatMessage, SequenceNumber, SequencedMessage};
+
+use super::{next_sequence_number, MessageStore};
+
+const SEGMENT_FILE_EXTENSION: &str = "segment";
+/// Segments are rewritten to a file with this extension before replacing the original segment.
+const COMPACTED_SEGMENT_FILE_EXTENSION: &str = "compacted";
+const TRUNCATIONS_FILE_NAME: &str = "truncations.json";
+const RECORD_HEADER_SIZE: u64 = 8;
+
+/// When to flush appended messages to disk.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FsyncPolicy {
+    /// Sync after every appended message; no appended message is lost when the machine crashes.
+    Always,
+    /// Sync after every n appended messages.
+    EveryNMessages(usize),
+    /// Leave flushing to the operating system.
+    Never,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SegmentFileOptions {
+    /// Start a new segment once the active one exceeds this size in bytes.
+    pub max_segment_size: u64,
+    pub fsync_policy: FsyncPolicy,
+}
+
+impl Default for SegmentFileOptions {
+    fn default() -> Self {
+        SegmentFileOptions {
+            max_segment_size: 64 * 1024 * 1024,
+            fsync_policy: FsyncPolicy::Always,
+        }
+    }
+}
+
+pub struct SegmentFileMessageStore {
+    directory: PathBuf,
+    options: SegmentFileOptions,
+    state: Mutex<SegmentFileState>,
+    /// Held while reading records, so that compaction does not replace a segment in the meantime.
+    /// Acquired before `state` if both are needed.
+    segment_files: RwLock<()>,
+    /// Ensures that only one compaction runs at a time.
+    compaction: Mutex<()>,
+}
+
+struct SegmentFileState {
+    active_segment: File,
+    active_segment_number: u64,
+    active_segment_size: u64,
+    /// Number of messages appended since the last sync.
+    unsynced_messages: usize,
+    /// Record locations per channel, ordered by sequence number.
+    index: HashMap<String, Vec<RecordLocation>>,
+    /// The sequence number every truncated channel has been truncated before.
+    truncations: HashMap<String, SequenceNumber>,
+    /// Set once a failed append could not be rolled back, since the end of the active segment is
+    /// unknown from then on. Appending fails until the store is reopened.
+    failure: Option<String>,
+}
+
+impl SegmentFileState {
+    fn next_sequence_number(&self, channel: &str) -> SequenceNumber {
+        next_sequence_number(
+            self.index
+                .get(channel)
+                .and_then(|locations| locations.last())
+                .map(|location| location.sequence_number),
+            self.truncations.get(channel).copied(),
+        )
+    }
+}
+
+#[derive(Clone, Copy)]
+struct RecordLocation {
+    sequence_number: SequenceNumber,
+    segment_number: u64,
+    /// Offset of the record's payload within the segment.
+    offset: u64,
+    length: u32,
+}
+
+impl SegmentFileMessageStore {
+    /// Opens the store in the given directory, creating it if necessary.
+    ///
+    /// A torn write at the end of the last segment is truncated.
+    pub fn open<P: AsRef<Path>>(directory: P, options: SegmentFileOptions) -> Result<Self> {
+        let directory = directory.as_ref().to_path_buf();
+        fs::create_dir_all(&directory)
+            .with_context(|| format!("could not create directory {}", directory.display()))?;
+
+        let truncations = read_truncations(&directory)?;
+        let mut segment_numbers = list_segment_numbers(&directory)?;
+        if segment_numbers.is_empty() {
+            segment_numbers.push(0);
+        }
+        let last_segment_number = *segment_numbers.last().unwrap();
+
+        let mut index: HashMap<String, Vec<RecordLocation>> = HashMap::new();
+        let mut active_segment_size = 0;
+        for &segment_number in &segment_numbers {
+            let is_last_segment = segment_number == last_segment_number;
+            let segment_path = segment_path(&directory, segment_number);
+
+            let (valid_size, total_size) =
+                read_segment(&segment_path, segment_number, |message, location| {
+                    let channel = message.chat_message.channel;
+                    if !is_truncated(&truncations, &channel, message.sequence_number) {
+                        index.entry(channel).or_default().push(location);
+                    }
+                })?;
+
+            if valid_size < total_size {
+                if !is_last_segment {
+                    bail!(
+                        "segment {} is corrupted at offset {valid_size}",
+                        segment_path.display()
+                    );
+                }
+
+                // A record that was only partially written when the process crashed.
+                let segment = OpenOptions::new().write(true).open(&segment_path)?;
+                segment.set_len(valid_size)?;
+                segment.sync_all()?;
+            }
+
+            if is_last_segment {
+                active_segment_size = valid_size;
+            }
+        }
+
+        let active_segment = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(segment_path(&directory, last_segment_number))?;
+
+        Ok(SegmentFileMessageStore {
+            directory,
+            options,
+            state: Mutex::new(SegmentFileState {
+                active_segment,
+                active_segment_number: last_segment_number,
+                active_segment_size,
+                unsynced_messages: 0,
+                index,
+                truncations,
+                failure: None,
+            }),
+            segment_files: RwLock::new(()),
+            compaction: Mutex::new(()),
+        })
+    }
+
+    fn read_message(&self, location: &RecordLocation) -> Result<SequencedMessage> {
+        let mut segment = File::open(segment_path(&self.directory, location.segment_number))?;
+        segment.seek(SeekFrom::Start(location.offset))?;
+
+        let mut payload = vec![0; location.length as usize];
+        segment.read_exact(&mut payload)?;
+
+        Ok(serde_json::from_slice(&payload)?)
+    }
+}
+
+impl MessageStore for SegmentFileMessageStore {
+    fn append(&self, chat_message: ChatMessage) -> Result<SequencedMessage> {
+        let mut state = self.state.lock().unwrap();
+        if let Some(failure) = &state.failure {
+            bail!("appending failed irrecoverably, reopen the store: {failure}");
+        }
+
+        let sequence_number = state.next_sequence_number(&chat_message.channel);
+        let sequenced_message = SequencedMessage {
+            sequence_number,
+            chat_message,
+        };
+
+        let payload = serde_json::to_vec(&sequenced_message)?;
+        let record = encode_record(&payload);
+
+        if state.active_segment_size > 0
+            && state.active_segment_size + record.len() as u64 > self.options.max_segment_size
+        {
+            state.active_segment.sync_data()?;
+            let segment_number = state.active_segment_number + 1;
+            state.active_segment = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(segment_path(&self.directory, segment_number))?;
+            state.active_segment_number = segment_number;
+            state.active_segment_size = 0;
+            state.unsynced_messages = 0;
+        }
+
+        if let Err(err) = self.write_record(&mut state, &record) {
+            // Remove what has been written of the record, so that it is neither read back when
+            // reopening the store nor makes later records unreadable.
+            let active_segment_size = state.active_segment_size;
+            let rollback = state
+                .active_segment
+                .set_len(active_segment_size)
+                .and_then(|()| state.active_segment.sync_data());
+            if let Err(rollback_err) = rollback {
+                state.failure = Some(format!(
+                    "could not remove a failed write from segment {}: {rollback_err}",
+                    state.active_segment_number
+                ));
+            }
+            return Err(err);
+        }
+
+        let location = RecordLocation {
+            sequence_number,
+            segment_number: state.active_segment_number,
+            offset: state.active_segment_size + RECORD_HEADER_SIZE,
+            length: payload.len() as u32,
+        };
+        state.active_segment_size += record.len() as u64;
+        state
+            .index
+            .entry(sequenced_message.chat_message.channel.clone())
+            .or_default()
+            .push(location);
+
+        Ok(sequenced_message)
+    }
+
+    fn messages_after(
+        &self,
+        channel: &str,
+        after: SequenceNumber,
+        limit: Option<usize>,
+    ) -> Result<Vec<SequencedMessage>> {
+        // Only hold the lock while looking up the locations; records are never modified once
+        // they are written, only moved by compaction.
+        let _segment_files = self.segment_files.read().unwrap();
+        let locations: Vec<RecordLocation> = {
+            let state = self.state.lock().unwrap();
+            match state.index.get(channel) {
+                None => vec![],
+                Some(locations) => {
+                    let start =
+                        locations.partition_point(|location| location.sequence_number <= after);
+                    locations[start..]
+                        .iter()
+                        .take(limit.unwrap_or(usize::MAX))
+                        .copied()
+                        .collect()
+                }
+            }
+        };
+
+        locations
+            .iter()
+            .map(|location| self.read_message(location))
+            .collect()
+    }
+
+    fn next_sequence_number(&self, channel: &str) -> Result<SequenceNumber> {
+        Ok(self.state.lock().unwrap().next_sequence_number(channel))
+    }
+
+    fn channels(&self) -> Result<Vec<String>> {
+        let state = self.state.lock().unwrap();
+        let mut channels: HashSet<String> = state.index.keys().cloned().collect();
+        channels.extend(state.truncations.keys().cloned());
+
+        Ok(channels.into_iter().collect())
+    }
+
+    fn truncate_before(&self, channel: &str, sequence_number: SequenceNumber) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let truncated_before = state.truncations.get(channel).copied();
+        if truncated_before.is_some_and(|before| before >= sequence_number) {
+            return Ok(());
+        }
+
+        let mut truncations = state.truncations.clone();
+        truncations.insert(channel.to_string(), sequence_number);
+        write_truncations(&self.directory, &truncations)?;
+        state.truncations = truncations;
+
+        if let Some(locations) = state.index.get_mut(channel) {
+            let end =
+                locations.partition_point(|location| location.sequence_number < sequence_number);
+            locations.drain(..end);
+        }
+
+        Ok(())
+    }
+
+    fn truncated_before(&self, channel: &str) -> Result<Option<SequenceNumber>> {
+        Ok(self.state.lock().unwrap().truncations.get(channel).copied())
+    }
+
+    fn compact(&self) -> Result<()> {
+        let _compaction = self.compaction.lock().unwrap();
+        let (active_segment_number, truncations) = {
+            let state = self.state.lock().unwrap();
+            (state.active_segment_number, state.truncations.clone())
+        };
+
+        for segment_number in list_segment_numbers(&self.directory)? {
+            if segment_number < active_segment_number {
+                self.compact_segment(segment_number, &truncations)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl SegmentFileMessageStore {
+    /// Writes the record to the active segment, syncing it according to the fsync policy.
+    fn write_record(&self, state: &mut SegmentFileState, record: &[u8]) -> Result<()> {
+        state.active_segment.write_all(record)?;
+
+        state.unsynced_messages += 1;
+        let needs_sync = match self.options.fsync_policy {
+            FsyncPolicy::Always => true,
+            FsyncPolicy::EveryNMessages(n) => state.unsynced_messages >= n,
+            FsyncPolicy::Never => false,
+        };
+        if needs_sync {
+            state.active_segment.sync_data()?;
+            state.unsynced_messages = 0;
+        }
+
+        Ok(())
+    }
+
+    /// Rewrites the segment without the messages removed according to `truncations`.
+    fn compact_segment(
+        &self,
+        segment_number: u64,
+        truncations: &HashMap<String, SequenceNumber>,
+    ) -> Result<()> {
+        let segment_path = segment_path(&self.directory, segment_number);
+
+        let mut retained_records = Vec::new();
+        let mut removed_records = 0;
+        read_segment(&segment_path, segment_number, |message, _| {
+            if is_truncated(
+                truncations,
+                &message.chat_message.channel,
+                message.sequence_number,
+            ) {
+                removed_records += 1;
+            } else {
+                retained_records.push(message);
+            }
+        })?;
+        if removed_records == 0 {
+            return Ok(());
+        }
+
+        if retained_records.is_empty() {
+            // Wait for ongoing reads, which may have looked up locations before the truncation.
+            let _segment_files = self.segment_files.write().unwrap();
+            fs::remove_file(&segment_path)?;
+            return Ok(());
+        }
+
+        // Write the retained records to a new file first, so that the segment is replaced
+        // atomically.
+        let compacted_path = segment_path.with_extension(COMPACTED_SEGMENT_FILE_EXTENSION);
+        let mut compacted_segment = File::create(&compacted_path)?;
+        let mut new_locations = HashMap::new();
+        let mut offset = 0;
+        for message in retained_records {
+            let payload = serde_json::to_vec(&message)?;
+            compacted_segment.write_all(&encode_record(&payload))?;
+
+            new_locations.insert(
+                (message.chat_message.channel, message.sequence_number),
+                offset + RECORD_HEADER_SIZE,
+            );
+            offset += RECORD_HEADER_SIZE + payload.len() as u64;
+        }
+        compacted_segment.sync_all()?;
+
+        let _segment_files = self.segment_files.write().unwrap();
+        fs::rename(&compacted_path, &segment_path)?;
+        let mut state = self.state.lock().unwrap();
+        for (channel, locations) in state.index.iter_mut() {
+            for location in locations
+                .iter_mut()
+                .filter(|location| location.segment_number == segment_number)
+            {
+                if let Some(&offset) =
+                    new_locations.get(&(channel.clone(), location.sequence_number))
+                {
+                    location.offset = offset;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn encode_record(payload: &[u8]) -> Vec<u8> {
+    let mut record = Vec::with_capacity(RECORD_HEADER_SIZE as usize + payload.len());
+    record.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    record.extend_from_slice(&crc32fast::hash(payload).to_le_bytes());
+    record.extend_from_slice(payload);
+
+    record
+}
+
+/// Whether the message with the given sequence number has been removed from the channel.
+fn is_truncated(
+    truncations: &HashMap<String, SequenceNumber>,
+    channel: &str,
+    sequence_number: SequenceNumber,
+) -> bool {
+    truncations
+        .get(channel)
+        .is_some_and(|&truncated_before| sequence_number < truncated_before)
+}
+
+fn read_truncations(directory: &Path) -> Result<HashMap<String, SequenceNumber>> {
+    match fs::read(directory.join(TRUNCATIONS_FILE_NAME)) {
+        Ok(contents) => Ok(serde_json::from_slice(&contents)?),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(HashMap::new()),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Replaces the truncations file atomically, so that a crash leaves either the old or the new one.
+fn write_truncations(
+    directory: &Path,
+    truncations: &HashMap<String, SequenceNumber>,
+) -> Result<()> {
+    let path = directory.join(TRUNCATIONS_FILE_NAME);
+    let temporary_path = path.with_extension("json.tmp");
+
+    let mut file = File::create(&temporary_path)?;
+    file.write_all(&serde_json::to_vec(truncations)?)?;
+    file.sync_all()?;
+    fs::rename(&temporary_path, &path)?;
+
+    Ok(())
+}
+
+fn segment_path(directory: &Path, segment_number: u64) -> PathBuf {
+    directory.join(format!("{segment_number:020}.{SEGMENT_FILE_EXTENSION}"))
+}
+
+fn list_segment_numbers(directory: &Path) -> Result<Vec<u64>> {
+    let mut segment_numbers = Vec::new();
+
+    for entry in fs::read_dir(directory)? {
+        let path = entry?.path();
+        if path.extension().and_then(|extension| extension.to_str()) != Some(SEGMENT_FILE_EXTENSION)
+        {
+            continue;
+        }
+
+        let segment_number = path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .and_then(|stem| stem.parse().ok())
+            .with_context(|| format!("invalid segment file name {}", path.display()))?;
+        segment_numbers.push(segment_number);
+    }
+    segment_numbers.sort_unstable();
+
+    Ok(segment_numbers)
+}
+
+/// Reads all intact records of the segment, passing them to `on_record`.
+///
+/// Returns the size up to the last intact record, as well as the total size of the segment.
+fn read_segment<F>(segment_path: &Path, segment_number: u64, mut on_record: F) -> Result<(u64, u64)>
+where
+    F: FnMut(SequencedMessage, RecordLocation),
+{
+    let contents = match fs::read(segment_path) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok((0, 0)),
+        Err(err) => return Err(err.into()),
+    };
+
+    let mut offset = 0;
+    while let Some((message, payload_length)) = parse_record(&contents[offset..]) {
+        let message = message?;
+        let payload_offset = offset as u64 + RECORD_HEADER_SIZE;
+        let location = RecordLocation {
+            sequence_number: message.sequence_number,
+            segment_number,
+            offset: payload_offset,
+            length: payload_length,
+        };
+        on_record(message, location);
+
+        offset = (payload_offset + payload_length as u64) as usize;
+    }
+
+    Ok((offset as u64, contents.len() as u64))
+}
+
+/// Parses the record at the start of `bytes`.
+///
+/// Returns `None` if there is no complete and intact record, i.e. at the end of the segment or on
+/// a torn write.
+fn parse_record(bytes: &[u8]) -> Option<(Result<SequencedMessage>, u32)> {
+    if (bytes.len() as u64) < RECORD_HEADER_SIZE {
+        return None;
+    }
+
+    let payload_length = u32::from_le_bytes(bytes[0..4].try_into().unwrap());
+    let checksum = u32::from_le_bytes(bytes[4..8].try_into().unwrap());
+    let payload = bytes
+        .get(RECORD_HEADER_SIZE as usize..)?
+        .get(..payload_length as usize)?;
+
+    if crc32fast::hash(payload) != checksum {
+        return None;
+    }
+
+    let message = serde_json::from_slice(payload).map_err(anyhow::Error::from);
+    Some((message, payload_length))
+}
@@ -1,14 +1,25 @@
-use std::time::Duration;
+use std::{
+    sync::{mpsc, Arc, Mutex},
+    time::Duration,
+};
 
-use common::{ChatMessage, DEFAULT_CHANNEL};
+use anyhow::{anyhow, Result};
+use common::{
+    forwarder_health::ItemErrorPolicy, ChatMessage, MessageId, SequenceNumber, SequencedMessage,
+    DEFAULT_CHANNEL,
+};
 use futures::StreamExt;
 
-use crate::message_log::MessageLog;
+use crate::{
+    message_log::MessageLog,
+    storage::{InMemoryMessageStore, MessageStore},
+};
 
-use super::TestMessageStream;
+use super::{redact_message_ids, TestMessageStream};
 
 #[tokio::test]
 async fn retrieve_messages() {
+    let _settings = redact_message_ids();
     let test_message_stream = TestMessageStream::new(vec![
         ChatMessage::new(DEFAULT_CHANNEL, "first message"),
         ChatMessage::new("some-other-channel", "second message"),
@@ -16,36 +27,525 @@ async fn retrieve_messages() {
     ])
     .boxed();
 
-    let message_log = MessageLog::new(test_message_stream);
+    let message_log = MessageLog::new(
+        Arc::new(InMemoryMessageStore::default()),
+        test_message_stream,
+        ItemErrorPolicy::Abort,
+    )
+    .unwrap();
 
     // Since the message is handled asynchronously, we have to wait a little.
     tokio::time::sleep(Duration::from_millis(100)).await;
 
     // assert that the messages can be retrieved
-    insta::assert_debug_snapshot!(message_log.messages_received(DEFAULT_CHANNEL), @r###"
+    insta::assert_debug_snapshot!(message_log.messages_received(DEFAULT_CHANNEL).unwrap(), @r###"
     [
-        ChatMessage {
-            channel: "default-channel",
-            message_text: "first message",
+        SequencedMessage {
+            sequence_number: 1,
+            chat_message: ChatMessage {
+                id: [id],
+                channel: "default-channel",
+                message_text: "first message",
+            },
         },
     ]
     "###);
 
-    insta::assert_debug_snapshot!(message_log.messages_received("some-other-channel"), @r###"
+    insta::assert_debug_snapshot!(message_log.messages_received("some-other-channel").unwrap(), @r###"
     [
-        ChatMessage {
-            channel: "some-other-channel",
-            message_text: "second message",
+        SequencedMessage {
+            sequence_number: 1,
+            chat_message: ChatMessage {
+                id: [id],
+                channel: "some-other-channel",
+                message_text: "second message",
+            },
         },
     ]
     "###);
 
-    insta::assert_debug_snapshot!(message_log.messages_received("yet-another-channel"), @r###"
+    insta::assert_debug_snapshot!(message_log.messages_received("yet-another-channel").unwrap(), @r###"
     [
-        ChatMessage {
-            channel: "yet-another-channel",
-            message_text: "third message",
+        SequencedMessage {
+            sequence_number: 1,
+            chat_message: ChatMessage {
+                id: [id],
+                channel: "yet-another-channel",
+                message_text: "third message",
+            },
         },
     ]
     "###);
 }
+
+#[tokio::test]
+async fn ignore_duplicate_messages() {
+    let _settings = redact_message_ids();
+    let message = ChatMessage::new(DEFAULT_CHANNEL, "message that is received twice");
+    let test_message_stream = TestMessageStream::new(vec![
+        message.clone(),
+        ChatMessage::new(DEFAULT_CHANNEL, "another message"),
+        message,
+    ])
+    .boxed();
+
+    let message_log = MessageLog::new(
+        Arc::new(InMemoryMessageStore::default()),
+        test_message_stream,
+        ItemErrorPolicy::Abort,
+    )
+    .unwrap();
+
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    insta::assert_debug_snapshot!(message_log.messages_received(DEFAULT_CHANNEL).unwrap(), @r###"
+    [
+        SequencedMessage {
+            sequence_number: 1,
+            chat_message: ChatMessage {
+                id: [id],
+                channel: "default-channel",
+                message_text: "message that is received twice",
+            },
+        },
+        SequencedMessage {
+            sequence_number: 2,
+            chat_message: ChatMessage {
+                id: [id],
+                channel: "default-channel",
+                message_text: "another message",
+            },
+        },
+    ]
+    "###);
+}
+
+#[tokio::test]
+async fn assign_sequence_numbers_per_channel() {
+    let test_message_stream = TestMessageStream::new(vec![
+        ChatMessage::new(DEFAULT_CHANNEL, "message 1 on default-channel"),
+        ChatMessage::new("some-other-channel", "message 1 on some-other-channel"),
+        ChatMessage::new(DEFAULT_CHANNEL, "message 2 on default-channel"),
+        ChatMessage::new(DEFAULT_CHANNEL, "message 3 on default-channel"),
+        ChatMessage::new("some-other-channel", "message 2 on some-other-channel"),
+    ])
+    .boxed();
+
+    let message_log = MessageLog::new(
+        Arc::new(InMemoryMessageStore::default()),
+        test_message_stream,
+        ItemErrorPolicy::Abort,
+    )
+    .unwrap();
+
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let sequence_numbers = |channel| {
+        message_log
+            .messages_received(channel)
+            .unwrap()
+            .into_iter()
+            .map(|message| (message.sequence_number, message.chat_message.message_text))
+            .collect::<Vec<_>>()
+    };
+    insta::assert_debug_snapshot!(sequence_numbers(DEFAULT_CHANNEL), @r###"
+    [
+        (
+            1,
+            "message 1 on default-channel",
+        ),
+        (
+            2,
+            "message 2 on default-channel",
+        ),
+        (
+            3,
+            "message 3 on default-channel",
+        ),
+    ]
+    "###);
+    insta::assert_debug_snapshot!(sequence_numbers("some-other-channel"), @r###"
+    [
+        (
+            1,
+            "message 1 on some-other-channel",
+        ),
+        (
+            2,
+            "message 2 on some-other-channel",
+        ),
+    ]
+    "###);
+}
+
+#[tokio::test]
+async fn retrieve_messages_after_sequence_number() {
+    let test_message_stream = TestMessageStream::new(vec![
+        ChatMessage::new(DEFAULT_CHANNEL, "message 1"),
+        ChatMessage::new("some-other-channel", "unrelated message"),
+        ChatMessage::new(DEFAULT_CHANNEL, "message 2"),
+        ChatMessage::new(DEFAULT_CHANNEL, "message 3"),
+        ChatMessage::new(DEFAULT_CHANNEL, "message 4"),
+    ])
+    .boxed();
+
+    let message_log = MessageLog::new(
+        Arc::new(InMemoryMessageStore::default()),
+        test_message_stream,
+        ItemErrorPolicy::Abort,
+    )
+    .unwrap();
+
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let message_texts = |after, limit| {
+        message_log
+            .messages_after(DEFAULT_CHANNEL, after, limit)
+            .unwrap()
+            .into_iter()
+            .map(|message| message.chat_message.message_text)
+            .collect::<Vec<_>>()
+    };
+    insta::assert_debug_snapshot!(message_texts(1, None), @r###"
+    [
+        "message 2",
+        "message 3",
+        "message 4",
+    ]
+    "###);
+    insta::assert_debug_snapshot!(message_texts(1, Some(2)), @r###"
+    [
+        "message 2",
+        "message 3",
+    ]
+    "###);
+    insta::assert_debug_snapshot!(message_texts(4, None), @"[]");
+}
+
+#[tokio::test]
+async fn retrieve_last_messages_before_sequence_number() {
+    let message_log = MessageLog::new(
+        Arc::new(InMemoryMessageStore::default()),
+        Box::pin(futures::stream::pending()),
+        ItemErrorPolicy::Abort,
+    )
+    .unwrap();
+    for i in 1..=5 {
+        message_log
+            .append(ChatMessage::new(DEFAULT_CHANNEL, format!("message {i}")))
+            .unwrap();
+    }
+    message_log.truncate_before(DEFAULT_CHANNEL, 2).unwrap();
+
+    let sequence_numbers = |before, count| {
+        message_log
+            .messages_before(DEFAULT_CHANNEL, before, count)
+            .unwrap()
+            .into_iter()
+            .map(|message| message.sequence_number)
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(sequence_numbers(None, 2), vec![4, 5]);
+    assert_eq!(sequence_numbers(Some(5), 2), vec![3, 4]);
+    assert_eq!(sequence_numbers(Some(100), 1), vec![5]);
+    // Removed messages are missing.
+    assert_eq!(sequence_numbers(Some(4), 3), vec![2, 3]);
+    assert_eq!(sequence_numbers(None, 0), Vec::<u64>::new());
+}
+
+#[tokio::test]
+async fn append_messages() {
+    let _settings = redact_message_ids();
+    let appended_message = ChatMessage::new(DEFAULT_CHANNEL, "appended message");
+    // After being appended, the message is published and thus received again by the log.
+    let test_message_stream = TestMessageStream::new(vec![
+        appended_message.clone(),
+        ChatMessage::new(DEFAULT_CHANNEL, "published message"),
+    ])
+    .boxed();
+
+    let message_log = MessageLog::new(
+        Arc::new(InMemoryMessageStore::default()),
+        test_message_stream,
+        ItemErrorPolicy::Abort,
+    )
+    .unwrap();
+
+    insta::assert_debug_snapshot!(message_log.append(appended_message.clone()).unwrap(), @r###"
+    Some(
+        SequencedMessage {
+            sequence_number: 1,
+            chat_message: ChatMessage {
+                id: [id],
+                channel: "default-channel",
+                message_text: "appended message",
+            },
+        },
+    )
+    "###);
+    // Appending is idempotent.
+    assert!(message_log.append(appended_message).unwrap().is_none());
+
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let message_texts: Vec<_> = message_log
+        .messages_received(DEFAULT_CHANNEL)
+        .unwrap()
+        .into_iter()
+        .map(|message| (message.sequence_number, message.chat_message.message_text))
+        .collect();
+    insta::assert_debug_snapshot!(message_texts, @r###"
+    [
+        (
+            1,
+            "appended message",
+        ),
+        (
+            2,
+            "published message",
+        ),
+    ]
+    "###);
+}
+
+#[tokio::test]
+async fn handle_erroneous_messages_according_to_policy() {
+    let test_message_stream = || {
+        futures::stream::iter(vec![
+            Ok(ChatMessage::new(DEFAULT_CHANNEL, "first message")),
+            Err(anyhow!("malformed message")),
+            Ok(ChatMessage::new(DEFAULT_CHANNEL, "second message")),
+        ])
+        .boxed()
+    };
+
+    let skipping_message_log = MessageLog::new(
+        Arc::new(InMemoryMessageStore::default()),
+        test_message_stream(),
+        ItemErrorPolicy::Skip,
+    )
+    .unwrap();
+    let aborting_message_log = MessageLog::new(
+        Arc::new(InMemoryMessageStore::default()),
+        test_message_stream(),
+        ItemErrorPolicy::Abort,
+    )
+    .unwrap();
+
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    insta::assert_debug_snapshot!(skipping_message_log.forwarder_health(), @r###"
+    ForwarderHealth {
+        status: Finished,
+        received_messages: 2,
+        forwarded_messages: 2,
+        forwarded_batches: 1,
+        queued_messages: 0,
+        queue_overflows: 0,
+        dropped_messages: 0,
+        skipped_errors: 1,
+        last_skipped_error: Some(
+            "malformed message",
+        ),
+    }
+    "###);
+    insta::assert_debug_snapshot!(aborting_message_log.forwarder_health(), @r###"
+    ForwarderHealth {
+        status: Failed {
+            error: "malformed message",
+        },
+        received_messages: 1,
+        forwarded_messages: 1,
+        forwarded_batches: 1,
+        queued_messages: 0,
+        queue_overflows: 0,
+        dropped_messages: 0,
+        skipped_errors: 0,
+        last_skipped_error: None,
+    }
+    "###);
+    assert_eq!(
+        aborting_message_log
+            .messages_received(DEFAULT_CHANNEL)
+            .unwrap()
+            .len(),
+        1
+    );
+}
+
+#[tokio::test]
+async fn wait_for_messages_appended_later() {
+    let message_log = MessageLog::new(
+        Arc::new(InMemoryMessageStore::default()),
+        Box::pin(futures::stream::pending()),
+        ItemErrorPolicy::Abort,
+    )
+    .unwrap();
+
+    let waiting_message_log = message_log.clone();
+    let waiting = tokio::spawn(async move {
+        waiting_message_log
+            .wait_for_messages_after(DEFAULT_CHANNEL, 0, None, Duration::from_secs(5))
+            .await
+    });
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    message_log
+        .append(ChatMessage::new(
+            "some-other-channel",
+            "another channel's message",
+        ))
+        .unwrap();
+    message_log
+        .append(ChatMessage::new(DEFAULT_CHANNEL, "the awaited message"))
+        .unwrap();
+
+    let messages = tokio::time::timeout(Duration::from_secs(1), waiting)
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    assert_eq!(messages.len(), 1);
+    assert_eq!(messages[0].chat_message.message_text, "the awaited message");
+
+    // Without new messages, the wait ends after the timeout.
+    let messages = message_log
+        .wait_for_messages_after(DEFAULT_CHANNEL, 1, None, Duration::from_millis(50))
+        .await
+        .unwrap();
+    assert!(messages.is_empty());
+}
+
+/// Blocks appending to [`BlockingMessageStore::BLOCKED_CHANNEL`] until released.
+struct BlockingMessageStore {
+    message_store: InMemoryMessageStore,
+    release: Mutex<mpsc::Receiver<()>>,
+}
+
+impl BlockingMessageStore {
+    const BLOCKED_CHANNEL: &'static str = "blocked-channel";
+}
+
+impl MessageStore for BlockingMessageStore {
+    fn append(&self, chat_message: ChatMessage) -> Result<SequencedMessage> {
+        if chat_message.channel == Self::BLOCKED_CHANNEL {
+            self.release.lock().unwrap().recv()?;
+        }
+
+        self.message_store.append(chat_message)
+    }
+
+    fn messages_after(
+        &self,
+        channel: &str,
+        after: SequenceNumber,
+        limit: Option<usize>,
+    ) -> Result<Vec<SequencedMessage>> {
+        self.message_store.messages_after(channel, after, limit)
+    }
+
+    fn next_sequence_number(&self, channel: &str) -> Result<SequenceNumber> {
+        self.message_store.next_sequence_number(channel)
+    }
+
+    fn channels(&self) -> Result<Vec<String>> {
+        self.message_store.channels()
+    }
+
+    fn truncate_before(&self, channel: &str, sequence_number: SequenceNumber) -> Result<()> {
+        self.message_store.truncate_before(channel, sequence_number)
+    }
+
+    fn truncated_before(&self, channel: &str) -> Result<Option<SequenceNumber>> {
+        self.message_store.truncated_before(channel)
+    }
+}
+
+#[tokio::test]
+async fn append_to_channels_independently() {
+    let (release, blocked_appends) = mpsc::channel();
+    let message_log = MessageLog::new(
+        Arc::new(BlockingMessageStore {
+            message_store: InMemoryMessageStore::default(),
+            release: Mutex::new(blocked_appends),
+        }),
+        Box::pin(futures::stream::pending()),
+        ItemErrorPolicy::Abort,
+    )
+    .unwrap();
+
+    let blocked_message = ChatMessage::new(BlockingMessageStore::BLOCKED_CHANNEL, "blocked");
+    let blocked_append = {
+        let message_log = message_log.clone();
+        let blocked_message = blocked_message.clone();
+        std::thread::spawn(move || message_log.append(blocked_message))
+    };
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    // Other channels do not wait for the blocked append.
+    let appended_message = message_log
+        .append(ChatMessage::new(DEFAULT_CHANNEL, "not blocked"))
+        .unwrap();
+    assert!(appended_message.is_some());
+    // A duplicate of the blocked message waits for the blocked append, since it may still fail.
+    let duplicate_append = {
+        let message_log = message_log.clone();
+        std::thread::spawn(move || message_log.append(blocked_message))
+    };
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert!(!duplicate_append.is_finished());
+
+    release.send(()).unwrap();
+    let appended_message = blocked_append.join().unwrap().unwrap();
+    assert_eq!(appended_message.unwrap().sequence_number, 1);
+    assert!(duplicate_append.join().unwrap().unwrap().is_none());
+    assert_eq!(
+        message_log
+            .messages_received(BlockingMessageStore::BLOCKED_CHANNEL)
+            .unwrap()
+            .len(),
+        1
+    );
+}
+
+#[tokio::test]
+async fn concurrent_duplicates_fail_with_the_append() {
+    let (release, blocked_appends) = mpsc::channel::<()>();
+    let message_log = MessageLog::new(
+        Arc::new(BlockingMessageStore {
+            message_store: InMemoryMessageStore::default(),
+            release: Mutex::new(blocked_appends),
+        }),
+        Box::pin(futures::stream::pending()),
+        ItemErrorPolicy::Abort,
+    )
+    .unwrap();
+
+    let mut blocked_message = ChatMessage::new(BlockingMessageStore::BLOCKED_CHANNEL, "blocked");
+    blocked_message.id = MessageId::nil();
+    let appends: Vec<_> = (0..2)
+        .map(|_| {
+            let message_log = message_log.clone();
+            let blocked_message = blocked_message.clone();
+            let append = std::thread::spawn(move || message_log.append(blocked_message));
+            std::thread::sleep(Duration::from_millis(50));
+            append
+        })
+        .collect();
+
+    // Fails the blocked append.
+    drop(release);
+    let errors: Vec<_> = appends
+        .into_iter()
+        .map(|append| append.join().unwrap().unwrap_err().to_string())
+        .collect();
+    insta::assert_debug_snapshot!(errors, @r###"
+    [
+        "receiving on a closed channel",
+        "appending message 00000000000000000000000000 concurrently failed: receiving on a closed channel",
+    ]
+    "###);
+    assert!(message_log
+        .messages_received(BlockingMessageStore::BLOCKED_CHANNEL)
+        .unwrap()
+        .is_empty());
+}
@@ -6,8 +6,23 @@ use std::{
 use anyhow::Result;
 use common::ChatMessage;
 use futures::Stream;
+use insta::internals::SettingsBindDropGuard;
 
 mod message_log;
+mod raft;
+mod replication;
+mod retention;
+mod sharding;
+mod snapshots;
+mod storage;
+
+/// Message ids are randomly generated; this replaces them in snapshots so that these stay stable.
+fn redact_message_ids() -> SettingsBindDropGuard {
+    let mut settings = insta::Settings::clone_current();
+    settings.add_filter(r"Ulid\(\s*\d+,?\s*\)", "[id]");
+
+    settings.bind_to_scope()
+}
 
 struct TestMessageStream {
     messages: Vec<ChatMessage>,
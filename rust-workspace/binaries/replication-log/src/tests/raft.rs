@@ -0,0 +1,957 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use common::{forwarder_health::ItemErrorPolicy, ChatMessage, DEFAULT_CHANNEL};
+use futures::StreamExt;
+use tempfile::TempDir;
+
+use crate::{
+    message_log::MessageLog,
+    raft::{
+        server::{apply_batches, raft_message_route, spawn_raft_server, RaftHandle, RaftTransport},
+        storage::{FileRaftStorage, InMemoryRaftStorage, RaftStorage},
+        Command, Entry, HardState, LogIndex, Message, MessageBody, NodeId, NotLeader, RaftConfig,
+        RaftMember, RaftNode, RaftRole, RaftTimeouts, ReadId, Snapshot, Term,
+    },
+    replication::{ChannelPosition, Leader},
+    storage::InMemoryMessageStore,
+};
+
+use super::TestMessageStream;
+
+const TIMEOUTS: RaftTimeouts = RaftTimeouts {
+    election_ticks: 10,
+    heartbeat_ticks: 2,
+};
+
+/// Identifies a committed entry: by the log matching property of Raft, two entries with the same
+/// index and term are the same.
+type EntryKey = (Term, Option<String>);
+
+fn entry_key(entry: &Entry) -> EntryKey {
+    match &entry.command {
+        Command::Noop | Command::TruncateBefore { .. } => (entry.term, None),
+        Command::Append(chat_message) => (entry.term, Some(chat_message.message_text.clone())),
+    }
+}
+
+/// Runs a group of nodes in a single thread, deterministically for a given seed: nodes are ticked
+/// in lockstep, and messages are delivered in a random order, unless the network is partitioned
+/// between the sender and the receiver.
+///
+/// Every committed entry is checked against the entries committed at the same index before, on any
+/// node, so that a test fails as soon as a committed entry is lost or replaced.
+struct Simulation {
+    /// `None` while a node is crashed.
+    nodes: BTreeMap<NodeId, Option<RaftNode>>,
+    storages: BTreeMap<NodeId, InMemoryRaftStorage>,
+    in_flight: Vec<Message>,
+    /// Messages are only delivered between nodes of the same group.
+    groups: BTreeMap<NodeId, usize>,
+    random_state: u64,
+    /// Every entry that has been committed on any node.
+    committed: BTreeMap<LogIndex, EntryKey>,
+    /// The texts of the messages applied by every node, in order; kept across crashes, like the
+    /// node's message log.
+    applied: BTreeMap<NodeId, Vec<String>>,
+    confirmed_reads: Vec<(NodeId, ReadId)>,
+    failed_reads: Vec<(NodeId, ReadId)>,
+}
+
+impl Simulation {
+    fn new(node_count: u64, seed: u64) -> Self {
+        let node_ids: Vec<NodeId> = (1..=node_count).collect();
+        let storages: BTreeMap<_, _> = node_ids
+            .iter()
+            .map(|&id| (id, InMemoryRaftStorage::new()))
+            .collect();
+
+        let mut simulation = Simulation {
+            nodes: BTreeMap::new(),
+            storages,
+            in_flight: Vec::new(),
+            groups: BTreeMap::new(),
+            random_state: seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1,
+            committed: BTreeMap::new(),
+            applied: node_ids.iter().map(|&id| (id, Vec::new())).collect(),
+            confirmed_reads: Vec::new(),
+            failed_reads: Vec::new(),
+        };
+        for id in node_ids {
+            simulation.restart(id);
+        }
+
+        simulation
+    }
+
+    fn node_ids(&self) -> Vec<NodeId> {
+        self.nodes.keys().copied().collect()
+    }
+
+    fn node(&mut self, id: NodeId) -> &mut RaftNode {
+        self.nodes.get_mut(&id).unwrap().as_mut().unwrap()
+    }
+
+    /// The leader with the highest term among the running nodes.
+    fn leader(&self) -> Option<NodeId> {
+        self.nodes
+            .values()
+            .flatten()
+            .filter(|node| node.role() == RaftRole::Leader)
+            .max_by_key(|node| node.term())
+            .map(|node| node.id())
+    }
+
+    /// Runs until a leader has been elected, returning it.
+    fn elect_leader(&mut self) -> NodeId {
+        for _ in 0..1000 {
+            self.run(1);
+            if let Some(leader) = self.leader() {
+                return leader;
+            }
+        }
+
+        panic!("no leader has been elected");
+    }
+
+    fn crash(&mut self, id: NodeId) {
+        self.nodes.insert(id, None);
+    }
+
+    /// Starts the node from its storage, as after a crash.
+    fn restart(&mut self, id: NodeId) {
+        let members = self.storages.keys().copied().collect::<Vec<_>>();
+        let storage = Box::new(self.storages[&id].clone());
+        let seed = self.next_random();
+
+        let node = RaftNode::new(id, &members, TIMEOUTS, storage, seed).unwrap();
+        self.nodes.insert(id, Some(node));
+    }
+
+    /// Partitions the network: only nodes within the same group can reach each other.
+    fn partition(&mut self, groups: &[&[NodeId]]) {
+        self.groups = groups
+            .iter()
+            .enumerate()
+            .flat_map(|(group, ids)| ids.iter().map(move |&id| (id, group)))
+            .collect();
+    }
+
+    fn heal(&mut self) {
+        self.groups.clear();
+    }
+
+    fn can_reach(&self, from: NodeId, to: NodeId) -> bool {
+        self.groups.get(&from) == self.groups.get(&to)
+    }
+
+    fn propose(&mut self, id: NodeId, text: &str) -> anyhow::Result<(Term, LogIndex)> {
+        let result = self
+            .node(id)
+            .propose(Command::Append(ChatMessage::new(DEFAULT_CHANNEL, text)));
+        self.process_ready();
+
+        result
+    }
+
+    fn read_index(&mut self, id: NodeId, read_id: ReadId) {
+        self.node(id).read_index(read_id).unwrap();
+        self.process_ready();
+    }
+
+    /// Ticks every running node the given number of times, delivering all messages in between.
+    fn run(&mut self, ticks: usize) {
+        for _ in 0..ticks {
+            for id in self.node_ids() {
+                if let Some(node) = self.nodes.get_mut(&id).unwrap() {
+                    node.tick().unwrap();
+                }
+            }
+            self.process_ready();
+            self.deliver_messages();
+        }
+    }
+
+    /// Delivers messages in a random order until no more messages are sent.
+    fn deliver_messages(&mut self) {
+        while !self.in_flight.is_empty() {
+            let mut messages = std::mem::take(&mut self.in_flight);
+            for i in (1..messages.len()).rev() {
+                let j = (self.next_random() % (i as u64 + 1)) as usize;
+                messages.swap(i, j);
+            }
+
+            for message in messages {
+                if !self.can_reach(message.from, message.to) {
+                    continue;
+                }
+                if let Some(node) = self.nodes.get_mut(&message.to).unwrap() {
+                    node.step(message).unwrap();
+                }
+            }
+            self.process_ready();
+        }
+    }
+
+    fn process_ready(&mut self) {
+        for id in self.node_ids() {
+            let Some(node) = self.nodes.get_mut(&id).unwrap() else {
+                continue;
+            };
+            let ready = node.take_ready();
+
+            self.in_flight.extend(ready.messages);
+            self.confirmed_reads
+                .extend(ready.reads.iter().map(|&(read_id, _)| (id, read_id)));
+            self.failed_reads
+                .extend(ready.failed_reads.iter().map(|&read_id| (id, read_id)));
+
+            if let Some(&(last_index, _)) = ready.committed_entries.last() {
+                for (index, entry) in ready.committed_entries {
+                    let key = entry_key(&entry);
+                    let committed = self.committed.entry(index).or_insert_with(|| key.clone());
+                    assert_eq!(
+                        *committed, key,
+                        "node {id} committed a different entry at index {index}"
+                    );
+                    if let (_, Some(text)) = key {
+                        self.applied.get_mut(&id).unwrap().push(text);
+                    }
+                }
+                node.record_applied(last_index).unwrap();
+            }
+
+            // Restores the messages of the snapshot, like copying them from the leader.
+            if let Some(pending_snapshot) = ready.snapshot {
+                let index = pending_snapshot.snapshot.last_included_index;
+                self.applied.insert(
+                    id,
+                    self.committed
+                        .range(..=index)
+                        .filter_map(|(_, (_, text))| text.clone())
+                        .collect(),
+                );
+                node.restore_snapshot(pending_snapshot).unwrap();
+                node.record_applied(index).unwrap();
+            }
+        }
+    }
+
+    /// Replaces the node's applied entries by a snapshot.
+    fn compact(&mut self, id: NodeId) {
+        let node = self.node(id);
+        node.compact(node.commit_index(), vec![]).unwrap();
+    }
+
+    /// The texts of all committed messages, in order.
+    fn committed_messages(&self) -> Vec<String> {
+        self.committed
+            .values()
+            .filter_map(|(_, text)| text.clone())
+            .collect()
+    }
+
+    /// xorshift64, so that a simulation only depends on its seed.
+    fn next_random(&mut self) -> u64 {
+        let mut x = self.random_state;
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.random_state = x;
+
+        x
+    }
+}
+
+#[test]
+fn leader_replicates_committed_messages() {
+    let mut simulation = Simulation::new(3, 1);
+    let leader = simulation.elect_leader();
+    for node_id in simulation.node_ids() {
+        assert_eq!(simulation.node(node_id).leader_id(), Some(leader));
+    }
+
+    let follower = simulation
+        .node_ids()
+        .into_iter()
+        .find(|&id| id != leader)
+        .unwrap();
+    let err = simulation.propose(follower, "to a follower").unwrap_err();
+    assert_eq!(
+        err.downcast_ref::<NotLeader>(),
+        Some(&NotLeader {
+            leader_id: Some(leader)
+        })
+    );
+
+    for text in ["first", "second", "third"] {
+        simulation.propose(leader, text).unwrap();
+    }
+    simulation.run(5);
+
+    insta::assert_debug_snapshot!(simulation.applied, @r###"
+    {
+        1: [
+            "first",
+            "second",
+            "third",
+        ],
+        2: [
+            "first",
+            "second",
+            "third",
+        ],
+        3: [
+            "first",
+            "second",
+            "third",
+        ],
+    }
+    "###);
+}
+
+#[test]
+fn minority_cannot_commit_and_loses_its_uncommitted_entries() {
+    let mut simulation = Simulation::new(5, 2);
+    let old_leader = simulation.elect_leader();
+    simulation
+        .propose(old_leader, "before the partition")
+        .unwrap();
+    simulation.run(5);
+
+    let (minority, majority): (Vec<_>, Vec<_>) = simulation
+        .node_ids()
+        .into_iter()
+        .partition(|&id| id == old_leader || id == old_leader % 5 + 1);
+    simulation.partition(&[&minority, &majority]);
+
+    // The old leader keeps accepting entries, but cannot commit them.
+    simulation.propose(old_leader, "uncommitted").unwrap();
+    simulation.run(50);
+    assert_eq!(simulation.node(old_leader).role(), RaftRole::Leader);
+
+    let new_leader = simulation.leader().unwrap();
+    assert!(majority.contains(&new_leader));
+    simulation
+        .propose(new_leader, "after the partition")
+        .unwrap();
+    simulation.run(5);
+    assert_eq!(
+        simulation.committed_messages(),
+        ["before the partition", "after the partition"]
+    );
+
+    simulation.heal();
+    simulation.run(20);
+    assert_eq!(simulation.node(old_leader).role(), RaftRole::Follower);
+    for node_id in simulation.node_ids() {
+        assert_eq!(
+            simulation.applied[&node_id],
+            ["before the partition", "after the partition"],
+            "node {node_id}"
+        );
+    }
+}
+
+#[test]
+fn restarted_nodes_keep_their_state() {
+    let mut simulation = Simulation::new(3, 3);
+    let leader = simulation.elect_leader();
+    let follower = simulation
+        .node_ids()
+        .into_iter()
+        .find(|&id| id != leader)
+        .unwrap();
+
+    simulation.propose(leader, "first").unwrap();
+    simulation.run(5);
+    simulation.crash(follower);
+    simulation
+        .propose(leader, "while a follower is down")
+        .unwrap();
+    simulation.run(5);
+    simulation.restart(follower);
+    simulation.run(5);
+    assert_eq!(
+        simulation.applied[&follower],
+        ["first", "while a follower is down"]
+    );
+
+    // The remaining nodes elect a new leader, which has every committed entry.
+    let term = simulation.node(leader).term();
+    simulation.crash(leader);
+    let new_leader = simulation.elect_leader();
+    assert!(simulation.node(new_leader).term() > term);
+    simulation.propose(new_leader, "after failover").unwrap();
+    simulation.run(5);
+    simulation.restart(leader);
+    simulation.run(20);
+
+    for node_id in simulation.node_ids() {
+        assert_eq!(
+            simulation.applied[&node_id],
+            ["first", "while a follower is down", "after failover"],
+            "node {node_id}"
+        );
+    }
+}
+
+#[test]
+fn lagging_nodes_catch_up_from_snapshots() {
+    let mut simulation = Simulation::new(3, 5);
+    let leader = simulation.elect_leader();
+    let follower = simulation
+        .node_ids()
+        .into_iter()
+        .find(|&id| id != leader)
+        .unwrap();
+
+    simulation.propose(leader, "first").unwrap();
+    simulation.run(5);
+    simulation.crash(follower);
+    for text in ["second", "third"] {
+        simulation.propose(leader, text).unwrap();
+    }
+    simulation.run(5);
+    for id in simulation.node_ids() {
+        if id != follower {
+            simulation.compact(id);
+        }
+    }
+    assert_eq!(simulation.node(leader).snapshot_index(), 4);
+    assert!(simulation.node(leader).entry(4).is_none());
+
+    // The follower lacks entries that the leader no longer has.
+    simulation.restart(follower);
+    simulation.run(5);
+    simulation.propose(leader, "after the snapshot").unwrap();
+    simulation.run(5);
+    assert_eq!(simulation.node(follower).snapshot_index(), 4);
+    for node_id in simulation.node_ids() {
+        assert_eq!(
+            simulation.applied[&node_id],
+            ["first", "second", "third", "after the snapshot"],
+            "node {node_id}"
+        );
+    }
+
+    // A restarted node continues after its snapshot.
+    simulation.crash(follower);
+    simulation.restart(follower);
+    assert_eq!(simulation.node(follower).commit_index(), 5);
+}
+
+#[test]
+fn reads_are_confirmed_by_a_majority() {
+    let mut simulation = Simulation::new(3, 4);
+    let leader = simulation.elect_leader();
+    simulation.run(5);
+
+    simulation.read_index(leader, 1);
+    simulation.run(1);
+    assert_eq!(simulation.confirmed_reads, [(leader, 1)]);
+
+    // A leader that has been cut off might have been replaced without knowing it.
+    let others: Vec<_> = simulation
+        .node_ids()
+        .into_iter()
+        .filter(|&id| id != leader)
+        .collect();
+    simulation.partition(&[&[leader], &others]);
+    simulation.read_index(leader, 2);
+    simulation.run(5);
+    assert_eq!(simulation.confirmed_reads, [(leader, 1)]);
+
+    // Once it learns about the new leader, the read fails.
+    simulation.run(50);
+    simulation.heal();
+    simulation.run(5);
+    assert_ne!(simulation.leader(), Some(leader));
+    assert_eq!(simulation.failed_reads, [(leader, 2)]);
+}
+
+#[test]
+fn committed_messages_are_never_lost_under_partitions_and_crashes() {
+    for seed in 1..=20 {
+        let mut simulation = Simulation::new(5, seed);
+        let node_ids = simulation.node_ids();
+        let mut crashed = BTreeSet::new();
+        let mut acknowledged = Vec::new();
+
+        for round in 0..200 {
+            match simulation.next_random() % 10 {
+                0 => {
+                    let mut shuffled = node_ids.clone();
+                    for i in (1..shuffled.len()).rev() {
+                        let j = (simulation.next_random() % (i as u64 + 1)) as usize;
+                        shuffled.swap(i, j);
+                    }
+                    let split = 1 + (simulation.next_random() % 4) as usize;
+                    simulation.partition(&[&shuffled[..split], &shuffled[split..]]);
+                }
+                1 => simulation.heal(),
+                2 if crashed.len() < 2 => {
+                    let id = node_ids[(simulation.next_random() % 5) as usize];
+                    if crashed.insert(id) {
+                        simulation.crash(id);
+                    }
+                }
+                3 => {
+                    if let Some(&id) = crashed.iter().next() {
+                        crashed.remove(&id);
+                        simulation.restart(id);
+                    }
+                }
+                _ => {
+                    if let Some(leader) = simulation.leader() {
+                        let text = format!("message {round}");
+                        let (term, index) = simulation.propose(leader, &text).unwrap();
+                        acknowledged.push((term, index, text));
+                    }
+                }
+            }
+            simulation.run(3);
+        }
+
+        simulation.heal();
+        for id in std::mem::take(&mut crashed) {
+            simulation.restart(id);
+        }
+        let leader = simulation.elect_leader();
+        simulation.propose(leader, "final").unwrap();
+        simulation.run(50);
+
+        // Every node has applied the same messages, which include every acknowledged proposal
+        // that was committed with the term it was proposed in.
+        let committed_messages = simulation.committed_messages();
+        assert!(committed_messages.ends_with(&["final".to_string()]));
+        for node_id in &node_ids {
+            assert_eq!(
+                simulation.applied[node_id], committed_messages,
+                "seed {seed}, node {node_id}"
+            );
+        }
+        for (term, index, text) in acknowledged {
+            let is_committed =
+                simulation.committed.get(&index) == Some(&(term, Some(text.clone())));
+            assert_eq!(
+                is_committed,
+                committed_messages.contains(&text),
+                "seed {seed}, {text}"
+            );
+        }
+    }
+}
+
+#[test]
+fn file_storage_survives_reopening() {
+    let directory = TempDir::new().unwrap();
+    let entry = |term, text| Entry {
+        term,
+        command: Command::Append(ChatMessage::new(DEFAULT_CHANNEL, text)),
+    };
+
+    let mut storage = FileRaftStorage::open(directory.path()).unwrap();
+    let state = storage.load().unwrap();
+    assert_eq!(state.hard_state, HardState::default());
+    assert!(state.log.is_empty());
+
+    let hard_state = HardState {
+        term: 2,
+        voted_for: Some(3),
+    };
+    storage.save_hard_state(&hard_state).unwrap();
+    storage
+        .append(&[entry(1, "first"), entry(1, "second"), entry(1, "third")])
+        .unwrap();
+    storage.truncate(2).unwrap();
+    storage.append(&[entry(2, "replaced")]).unwrap();
+    storage.save_applied_index(1).unwrap();
+    drop(storage);
+
+    let mut storage = FileRaftStorage::open(directory.path()).unwrap();
+    let state = storage.load().unwrap();
+    assert_eq!(state.hard_state, hard_state);
+    assert_eq!(
+        state.log.iter().map(entry_key).collect::<Vec<_>>(),
+        [
+            (1, Some("first".to_string())),
+            (2, Some("replaced".to_string()))
+        ]
+    );
+    assert_eq!(storage.applied_index().unwrap(), 1);
+
+    // A torn write is dropped.
+    let log_path = directory.path().join("log");
+    let mut contents = std::fs::read(&log_path).unwrap();
+    contents.extend_from_slice(&[42, 0, 0, 0, 1, 2]);
+    std::fs::write(&log_path, contents).unwrap();
+    let mut storage = FileRaftStorage::open(directory.path()).unwrap();
+    assert_eq!(storage.load().unwrap().log.len(), 2);
+    storage.append(&[entry(2, "after the torn write")]).unwrap();
+    assert_eq!(storage.load().unwrap().log.len(), 3);
+}
+
+#[test]
+fn file_storage_replaces_entries_by_snapshots() {
+    let directory = TempDir::new().unwrap();
+    let entry = |text| Entry {
+        term: 1,
+        command: Command::Append(ChatMessage::new(DEFAULT_CHANNEL, text)),
+    };
+    let snapshot = |last_included_index| Snapshot {
+        last_included_index,
+        last_included_term: 1,
+        channel_positions: vec![ChannelPosition {
+            channel: DEFAULT_CHANNEL.to_string(),
+            next_sequence_number: last_included_index + 1,
+        }],
+    };
+
+    let mut storage = FileRaftStorage::open(directory.path()).unwrap();
+    storage.load().unwrap();
+    storage
+        .append(&[entry("first"), entry("second"), entry("third")])
+        .unwrap();
+    storage.save_snapshot(&snapshot(2)).unwrap();
+    storage.append(&[entry("fourth")]).unwrap();
+    storage.truncate(5).unwrap();
+    assert!(storage.truncate(2).is_err());
+
+    let mut storage = FileRaftStorage::open(directory.path()).unwrap();
+    let state = storage.load().unwrap();
+    assert_eq!(state.snapshot, snapshot(2));
+    assert_eq!(
+        state.log.iter().map(entry_key).collect::<Vec<_>>(),
+        [
+            (1, Some("third".to_string())),
+            (1, Some("fourth".to_string()))
+        ]
+    );
+
+    // A snapshot beyond the end of the log replaces all of it.
+    storage.save_snapshot(&snapshot(6)).unwrap();
+    storage.append(&[entry("seventh")]).unwrap();
+    let state = FileRaftStorage::open(directory.path())
+        .unwrap()
+        .load()
+        .unwrap();
+    assert_eq!(state.snapshot, snapshot(6));
+    assert_eq!(
+        state.log.iter().map(entry_key).collect::<Vec<_>>(),
+        [(1, Some("seventh".to_string()))]
+    );
+}
+
+/// Delivers messages between servers within the same process, unless the sender or the receiver
+/// is isolated.
+#[derive(Default)]
+struct ChannelTransport {
+    handles: Mutex<HashMap<NodeId, RaftHandle>>,
+    message_logs: Mutex<HashMap<NodeId, MessageLog>>,
+    isolated: Mutex<HashSet<NodeId>>,
+}
+
+impl RaftTransport for ChannelTransport {
+    fn send(&self, message: Message) {
+        let isolated = self.isolated.lock().unwrap();
+        if isolated.contains(&message.from) || isolated.contains(&message.to) {
+            return;
+        }
+        let Some(handle) = self.handles.lock().unwrap().get(&message.to).cloned() else {
+            return;
+        };
+
+        tokio::spawn(async move {
+            let _ = handle.step(message).await;
+        });
+    }
+
+    fn snapshot_source(&self, node_id: NodeId) -> Option<Arc<dyn Leader>> {
+        let message_log = self.message_logs.lock().unwrap().get(&node_id)?.clone();
+
+        Some(Arc::new(message_log))
+    }
+}
+
+/// Starts a group of three servers, returning their handles and message logs.
+fn spawn_raft_servers(
+    raft_config: &RaftConfig,
+    transport: &Arc<ChannelTransport>,
+) -> Vec<(RaftHandle, MessageLog)> {
+    let mut servers = Vec::new();
+    for id in 1..=3 {
+        let message_log = MessageLog::new(
+            Arc::new(InMemoryMessageStore::default()),
+            TestMessageStream::new(vec![]).boxed(),
+            ItemErrorPolicy::Abort,
+        )
+        .unwrap();
+        let node = RaftNode::new(
+            id,
+            &[1, 2, 3],
+            raft_config.timeouts(),
+            Box::new(InMemoryRaftStorage::new()),
+            id,
+        )
+        .unwrap();
+        let (handle, _) =
+            spawn_raft_server(node, message_log.clone(), transport.clone(), raft_config);
+        transport.handles.lock().unwrap().insert(id, handle.clone());
+        transport
+            .message_logs
+            .lock()
+            .unwrap()
+            .insert(id, message_log.clone());
+        servers.push((handle, message_log));
+    }
+
+    servers
+}
+
+async fn wait_for_leader(servers: &[(RaftHandle, MessageLog)]) -> (RaftHandle, MessageLog) {
+    loop {
+        if let Some(server) = servers.iter().find(|(handle, _)| handle.is_leader()) {
+            return server.clone();
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+}
+
+fn raft_config() -> RaftConfig {
+    RaftConfig {
+        node_id: Some(1),
+        members: (1..=3)
+            .map(|id| RaftMember {
+                id,
+                url: format!("http://node-{id}"),
+            })
+            .collect(),
+        secret: Some("raft-secret".to_string()),
+        tick_interval: Duration::from_millis(5),
+        ..RaftConfig::default()
+    }
+}
+
+#[tokio::test]
+async fn servers_apply_committed_messages_to_their_logs() {
+    let transport = Arc::new(ChannelTransport::default());
+    let servers = spawn_raft_servers(&raft_config(), &transport);
+
+    let leader = wait_for_leader(&servers).await;
+    let (follower, _) = servers
+        .iter()
+        .find(|(handle, _)| !handle.is_leader())
+        .unwrap();
+
+    let chat_message = ChatMessage::new(DEFAULT_CHANNEL, "replicated");
+    let appended_message = leader
+        .0
+        .append(chat_message.clone())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(appended_message.sequence_number, 1);
+    // Appending the same message again is committed, but not stored again.
+    assert!(leader.0.append(chat_message).await.unwrap().is_none());
+
+    leader.0.read_barrier().await.unwrap();
+    assert_eq!(
+        leader.1.messages_received(DEFAULT_CHANNEL).unwrap().len(),
+        1
+    );
+    let err = follower.read_barrier().await.unwrap_err();
+    assert!(err.is::<NotLeader>());
+    assert_eq!(
+        follower.leader_url(),
+        Some(format!("http://node-{}", leader.0.status().node_id))
+    );
+
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    for (handle, message_log) in &servers {
+        let messages = message_log.messages_received(DEFAULT_CHANNEL).unwrap();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].chat_message.message_text, "replicated");
+        assert_eq!(handle.status().applied_index, 3);
+    }
+}
+
+#[tokio::test]
+async fn servers_replicate_truncations_and_restore_snapshots() {
+    let raft_config = RaftConfig {
+        snapshot_interval: 2,
+        ..raft_config()
+    };
+    let transport = Arc::new(ChannelTransport::default());
+    let servers = spawn_raft_servers(&raft_config, &transport);
+    let (leader, leader_log) = wait_for_leader(&servers).await;
+    let (lagging, lagging_log) = servers
+        .iter()
+        .find(|(handle, _)| !handle.is_leader())
+        .unwrap()
+        .clone();
+
+    transport
+        .isolated
+        .lock()
+        .unwrap()
+        .insert(lagging.status().node_id);
+    for i in 1..=6 {
+        leader
+            .append(ChatMessage::new(DEFAULT_CHANNEL, format!("message {i}")))
+            .await
+            .unwrap();
+    }
+    leader.truncate_before(DEFAULT_CHANNEL, 3).await.unwrap();
+    leader
+        .append(ChatMessage::new("some-other-channel", "message 1"))
+        .await
+        .unwrap();
+    leader.read_barrier().await.unwrap();
+    assert!(leader.status().snapshot_index > 0);
+    assert!(lagging_log.channels().unwrap().is_empty());
+
+    transport.isolated.lock().unwrap().clear();
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert!(lagging.status().snapshot_index > 0);
+    let message_positions = |message_log: &MessageLog, channel| {
+        message_log
+            .messages_received(channel)
+            .unwrap()
+            .into_iter()
+            .map(|message| (message.sequence_number, message.chat_message.id))
+            .collect::<Vec<_>>()
+    };
+    for channel in [DEFAULT_CHANNEL, "some-other-channel"] {
+        assert_eq!(
+            message_positions(&lagging_log, channel),
+            message_positions(&leader_log, channel),
+            "{channel}"
+        );
+    }
+    assert_eq!(
+        lagging_log.truncated_before(DEFAULT_CHANNEL).unwrap(),
+        Some(3)
+    );
+}
+
+#[tokio::test]
+async fn pending_appends_fail_once_the_leader_steps_down() {
+    let transport = Arc::new(ChannelTransport::default());
+    let servers = spawn_raft_servers(&raft_config(), &transport);
+    let (old_leader, _) = wait_for_leader(&servers).await;
+    let old_leader_id = old_leader.status().node_id;
+
+    transport.isolated.lock().unwrap().insert(old_leader_id);
+    let append = tokio::spawn({
+        let old_leader = old_leader.clone();
+        async move {
+            old_leader
+                .append(ChatMessage::new(DEFAULT_CHANNEL, "never committed"))
+                .await
+        }
+    });
+    let others: Vec<_> = servers
+        .iter()
+        .filter(|(handle, _)| handle.status().node_id != old_leader_id)
+        .cloned()
+        .collect();
+    wait_for_leader(&others).await;
+
+    // The old leader learns about the new term and gives up the append instead of letting it
+    // time out.
+    transport.isolated.lock().unwrap().clear();
+    let err = tokio::time::timeout(Duration::from_secs(1), append)
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap_err();
+    assert!(err.is::<NotLeader>());
+}
+
+#[tokio::test]
+async fn raft_messages_need_the_secret() {
+    let raft_config = raft_config();
+    let transport = Arc::new(ChannelTransport::default());
+    let servers = spawn_raft_servers(&raft_config, &transport);
+    let (node, _) = wait_for_leader(&servers).await;
+    let route = raft_message_route(node.clone(), &raft_config);
+    // Would make the node step down if it was accepted.
+    let message = Message {
+        from: 2,
+        to: node.status().node_id,
+        term: 1000,
+        body: MessageBody::RequestVote {
+            last_log_index: 0,
+            last_log_term: 0,
+        },
+    };
+
+    for authorization in [None, Some("Bearer wrong-secret"), Some("raft-secret")] {
+        let mut request = warp::test::request().method("POST").path("/raft");
+        if let Some(authorization) = authorization {
+            request = request.header("authorization", authorization);
+        }
+        let response = request.json(&message).reply(&route).await;
+        assert_eq!(response.status(), 401, "{authorization:?}");
+    }
+    assert!(node.status().term < 1000);
+
+    let response = warp::test::request()
+        .method("POST")
+        .path("/raft")
+        .header("authorization", "Bearer raft-secret")
+        .json(&message)
+        .reply(&route)
+        .await;
+    assert_eq!(response.status(), 204);
+}
+
+#[test]
+fn applied_batches_stay_within_the_deduplication_window() {
+    let append = |index: LogIndex| {
+        (
+            index,
+            Entry {
+                term: 1,
+                command: Command::Append(ChatMessage::new(DEFAULT_CHANNEL, "message")),
+            },
+        )
+    };
+    let truncate = |index: LogIndex| {
+        (
+            index,
+            Entry {
+                term: 1,
+                command: Command::TruncateBefore {
+                    channel: DEFAULT_CHANNEL.to_string(),
+                    sequence_number: 10,
+                },
+            },
+        )
+    };
+    let entries = (1..=2501)
+        .map(|index| match index {
+            1201 => truncate(index),
+            _ => append(index),
+        })
+        .collect();
+
+    let batches: Vec<(LogIndex, LogIndex)> = apply_batches(entries)
+        .iter()
+        .map(|batch| (batch[0].0, batch[batch.len() - 1].0))
+        .collect();
+    assert_eq!(
+        batches,
+        [
+            (1, 1000),
+            (1001, 1200),
+            (1201, 1201),
+            (1202, 2201),
+            (2202, 2501)
+        ]
+    );
+}
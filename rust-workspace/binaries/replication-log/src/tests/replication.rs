@@ -0,0 +1,376 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use common::{
+    forwarder_health::ItemErrorPolicy, ChatMessage, MessagesRemoved, SequenceNumber,
+    SequencedMessage, DEFAULT_CHANNEL, TRUNCATED_BEFORE_HEADER,
+};
+use futures::StreamExt;
+use warp::{http::Response, Filter};
+
+use crate::{
+    message_log::MessageLog,
+    replication::{
+        replicate_once, replicate_up_to, spawn_follower_task, ChannelPosition, EpochFence,
+        HttpLeader, Leader, Replication, ReplicationConfig, ReplicationRound, Role,
+        LEADER_EPOCH_HEADER,
+    },
+    storage::{InMemoryMessageStore, MessageStore},
+};
+
+use super::TestMessageStream;
+
+fn message_log(message_store: Arc<InMemoryMessageStore>) -> MessageLog {
+    MessageLog::new(
+        message_store,
+        TestMessageStream::new(vec![]).boxed(),
+        ItemErrorPolicy::Abort,
+    )
+    .unwrap()
+}
+
+fn append_messages(message_log: &MessageLog, channel: &str, count: usize) {
+    for i in 1..=count {
+        message_log
+            .append(ChatMessage::new(channel, format!("message {i}")))
+            .unwrap();
+    }
+}
+
+/// Returns the sequence numbers and ids of the channel's messages, which have to be the same on
+/// the leader and its followers.
+fn message_positions(message_log: &MessageLog, channel: &str) -> Vec<(u64, String)> {
+    message_log
+        .messages_received(channel)
+        .unwrap()
+        .into_iter()
+        .map(|message| (message.sequence_number, message.chat_message.id.to_string()))
+        .collect()
+}
+
+struct UnavailableLeader;
+
+#[async_trait]
+impl Leader for UnavailableLeader {
+    async fn channel_positions(&self) -> Result<Vec<ChannelPosition>> {
+        Err(anyhow!("the leader is unavailable"))
+    }
+
+    async fn messages_after(
+        &self,
+        _channel: &str,
+        _after: SequenceNumber,
+        _limit: usize,
+    ) -> Result<Vec<SequencedMessage>> {
+        Err(anyhow!("the leader is unavailable"))
+    }
+}
+
+#[tokio::test]
+async fn follower_replicates_every_channel() {
+    let leader = message_log(Arc::default());
+    let follower = message_log(Arc::default());
+    append_messages(&leader, DEFAULT_CHANNEL, 3);
+    append_messages(&leader, "some-other-channel", 2);
+
+    // Messages are retrieved in several pages.
+    let round = replicate_once(&follower, &leader, 2).await.unwrap();
+    assert_eq!(
+        round,
+        ReplicationRound {
+            lag_messages: 5,
+            replicated_messages: 5,
+        }
+    );
+    for channel in [DEFAULT_CHANNEL, "some-other-channel"] {
+        assert_eq!(
+            message_positions(&follower, channel),
+            message_positions(&leader, channel)
+        );
+    }
+
+    append_messages(&leader, DEFAULT_CHANNEL, 1);
+    let round = replicate_once(&follower, &leader, 2).await.unwrap();
+    assert_eq!(round.lag_messages, 1);
+    assert_eq!(
+        message_positions(&follower, DEFAULT_CHANNEL),
+        message_positions(&leader, DEFAULT_CHANNEL)
+    );
+
+    let round = replicate_once(&follower, &leader, 2).await.unwrap();
+    assert_eq!(round.lag_messages, 0);
+}
+
+#[tokio::test]
+async fn follower_keeps_sequence_numbers_of_truncated_leader() {
+    let leader_store = Arc::new(InMemoryMessageStore::default());
+    let leader = message_log(Arc::clone(&leader_store));
+    let follower = message_log(Arc::default());
+    append_messages(&leader, DEFAULT_CHANNEL, 3);
+    append_messages(&leader, "some-other-channel", 2);
+    leader_store.truncate_before(DEFAULT_CHANNEL, 3).unwrap();
+    // All messages of this channel have been removed.
+    leader_store
+        .truncate_before("some-other-channel", 3)
+        .unwrap();
+
+    replicate_once(&follower, &leader, 10).await.unwrap();
+    assert_eq!(
+        message_positions(&follower, DEFAULT_CHANNEL),
+        message_positions(&leader, DEFAULT_CHANNEL)
+    );
+    assert_eq!(follower.truncated_before(DEFAULT_CHANNEL).unwrap(), Some(3));
+    assert_eq!(
+        follower.next_sequence_number("some-other-channel").unwrap(),
+        3
+    );
+
+    append_messages(&leader, "some-other-channel", 1);
+    replicate_once(&follower, &leader, 10).await.unwrap();
+    assert_eq!(
+        message_positions(&follower, "some-other-channel"),
+        message_positions(&leader, "some-other-channel")
+    );
+}
+
+#[tokio::test]
+async fn promoted_follower_stops_replicating() {
+    let leader = message_log(Arc::default());
+    let follower = message_log(Arc::default());
+    let replication = Replication::follower("http://leader", Arc::default());
+    let _follower_task = spawn_follower_task(
+        follower.clone(),
+        replication.clone(),
+        Arc::new(leader.clone()),
+        ReplicationConfig {
+            poll_interval: Duration::from_millis(10),
+            ..ReplicationConfig::default()
+        },
+    );
+
+    append_messages(&leader, DEFAULT_CHANNEL, 2);
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let status = replication.status();
+    assert_eq!(status.role, Role::Follower);
+    assert_eq!(status.leader_urls, ["http://leader"]);
+    assert_eq!(status.replicated_messages, 2);
+    assert_eq!(status.lag_messages, 0);
+    assert!(status.seconds_since_last_sync.is_some());
+    assert_eq!(
+        message_positions(&follower, DEFAULT_CHANNEL),
+        message_positions(&leader, DEFAULT_CHANNEL)
+    );
+
+    replication.promote(1).await.unwrap();
+    assert!(replication.is_leader());
+    assert!(replication.leader_urls().is_empty());
+    assert_eq!(replication.epoch(), 1);
+    assert!(replication.promote(2).await.is_err());
+
+    // The former leader's messages are no longer replicated; the promoted follower continues its
+    // channels' sequence numbers instead.
+    append_messages(&leader, DEFAULT_CHANNEL, 1);
+    let message = follower
+        .append(ChatMessage::new(DEFAULT_CHANNEL, "message after failover"))
+        .unwrap()
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(message.sequence_number, 3);
+    assert_eq!(
+        follower.messages_received(DEFAULT_CHANNEL).unwrap().len(),
+        3
+    );
+}
+
+#[tokio::test]
+async fn followers_report_replication_errors() {
+    let replication = Replication::follower("http://leader", Arc::default());
+    let follower = message_log(Arc::default());
+    let _follower_task = spawn_follower_task(
+        follower,
+        replication.clone(),
+        Arc::new(UnavailableLeader),
+        ReplicationConfig::default(),
+    );
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let status = replication.status();
+    assert_eq!(
+        status.last_error.as_deref(),
+        Some("the leader is unavailable")
+    );
+    assert!(status.seconds_since_last_sync.is_none());
+    assert_eq!(
+        Replication::leader(Arc::default()).status().role,
+        Role::Leader
+    );
+}
+
+#[tokio::test]
+async fn promotion_needs_a_greater_epoch() {
+    let epoch_fence = Arc::new(EpochFence::default());
+    epoch_fence.observe(2).await.unwrap();
+    let replication = Replication::follower("http://leader", Arc::clone(&epoch_fence));
+
+    let err = replication.promote(2).await.unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "the new epoch has to be greater than the current epoch 2"
+    );
+    assert!(!replication.is_leader());
+
+    replication.promote(3).await.unwrap();
+    assert_eq!(replication.status().epoch, 3);
+}
+
+#[tokio::test]
+async fn epoch_is_kept_across_restarts() {
+    let data_dir = tempfile::tempdir().unwrap();
+    let path = data_dir.path().join("replication-epoch");
+
+    let epoch_fence = EpochFence::open(path.clone()).unwrap();
+    assert_eq!(epoch_fence.epoch(), 0);
+    epoch_fence.observe(4).await.unwrap();
+
+    let epoch_fence = EpochFence::open(path).unwrap();
+    assert_eq!(epoch_fence.epoch(), 4);
+    assert!(epoch_fence.observe(3).await.is_err());
+}
+
+/// Serves an HTTP leader in the given epoch, recording the requested paths and queries.
+fn serve_leader(epoch: u64) -> (String, Arc<Mutex<Vec<String>>>) {
+    let requests = Arc::new(Mutex::new(Vec::new()));
+    let recorded_requests = Arc::clone(&requests);
+    let routes = warp::path::full()
+        .and(warp::query::raw().or(warp::any().map(String::new)).unify())
+        .map(move |path: warp::path::FullPath, query: String| {
+            recorded_requests
+                .lock()
+                .unwrap()
+                .push(format!("{}?{query}", path.as_str()));
+            let body = if path.as_str() == "/channels" {
+                r#"[{"channel":"a channel/with?slashes","next_sequence_number":1}]"#
+            } else {
+                "[]"
+            };
+            Response::builder()
+                .header(LEADER_EPOCH_HEADER, epoch)
+                .body(body)
+        });
+    let (address, server) = warp::serve(routes).bind_ephemeral(([127, 0, 0, 1], 0));
+    tokio::spawn(server);
+
+    (format!("http://{address}"), requests)
+}
+
+#[tokio::test]
+async fn http_leader_encodes_channel_names() {
+    let (leader_url, requests) = serve_leader(0);
+    let leader = HttpLeader::new(leader_url, Duration::from_secs(5), Arc::default()).unwrap();
+
+    let channel_positions = leader.channel_positions().await.unwrap();
+    leader
+        .messages_after(&channel_positions[0].channel, 7, 100)
+        .await
+        .unwrap();
+    insta::assert_debug_snapshot!(requests.lock().unwrap(), @r###"
+    [
+        "/channels?",
+        "/messages/a%20channel%2Fwith%3Fslashes?after=7&limit=100",
+    ]
+    "###);
+}
+
+/// Serves the messages of the log like the `/messages` endpoint of the replication log, reporting
+/// removed messages by the [`TRUNCATED_BEFORE_HEADER`].
+fn serve_messages(message_log: MessageLog) -> String {
+    let routes = warp::path!("messages" / String)
+        .and(warp::query::<HashMap<String, u64>>())
+        .map(move |channel: String, query: HashMap<String, u64>| {
+            let after = query["after"];
+            let messages = message_log
+                .messages_after(&channel, after, Some(query["limit"] as usize))
+                .unwrap();
+            let mut response = Response::builder();
+            if let Some(truncated_before) = message_log.truncated_before(&channel).unwrap() {
+                if messages
+                    .first()
+                    .map_or(truncated_before, |message| message.sequence_number)
+                    > after + 1
+                {
+                    response = response.header(TRUNCATED_BEFORE_HEADER, truncated_before);
+                }
+            }
+            response.body(serde_json::to_string(&messages).unwrap())
+        });
+    let (address, server) = warp::serve(routes).bind_ephemeral(([127, 0, 0, 1], 0));
+    tokio::spawn(server);
+
+    format!("http://{address}")
+}
+
+#[tokio::test]
+async fn lagging_followers_skip_messages_removed_by_the_leader() {
+    let leader_store = Arc::new(InMemoryMessageStore::default());
+    let leader_log = message_log(Arc::clone(&leader_store));
+    let follower = message_log(Arc::default());
+    append_messages(&leader_log, DEFAULT_CHANNEL, 2);
+    replicate_once(&follower, &leader_log, 10).await.unwrap();
+    append_messages(&leader_log, DEFAULT_CHANNEL, 3);
+    leader_store.truncate_before(DEFAULT_CHANNEL, 4).unwrap();
+    let leader = HttpLeader::new(
+        serve_messages(leader_log.clone()),
+        Duration::from_secs(5),
+        Arc::default(),
+    )
+    .unwrap();
+
+    let err = leader
+        .messages_after(DEFAULT_CHANNEL, 2, 10)
+        .await
+        .unwrap_err();
+    assert_eq!(
+        err.downcast_ref::<MessagesRemoved>(),
+        Some(&MessagesRemoved {
+            truncated_before: 4
+        })
+    );
+
+    let leader_positions = leader_log.channel_positions().unwrap();
+    replicate_up_to(&follower, &leader, leader_positions, 10)
+        .await
+        .unwrap();
+    assert_eq!(follower.truncated_before(DEFAULT_CHANNEL).unwrap(), Some(4));
+    let sequence_numbers: Vec<SequenceNumber> = follower
+        .messages_received(DEFAULT_CHANNEL)
+        .unwrap()
+        .into_iter()
+        .map(|message| message.sequence_number)
+        .collect();
+    assert_eq!(sequence_numbers, [4, 5]);
+}
+
+#[tokio::test]
+async fn followers_refuse_superseded_leaders() {
+    let (leader_url, _) = serve_leader(1);
+    let epoch_fence = Arc::new(EpochFence::default());
+    let leader =
+        HttpLeader::new(leader_url, Duration::from_secs(5), Arc::clone(&epoch_fence)).unwrap();
+
+    leader.channel_positions().await.unwrap();
+    assert_eq!(epoch_fence.epoch(), 1);
+
+    // Another follower has been promoted and replicated meanwhile.
+    epoch_fence.observe(2).await.unwrap();
+    let err = leader.channel_positions().await.unwrap_err();
+    assert_eq!(
+        format!("{err:#}").split_once(": ").unwrap().1,
+        "the leader's epoch 1 has been superseded by epoch 2"
+    );
+}
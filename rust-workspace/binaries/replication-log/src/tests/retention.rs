@@ -0,0 +1,212 @@
+use std::time::{Duration, SystemTime};
+
+use common::{ChatMessage, MessageId, DEFAULT_CHANNEL};
+
+use crate::{
+    retention::{enforce_retention, RetentionConfig, RetentionPolicy},
+    storage::{InMemoryMessageStore, MessageStore},
+};
+
+/// A store with messages of the default channel with the given texts, created the given time
+/// before `now`.
+fn message_store(now: SystemTime, messages: &[(&str, Duration)]) -> InMemoryMessageStore {
+    let message_store = InMemoryMessageStore::default();
+    for &(message_text, age) in messages {
+        message_store
+            .append(ChatMessage {
+                id: MessageId::from_datetime(now - age),
+                channel: DEFAULT_CHANNEL.to_string(),
+                message_text: message_text.to_string(),
+            })
+            .unwrap();
+    }
+
+    message_store
+}
+
+fn config(channels: &[(&str, RetentionPolicy)]) -> RetentionConfig {
+    RetentionConfig {
+        channels: channels
+            .iter()
+            .map(|(pattern, policy)| (pattern.to_string(), policy.clone()))
+            .collect(),
+        ..RetentionConfig::default()
+    }
+}
+
+#[test]
+fn retention_horizon_applies_every_limit() {
+    let now = SystemTime::now();
+    let minute = Duration::from_secs(60);
+    let message_store = message_store(
+        now,
+        &[
+            ("first", 3 * minute),
+            ("second", 2 * minute),
+            ("third", minute),
+        ],
+    );
+
+    let horizon = |policy: RetentionPolicy| {
+        policy
+            .retention_horizon(&message_store, DEFAULT_CHANNEL, now)
+            .unwrap()
+    };
+
+    assert_eq!(horizon(RetentionPolicy::unbounded()), None);
+    assert_eq!(
+        horizon(RetentionPolicy {
+            max_messages: Some(2),
+            ..RetentionPolicy::default()
+        }),
+        Some(2)
+    );
+    assert_eq!(
+        horizon(RetentionPolicy {
+            max_messages: Some(0),
+            ..RetentionPolicy::default()
+        }),
+        Some(4)
+    );
+    // "third" and "second" take 11 bytes.
+    assert_eq!(
+        horizon(RetentionPolicy {
+            max_bytes: Some(11),
+            ..RetentionPolicy::default()
+        }),
+        Some(2)
+    );
+    assert_eq!(
+        horizon(RetentionPolicy {
+            max_age: Some(150 * Duration::from_secs(1)),
+            ..RetentionPolicy::default()
+        }),
+        Some(2)
+    );
+    // The strictest limit wins.
+    assert_eq!(
+        horizon(RetentionPolicy {
+            max_messages: Some(2),
+            max_age: Some(90 * Duration::from_secs(1)),
+            max_bytes: Some(1024),
+        }),
+        Some(3)
+    );
+}
+
+#[test]
+fn channels_are_subject_to_the_longest_matching_pattern() {
+    let policy = |max_messages| RetentionPolicy {
+        max_messages: Some(max_messages),
+        ..RetentionPolicy::default()
+    };
+    let config = config(&[
+        ("logs-*", policy(1)),
+        ("logs-*-debug", policy(2)),
+        ("logs-audit", policy(3)),
+        ("*-archive", policy(4)),
+    ]);
+
+    let max_messages = |channel| config.policy(channel).max_messages;
+    assert_eq!(max_messages("logs-web"), Some(1));
+    assert_eq!(max_messages("logs-"), Some(1));
+    assert_eq!(max_messages("logs-web-debug"), Some(2));
+    assert_eq!(max_messages("logs-audit"), Some(3));
+    assert_eq!(max_messages("chat-archive"), Some(4));
+    assert_eq!(max_messages(DEFAULT_CHANNEL), None);
+    assert_eq!(max_messages("log-web"), None);
+}
+
+#[test]
+fn enforce_retention_truncates_channels() {
+    let message_store = InMemoryMessageStore::default();
+    for i in 1..=3 {
+        message_store
+            .append(ChatMessage::new(DEFAULT_CHANNEL, format!("message {i}")))
+            .unwrap();
+        message_store
+            .append(ChatMessage::new("chat-archive", format!("message {i}")))
+            .unwrap();
+    }
+
+    let config = config(&[(
+        DEFAULT_CHANNEL,
+        RetentionPolicy {
+            max_messages: Some(1),
+            ..RetentionPolicy::default()
+        },
+    )]);
+    enforce_retention(&message_store, &config, SystemTime::now()).unwrap();
+
+    assert_eq!(
+        message_store.truncated_before(DEFAULT_CHANNEL).unwrap(),
+        Some(3)
+    );
+    assert_eq!(
+        message_store
+            .messages_after(DEFAULT_CHANNEL, 0, None)
+            .unwrap()
+            .len(),
+        1
+    );
+    assert_eq!(
+        message_store.truncated_before("chat-archive").unwrap(),
+        None
+    );
+    assert_eq!(
+        message_store
+            .messages_after("chat-archive", 0, None)
+            .unwrap()
+            .len(),
+        3
+    );
+}
+
+#[test]
+fn invalid_retention_config() {
+    let mut config = config(&[("", RetentionPolicy::unbounded())]);
+    assert!(config.validate().is_err());
+
+    config.channels.clear();
+    assert!(config.validate().is_ok());
+    config.compaction_interval = Duration::ZERO;
+    assert!(config.validate().is_err());
+}
+
+#[test]
+fn retention_horizon_only_considers_retained_messages() {
+    let now = SystemTime::now();
+    let message_store = message_store(now, &[("x", Duration::ZERO); 2500]);
+    message_store.truncate_before(DEFAULT_CHANNEL, 100).unwrap();
+
+    let horizon = |policy: RetentionPolicy| {
+        policy
+            .retention_horizon(&message_store, DEFAULT_CHANNEL, now)
+            .unwrap()
+    };
+
+    // The sizes of the messages are summed up across several pages.
+    assert_eq!(
+        horizon(RetentionPolicy {
+            max_bytes: Some(1500),
+            ..RetentionPolicy::default()
+        }),
+        Some(1001)
+    );
+    // The channel has been truncated before already.
+    assert_eq!(
+        horizon(RetentionPolicy {
+            max_messages: Some(2450),
+            max_bytes: Some(2450),
+            ..RetentionPolicy::default()
+        }),
+        None
+    );
+    assert_eq!(
+        horizon(RetentionPolicy {
+            max_age: Some(Duration::from_secs(60)),
+            ..RetentionPolicy::default()
+        }),
+        None
+    );
+}
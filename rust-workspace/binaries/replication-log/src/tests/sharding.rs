@@ -0,0 +1,298 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    sync::Arc,
+    time::Duration,
+};
+
+use common::{
+    forwarder_health::ItemErrorPolicy,
+    sharding::{Shard, ShardMap},
+    ChatMessage,
+};
+use futures::StreamExt;
+
+use crate::{
+    message_log::MessageLog,
+    replication::{replicate_once, Leader, Replication},
+    sharding::{
+        buffer_until_promoted, remove_foreign_channels, PreviousShards, ShardAssignment,
+        ShardingConfig,
+    },
+    storage::InMemoryMessageStore,
+};
+
+use super::TestMessageStream;
+
+fn message_log() -> MessageLog {
+    MessageLog::new(
+        Arc::new(InMemoryMessageStore::default()),
+        TestMessageStream::new(vec![]).boxed(),
+        ItemErrorPolicy::Abort,
+    )
+    .unwrap()
+}
+
+fn shard_map(shard_count: usize) -> ShardMap {
+    ShardMap::new(
+        (1..=shard_count)
+            .map(|i| Shard {
+                id: format!("shard-{i}"),
+                url: format!("http://replication-log-shard-{i}:80"),
+            })
+            .collect(),
+    )
+}
+
+fn channels() -> Vec<String> {
+    (0..30).map(|i| format!("channel-{i}")).collect()
+}
+
+/// Returns the sequence numbers and ids of every channel's messages.
+fn message_positions(message_log: &MessageLog) -> BTreeMap<String, Vec<(u64, String)>> {
+    message_log
+        .channels()
+        .unwrap()
+        .into_iter()
+        .map(|channel| {
+            let positions = message_log
+                .messages_received(&channel)
+                .unwrap()
+                .into_iter()
+                .map(|message| (message.sequence_number, message.chat_message.id.to_string()))
+                .collect();
+            (channel, positions)
+        })
+        .filter(|(_, positions): &(String, Vec<_>)| !positions.is_empty())
+        .collect()
+}
+
+#[tokio::test]
+async fn added_shard_takes_over_its_channels() {
+    let previous_shard_map = shard_map(2);
+    let old_shards: BTreeMap<_, _> = previous_shard_map
+        .shards
+        .iter()
+        .map(|shard| (shard.id.clone(), message_log()))
+        .collect();
+    let previous_assignment =
+        ShardAssignment::new("shard-1".to_string(), previous_shard_map.clone());
+    for channel in channels() {
+        let old_shard = &old_shards[&previous_assignment.owner(&channel).id];
+        for i in 1..=3 {
+            old_shard
+                .append(ChatMessage::new(channel.as_str(), format!("message {i}")))
+                .unwrap();
+        }
+    }
+
+    let assignment = ShardAssignment::new("shard-3".to_string(), shard_map(3));
+    let previous_shards = PreviousShards::new(assignment.clone(), previous_shard_map, |shard| {
+        Arc::new(old_shards[&shard.id].clone())
+    });
+    let new_shard = message_log();
+    let mut shards: HashMap<String, Arc<dyn Leader>> = old_shards
+        .iter()
+        .map(|(shard_id, old_shard)| (shard_id.clone(), Arc::new(old_shard.clone()) as _))
+        .collect();
+    shards.insert("shard-3".to_string(), Arc::new(new_shard.clone()));
+
+    // The previous shards keep the moved channels until the new shard has copied them.
+    for (shard_id, old_shard) in &old_shards {
+        let removed_channels = remove_foreign_channels(
+            old_shard,
+            &ShardAssignment::new(shard_id.clone(), shard_map(3)),
+            &shards,
+        )
+        .await
+        .unwrap();
+        assert!(removed_channels.removed.is_empty());
+        assert!(!removed_channels.not_copied.is_empty());
+    }
+
+    replicate_once(&new_shard, &previous_shards, 2)
+        .await
+        .unwrap();
+
+    // The new shard has copied exactly the channels that move to it, with their sequence numbers.
+    let moved_channels: Vec<_> = channels()
+        .into_iter()
+        .filter(|channel| assignment.owns(channel))
+        .collect();
+    assert!(!moved_channels.is_empty());
+    let new_shard_positions = message_positions(&new_shard);
+    assert!(new_shard_positions
+        .keys()
+        .eq(moved_channels.iter().collect::<BTreeSet<_>>()));
+    for (channel, positions) in &new_shard_positions {
+        let old_shard = &old_shards[&previous_assignment.owner(channel).id];
+        assert_eq!(message_positions(old_shard)[channel], *positions);
+    }
+
+    // Afterwards, the previous shards drop the moved channels.
+    for (shard_id, old_shard) in &old_shards {
+        let old_channels = message_positions(old_shard).len();
+        let removed_channels = remove_foreign_channels(
+            old_shard,
+            &ShardAssignment::new(shard_id.clone(), shard_map(3)),
+            &shards,
+        )
+        .await
+        .unwrap();
+        assert!(removed_channels.not_copied.is_empty());
+        let removed_channels = removed_channels.removed;
+
+        assert!(removed_channels
+            .iter()
+            .all(|channel| moved_channels.contains(channel)));
+        assert_eq!(
+            message_positions(old_shard).len(),
+            old_channels - removed_channels.len()
+        );
+        // Sequence numbers are not reused, should the channel ever move back.
+        for channel in &removed_channels {
+            assert_eq!(old_shard.next_sequence_number(channel).unwrap(), 4);
+        }
+    }
+    let remaining_channels: usize = old_shards
+        .values()
+        .map(|old_shard| message_positions(old_shard).len())
+        .sum();
+    assert_eq!(remaining_channels + moved_channels.len(), channels().len());
+}
+
+#[tokio::test]
+async fn new_shard_stores_the_buffered_messages_once_promoted() {
+    let (sender, receiver) = futures::channel::mpsc::unbounded();
+    let replication = Replication::follower_of_several(
+        vec!["http://shard-1".to_string(), "http://shard-2".to_string()],
+        Arc::default(),
+    );
+    let message_store = Arc::new(InMemoryMessageStore::default());
+    let new_shard = MessageLog::new(
+        message_store.clone(),
+        buffer_until_promoted(receiver.map(Ok).boxed(), replication.clone(), message_store),
+        ItemErrorPolicy::Abort,
+    )
+    .unwrap();
+
+    let messages: Vec<_> = (1..=3)
+        .map(|i| ChatMessage::new("channel-1", format!("message {i}")))
+        .collect();
+    for message in &messages {
+        sender.unbounded_send(message.clone()).unwrap();
+    }
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert!(new_shard.messages_received("channel-1").unwrap().is_empty());
+
+    // The first message has been copied from the previous shard meanwhile.
+    new_shard.append(messages[0].clone()).unwrap();
+    replication.promote(1).await.unwrap();
+    sender
+        .unbounded_send(ChatMessage::new("channel-1", "message 4"))
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    let stored_messages: Vec<_> = new_shard
+        .messages_received("channel-1")
+        .unwrap()
+        .into_iter()
+        .map(|message| (message.sequence_number, message.chat_message.message_text))
+        .collect();
+    insta::assert_debug_snapshot!(stored_messages, @r###"
+    [
+        (
+            1,
+            "message 1",
+        ),
+        (
+            2,
+            "message 2",
+        ),
+        (
+            3,
+            "message 3",
+        ),
+        (
+            4,
+            "message 4",
+        ),
+    ]
+    "###);
+}
+
+#[tokio::test]
+async fn new_shard_keeps_every_buffered_message() {
+    let (sender, receiver) = futures::channel::mpsc::unbounded();
+    let replication = Replication::follower_of_several(
+        vec!["http://shard-1".to_string(), "http://shard-2".to_string()],
+        Arc::default(),
+    );
+    let message_store = Arc::new(InMemoryMessageStore::default());
+    let new_shard = MessageLog::new(
+        message_store.clone(),
+        buffer_until_promoted(receiver.map(Ok).boxed(), replication.clone(), message_store),
+        ItemErrorPolicy::Abort,
+    )
+    .unwrap();
+
+    // More messages than the deduplication window, of which the previous shard stored the older
+    // ones before the shard map changed; these have been copied by the time of the promotion.
+    let messages: Vec<_> = (1..=2500)
+        .map(|i| ChatMessage::new("channel-1", format!("message {i}")))
+        .collect();
+    for message in &messages {
+        sender.unbounded_send(message.clone()).unwrap();
+    }
+    for message in &messages[..1200] {
+        new_shard.append(message.clone()).unwrap();
+    }
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    replication.promote(1).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    let stored_messages: Vec<_> = new_shard
+        .messages_received("channel-1")
+        .unwrap()
+        .into_iter()
+        .map(|message| (message.sequence_number, message.chat_message.id))
+        .collect();
+    let expected_messages: Vec<_> = messages
+        .iter()
+        .zip(1..)
+        .map(|(message, sequence_number)| (sequence_number, message.id))
+        .collect();
+    assert_eq!(stored_messages, expected_messages);
+}
+
+#[test]
+fn sharding_config_is_validated() {
+    let config = |shard_id: Option<&str>, shard_map, previous_shard_map| ShardingConfig {
+        shard_id: shard_id.map(str::to_string),
+        shard_map,
+        previous_shard_map,
+    };
+
+    assert!(config(None, None, None).validate().is_ok());
+    assert!(config(Some("shard-2"), Some(shard_map(2)), None)
+        .validate()
+        .is_ok());
+    assert!(
+        config(Some("shard-3"), Some(shard_map(3)), Some(shard_map(2)))
+            .validate()
+            .is_ok()
+    );
+
+    let error = |config: ShardingConfig| format!("{:#}", config.validate().unwrap_err());
+    insta::assert_snapshot!(error(config(None, Some(shard_map(2)), None)), @"a shard map needs the id of this instance's shard");
+    insta::assert_snapshot!(error(config(Some("shard-1"), None, None)), @r###"
+    shard "shard-1" needs a shard map
+    "###);
+    insta::assert_snapshot!(error(config(Some("shard-3"), Some(shard_map(2)), None)), @r###"
+    the shard map must include this instance's shard "shard-3"
+    "###);
+    let mut invalid_shard_map = shard_map(2);
+    invalid_shard_map.shards[1].url = "shard-2".to_string();
+    insta::assert_snapshot!(error(config(Some("shard-1"), Some(invalid_shard_map), None)), @r###"
+    invalid shard map: invalid URL of shard "shard-2": relative URL without a base
+    "###);
+}
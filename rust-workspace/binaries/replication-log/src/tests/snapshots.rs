@@ -0,0 +1,107 @@
+use std::sync::Arc;
+
+use common::{
+    forwarder_health::ItemErrorPolicy, snapshot::ChannelSnapshot, ChatMessage, DEFAULT_CHANNEL,
+};
+use futures::StreamExt;
+
+use crate::{
+    message_log::MessageLog,
+    snapshots::Snapshots,
+    storage::{InMemoryMessageStore, MessageStore},
+};
+
+use super::TestMessageStream;
+
+fn message_log(message_store: Arc<InMemoryMessageStore>) -> MessageLog {
+    MessageLog::new(
+        message_store,
+        TestMessageStream::new(vec![]).boxed(),
+        ItemErrorPolicy::Abort,
+    )
+    .unwrap()
+}
+
+fn append_messages(message_log: &MessageLog, channel: &str, message_texts: &[&str]) {
+    for message_text in message_texts {
+        message_log
+            .append(ChatMessage::new(channel, *message_text))
+            .unwrap();
+    }
+}
+
+/// Decodes the channel's snapshot, returning its watermark and message texts.
+fn snapshot(snapshots: &Snapshots, channel: &str) -> (u64, Vec<String>) {
+    let stored_snapshot = snapshots.get(channel).unwrap();
+    let snapshot = ChannelSnapshot::decode(channel, &stored_snapshot.encoded_snapshot).unwrap();
+    let message_texts = snapshot
+        .messages
+        .into_iter()
+        .map(|message| message.chat_message.message_text)
+        .collect();
+
+    (snapshot.watermark, message_texts)
+}
+
+#[tokio::test]
+async fn snapshots_contain_the_most_recent_messages() {
+    let message_log = message_log(Arc::default());
+    let snapshots = Snapshots::default();
+    append_messages(&message_log, DEFAULT_CHANNEL, &["message 1", "message 2"]);
+    append_messages(&message_log, "some-other-channel", &["unrelated message"]);
+
+    snapshots.take_snapshots(&message_log, 2).unwrap();
+    insta::assert_debug_snapshot!(snapshot(&snapshots, DEFAULT_CHANNEL), @r###"
+    (
+        2,
+        [
+            "message 1",
+            "message 2",
+        ],
+    )
+    "###);
+    insta::assert_debug_snapshot!(snapshot(&snapshots, "some-other-channel"), @r###"
+    (
+        1,
+        [
+            "unrelated message",
+        ],
+    )
+    "###);
+    assert!(snapshots.get("yet-another-channel").is_none());
+
+    append_messages(&message_log, DEFAULT_CHANNEL, &["message 3"]);
+    snapshots.take_snapshots(&message_log, 2).unwrap();
+    insta::assert_debug_snapshot!(snapshot(&snapshots, DEFAULT_CHANNEL), @r###"
+    (
+        3,
+        [
+            "message 2",
+            "message 3",
+        ],
+    )
+    "###);
+}
+
+#[tokio::test]
+async fn snapshots_omit_removed_messages() {
+    let message_store = Arc::new(InMemoryMessageStore::default());
+    let message_log = message_log(Arc::clone(&message_store));
+    let snapshots = Snapshots::default();
+    append_messages(&message_log, DEFAULT_CHANNEL, &["message 1", "message 2"]);
+    snapshots.take_snapshots(&message_log, 10).unwrap();
+
+    message_store.truncate_before(DEFAULT_CHANNEL, 2).unwrap();
+    append_messages(&message_log, DEFAULT_CHANNEL, &["message 3"]);
+    snapshots.take_snapshots(&message_log, 10).unwrap();
+
+    insta::assert_debug_snapshot!(snapshot(&snapshots, DEFAULT_CHANNEL), @r###"
+    (
+        3,
+        [
+            "message 2",
+            "message 3",
+        ],
+    )
+    "###);
+}
@@ -0,0 +1,520 @@
+use std::{fs::OpenOptions, io::Write, sync::Arc, time::Duration};
+
+use common::{forwarder_health::ItemErrorPolicy, ChatMessage, DEFAULT_CHANNEL};
+use futures::StreamExt;
+
+use crate::{
+    message_log::MessageLog,
+    storage::{
+        open_message_store, FsyncPolicy, InMemoryMessageStore, MessageStore, RedbMessageStore,
+        SegmentFileMessageStore, SegmentFileOptions, StorageBackend,
+    },
+};
+
+use super::TestMessageStream;
+
+fn message_texts(message_store: &dyn MessageStore, channel: &str) -> Vec<(u64, String)> {
+    message_store
+        .messages_after(channel, 0, None)
+        .unwrap()
+        .into_iter()
+        .map(|message| (message.sequence_number, message.chat_message.message_text))
+        .collect()
+}
+
+fn segment_files(directory: &std::path::Path) -> Vec<std::path::PathBuf> {
+    let mut segment_files: Vec<_> = std::fs::read_dir(directory)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| {
+            path.extension()
+                .is_some_and(|extension| extension == "segment")
+        })
+        .collect();
+    segment_files.sort();
+
+    segment_files
+}
+
+fn append_messages_on_two_channels(message_store: &dyn MessageStore) {
+    message_store
+        .append(ChatMessage::new(DEFAULT_CHANNEL, "message 1"))
+        .unwrap();
+    message_store
+        .append(ChatMessage::new("some-other-channel", "unrelated message"))
+        .unwrap();
+    message_store
+        .append(ChatMessage::new(DEFAULT_CHANNEL, "message 2"))
+        .unwrap();
+}
+
+/// Truncates the default channel after [`append_messages_on_two_channels`] and appends another
+/// message.
+fn truncate_and_append(message_store: &dyn MessageStore) {
+    message_store.truncate_before(DEFAULT_CHANNEL, 2).unwrap();
+    // Truncating never brings messages back.
+    message_store.truncate_before(DEFAULT_CHANNEL, 1).unwrap();
+    message_store
+        .append(ChatMessage::new(DEFAULT_CHANNEL, "message 3"))
+        .unwrap();
+}
+
+fn assert_truncated(message_store: &dyn MessageStore) {
+    assert_eq!(
+        message_store.truncated_before(DEFAULT_CHANNEL).unwrap(),
+        Some(2)
+    );
+    assert_eq!(
+        message_store
+            .truncated_before("some-other-channel")
+            .unwrap(),
+        None
+    );
+    assert_eq!(message_texts(message_store, "some-other-channel").len(), 1);
+
+    let mut channels = message_store.channels().unwrap();
+    channels.sort();
+    assert_eq!(channels, [DEFAULT_CHANNEL, "some-other-channel"]);
+}
+
+#[test]
+fn in_memory_store_assigns_sequence_numbers() {
+    let message_store = InMemoryMessageStore::default();
+
+    append_messages_on_two_channels(&message_store);
+
+    insta::assert_debug_snapshot!(message_texts(&message_store, DEFAULT_CHANNEL), @r###"
+    [
+        (
+            1,
+            "message 1",
+        ),
+        (
+            2,
+            "message 2",
+        ),
+    ]
+    "###);
+}
+
+#[test]
+fn redb_store_reads_ranges_per_channel() {
+    let directory = tempfile::tempdir().unwrap();
+    let message_store = RedbMessageStore::open(directory.path().join("messages.redb")).unwrap();
+
+    append_messages_on_two_channels(&message_store);
+
+    insta::assert_debug_snapshot!(message_texts(&message_store, DEFAULT_CHANNEL), @r###"
+    [
+        (
+            1,
+            "message 1",
+        ),
+        (
+            2,
+            "message 2",
+        ),
+    ]
+    "###);
+    insta::assert_debug_snapshot!(message_texts(&message_store, "some-other-channel"), @r###"
+    [
+        (
+            1,
+            "unrelated message",
+        ),
+    ]
+    "###);
+
+    let messages_after_first = message_store
+        .messages_after(DEFAULT_CHANNEL, 1, Some(1))
+        .unwrap();
+    assert_eq!(messages_after_first.len(), 1);
+    assert_eq!(messages_after_first[0].sequence_number, 2);
+    assert!(message_store
+        .messages_after(DEFAULT_CHANNEL, 2, None)
+        .unwrap()
+        .is_empty());
+}
+
+#[test]
+fn redb_store_keeps_messages_after_reopening() {
+    let directory = tempfile::tempdir().unwrap();
+    let database_path = directory.path().join("messages.redb");
+
+    {
+        let message_store = RedbMessageStore::open(&database_path).unwrap();
+        append_messages_on_two_channels(&message_store);
+    }
+
+    let message_store = RedbMessageStore::open(&database_path).unwrap();
+    assert_eq!(
+        message_store
+            .recent_message_ids(DEFAULT_CHANNEL, 10)
+            .unwrap()
+            .len(),
+        2
+    );
+
+    message_store
+        .append(ChatMessage::new(DEFAULT_CHANNEL, "message 3"))
+        .unwrap();
+    insta::assert_debug_snapshot!(message_texts(&message_store, DEFAULT_CHANNEL), @r###"
+    [
+        (
+            1,
+            "message 1",
+        ),
+        (
+            2,
+            "message 2",
+        ),
+        (
+            3,
+            "message 3",
+        ),
+    ]
+    "###);
+}
+
+#[test]
+fn every_store_truncates_channels() {
+    let directory = tempfile::tempdir().unwrap();
+
+    for backend in ["memory", "segment-files", "redb"] {
+        let backend: StorageBackend = backend.parse().unwrap();
+        let message_store = open_message_store(
+            backend,
+            Some(directory.path()),
+            SegmentFileOptions::default(),
+        )
+        .unwrap();
+
+        append_messages_on_two_channels(message_store.as_ref());
+        truncate_and_append(message_store.as_ref());
+        message_store.compact().unwrap();
+
+        assert_truncated(message_store.as_ref());
+        assert_eq!(
+            message_store.next_sequence_number(DEFAULT_CHANNEL).unwrap(),
+            4
+        );
+        assert_eq!(
+            message_store
+                .next_sequence_number("unknown-channel")
+                .unwrap(),
+            1
+        );
+        assert_eq!(
+            message_texts(message_store.as_ref(), DEFAULT_CHANNEL),
+            [(2, "message 2".to_string()), (3, "message 3".to_string())],
+            "{backend:?}"
+        );
+    }
+}
+
+#[test]
+fn truncating_all_messages_keeps_sequence_numbers() {
+    let message_store = InMemoryMessageStore::default();
+    append_messages_on_two_channels(&message_store);
+
+    message_store.truncate_before(DEFAULT_CHANNEL, 10).unwrap();
+    assert!(message_texts(&message_store, DEFAULT_CHANNEL).is_empty());
+
+    // Sequence numbers are never reused, even after all messages have been removed.
+    let message = message_store
+        .append(ChatMessage::new(DEFAULT_CHANNEL, "message 3"))
+        .unwrap();
+    assert_eq!(message.sequence_number, 10);
+}
+
+#[test]
+fn redb_store_keeps_truncation_after_reopening() {
+    let directory = tempfile::tempdir().unwrap();
+    let database_path = directory.path().join("messages.redb");
+
+    {
+        let message_store = RedbMessageStore::open(&database_path).unwrap();
+        append_messages_on_two_channels(&message_store);
+        message_store.truncate_before(DEFAULT_CHANNEL, 3).unwrap();
+    }
+
+    let message_store = RedbMessageStore::open(&database_path).unwrap();
+    assert!(message_store
+        .recent_message_ids(DEFAULT_CHANNEL, 10)
+        .unwrap()
+        .is_empty());
+    assert_eq!(
+        message_store.truncated_before(DEFAULT_CHANNEL).unwrap(),
+        Some(3)
+    );
+    let message = message_store
+        .append(ChatMessage::new(DEFAULT_CHANNEL, "message 3"))
+        .unwrap();
+    assert_eq!(message.sequence_number, 3);
+}
+
+#[test]
+fn segment_file_store_compacts_segments() {
+    let directory = tempfile::tempdir().unwrap();
+    let options = SegmentFileOptions {
+        // Small enough to only fit one message per segment.
+        max_segment_size: 64,
+        fsync_policy: FsyncPolicy::Always,
+    };
+
+    {
+        let message_store =
+            SegmentFileMessageStore::open(directory.path(), options.clone()).unwrap();
+        append_messages_on_two_channels(&message_store);
+        truncate_and_append(&message_store);
+        assert_eq!(segment_files(directory.path()).len(), 4);
+
+        message_store.compact().unwrap();
+        // Only the segment of the truncated first message became empty.
+        assert_eq!(segment_files(directory.path()).len(), 3);
+        assert_truncated(&message_store);
+    }
+
+    let message_store = SegmentFileMessageStore::open(directory.path(), options).unwrap();
+    assert_truncated(&message_store);
+    // Only the requested number of the most recent messages is read.
+    assert_eq!(
+        message_store
+            .recent_message_ids(DEFAULT_CHANNEL, 1)
+            .unwrap()
+            .len(),
+        1
+    );
+    assert_eq!(
+        message_store
+            .recent_message_ids(DEFAULT_CHANNEL, 10)
+            .unwrap()
+            .len(),
+        2
+    );
+    let message = message_store
+        .append(ChatMessage::new(DEFAULT_CHANNEL, "message 4"))
+        .unwrap();
+    assert_eq!(message.sequence_number, 4);
+    insta::assert_debug_snapshot!(message_texts(&message_store, DEFAULT_CHANNEL), @r###"
+    [
+        (
+            2,
+            "message 2",
+        ),
+        (
+            3,
+            "message 3",
+        ),
+        (
+            4,
+            "message 4",
+        ),
+    ]
+    "###);
+}
+
+#[test]
+fn open_storage_backends() {
+    let directory = tempfile::tempdir().unwrap();
+
+    for backend in ["memory", "segment-files", "redb"] {
+        let backend: StorageBackend = backend.parse().unwrap();
+        let message_store = open_message_store(
+            backend,
+            Some(directory.path()),
+            SegmentFileOptions::default(),
+        )
+        .unwrap();
+
+        append_messages_on_two_channels(message_store.as_ref());
+        assert_eq!(
+            message_texts(message_store.as_ref(), DEFAULT_CHANNEL).len(),
+            2
+        );
+    }
+
+    assert!("sqlite".parse::<StorageBackend>().is_err());
+    assert!(open_message_store(StorageBackend::Redb, None, SegmentFileOptions::default()).is_err());
+}
+
+#[test]
+fn segment_file_store_spans_multiple_segments() {
+    let directory = tempfile::tempdir().unwrap();
+    let options = SegmentFileOptions {
+        // Small enough to only fit one message per segment.
+        max_segment_size: 64,
+        fsync_policy: FsyncPolicy::EveryNMessages(2),
+    };
+    let message_store = SegmentFileMessageStore::open(directory.path(), options).unwrap();
+
+    for i in 1..=3 {
+        message_store
+            .append(ChatMessage::new(DEFAULT_CHANNEL, format!("message {i}")))
+            .unwrap();
+    }
+    message_store
+        .append(ChatMessage::new("some-other-channel", "unrelated message"))
+        .unwrap();
+
+    assert_eq!(segment_files(directory.path()).len(), 4);
+    insta::assert_debug_snapshot!(message_texts(&message_store, DEFAULT_CHANNEL), @r###"
+    [
+        (
+            1,
+            "message 1",
+        ),
+        (
+            2,
+            "message 2",
+        ),
+        (
+            3,
+            "message 3",
+        ),
+    ]
+    "###);
+
+    let messages_after_first = message_store
+        .messages_after(DEFAULT_CHANNEL, 1, Some(1))
+        .unwrap();
+    assert_eq!(messages_after_first.len(), 1);
+    assert_eq!(messages_after_first[0].sequence_number, 2);
+}
+
+#[test]
+fn segment_file_store_truncates_partial_record_on_reopen() {
+    let directory = tempfile::tempdir().unwrap();
+
+    {
+        let message_store =
+            SegmentFileMessageStore::open(directory.path(), SegmentFileOptions::default()).unwrap();
+        message_store
+            .append(ChatMessage::new(DEFAULT_CHANNEL, "message 1"))
+            .unwrap();
+        message_store
+            .append(ChatMessage::new(DEFAULT_CHANNEL, "message 2"))
+            .unwrap();
+    }
+
+    // Append the beginning of a record, as left behind by a process that crashed while appending.
+    let segment_file = segment_files(directory.path()).pop().unwrap();
+    let intact_size = segment_file.metadata().unwrap().len();
+    OpenOptions::new()
+        .append(true)
+        .open(&segment_file)
+        .unwrap()
+        .write_all(&[42, 0, 0, 0, 1, 2, 3, 4, b'{'])
+        .unwrap();
+
+    let message_store =
+        SegmentFileMessageStore::open(directory.path(), SegmentFileOptions::default()).unwrap();
+    assert_eq!(segment_file.metadata().unwrap().len(), intact_size);
+
+    message_store
+        .append(ChatMessage::new(DEFAULT_CHANNEL, "message 3"))
+        .unwrap();
+    insta::assert_debug_snapshot!(message_texts(&message_store, DEFAULT_CHANNEL), @r###"
+    [
+        (
+            1,
+            "message 1",
+        ),
+        (
+            2,
+            "message 2",
+        ),
+        (
+            3,
+            "message 3",
+        ),
+    ]
+    "###);
+}
+
+#[test]
+fn segment_file_store_rejects_corrupted_segment() {
+    let directory = tempfile::tempdir().unwrap();
+    let options = SegmentFileOptions {
+        max_segment_size: 64,
+        fsync_policy: FsyncPolicy::Always,
+    };
+
+    {
+        let message_store =
+            SegmentFileMessageStore::open(directory.path(), options.clone()).unwrap();
+        message_store
+            .append(ChatMessage::new(DEFAULT_CHANNEL, "message 1"))
+            .unwrap();
+        message_store
+            .append(ChatMessage::new(DEFAULT_CHANNEL, "message 2"))
+            .unwrap();
+    }
+
+    // Only the last segment may end with a torn write.
+    let first_segment_file = segment_files(directory.path()).remove(0);
+    OpenOptions::new()
+        .append(true)
+        .open(first_segment_file)
+        .unwrap()
+        .write_all(&[0])
+        .unwrap();
+
+    assert!(SegmentFileMessageStore::open(directory.path(), options).is_err());
+}
+
+#[tokio::test]
+async fn message_log_keeps_messages_after_restart() {
+    let directory = tempfile::tempdir().unwrap();
+    let message = ChatMessage::new(DEFAULT_CHANNEL, "message 1");
+
+    {
+        let message_store =
+            SegmentFileMessageStore::open(directory.path(), SegmentFileOptions::default()).unwrap();
+        let test_message_stream = TestMessageStream::new(vec![message.clone()]).boxed();
+        let _message_log = MessageLog::new(
+            Arc::new(message_store),
+            test_message_stream,
+            ItemErrorPolicy::Abort,
+        )
+        .unwrap();
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+
+    let message_store =
+        SegmentFileMessageStore::open(directory.path(), SegmentFileOptions::default()).unwrap();
+    // The message is received again after the restart, e.g. because it was still in flight.
+    let test_message_stream = TestMessageStream::new(vec![
+        message,
+        ChatMessage::new(DEFAULT_CHANNEL, "message 2"),
+    ])
+    .boxed();
+    let message_log = MessageLog::new(
+        Arc::new(message_store),
+        test_message_stream,
+        ItemErrorPolicy::Abort,
+    )
+    .unwrap();
+
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let message_texts: Vec<_> = message_log
+        .messages_received(DEFAULT_CHANNEL)
+        .unwrap()
+        .into_iter()
+        .map(|message| (message.sequence_number, message.chat_message.message_text))
+        .collect();
+    insta::assert_debug_snapshot!(message_texts, @r###"
+    [
+        (
+            1,
+            "message 1",
+        ),
+        (
+            2,
+            "message 2",
+        ),
+    ]
+    "###);
+}
@@ -0,0 +1,95 @@
+use std::sync::Arc;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use futures::StreamExt;
+use redis::{aio::MultiplexedConnection, AsyncCommands};
+use tokio::sync::Mutex;
+
+use crate::{ChatMessage, ChatMessageStream, ReceivedMessage, ReceivedMessageStream};
+
+/// Publishes messages to their channel on the message broker.
+#[async_trait]
+pub trait ChannelPublisher: Send + Sync {
+    async fn publish(&self, chat_message: &ChatMessage) -> Result<()>;
+}
+
+pub struct RedisChannelPublisher {
+    redis_client: redis::Client,
+    /// Established on the first publish and reused afterwards.
+    connection: Mutex<Option<MultiplexedConnection>>,
+}
+
+impl RedisChannelPublisher {
+    pub fn new(redis_url: &str) -> Result<Self> {
+        Ok(RedisChannelPublisher {
+            redis_client: redis::Client::open(redis_url)?,
+            connection: Default::default(),
+        })
+    }
+}
+
+#[async_trait]
+impl ChannelPublisher for RedisChannelPublisher {
+    async fn publish(&self, chat_message: &ChatMessage) -> Result<()> {
+        let payload = serde_json::to_string(chat_message)?;
+
+        let mut connection_guard = self.connection.lock().await;
+        let connection = match connection_guard.as_mut() {
+            Some(connection) => connection,
+            None => {
+                connection_guard.insert(self.redis_client.get_multiplexed_tokio_connection().await?)
+            }
+        };
+
+        let result: redis::RedisResult<()> =
+            connection.publish(&chat_message.channel, payload).await;
+        if result.is_err() {
+            // Reconnect on the next publish.
+            *connection_guard = None;
+        }
+
+        Ok(result?)
+    }
+}
+
+/// Republishes the plain text payloads of the stream as [`ChatMessage`]s with a new id, so that
+/// every receiver gets them with the same id, and drops them from the stream; the republished
+/// messages are received like any other.
+///
+/// Only one receiver may republish a channel's plain text payloads, otherwise they are published
+/// more than once. `republishes` tells whether this receiver is the one for the given channel.
+pub fn republish_plain_text<F>(
+    stream: ReceivedMessageStream,
+    channel_publisher: Arc<dyn ChannelPublisher>,
+    republishes: F,
+) -> ChatMessageStream
+where
+    F: Fn(&str) -> bool + Send + Sync + 'static,
+{
+    let republishes = Arc::new(republishes);
+
+    Box::pin(stream.filter_map(move |received_message| {
+        let channel_publisher = Arc::clone(&channel_publisher);
+        let republishes = Arc::clone(&republishes);
+        async move {
+            let (channel, message_text) = match received_message {
+                Ok(ReceivedMessage::Chat(chat_message)) => return Some(Ok(chat_message)),
+                Ok(ReceivedMessage::PlainText {
+                    channel,
+                    message_text,
+                }) => (channel, message_text),
+                Err(err) => return Some(Err(err)),
+            };
+            if !republishes(&channel) {
+                return None;
+            }
+
+            let chat_message = ChatMessage::new(channel, message_text);
+            if let Err(err) = channel_publisher.publish(&chat_message).await {
+                println!("Republishing a plain text message failed: {err:#}");
+            }
+            None
+        }
+    }))
+}
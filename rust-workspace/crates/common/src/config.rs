@@ -0,0 +1,228 @@
+//! Configuration of the binaries, assembled from several layers, each overriding the previous ones:
+//! 1. the defaults of the configuration type,
+//! 2. a TOML file, given via `--config <file>` or the environment variable `<PREFIX>_CONFIG`,
+//! 3. environment variables named `<PREFIX>__<SECTION>__<KEY>`, e.g.
+//!    `CHAT_SERVER__MESSAGE_BROKER__URL`,
+//! 4. command line flags of the form `--set <section>.<key>=<value>`, e.g.
+//!    `--set message_broker.url=redis://localhost:6379`.
+//!
+//! Values given via environment variables or flags are parsed as TOML values if possible (e.g.
+//! `8000`, `true` or `["a", "b"]`) and taken as strings otherwise, or if the setting cannot be
+//! deserialized from the parsed value (e.g. a name like `42`); they must not contain line breaks.
+//! The assembled configuration is validated before it is used, so that misconfigurations surface
+//! at startup.
+
+use std::{net::SocketAddr, path::PathBuf};
+
+use anyhow::{anyhow, bail, Context, Result};
+use clap::Parser;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use toml::{Table, Value};
+
+/// A configuration that can be assembled by [`load_config`].
+pub trait Config: Default + Serialize + DeserializeOwned {
+    /// The prefix of the environment variables configuring the binary, e.g. `CHAT_SERVER`.
+    const ENV_PREFIX: &'static str;
+
+    /// Checks constraints that cannot be expressed by the types of the settings.
+    fn validate(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// The command line flags of a binary.
+#[derive(Debug, Default, Parser)]
+#[command(
+    about = "Settings are read from a TOML file, environment variables and the flags below, \
+each overriding the previous ones."
+)]
+pub struct ConfigArgs {
+    /// A TOML file to read the configuration from.
+    #[arg(long, value_name = "FILE")]
+    pub config: Option<PathBuf>,
+    /// Overrides a setting of the configuration file, e.g. `server.bind_address=127.0.0.1:8000`.
+    #[arg(long = "set", value_name = "KEY=VALUE")]
+    pub overrides: Vec<String>,
+}
+
+/// Where the HTTP server of a binary listens.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ServerConfig {
+    pub bind_address: SocketAddr,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            bind_address: ([0, 0, 0, 0], 8000).into(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MessageBrokerConfig {
+    /// The URL of the redis instance used as message broker.
+    pub url: String,
+}
+
+impl Default for MessageBrokerConfig {
+    fn default() -> Self {
+        MessageBrokerConfig {
+            url: "redis://message-broker-service:6379".to_string(),
+        }
+    }
+}
+
+impl MessageBrokerConfig {
+    pub fn validate(&self) -> Result<()> {
+        redis::Client::open(self.url.as_str())
+            .with_context(|| format!("invalid message broker URL {:?}", self.url))?;
+
+        Ok(())
+    }
+}
+
+/// Assembles the configuration from the command line flags and environment variables of the
+/// process, see the [module documentation](self).
+pub fn load_config<C: Config>() -> Result<C> {
+    load_config_from(&ConfigArgs::parse(), std::env::vars())
+}
+
+/// Like [`load_config`], but with the given command line flags and environment variables.
+pub fn load_config_from<C: Config>(
+    args: &ConfigArgs,
+    env_vars: impl IntoIterator<Item = (String, String)>,
+) -> Result<C> {
+    let env_vars: Vec<(String, String)> = env_vars.into_iter().collect();
+    let mut settings = Table::try_from(C::default())?;
+
+    let config_file = args.config.clone().or_else(|| {
+        let config_file_var = format!("{}_CONFIG", C::ENV_PREFIX);
+        env_vars
+            .iter()
+            .find(|(name, _)| *name == config_file_var)
+            .map(|(_, value)| PathBuf::from(value))
+    });
+    if let Some(config_file) = config_file {
+        let content = std::fs::read_to_string(&config_file)
+            .with_context(|| format!("cannot read configuration file {config_file:?}"))?;
+        let file_settings: Table = toml::from_str(&content)
+            .with_context(|| format!("invalid configuration file {config_file:?}"))?;
+        merge(&mut settings, file_settings);
+    }
+
+    // Every override along with the context of errors.
+    let mut overrides = Vec::new();
+    let env_var_prefix = format!("{}__", C::ENV_PREFIX);
+    for (name, value) in &env_vars {
+        if let Some(key) = name.strip_prefix(&env_var_prefix) {
+            let key: Vec<String> = key.split("__").map(str::to_lowercase).collect();
+            overrides.push((
+                key,
+                value.clone(),
+                format!("invalid environment variable {name}"),
+            ));
+        }
+    }
+    for setting in &args.overrides {
+        let (key, value) = setting
+            .split_once('=')
+            .ok_or_else(|| anyhow!("expected KEY=VALUE, got {setting:?}"))?;
+        let key: Vec<String> = key.trim().split('.').map(str::to_string).collect();
+        overrides.push((
+            key,
+            value.trim().to_string(),
+            format!("invalid setting {setting:?}"),
+        ));
+    }
+    for (key, value, context) in &overrides {
+        parse_value(value)
+            .and_then(|value| set(&mut settings, key, value))
+            .context(context.clone())?;
+    }
+
+    let config: C = deserialize(settings, &overrides).context("invalid configuration")?;
+    config.validate().context("invalid configuration")?;
+
+    Ok(config)
+}
+
+/// Deserializes the settings. Overrides are parsed as TOML values, so if a setting cannot be
+/// deserialized from such a value, e.g. a name consisting of digits, the override is taken as a
+/// plain string instead.
+fn deserialize<C: Config>(
+    mut settings: Table,
+    overrides: &[(Vec<String>, String, String)],
+) -> Result<C> {
+    let mut retried = vec![false; overrides.len()];
+    loop {
+        let err = match serde_path_to_error::deserialize(Value::Table(settings.clone())) {
+            Ok(config) => return Ok(config),
+            Err(err) => err,
+        };
+        let path: Vec<String> = err.path().iter().map(ToString::to_string).collect();
+        // Later overrides of the same setting take precedence.
+        let Some(index) = overrides.iter().rposition(|(key, _, _)| *key == path) else {
+            return Err(err.into_inner().into());
+        };
+        let (key, value, _) = &overrides[index];
+        if retried[index] || matches!(parse_value(value)?, Value::String(_)) {
+            return Err(err.into_inner().into());
+        }
+
+        retried[index] = true;
+        set(&mut settings, key, Value::String(value.clone()))?;
+    }
+}
+
+/// Recursively overrides the settings of `base` with those of `overrides`.
+fn merge(base: &mut Table, overrides: Table) {
+    for (key, value) in overrides {
+        match (base.get_mut(&key), value) {
+            (Some(Value::Table(base_table)), Value::Table(override_table)) => {
+                merge(base_table, override_table)
+            }
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+fn set(settings: &mut Table, key: &[String], value: Value) -> Result<()> {
+    match key {
+        [] => bail!("the key is empty"),
+        [name] => {
+            settings.insert(name.clone(), value);
+        }
+        [section_name, rest @ ..] => {
+            let section = settings
+                .entry(section_name.clone())
+                .or_insert_with(|| Value::Table(Table::new()));
+            match section {
+                Value::Table(section) => set(section, rest, value)?,
+                _ => bail!("{section_name} is not a section"),
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Parses the value of an override as a TOML value, or takes it as a string if it is none.
+fn parse_value(value: &str) -> Result<Value> {
+    // Line breaks would allow the value to add further settings.
+    if value.contains(['\n', '\r']) {
+        bail!("values must not contain line breaks");
+    }
+
+    let value = toml::from_str::<Table>(&format!("value = {value}"))
+        .ok()
+        .filter(|table| table.len() == 1)
+        .and_then(|mut table| table.remove("value"))
+        .unwrap_or_else(|| Value::String(value.to_string()));
+
+    Ok(value)
+}
@@ -0,0 +1,195 @@
+//! Tracking the health of tasks that forward the messages of a stream, e.g. the
+//! [`StreamForwarder`](crate::stream_forwarder::StreamForwarder).
+
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, Mutex,
+};
+
+use anyhow::{Error, Result};
+use serde::{Deserialize, Serialize};
+use tokio::task::JoinHandle;
+use utoipa::ToSchema;
+
+/// What to do if the stream yields an error instead of a message, e.g. because a message could not
+/// be deserialized.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ItemErrorPolicy {
+    /// Stop forwarding; the forwarder is [`ForwarderStatus::Failed`] afterwards.
+    #[default]
+    Abort,
+    /// Skip the erroneous item and keep forwarding; skipped items are counted.
+    Skip,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum ForwarderStatus {
+    Running,
+    /// The stream ended.
+    Finished,
+    /// Forwarding stopped because of an error, or the task panicked.
+    Failed {
+        error: String,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct ForwarderHealth {
+    #[serde(flatten)]
+    pub status: ForwarderStatus,
+    /// The number of messages received from the stream, including dropped ones.
+    pub received_messages: u64,
+    /// The number of messages in batches that the sink accepted.
+    pub forwarded_messages: u64,
+    /// The number of batches the forwarded messages were handed to the sink in.
+    pub forwarded_batches: u64,
+    /// The number of messages received but not yet forwarded.
+    pub queued_messages: u64,
+    /// How often a message was received while the queue was full, see
+    /// [`OverflowPolicy`](crate::ingestion_queue::OverflowPolicy).
+    pub queue_overflows: u64,
+    /// The number of messages dropped according to
+    /// [`OverflowPolicy::DropOldest`](crate::ingestion_queue::OverflowPolicy::DropOldest).
+    pub dropped_messages: u64,
+    /// The number of errors skipped according to [`ItemErrorPolicy::Skip`].
+    pub skipped_errors: u64,
+    /// The most recent skipped error.
+    pub last_skipped_error: Option<String>,
+}
+
+impl Default for ForwarderHealth {
+    fn default() -> Self {
+        ForwarderHealth {
+            status: ForwarderStatus::Running,
+            received_messages: 0,
+            forwarded_messages: 0,
+            forwarded_batches: 0,
+            queued_messages: 0,
+            queue_overflows: 0,
+            dropped_messages: 0,
+            skipped_errors: 0,
+            last_skipped_error: None,
+        }
+    }
+}
+
+/// Shared between a forwarder and its task, which records its progress.
+///
+/// The counters are atomics, since they are updated for every message; a snapshot of them via
+/// [`health`](Self::health) is therefore not necessarily consistent across counters.
+#[derive(Clone, Default)]
+pub struct ForwarderHealthTracker {
+    health: Arc<TrackedHealth>,
+}
+
+struct TrackedHealth {
+    status: Mutex<ForwarderStatus>,
+    received_messages: AtomicU64,
+    forwarded_messages: AtomicU64,
+    forwarded_batches: AtomicU64,
+    queued_messages: AtomicU64,
+    queue_overflows: AtomicU64,
+    dropped_messages: AtomicU64,
+    skipped_errors: AtomicU64,
+    last_skipped_error: Mutex<Option<String>>,
+}
+
+impl Default for TrackedHealth {
+    fn default() -> Self {
+        TrackedHealth {
+            status: Mutex::new(ForwarderStatus::Running),
+            received_messages: Default::default(),
+            forwarded_messages: Default::default(),
+            forwarded_batches: Default::default(),
+            queued_messages: Default::default(),
+            queue_overflows: Default::default(),
+            dropped_messages: Default::default(),
+            skipped_errors: Default::default(),
+            last_skipped_error: Default::default(),
+        }
+    }
+}
+
+impl ForwarderHealthTracker {
+    pub fn record_received_message(&self) {
+        self.health
+            .received_messages
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_forwarded_batch(&self, batch_size: usize) {
+        self.health
+            .forwarded_messages
+            .fetch_add(batch_size as u64, Ordering::Relaxed);
+        self.health
+            .forwarded_batches
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_queued_messages(&self, queued_messages: usize) {
+        self.health
+            .queued_messages
+            .store(queued_messages as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_queue_overflow(&self) {
+        self.health.queue_overflows.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_dropped_message(&self) {
+        self.health.dropped_messages.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Handles an erroneous item according to the policy: either records it as skipped or returns
+    /// it, so that forwarding can be aborted.
+    pub fn handle_item_error(&self, item_error_policy: ItemErrorPolicy, err: Error) -> Result<()> {
+        match item_error_policy {
+            ItemErrorPolicy::Abort => Err(err),
+            ItemErrorPolicy::Skip => {
+                println!("Skipping erroneous message: {err}");
+                *self.health.last_skipped_error.lock().unwrap() = Some(err.to_string());
+                self.health.skipped_errors.fetch_add(1, Ordering::Relaxed);
+
+                Ok(())
+            }
+        }
+    }
+
+    /// Records the outcome of the forwarding task.
+    pub fn record_result(&self, result: &Result<()>) {
+        *self.health.status.lock().unwrap() = match result {
+            Ok(()) => ForwarderStatus::Finished,
+            Err(err) => {
+                println!("Forwarding messages failed: {err}");
+                ForwarderStatus::Failed {
+                    error: err.to_string(),
+                }
+            }
+        };
+    }
+
+    /// Returns the health of the forwarding task run by `join_handle`.
+    pub fn health<T>(&self, join_handle: &JoinHandle<T>) -> ForwarderHealth {
+        let health = &self.health;
+        let mut health = ForwarderHealth {
+            status: health.status.lock().unwrap().clone(),
+            received_messages: health.received_messages.load(Ordering::Relaxed),
+            forwarded_messages: health.forwarded_messages.load(Ordering::Relaxed),
+            forwarded_batches: health.forwarded_batches.load(Ordering::Relaxed),
+            queued_messages: health.queued_messages.load(Ordering::Relaxed),
+            queue_overflows: health.queue_overflows.load(Ordering::Relaxed),
+            dropped_messages: health.dropped_messages.load(Ordering::Relaxed),
+            skipped_errors: health.skipped_errors.load(Ordering::Relaxed),
+            last_skipped_error: health.last_skipped_error.lock().unwrap().clone(),
+        };
+        // The task did not get to record its result.
+        if health.status == ForwarderStatus::Running && join_handle.is_finished() {
+            health.status = ForwarderStatus::Failed {
+                error: "the forwarding task panicked".to_string(),
+            };
+        }
+
+        health
+    }
+}
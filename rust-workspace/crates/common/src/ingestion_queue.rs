@@ -0,0 +1,160 @@
+//! The bounded queue between receiving messages from a stream and handing them to a sink, see
+//! [`StreamForwarder`](crate::stream_forwarder::StreamForwarder).
+//!
+//! Messages are handed to the sink in batches, so that a burst of messages does not acquire the
+//! sink's lock once per message. If the sink cannot keep up, the queue fills up and the
+//! [`OverflowPolicy`] determines what happens to further messages.
+
+use std::{collections::VecDeque, sync::Mutex};
+
+use anyhow::{anyhow, bail, Result};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Notify;
+
+use crate::forwarder_health::ForwarderHealthTracker;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct IngestionConfig {
+    /// The maximum number of messages that have been received but not yet handed to the sink.
+    pub queue_capacity: usize,
+    /// The maximum number of messages handed to the sink at once.
+    pub max_batch_size: usize,
+    pub overflow_policy: OverflowPolicy,
+}
+
+impl Default for IngestionConfig {
+    fn default() -> Self {
+        IngestionConfig {
+            queue_capacity: 1024,
+            max_batch_size: 64,
+            overflow_policy: OverflowPolicy::default(),
+        }
+    }
+}
+
+/// What to do with a received message if the queue is full.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OverflowPolicy {
+    /// Stop receiving messages until the sink has caught up, which slows down the stream.
+    #[default]
+    Block,
+    /// Drop the oldest queued message to make room; dropped messages are counted.
+    DropOldest,
+    /// Stop forwarding; the forwarder is
+    /// [`ForwarderStatus::Failed`](crate::forwarder_health::ForwarderStatus::Failed) afterwards.
+    Disconnect,
+}
+
+pub(crate) struct IngestionQueue<T> {
+    config: IngestionConfig,
+    state: Mutex<QueueState<T>>,
+    message_available: Notify,
+    space_available: Notify,
+    health_tracker: ForwarderHealthTracker,
+}
+
+struct QueueState<T> {
+    messages: VecDeque<T>,
+    /// Set once either side stops; no further messages are accepted then.
+    is_closed: bool,
+}
+
+impl IngestionConfig {
+    pub fn validate(&self) -> Result<()> {
+        if self.queue_capacity == 0 || self.max_batch_size == 0 {
+            bail!("the queue capacity and the maximum batch size must be positive");
+        }
+
+        Ok(())
+    }
+}
+
+impl<T> IngestionQueue<T> {
+    pub(crate) fn new(config: IngestionConfig, health_tracker: ForwarderHealthTracker) -> Self {
+        IngestionQueue {
+            config,
+            state: Mutex::new(QueueState {
+                messages: VecDeque::new(),
+                is_closed: false,
+            }),
+            message_available: Notify::new(),
+            space_available: Notify::new(),
+            health_tracker,
+        }
+    }
+
+    /// Queues the message, handling a full queue according to the [`OverflowPolicy`].
+    ///
+    /// Fails if the queue has been closed or overflowed with [`OverflowPolicy::Disconnect`].
+    pub(crate) async fn push(&self, message: T) -> Result<()> {
+        let mut message = Some(message);
+        loop {
+            // Registers for notifications before checking, so that none is missed.
+            let space_available = self.space_available.notified();
+            {
+                let mut state = self.state.lock().unwrap();
+                if state.is_closed {
+                    return Err(anyhow!("the ingestion queue has been closed"));
+                }
+
+                let is_full = state.messages.len() >= self.config.queue_capacity;
+                if is_full {
+                    self.health_tracker.record_queue_overflow();
+                }
+                if !is_full || self.config.overflow_policy == OverflowPolicy::DropOldest {
+                    if is_full && state.messages.pop_front().is_some() {
+                        self.health_tracker.record_dropped_message();
+                    }
+                    state.messages.extend(message.take());
+                    self.health_tracker
+                        .record_queued_messages(state.messages.len());
+                    self.message_available.notify_one();
+
+                    return Ok(());
+                }
+                if self.config.overflow_policy == OverflowPolicy::Disconnect {
+                    return Err(anyhow!(
+                        "more than {} messages were waiting to be forwarded",
+                        self.config.queue_capacity
+                    ));
+                }
+            }
+
+            space_available.await;
+        }
+    }
+
+    /// Waits for messages and takes up to [`IngestionConfig::max_batch_size`] of them, or returns
+    /// `None` once the queue has been closed and drained.
+    pub(crate) async fn pop_batch(&self) -> Option<Vec<T>> {
+        loop {
+            let message_available = self.message_available.notified();
+            {
+                let mut state = self.state.lock().unwrap();
+                if !state.messages.is_empty() {
+                    let batch_size = state.messages.len().min(self.config.max_batch_size.max(1));
+                    let batch: Vec<T> = state.messages.drain(..batch_size).collect();
+                    self.health_tracker
+                        .record_queued_messages(state.messages.len());
+                    self.space_available.notify_one();
+
+                    return Some(batch);
+                }
+                if state.is_closed {
+                    return None;
+                }
+            }
+
+            message_available.await;
+        }
+    }
+
+    /// Stops accepting messages; the queued messages can still be taken.
+    pub(crate) fn close(&self) {
+        self.state.lock().unwrap().is_closed = true;
+        self.message_available.notify_one();
+        self.space_available.notify_one();
+    }
+}
@@ -1,34 +1,135 @@
-use std::pin::Pin;
+use std::{fmt, pin::Pin};
 
-use anyhow::Result;
+use anyhow::{bail, Result};
 use futures::Stream;
 use redis::Msg;
 use serde::{Deserialize, Serialize};
+use ulid::Ulid;
+use utoipa::ToSchema;
 
-pub mod stream_to_vec_forwarder;
+pub mod channel_publisher;
+pub mod config;
+pub mod forwarder_health;
+pub mod ingestion_queue;
+pub mod message_sink;
+pub mod recent_message_ids;
+pub mod sharding;
+pub mod snapshot;
+pub mod stream_forwarder;
+
+#[cfg(test)]
+mod tests;
 
 pub static DEFAULT_CHANNEL: &str = "default-channel";
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+/// Globally unique identifier of a [`ChatMessage`], assigned once when the message is created.
+///
+/// ULIDs are lexicographically sortable by their creation time.
+pub type MessageId = Ulid;
+
+#[derive(Clone, Debug, Serialize, Deserialize, ToSchema)]
 pub struct ChatMessage {
+    #[schema(value_type = String, example = "01ARZ3NDEKTSV4RRFFQ69G5FAV")]
+    pub id: MessageId,
     pub channel: String,
     pub message_text: String,
 }
 
 impl ChatMessage {
+    /// Creates a new message with a freshly generated id.
     pub fn new<S1: Into<String>, S2: Into<String>>(channel: S1, message_text: S2) -> Self {
         ChatMessage {
+            id: MessageId::new(),
             channel: channel.into(),
             message_text: message_text.into(),
         }
     }
 }
 
+/// Position of a message within its channel in the replication log.
+///
+/// Sequence numbers are strictly increasing per channel, starting at 1.
+pub type SequenceNumber = u64;
+
+/// The HTTP header by which the replication log reports that messages it was asked for have been
+/// removed, e.g. by a retention policy; its value is the sequence number from which on messages are
+/// retained.
+pub const TRUNCATED_BEFORE_HEADER: &str = "Truncated-Before";
+
+/// Some of the requested messages have been removed from the replication log, e.g. by a retention
+/// policy, so they cannot be retrieved anymore.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MessagesRemoved {
+    /// The sequence number from which on the channel's messages are retained.
+    pub truncated_before: SequenceNumber,
+}
+
+impl fmt::Display for MessagesRemoved {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "the messages before sequence number {} have been removed",
+            self.truncated_before
+        )
+    }
+}
+
+impl std::error::Error for MessagesRemoved {}
+
+/// A [`ChatMessage`] together with its position in the replication log.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SequencedMessage {
+    pub sequence_number: SequenceNumber,
+    #[serde(flatten)]
+    pub chat_message: ChatMessage,
+}
+
 pub type ChatMessageStream = Pin<Box<dyn Stream<Item = Result<ChatMessage>> + Send>>;
 
-pub fn chat_message_from_redis_msg(msg: Msg) -> Result<ChatMessage> {
-    Ok(ChatMessage {
-        channel: msg.get_channel()?,
-        message_text: msg.get_payload()?,
-    })
+/// A message received from the message broker, see [`received_message_from_redis_msg`].
+#[derive(Clone, Debug)]
+pub enum ReceivedMessage {
+    /// A JSON-serialized [`ChatMessage`].
+    Chat(ChatMessage),
+    /// A plain text payload (e.g. published manually via `redis-cli`), which carries no id.
+    PlainText {
+        channel: String,
+        message_text: String,
+    },
+}
+
+pub type ReceivedMessageStream = Pin<Box<dyn Stream<Item = Result<ReceivedMessage>> + Send>>;
+
+/// Messages are published to the message broker as JSON-serialized [`ChatMessage`]s.
+///
+/// Plain text payloads are accepted as well, see [`ReceivedMessage::PlainText`].
+///
+/// Fails if the message names another channel than the one it was published to, so that a
+/// message cannot end up in a channel that its publisher did not publish it to.
+pub fn received_message_from_redis_msg(msg: Msg) -> Result<ReceivedMessage> {
+    let channel: String = msg.get_channel()?;
+    let payload: String = msg.get_payload()?;
+
+    match serde_json::from_str::<ChatMessage>(&payload) {
+        Ok(chat_message) if chat_message.channel != channel => bail!(
+            "message {} for channel {:?} was published to channel {channel:?}",
+            chat_message.id,
+            chat_message.channel
+        ),
+        Ok(chat_message) => Ok(ReceivedMessage::Chat(chat_message)),
+        Err(_) => Ok(ReceivedMessage::PlainText {
+            channel,
+            message_text: payload,
+        }),
+    }
+}
+
+/// Like [`received_message_from_redis_msg`], but skips plain text payloads: every receiver would
+/// assign them another id. The replication log republishes them as [`ChatMessage`]s instead, see
+/// [`channel_publisher::republish_plain_text`].
+pub fn chat_message_from_redis_msg(msg: Msg) -> Result<Option<ChatMessage>> {
+    match received_message_from_redis_msg(msg)? {
+        ReceivedMessage::Chat(chat_message) => Ok(Some(chat_message)),
+        ReceivedMessage::PlainText { .. } => Ok(None),
+    }
 }
@@ -0,0 +1,175 @@
+//! Destinations that the messages of a stream are forwarded to, see
+//! [`StreamForwarder`](crate::stream_forwarder::StreamForwarder).
+//!
+//! Sinks compose: a shared sink is an `Arc<Mutex<S>>`, a [`FanOutSink`] forwards to several sinks
+//! and a [`ClosureSink`] adapts anything else, e.g. a storage backend or a metrics counter. Sinks
+//! that perform blocking I/O are wrapped in a [`BlockingSink`].
+
+use std::sync::{Arc, Mutex};
+
+use anyhow::Result;
+use tokio::sync::broadcast;
+
+/// Receives forwarded messages. Failing to receive a message stops forwarding.
+pub trait MessageSink<T>: Send + 'static {
+    fn send(&mut self, message: T) -> Result<()>;
+
+    /// Receives several messages at once. Sinks that need to acquire a lock or similar should
+    /// override this to do so once per batch.
+    fn send_batch(&mut self, messages: Vec<T>) -> Result<()> {
+        for message in messages {
+            self.send(message)?;
+        }
+
+        Ok(())
+    }
+
+    /// Whether sending blocks the thread, e.g. on disk I/O, so that it must not run on the async
+    /// runtime's worker threads, see [`BlockingSink`].
+    fn is_blocking(&self) -> bool {
+        false
+    }
+}
+
+impl<T: Send + 'static> MessageSink<T> for Vec<T> {
+    fn send(&mut self, message: T) -> Result<()> {
+        self.push(message);
+
+        Ok(())
+    }
+
+    fn send_batch(&mut self, messages: Vec<T>) -> Result<()> {
+        self.extend(messages);
+
+        Ok(())
+    }
+}
+
+/// Shares a sink with others, e.g. to read the messages of a `Vec` while they are forwarded.
+impl<T, S: MessageSink<T>> MessageSink<T> for Arc<Mutex<S>> {
+    fn send(&mut self, message: T) -> Result<()> {
+        self.lock().unwrap().send(message)
+    }
+
+    fn send_batch(&mut self, messages: Vec<T>) -> Result<()> {
+        self.lock().unwrap().send_batch(messages)
+    }
+}
+
+impl<T: Send + 'static> MessageSink<T> for broadcast::Sender<T> {
+    fn send(&mut self, message: T) -> Result<()> {
+        // Fails only if nobody is listening, which is fine.
+        let _ = broadcast::Sender::send(self, message);
+
+        Ok(())
+    }
+}
+
+pub struct ClosureSink<F> {
+    send: F,
+}
+
+impl<F> ClosureSink<F> {
+    pub fn new<T>(send: F) -> Self
+    where
+        F: FnMut(T) -> Result<()>,
+    {
+        ClosureSink { send }
+    }
+}
+
+impl<T, F: FnMut(T) -> Result<()> + Send + 'static> MessageSink<T> for ClosureSink<F> {
+    fn send(&mut self, message: T) -> Result<()> {
+        (self.send)(message)
+    }
+}
+
+/// Marks a sink as blocking, so that batches are sent to it on tokio's blocking thread pool.
+pub struct BlockingSink<S> {
+    sink: S,
+}
+
+impl<S> BlockingSink<S> {
+    pub fn new(sink: S) -> Self {
+        BlockingSink { sink }
+    }
+}
+
+impl<T, S: MessageSink<T>> MessageSink<T> for BlockingSink<S> {
+    fn send(&mut self, message: T) -> Result<()> {
+        self.sink.send(message)
+    }
+
+    fn send_batch(&mut self, messages: Vec<T>) -> Result<()> {
+        self.sink.send_batch(messages)
+    }
+
+    fn is_blocking(&self) -> bool {
+        true
+    }
+}
+
+/// Sends every message to each of its sinks, in the order they were added, before sending the next
+/// message of a batch.
+pub struct FanOutSink<T> {
+    sinks: Vec<Box<dyn MessageSink<T>>>,
+}
+
+impl<T> Default for FanOutSink<T> {
+    fn default() -> Self {
+        FanOutSink { sinks: Vec::new() }
+    }
+}
+
+impl<T> FanOutSink<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_sink<S: MessageSink<T>>(mut self, sink: S) -> Self {
+        self.sinks.push(Box::new(sink));
+        self
+    }
+}
+
+impl<T: Clone + Send + 'static> MessageSink<T> for FanOutSink<T> {
+    fn send(&mut self, message: T) -> Result<()> {
+        for sink in &mut self.sinks {
+            sink.send(message.clone())?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Sends every message to a shared sink and then to `sender`, while the shared sink is still
+/// locked. So whoever subscribes to the sender while holding the lock receives exactly the
+/// messages that are not yet in the shared sink.
+pub struct NotifyingSink<S, T> {
+    sink: Arc<Mutex<S>>,
+    sender: broadcast::Sender<T>,
+}
+
+impl<S, T> NotifyingSink<S, T> {
+    pub fn new(sink: Arc<Mutex<S>>, sender: broadcast::Sender<T>) -> Self {
+        NotifyingSink { sink, sender }
+    }
+}
+
+impl<T: Clone + Send + 'static, S: MessageSink<T>> MessageSink<T> for NotifyingSink<S, T> {
+    fn send(&mut self, message: T) -> Result<()> {
+        let mut sink = self.sink.lock().unwrap();
+        MessageSink::send(&mut self.sender, message.clone())?;
+
+        sink.send(message)
+    }
+
+    fn send_batch(&mut self, messages: Vec<T>) -> Result<()> {
+        let mut sink = self.sink.lock().unwrap();
+        for message in &messages {
+            MessageSink::send(&mut self.sender, message.clone())?;
+        }
+
+        sink.send_batch(messages)
+    }
+}
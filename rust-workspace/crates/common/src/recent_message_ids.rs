@@ -0,0 +1,67 @@
+//! Remembering the ids of recently seen messages, to drop messages that are received more than
+//! once.
+
+use std::collections::{HashSet, VecDeque};
+
+use crate::MessageId;
+
+/// The number of ids remembered by default, see [`RecentMessageIds::new`].
+pub const DEFAULT_CAPACITY: usize = 10_000;
+
+/// A set of the most recently inserted message ids, bounded by its capacity: once it is full,
+/// inserting an id forgets the id that was inserted first.
+///
+/// Duplicates are received shortly after each other (e.g. once from the replication log and once
+/// live), so a message is only passed on twice if more than `capacity` other messages are received
+/// in between.
+#[derive(Clone, Debug)]
+pub struct RecentMessageIds {
+    capacity: usize,
+    ids: HashSet<MessageId>,
+    /// The remembered ids in the order they were inserted.
+    insertion_order: VecDeque<MessageId>,
+}
+
+impl Default for RecentMessageIds {
+    fn default() -> Self {
+        Self::new(DEFAULT_CAPACITY)
+    }
+}
+
+impl RecentMessageIds {
+    pub fn new(capacity: usize) -> Self {
+        RecentMessageIds {
+            capacity: capacity.max(1),
+            ids: HashSet::new(),
+            insertion_order: VecDeque::new(),
+        }
+    }
+
+    /// Remembers the id, returning whether it was not remembered yet (like [`HashSet::insert`]).
+    pub fn insert(&mut self, id: MessageId) -> bool {
+        if !self.ids.insert(id) {
+            return false;
+        }
+
+        self.insertion_order.push_back(id);
+        if self.insertion_order.len() > self.capacity {
+            if let Some(oldest_id) = self.insertion_order.pop_front() {
+                self.ids.remove(&oldest_id);
+            }
+        }
+
+        true
+    }
+
+    pub fn contains(&self, id: &MessageId) -> bool {
+        self.ids.contains(id)
+    }
+
+    pub fn len(&self) -> usize {
+        self.ids.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ids.is_empty()
+    }
+}
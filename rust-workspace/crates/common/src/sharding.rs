@@ -0,0 +1,127 @@
+//! Partitioning channels across several replication log shards by consistent hashing.
+//!
+//! Every shard is placed on a hash ring at several points (virtual nodes); a channel belongs to the
+//! shard owning the first point at or after the channel's hash. Adding a shard therefore only moves
+//! the channels that now hash to one of its points, roughly `1 / shard count` of them, and every
+//! moved channel moves to the new shard.
+//!
+//! The hash function is fixed, so that every service assigns channels to the same shards.
+
+use std::collections::BTreeMap;
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Shard {
+    /// Identifies the shard on the hash ring; changing it moves the shard's channels.
+    pub id: String,
+    /// The base URL of the shard's HTTP API.
+    pub url: String,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ShardMap {
+    pub shards: Vec<Shard>,
+    /// The number of points per shard on the hash ring; more points spread channels more evenly.
+    #[serde(default = "default_virtual_nodes")]
+    pub virtual_nodes: usize,
+}
+
+fn default_virtual_nodes() -> usize {
+    64
+}
+
+impl ShardMap {
+    pub fn new(shards: Vec<Shard>) -> Self {
+        ShardMap {
+            shards,
+            virtual_nodes: default_virtual_nodes(),
+        }
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if self.shards.is_empty() {
+            bail!("the shard map must contain at least one shard");
+        }
+        for (i, shard) in self.shards.iter().enumerate() {
+            if self.shards[..i].iter().any(|other| other.id == shard.id) {
+                bail!("shard {:?} is listed more than once", shard.id);
+            }
+            url::Url::parse(&shard.url)
+                .with_context(|| format!("invalid URL of shard {:?}", shard.id))?;
+        }
+        if self.virtual_nodes == 0 {
+            bail!("the number of virtual nodes must be positive");
+        }
+
+        Ok(())
+    }
+
+    pub fn shard(&self, shard_id: &str) -> Option<&Shard> {
+        self.shards.iter().find(|shard| shard.id == shard_id)
+    }
+}
+
+/// Assigns channels to the shards of a [`ShardMap`].
+#[derive(Clone, Debug)]
+pub struct HashRing {
+    shard_map: ShardMap,
+    /// The points of every shard on the ring, mapped to the shard's index.
+    points: BTreeMap<u64, usize>,
+}
+
+impl HashRing {
+    /// # Panics
+    ///
+    /// If the map contains no shards, see [`ShardMap::validate`].
+    pub fn new(shard_map: ShardMap) -> Self {
+        assert!(
+            !shard_map.shards.is_empty(),
+            "the shard map contains no shards"
+        );
+
+        let mut points = BTreeMap::new();
+        for (shard_index, shard) in shard_map.shards.iter().enumerate() {
+            for virtual_node in 0..shard_map.virtual_nodes.max(1) {
+                let point = hash(format!("{}#{virtual_node}", shard.id).as_bytes());
+                // On the unlikely collision, the shard listed first wins, on every instance alike.
+                points.entry(point).or_insert(shard_index);
+            }
+        }
+
+        HashRing { shard_map, points }
+    }
+
+    pub fn shard_map(&self) -> &ShardMap {
+        &self.shard_map
+    }
+
+    /// Returns the shard that stores the channel's messages.
+    pub fn shard_for(&self, channel: &str) -> &Shard {
+        let channel_hash = hash(channel.as_bytes());
+        let (_, &shard_index) = self
+            .points
+            .range(channel_hash..)
+            .next()
+            .or_else(|| self.points.iter().next())
+            .unwrap();
+
+        &self.shard_map.shards[shard_index]
+    }
+}
+
+/// 64-bit FNV-1a, followed by the SplitMix64 finalizer to spread similar inputs across the ring.
+fn hash(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for &byte in bytes {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+
+    hash = (hash ^ (hash >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    hash = (hash ^ (hash >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    hash ^ (hash >> 31)
+}
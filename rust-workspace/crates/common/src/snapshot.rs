@@ -0,0 +1,53 @@
+//! The format in which the replication log hands out snapshots of a channel's recent history.
+//!
+//! A snapshot is encoded as gzip-compressed JSON, so that a new chat-server instance can download
+//! the recent history of a channel with a single, compact request and then only has to retrieve
+//! the messages after the snapshot's watermark.
+
+use std::io::Read;
+
+use anyhow::{bail, Result};
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use serde::{Deserialize, Serialize};
+
+use crate::{SequenceNumber, SequencedMessage};
+
+/// The media type of an encoded snapshot.
+pub const SNAPSHOT_CONTENT_TYPE: &str = "application/gzip";
+
+/// The most recent messages of a channel up to a certain point.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ChannelSnapshot {
+    pub channel: String,
+    /// The sequence number of the last message that the snapshot accounts for; newer messages
+    /// have to be retrieved from the replication log.
+    pub watermark: SequenceNumber,
+    /// The most recent messages up to and including the watermark, ordered by their sequence
+    /// number. Older messages are omitted.
+    pub messages: Vec<SequencedMessage>,
+}
+
+impl ChannelSnapshot {
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        serde_json::to_writer(&mut encoder, self)?;
+
+        Ok(encoder.finish()?)
+    }
+
+    /// Decodes a snapshot of the given channel.
+    pub fn decode(channel: &str, encoded_snapshot: &[u8]) -> Result<Self> {
+        let mut json = Vec::new();
+        GzDecoder::new(encoded_snapshot).read_to_end(&mut json)?;
+
+        let snapshot: ChannelSnapshot = serde_json::from_slice(&json)?;
+        if snapshot.channel != channel {
+            bail!(
+                "expected a snapshot of channel {channel:?}, got one of {:?}",
+                snapshot.channel
+            );
+        }
+
+        Ok(snapshot)
+    }
+}
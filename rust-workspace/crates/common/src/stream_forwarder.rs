@@ -0,0 +1,164 @@
+use std::pin::Pin;
+
+use anyhow::Result;
+use futures::{
+    future::{self, Either},
+    Stream, StreamExt,
+};
+use stream_cancel::{Trigger, Tripwire};
+use tokio::task::JoinHandle;
+
+use crate::{
+    forwarder_health::{ForwarderHealth, ForwarderHealthTracker, ItemErrorPolicy},
+    ingestion_queue::{IngestionConfig, IngestionQueue},
+    message_sink::MessageSink,
+};
+
+pub struct StreamForwarder {
+    /// The handle of the forwarding task, used to detect whether it panicked.
+    message_reception_worker_handle: JoinHandle<()>,
+    health_tracker: ForwarderHealthTracker,
+    /// When this is dropped, the stream is cancelled and we stop forwarding.
+    _stream_cancellation_trigger: Trigger,
+}
+
+impl StreamForwarder {
+    /// Asynchronously sends every message of the stream to the sink, using the default
+    /// [`IngestionConfig`].
+    ///
+    /// Erroneous items of the stream are handled according to `item_error_policy`, whereas an
+    /// error of the sink always stops forwarding.
+    ///
+    /// Will automatically stop forwarding when dropped.
+    pub fn new<T: Send + 'static, S: MessageSink<T>>(
+        incoming_message_stream: Pin<Box<dyn Stream<Item = Result<T>> + Send>>,
+        sink: S,
+        item_error_policy: ItemErrorPolicy,
+    ) -> Self {
+        Self::with_ingestion_config(
+            incoming_message_stream,
+            sink,
+            item_error_policy,
+            IngestionConfig::default(),
+        )
+    }
+
+    /// Like [`StreamForwarder::new`], but received messages are queued and handed to the sink in
+    /// batches as configured, see the [`ingestion_queue`](crate::ingestion_queue) module.
+    pub fn with_ingestion_config<T: Send + 'static, S: MessageSink<T>>(
+        incoming_message_stream: Pin<Box<dyn Stream<Item = Result<T>> + Send>>,
+        sink: S,
+        item_error_policy: ItemErrorPolicy,
+        ingestion_config: IngestionConfig,
+    ) -> Self {
+        use stream_cancel::StreamExt;
+        let (stream_cancellation_trigger, tripwire) = Tripwire::new();
+        let cancellable_stream = incoming_message_stream.take_until_if(tripwire);
+
+        let health_tracker = ForwarderHealthTracker::default();
+        let task_health_tracker = health_tracker.clone();
+        let join_handle = tokio::spawn(async move {
+            let ingestion_queue =
+                IngestionQueue::new(ingestion_config, task_health_tracker.clone());
+            let receiving = receive_messages(
+                Box::pin(cancellable_stream),
+                &ingestion_queue,
+                item_error_policy,
+                &task_health_tracker,
+            );
+            let forwarding = forward_messages(&ingestion_queue, sink, &task_health_tracker);
+            futures::pin_mut!(receiving, forwarding);
+
+            let result = match future::select(receiving, forwarding).await {
+                // The queued messages are still forwarded, even if receiving failed.
+                Either::Left((receiving_result, forwarding)) => {
+                    forwarding.await.and(receiving_result)
+                }
+                // Forwarding only stops early if the sink fails.
+                Either::Right((forwarding_result, _)) => forwarding_result,
+            };
+            task_health_tracker.record_result(&result);
+        });
+
+        Self {
+            message_reception_worker_handle: join_handle,
+            health_tracker,
+            _stream_cancellation_trigger: stream_cancellation_trigger,
+        }
+    }
+
+    /// Whether messages are still being forwarded, along with some statistics.
+    pub fn health(&self) -> ForwarderHealth {
+        self.health_tracker
+            .health(&self.message_reception_worker_handle)
+    }
+}
+
+/// Queues the messages of the stream, closing the queue once the stream ends or fails.
+async fn receive_messages<T>(
+    mut incoming_message_stream: Pin<Box<dyn Stream<Item = Result<T>> + Send>>,
+    ingestion_queue: &IngestionQueue<T>,
+    item_error_policy: ItemErrorPolicy,
+    health_tracker: &ForwarderHealthTracker,
+) -> Result<()> {
+    let result = async {
+        while let Some(msg) = incoming_message_stream.next().await {
+            match msg {
+                Ok(msg) => {
+                    health_tracker.record_received_message();
+                    ingestion_queue.push(msg).await?;
+                }
+                Err(err) => health_tracker.handle_item_error(item_error_policy, err)?,
+            }
+        }
+
+        Ok(())
+    }
+    .await;
+    ingestion_queue.close();
+
+    result
+}
+
+/// Sends the queued messages to the sink in batches, until the queue is closed and drained.
+///
+/// Batches are sent to [blocking](MessageSink::is_blocking) sinks on the blocking thread pool.
+async fn forward_messages<T: Send + 'static, S: MessageSink<T>>(
+    ingestion_queue: &IngestionQueue<T>,
+    mut sink: S,
+    health_tracker: &ForwarderHealthTracker,
+) -> Result<()> {
+    while let Some(batch) = ingestion_queue.pop_batch().await {
+        let batch_size = batch.len();
+        let result = if sink.is_blocking() {
+            let sending = tokio::task::spawn_blocking(move || {
+                let result = sink.send_batch(batch);
+                (sink, result)
+            });
+            match sending.await {
+                Ok((returned_sink, result)) => {
+                    sink = returned_sink;
+                    result
+                }
+                // The sink panicked and is gone.
+                Err(err) => {
+                    ingestion_queue.close();
+                    return Err(err.into());
+                }
+            }
+        } else {
+            sink.send_batch(batch)
+        };
+        if let Err(err) = result {
+            ingestion_queue.close();
+            return Err(err);
+        }
+        health_tracker.record_forwarded_batch(batch_size);
+
+        // A sink is usually guarded by a blocking lock, so give other tasks a chance to acquire it
+        // between batches.
+        tokio::task::yield_now().await;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,216 @@
+use std::io::Write;
+
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::config::{load_config_from, Config, ConfigArgs, MessageBrokerConfig, ServerConfig};
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TestConfig {
+    server: ServerConfig,
+    message_broker: MessageBrokerConfig,
+    channels: Vec<String>,
+    page_size: usize,
+    node_name: Option<String>,
+}
+
+impl Config for TestConfig {
+    const ENV_PREFIX: &'static str = "TEST";
+
+    fn validate(&self) -> Result<()> {
+        self.message_broker.validate()?;
+        if self.page_size > 1000 {
+            bail!("the page size must not exceed 1000");
+        }
+
+        Ok(())
+    }
+}
+
+fn config_file(content: &str) -> tempfile::NamedTempFile {
+    let mut file = tempfile::NamedTempFile::new().unwrap();
+    file.write_all(content.as_bytes()).unwrap();
+    file
+}
+
+fn env_vars(env_vars: &[(&str, &str)]) -> Vec<(String, String)> {
+    env_vars
+        .iter()
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .collect()
+}
+
+#[test]
+fn use_defaults_without_configuration() {
+    let config: TestConfig = load_config_from(&ConfigArgs::default(), Vec::new()).unwrap();
+
+    insta::assert_debug_snapshot!(config, @r###"
+    TestConfig {
+        server: ServerConfig {
+            bind_address: 0.0.0.0:8000,
+        },
+        message_broker: MessageBrokerConfig {
+            url: "redis://message-broker-service:6379",
+        },
+        channels: [],
+        page_size: 0,
+        node_name: None,
+    }
+    "###);
+}
+
+#[test]
+fn override_settings_layer_by_layer() {
+    let file = config_file(
+        r#"
+        channels = ["from-file"]
+        page_size = 10
+
+        [server]
+        bind_address = "127.0.0.1:9000"
+
+        [message_broker]
+        url = "redis://from-file:6379"
+        "#,
+    );
+    let args = ConfigArgs {
+        config: Some(file.path().to_path_buf()),
+        overrides: vec!["page_size=30".to_string()],
+    };
+    let env_vars = env_vars(&[
+        ("TEST__MESSAGE_BROKER__URL", "redis://from-env:6379"),
+        ("TEST__CHANNELS", r#"["from-env", "another-channel"]"#),
+        ("TEST__PAGE_SIZE", "20"),
+        ("UNRELATED__PAGE_SIZE", "40"),
+    ]);
+
+    let config: TestConfig = load_config_from(&args, env_vars).unwrap();
+
+    insta::assert_debug_snapshot!(config, @r###"
+    TestConfig {
+        server: ServerConfig {
+            bind_address: 127.0.0.1:9000,
+        },
+        message_broker: MessageBrokerConfig {
+            url: "redis://from-env:6379",
+        },
+        channels: [
+            "from-env",
+            "another-channel",
+        ],
+        page_size: 30,
+        node_name: None,
+    }
+    "###);
+}
+
+#[test]
+fn read_the_configuration_file_given_by_the_environment() {
+    let file = config_file("page_size = 10");
+    let env_vars = env_vars(&[("TEST_CONFIG", file.path().to_str().unwrap())]);
+
+    let config: TestConfig = load_config_from(&ConfigArgs::default(), env_vars).unwrap();
+
+    assert_eq!(config.page_size, 10);
+}
+
+#[test]
+fn take_values_as_strings_if_they_cannot_be_deserialized_otherwise() {
+    let load_with = |overrides: &[&str], env_vars: Vec<(String, String)>| {
+        let args = ConfigArgs {
+            config: None,
+            overrides: overrides
+                .iter()
+                .map(|setting| setting.to_string())
+                .collect(),
+        };
+        let config: TestConfig = load_config_from(&args, env_vars).unwrap();
+        (config.node_name, config.page_size)
+    };
+
+    insta::assert_debug_snapshot!(
+        load_with(&["page_size=20"], env_vars(&[("TEST__NODE_NAME", "42")])),
+        @r###"
+    (
+        Some(
+            "42",
+        ),
+        20,
+    )
+    "###
+    );
+    insta::assert_debug_snapshot!(load_with(&["node_name=2024-01-01"], Vec::new()), @r###"
+    (
+        Some(
+            "2024-01-01",
+        ),
+        0,
+    )
+    "###);
+}
+
+#[test]
+fn take_the_last_override_of_a_setting_as_string() {
+    let args = ConfigArgs {
+        config: None,
+        overrides: vec!["node_name=7".to_string()],
+    };
+    let env_vars = env_vars(&[("TEST__NODE_NAME", "node-1"), ("TEST__PAGE_SIZE", "5")]);
+
+    let config: TestConfig = load_config_from(&args, env_vars).unwrap();
+
+    assert_eq!(config.node_name.as_deref(), Some("7"));
+    assert_eq!(config.page_size, 5);
+}
+
+#[test]
+fn reject_line_breaks_in_environment_variables() {
+    let env_vars = env_vars(&[(
+        "TEST__NODE_NAME",
+        "\"a\"\n[server]\nbind_address = \"127.0.0.1:1\"",
+    )]);
+
+    let err = load_config_from::<TestConfig>(&ConfigArgs::default(), env_vars).unwrap_err();
+
+    insta::assert_snapshot!(format!("{err:#}"), @"invalid environment variable TEST__NODE_NAME: values must not contain line breaks");
+}
+
+#[test]
+fn reject_invalid_configurations() {
+    let load_with = |overrides: &[&str]| {
+        let args = ConfigArgs {
+            config: None,
+            overrides: overrides
+                .iter()
+                .map(|setting| setting.to_string())
+                .collect(),
+        };
+        let err = load_config_from::<TestConfig>(&args, Vec::new()).unwrap_err();
+        format!("{err:#}")
+    };
+
+    insta::assert_snapshot!(load_with(&["page_size=2000"]), @"invalid configuration: the page size must not exceed 1000");
+    insta::assert_snapshot!(load_with(&["page_size=many"]), @r###"
+    invalid configuration: invalid type: string "many", expected usize
+    in `page_size`
+
+    "###);
+    insta::assert_snapshot!(load_with(&["server.bind_addres=127.0.0.1:9000"]), @r###"
+    invalid configuration: unknown field `bind_addres`, expected `bind_address`
+    in `server`
+
+    "###);
+    insta::assert_snapshot!(load_with(&["message_broker.url=localhost"]), @r###"
+    invalid configuration: invalid message broker URL "localhost": Redis URL did not parse
+    "###);
+    insta::assert_snapshot!(load_with(&["page_size.limit=10"]), @r###"
+    invalid setting "page_size.limit=10": page_size is not a section
+    "###);
+    insta::assert_snapshot!(load_with(&["node_name=\"a\"\npage_size = 2000"]), @r###"
+    invalid setting "node_name=\"a\"\npage_size = 2000": values must not contain line breaks
+    "###);
+    insta::assert_snapshot!(load_with(&["page_size"]), @r###"
+    expected KEY=VALUE, got "page_size"
+    "###);
+}
@@ -0,0 +1,142 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use futures::StreamExt;
+
+use crate::{
+    forwarder_health::{ForwarderHealth, ItemErrorPolicy},
+    ingestion_queue::{IngestionConfig, OverflowPolicy},
+    stream_forwarder::StreamForwarder,
+};
+
+/// Forwards the numbers 1 to 10, which are all received before the first batch is forwarded.
+async fn forward_numbers(ingestion_config: IngestionConfig) -> (Vec<u32>, ForwarderHealth) {
+    let numbers = Arc::new(Mutex::new(Vec::new()));
+    let forwarder = StreamForwarder::with_ingestion_config(
+        futures::stream::iter((1..=10).map(Ok)).boxed(),
+        Arc::clone(&numbers),
+        ItemErrorPolicy::Abort,
+        ingestion_config,
+    );
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let numbers = numbers.lock().unwrap().clone();
+    (numbers, forwarder.health())
+}
+
+#[tokio::test]
+async fn forward_messages_in_batches() {
+    let (numbers, health) = forward_numbers(IngestionConfig {
+        queue_capacity: 100,
+        max_batch_size: 4,
+        overflow_policy: OverflowPolicy::Block,
+    })
+    .await;
+
+    assert_eq!(numbers, (1..=10).collect::<Vec<_>>());
+    insta::assert_debug_snapshot!(health, @r###"
+    ForwarderHealth {
+        status: Finished,
+        received_messages: 10,
+        forwarded_messages: 10,
+        forwarded_batches: 3,
+        queued_messages: 0,
+        queue_overflows: 0,
+        dropped_messages: 0,
+        skipped_errors: 0,
+        last_skipped_error: None,
+    }
+    "###);
+}
+
+#[tokio::test]
+async fn block_while_the_queue_is_full() {
+    let (numbers, health) = forward_numbers(IngestionConfig {
+        queue_capacity: 3,
+        max_batch_size: 2,
+        overflow_policy: OverflowPolicy::Block,
+    })
+    .await;
+
+    assert_eq!(numbers, (1..=10).collect::<Vec<_>>());
+    insta::assert_debug_snapshot!(health, @r###"
+    ForwarderHealth {
+        status: Finished,
+        received_messages: 10,
+        forwarded_messages: 10,
+        forwarded_batches: 5,
+        queued_messages: 0,
+        queue_overflows: 4,
+        dropped_messages: 0,
+        skipped_errors: 0,
+        last_skipped_error: None,
+    }
+    "###);
+}
+
+#[tokio::test]
+async fn drop_the_oldest_messages_if_the_queue_is_full() {
+    let (numbers, health) = forward_numbers(IngestionConfig {
+        queue_capacity: 3,
+        max_batch_size: 2,
+        overflow_policy: OverflowPolicy::DropOldest,
+    })
+    .await;
+
+    insta::assert_debug_snapshot!(numbers, @r###"
+    [
+        8,
+        9,
+        10,
+    ]
+    "###);
+    insta::assert_debug_snapshot!(health, @r###"
+    ForwarderHealth {
+        status: Finished,
+        received_messages: 10,
+        forwarded_messages: 3,
+        forwarded_batches: 2,
+        queued_messages: 0,
+        queue_overflows: 7,
+        dropped_messages: 7,
+        skipped_errors: 0,
+        last_skipped_error: None,
+    }
+    "###);
+}
+
+#[tokio::test]
+async fn disconnect_if_the_queue_is_full() {
+    let (numbers, health) = forward_numbers(IngestionConfig {
+        queue_capacity: 3,
+        max_batch_size: 2,
+        overflow_policy: OverflowPolicy::Disconnect,
+    })
+    .await;
+
+    // The messages queued before the overflow are still forwarded.
+    insta::assert_debug_snapshot!(numbers, @r###"
+    [
+        1,
+        2,
+        3,
+    ]
+    "###);
+    insta::assert_debug_snapshot!(health, @r###"
+    ForwarderHealth {
+        status: Failed {
+            error: "more than 3 messages were waiting to be forwarded",
+        },
+        received_messages: 4,
+        forwarded_messages: 3,
+        forwarded_batches: 2,
+        queued_messages: 0,
+        queue_overflows: 1,
+        dropped_messages: 0,
+        skipped_errors: 0,
+        last_skipped_error: None,
+    }
+    "###);
+}
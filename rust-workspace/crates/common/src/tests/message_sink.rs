@@ -0,0 +1,189 @@
+use std::{
+    pin::Pin,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+use futures::{Stream, StreamExt};
+use tokio::sync::broadcast;
+
+use crate::{
+    forwarder_health::ItemErrorPolicy,
+    message_sink::{BlockingSink, ClosureSink, FanOutSink, MessageSink, NotifyingSink},
+    stream_forwarder::StreamForwarder,
+};
+
+fn message_stream(
+    messages: Vec<Result<&'static str>>,
+) -> Pin<Box<dyn Stream<Item = Result<&'static str>> + Send>> {
+    futures::stream::iter(messages).boxed()
+}
+
+#[tokio::test]
+async fn fan_out_to_several_sinks() {
+    let messages = Arc::new(Mutex::new(Vec::new()));
+    let (sender, mut receiver) = broadcast::channel(16);
+    let message_count = Arc::new(Mutex::new(0));
+    let counter = Arc::clone(&message_count);
+
+    let sink = FanOutSink::new()
+        .with_sink(Arc::clone(&messages))
+        .with_sink(sender)
+        .with_sink(ClosureSink::new(move |_message| {
+            *counter.lock().unwrap() += 1;
+            Ok(())
+        }));
+    let _forwarder = StreamForwarder::new(
+        message_stream(vec![Ok("first message"), Ok("second message")]),
+        sink,
+        ItemErrorPolicy::Abort,
+    );
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    insta::assert_debug_snapshot!(messages.lock().unwrap(), @r###"
+    [
+        "first message",
+        "second message",
+    ]
+    "###);
+    insta::assert_debug_snapshot!(
+        (receiver.recv().await.unwrap(), receiver.recv().await.unwrap()),
+        @r###"
+    (
+        "first message",
+        "second message",
+    )
+    "###
+    );
+    assert_eq!(*message_count.lock().unwrap(), 2);
+}
+
+#[tokio::test]
+async fn stop_forwarding_if_the_sink_fails() {
+    let messages = Arc::new(Mutex::new(Vec::new()));
+    let sink = FanOutSink::new()
+        .with_sink(ClosureSink::new(|message| match message {
+            "unstorable message" => Err(anyhow!("cannot store {message}")),
+            _ => Ok(()),
+        }))
+        .with_sink(Arc::clone(&messages));
+
+    let forwarder = StreamForwarder::new(
+        message_stream(vec![
+            Ok("first message"),
+            Err(anyhow!("malformed message")),
+            Ok("unstorable message"),
+            Ok("last message"),
+        ]),
+        sink,
+        ItemErrorPolicy::Skip,
+    );
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    insta::assert_debug_snapshot!(messages.lock().unwrap(), @r###"
+    [
+        "first message",
+    ]
+    "###);
+    insta::assert_debug_snapshot!(forwarder.health(), @r###"
+    ForwarderHealth {
+        status: Failed {
+            error: "cannot store unstorable message",
+        },
+        received_messages: 3,
+        forwarded_messages: 0,
+        forwarded_batches: 0,
+        queued_messages: 0,
+        queue_overflows: 0,
+        dropped_messages: 0,
+        skipped_errors: 1,
+        last_skipped_error: Some(
+            "malformed message",
+        ),
+    }
+    "###);
+}
+
+#[tokio::test]
+async fn notify_while_the_shared_sink_is_locked() {
+    let messages = Arc::new(Mutex::new(vec!["previous message"]));
+    let (sender, _) = broadcast::channel(16);
+    let mut sink = NotifyingSink::new(Arc::clone(&messages), sender.clone());
+
+    // Snapshot the sink and subscribe to new messages atomically.
+    let (previous_messages, mut receiver) = {
+        let messages = messages.lock().unwrap();
+        (messages.clone(), sender.subscribe())
+    };
+    sink.send("new message").unwrap();
+
+    insta::assert_debug_snapshot!(previous_messages, @r###"
+    [
+        "previous message",
+    ]
+    "###);
+    insta::assert_debug_snapshot!(receiver.recv().await.unwrap(), @r###"
+    "new message"
+    "###);
+    insta::assert_debug_snapshot!(messages.lock().unwrap(), @r###"
+    [
+        "previous message",
+        "new message",
+    ]
+    "###);
+}
+
+#[tokio::test]
+async fn stop_forwarding_when_dropped() {
+    let messages = Arc::new(Mutex::new(Vec::new()));
+    let (sender, receiver) = broadcast::channel(16);
+    let stream = broadcast_stream(receiver);
+
+    let forwarder = StreamForwarder::new(stream, Arc::clone(&messages), ItemErrorPolicy::Abort);
+    sender.send("forwarded message").unwrap();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    drop(forwarder);
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    // Fails if the stream, and thus the receiver, has been dropped.
+    assert!(sender.send("dropped message").is_err());
+    insta::assert_debug_snapshot!(messages.lock().unwrap(), @r###"
+    [
+        "forwarded message",
+    ]
+    "###);
+}
+
+fn broadcast_stream(
+    receiver: broadcast::Receiver<&'static str>,
+) -> Pin<Box<dyn Stream<Item = Result<&'static str>> + Send>> {
+    futures::stream::unfold(receiver, |mut receiver| async move {
+        let message = receiver.recv().await.ok()?;
+        Some((Ok(message), receiver))
+    })
+    .boxed()
+}
+
+#[tokio::test]
+async fn send_to_blocking_sinks_on_another_thread() {
+    let test_thread = std::thread::current().id();
+    let sending_threads = Arc::new(Mutex::new(Vec::new()));
+    let threads = Arc::clone(&sending_threads);
+
+    let sink = BlockingSink::new(ClosureSink::new(move |_message| {
+        threads.lock().unwrap().push(std::thread::current().id());
+        Ok(())
+    }));
+    let forwarder = StreamForwarder::new(
+        message_stream(vec![Ok("first message"), Ok("second message")]),
+        sink,
+        ItemErrorPolicy::Abort,
+    );
+
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(forwarder.health().forwarded_messages, 2);
+    let sending_threads = sending_threads.lock().unwrap();
+    assert_eq!(sending_threads.len(), 2);
+    assert!(sending_threads.iter().all(|&thread| thread != test_thread));
+}
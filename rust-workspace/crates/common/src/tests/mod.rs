@@ -0,0 +1,7 @@
+mod config;
+mod ingestion_queue;
+mod message_sink;
+mod recent_message_ids;
+mod redis_msg;
+mod sharding;
+mod snapshot;
@@ -0,0 +1,22 @@
+use crate::{recent_message_ids::RecentMessageIds, MessageId};
+
+#[test]
+fn forgets_the_ids_inserted_first_once_full() {
+    let ids: Vec<_> = (0..4).map(|_| MessageId::new()).collect();
+    let mut recent_message_ids = RecentMessageIds::new(3);
+
+    assert!(recent_message_ids.insert(ids[0]));
+    assert!(recent_message_ids.insert(ids[1]));
+    assert!(!recent_message_ids.insert(ids[0]));
+    assert!(recent_message_ids.insert(ids[2]));
+    assert!(recent_message_ids.insert(ids[3]));
+
+    assert_eq!(recent_message_ids.len(), 3);
+    assert!(!recent_message_ids.contains(&ids[0]));
+    assert!(recent_message_ids.contains(&ids[1]));
+    assert!(recent_message_ids.contains(&ids[3]));
+
+    // Forgotten ids are considered new again.
+    assert!(recent_message_ids.insert(ids[0]));
+    assert!(!recent_message_ids.contains(&ids[1]));
+}
@@ -0,0 +1,118 @@
+use std::sync::{Arc, Mutex};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use futures::StreamExt;
+use redis::{Msg, Value};
+
+use crate::{
+    channel_publisher::{republish_plain_text, ChannelPublisher},
+    chat_message_from_redis_msg, received_message_from_redis_msg, ChatMessage, MessageId,
+    ReceivedMessage, DEFAULT_CHANNEL,
+};
+
+fn redis_msg(channel: &str, payload: &str) -> Msg {
+    Msg::from_value(&Value::Bulk(vec![
+        Value::Data(b"message".to_vec()),
+        Value::Data(channel.as_bytes().to_vec()),
+        Value::Data(payload.as_bytes().to_vec()),
+    ]))
+    .unwrap()
+}
+
+#[test]
+fn accepts_json_and_plain_text_payloads() {
+    let chat_message = ChatMessage::new(DEFAULT_CHANNEL, "Hello!");
+    let payload = serde_json::to_string(&chat_message).unwrap();
+
+    let received_message = chat_message_from_redis_msg(redis_msg(DEFAULT_CHANNEL, &payload))
+        .unwrap()
+        .unwrap();
+    assert_eq!(received_message.id, chat_message.id);
+    assert_eq!(received_message.message_text, "Hello!");
+
+    let received_message =
+        received_message_from_redis_msg(redis_msg(DEFAULT_CHANNEL, "Hi!")).unwrap();
+    assert!(matches!(
+        received_message,
+        ReceivedMessage::PlainText { channel, message_text }
+            if channel == DEFAULT_CHANNEL && message_text == "Hi!"
+    ));
+    // Chat servers only receive plain text messages once they have been republished.
+    assert!(
+        chat_message_from_redis_msg(redis_msg(DEFAULT_CHANNEL, "Hi!"))
+            .unwrap()
+            .is_none()
+    );
+}
+
+/// Records the payloads of the published messages, like the message broker would deliver them.
+#[derive(Default)]
+struct RecordingPublisher {
+    payloads: Mutex<Vec<(String, String)>>,
+}
+
+#[async_trait]
+impl ChannelPublisher for RecordingPublisher {
+    async fn publish(&self, chat_message: &ChatMessage) -> Result<()> {
+        self.payloads.lock().unwrap().push((
+            chat_message.channel.clone(),
+            serde_json::to_string(chat_message)?,
+        ));
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn plain_text_messages_are_received_with_a_single_id() {
+    let publisher = Arc::new(RecordingPublisher::default());
+    let receive = |payload: &str, republishes: bool| {
+        let received_message = received_message_from_redis_msg(redis_msg(DEFAULT_CHANNEL, payload));
+        republish_plain_text(
+            futures::stream::iter([received_message]).boxed(),
+            Arc::clone(&publisher) as _,
+            move |_| republishes,
+        )
+        .map(Result::unwrap)
+        .collect::<Vec<_>>()
+    };
+
+    // The replication log republishes the plain text message instead of storing it, and a chat
+    // server ignores it.
+    assert!(receive("Hi!", true).await.is_empty());
+    assert!(
+        chat_message_from_redis_msg(redis_msg(DEFAULT_CHANNEL, "Hi!"))
+            .unwrap()
+            .is_none()
+    );
+    let payloads = publisher.payloads.lock().unwrap().clone();
+    assert_eq!(payloads.len(), 1);
+    let (channel, payload) = &payloads[0];
+    assert_eq!(channel, DEFAULT_CHANNEL);
+
+    // Both receive the republished message with the same id.
+    let log_messages = receive(payload, true).await;
+    let chat_server_message = chat_message_from_redis_msg(redis_msg(DEFAULT_CHANNEL, payload))
+        .unwrap()
+        .unwrap();
+    assert_eq!(log_messages.len(), 1);
+    assert_eq!(log_messages[0].id, chat_server_message.id);
+    assert_eq!(chat_server_message.message_text, "Hi!");
+    assert_eq!(publisher.payloads.lock().unwrap().len(), 1);
+
+    // A receiver that does not republish the channel drops the plain text message.
+    assert!(receive("Hi again!", false).await.is_empty());
+    assert_eq!(publisher.payloads.lock().unwrap().len(), 1);
+}
+
+#[test]
+fn rejects_messages_of_another_channel() {
+    let mut chat_message = ChatMessage::new("some-other-channel", "Hello!");
+    chat_message.id = MessageId::nil();
+    let payload = serde_json::to_string(&chat_message).unwrap();
+
+    let err = chat_message_from_redis_msg(redis_msg(DEFAULT_CHANNEL, &payload)).unwrap_err();
+    insta::assert_snapshot!(err.to_string(), @r###"
+    message 00000000000000000000000000 for channel "some-other-channel" was published to channel "default-channel"
+    "###);
+}
@@ -0,0 +1,100 @@
+use crate::sharding::{HashRing, Shard, ShardMap};
+
+fn shard_map(shard_count: usize) -> ShardMap {
+    ShardMap::new(
+        (1..=shard_count)
+            .map(|i| Shard {
+                id: format!("shard-{i}"),
+                url: format!("http://replication-log-shard-{i}:80"),
+            })
+            .collect(),
+    )
+}
+
+fn channels() -> Vec<String> {
+    (0..3000).map(|i| format!("channel-{i}")).collect()
+}
+
+#[test]
+fn channels_are_assigned_to_the_same_shards_everywhere() {
+    let hash_ring = HashRing::new(shard_map(3));
+
+    // Changing these assignments breaks every deployed shard map.
+    let assignments: Vec<_> = ["default-channel", "lobby", "channel-1", "channel-2"]
+        .into_iter()
+        .map(|channel| (channel, hash_ring.shard_for(channel).id.as_str()))
+        .collect();
+    insta::assert_debug_snapshot!(assignments, @r###"
+    [
+        (
+            "default-channel",
+            "shard-1",
+        ),
+        (
+            "lobby",
+            "shard-2",
+        ),
+        (
+            "channel-1",
+            "shard-2",
+        ),
+        (
+            "channel-2",
+            "shard-3",
+        ),
+    ]
+    "###);
+}
+
+#[test]
+fn channels_are_spread_evenly() {
+    let hash_ring = HashRing::new(shard_map(4));
+
+    for shard in &hash_ring.shard_map().shards {
+        let shard_channels = channels()
+            .iter()
+            .filter(|channel| hash_ring.shard_for(channel) == shard)
+            .count();
+        // Each shard gets a quarter of the channels, give or take a third.
+        assert!(
+            (500..=1000).contains(&shard_channels),
+            "{} has {shard_channels} channels",
+            shard.id
+        );
+    }
+}
+
+#[test]
+fn adding_a_shard_only_moves_channels_to_it() {
+    let old_hash_ring = HashRing::new(shard_map(3));
+    let new_hash_ring = HashRing::new(shard_map(4));
+
+    let mut moved_channels = 0;
+    for channel in channels() {
+        let old_shard = old_hash_ring.shard_for(&channel);
+        let new_shard = new_hash_ring.shard_for(&channel);
+        if old_shard != new_shard {
+            assert_eq!(new_shard.id, "shard-4");
+            moved_channels += 1;
+        }
+    }
+    assert!((500..=1000).contains(&moved_channels), "{moved_channels}");
+}
+
+#[test]
+fn shard_maps_are_validated() {
+    assert!(shard_map(2).validate().is_ok());
+    assert!(shard_map(0).validate().is_err());
+
+    let mut duplicate_shards = shard_map(2);
+    duplicate_shards.shards[1].id = "shard-1".to_string();
+    assert!(duplicate_shards.validate().is_err());
+
+    let mut relative_url = shard_map(2);
+    relative_url.shards[1].url = "shard-2".to_string();
+    assert!(relative_url.validate().is_err());
+
+    let mut without_virtual_nodes = shard_map(2);
+    without_virtual_nodes.virtual_nodes = 0;
+    assert!(without_virtual_nodes.validate().is_err());
+}
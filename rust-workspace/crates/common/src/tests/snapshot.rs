@@ -0,0 +1,30 @@
+use crate::{snapshot::ChannelSnapshot, ChatMessage, SequencedMessage, DEFAULT_CHANNEL};
+
+#[test]
+fn encoded_snapshots_are_compressed() {
+    let messages: Vec<_> = (1..=100)
+        .map(|sequence_number| SequencedMessage {
+            sequence_number,
+            chat_message: ChatMessage::new(DEFAULT_CHANNEL, "a message that repeats itself"),
+        })
+        .collect();
+    let snapshot = ChannelSnapshot {
+        channel: DEFAULT_CHANNEL.to_string(),
+        watermark: 100,
+        messages,
+    };
+
+    let encoded_snapshot = snapshot.encode().unwrap();
+    assert!(encoded_snapshot.len() < serde_json::to_vec(&snapshot).unwrap().len() / 2);
+
+    let decoded_snapshot = ChannelSnapshot::decode(DEFAULT_CHANNEL, &encoded_snapshot).unwrap();
+    assert_eq!(decoded_snapshot.watermark, 100);
+    assert_eq!(decoded_snapshot.messages.len(), 100);
+    assert_eq!(
+        decoded_snapshot.messages[99].chat_message.id,
+        snapshot.messages[99].chat_message.id
+    );
+
+    assert!(ChannelSnapshot::decode("some-other-channel", &encoded_snapshot).is_err());
+    assert!(ChannelSnapshot::decode(DEFAULT_CHANNEL, b"not a snapshot").is_err());
+}